@@ -0,0 +1,130 @@
+//! Write barriers, to order the blocks written back by `BlockCache`.
+
+use alloc::{string::String, sync::Arc};
+
+use spin::Mutex;
+
+use crate::{
+    block_cache::BlockCacheBuffer,
+    block_dev::{BlockDevice, BlockId},
+};
+
+/// Wraps a device to order its writes around barriers.
+///
+/// `BlockCache` writes a block back when it's synced or evicted, so the
+/// blocks modified one after another may reach the device in any order.
+/// Every write issued before a `barrier` is done before any write after
+/// it starts.
+pub struct BarrierBlockDevice {
+    dev:    Arc<dyn BlockDevice>,
+    /// Held by writes and barriers, so a barrier never lands in the
+    /// middle of a write.
+    writes: Mutex<()>,
+}
+
+impl BarrierBlockDevice {
+    pub fn new(dev: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            dev,
+            writes: Mutex::new(()),
+        }
+    }
+
+    /// Writes back the modified blocks in `cache`, then flushes the
+    /// device before any later write.
+    pub fn barrier(&self, cache: &Mutex<BlockCacheBuffer>) -> Result<(), String> {
+        cache.lock().flush();
+        let _writes = self.writes.lock();
+        self.dev.flush()
+    }
+}
+
+impl BlockDevice for BarrierBlockDevice {
+    fn read(&self, block_id: BlockId, buf: &mut [u8]) -> Result<(), String> {
+        self.dev.read(block_id, buf)
+    }
+
+    fn write(&self, block_id: BlockId, buf: &[u8]) -> Result<(), String> {
+        let _writes = self.writes.lock();
+        self.dev.write(block_id, buf)
+    }
+
+    fn read_many(&self, start: BlockId, bufs: &mut [&mut [u8]]) -> Result<(), String> {
+        self.dev.read_many(start, bufs)
+    }
+
+    fn write_many(&self, start: BlockId, bufs: &[&[u8]]) -> Result<(), String> {
+        let _writes = self.writes.lock();
+        self.dev.write_many(start, bufs)
+    }
+
+    fn supports_partial_write(&self) -> bool {
+        self.dev.supports_partial_write()
+    }
+
+    fn write_partial(&self, block_id: BlockId, offset: usize, buf: &[u8]) -> Result<(), String> {
+        let _writes = self.writes.lock();
+        self.dev.write_partial(block_id, offset, buf)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        let _writes = self.writes.lock();
+        self.dev.flush()
+    }
+
+    fn discard(&self, block_id: BlockId, count: usize) -> Result<(), String> {
+        let _writes = self.writes.lock();
+        self.dev.discard(block_id, count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::{
+        ram_disk::RamDisk,
+        recording::{Op, Record, RecordingBlockDevice},
+    };
+
+    /// Returns the blocks written by `records`, sorted.
+    fn written(records: &[Record]) -> Vec<BlockId> {
+        let mut blocks: Vec<_> = records
+            .iter()
+            .inspect(|r| assert_eq!(r.op, Op::Write))
+            .map(|r| r.block_id)
+            .collect();
+        blocks.sort();
+        blocks
+    }
+
+    fn modify(cache: &Mutex<BlockCacheBuffer>, block_id: BlockId, dev: &Arc<BarrierBlockDevice>) {
+        cache
+            .lock()
+            .get(block_id, dev.clone())
+            .lock()
+            .write(0, |value: &mut u64| *value += 1);
+    }
+
+    #[test]
+    fn test_barrier_orders_writes() {
+        let recorder = Arc::new(RecordingBlockDevice::new(Arc::new(RamDisk::new(16))));
+        let dev = Arc::new(BarrierBlockDevice::new(recorder.clone()));
+        let cache = Mutex::new(BlockCacheBuffer::new(8));
+
+        modify(&cache, 1, &dev);
+        modify(&cache, 2, &dev);
+        dev.barrier(&cache).unwrap();
+        modify(&cache, 3, &dev);
+        modify(&cache, 1, &dev);
+        cache.lock().flush();
+
+        // The blocks synced by a barrier come before it in any order.
+        let mut log = recorder.log();
+        log.retain(|r| r.op != Op::Read);
+        let barrier = log.iter().position(|r| r.op == Op::Flush).unwrap();
+        assert_eq!(written(&log[..barrier]), [1, 2]);
+        assert_eq!(written(&log[barrier + 1..]), [1, 3]);
+    }
+}
@@ -41,6 +41,7 @@ fn main() {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(true)
         .open(fs_name)
         .unwrap();
     fs_fd.set_len(FS_SIZE).unwrap();
@@ -75,6 +76,8 @@ fn main() {
             copy2(&fs, file_path, &mut bin_dir);
         }
     }
+
+    fs.close();
 }
 
 fn copy2(fs: &Arc<FileSystem>, src: &Path, dst: &mut MutexGuard<Inode>) {
@@ -98,7 +101,9 @@ fn copy2(fs: &Arc<FileSystem>, src: &Path, dst: &mut MutexGuard<Inode>) {
             break;
         }
 
-        fs.write_inode(&mut file, read_count, &buffer);
+        if let Err(err) = fs.write_inode_all(&mut file, read_count, &buffer[..offset]) {
+            panic!("failed to copy {}: {}", src.display(), err);
+        }
         read_count += offset;
     }
 }
@@ -1,62 +1,174 @@
-use core::mem::size_of;
-
-use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    mem::size_of,
+    ops::Range,
+    slice::{from_raw_parts, from_raw_parts_mut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[cfg(any(test, feature = "cache-debug"))]
+use alloc::{format, string::String};
+use alloc::{collections::VecDeque, sync::Arc, vec, vec::Vec};
+use log::{error, warn};
 use spin::Mutex;
 
+#[cfg(any(test, feature = "cache-debug"))]
+use crate::cache_debug::Holders;
 use crate::block_dev::{BlockDevice, BlockId, InBlockOffset, BLOCK_SIZE};
 
 /// The size of cache buffer.
 pub const BLOCK_BUFFER_SIZE: usize = 64;
 
+/// Counts the modified blocks not written back yet.
+#[derive(Default)]
+struct DirtyCounter {
+    count:      AtomicUsize,
+    high_water: AtomicUsize,
+    /// Warns when more blocks than this are dirty, 0 never warns.
+    threshold:  usize,
+}
+
+impl DirtyCounter {
+    fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            ..Default::default()
+        }
+    }
+
+    fn inc(&self) {
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        let high_water = self.high_water.fetch_max(count, Ordering::Relaxed);
+        if self.threshold > 0 && count > self.threshold && count > high_water {
+            warn!(
+                "block_cache: {} dirty blocks exceed the threshold {}",
+                count, self.threshold
+            );
+        }
+    }
+
+    fn dec(&self) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The data of a block, aligned for any `T` read from it up to `u64`.
+#[repr(C, align(8))]
+struct AlignedBlock([u8; BLOCK_SIZE]);
+
 pub struct BlockCache {
-    cache:     [u8; BLOCK_SIZE],
+    cache:     AlignedBlock,
     block_id:  BlockId,
     block_dev: Arc<dyn BlockDevice>,
-    modified:  bool,
+    /// The bytes modified since the last sync, `None` if clean.
+    modified:  Option<Range<usize>>,
+    /// Never evicted from `BlockCacheBuffer`.
+    pinned:    bool,
+    dirty:     Arc<DirtyCounter>,
 }
 
 impl BlockCache {
     /// Loads a new block from disk.
     pub fn new(block_id: BlockId, block_dev: Arc<dyn BlockDevice>) -> Self {
+        Self::load(block_id, block_dev, Arc::default())
+    }
+
+    fn load(block_id: BlockId, block_dev: Arc<dyn BlockDevice>, dirty: Arc<DirtyCounter>) -> Self {
         let mut cache = [0u8; BLOCK_SIZE];
-        block_dev.read(block_id, &mut cache);
+        if let Err(err) = block_dev.read(block_id, &mut cache) {
+            error!("block_cache: failed to read block {}: {}", block_id, err);
+        }
+        Self::with_data(block_id, cache, block_dev, dirty)
+    }
+
+    /// Creates a cache of the block already read into `cache`.
+    fn with_data(
+        block_id: BlockId,
+        cache: [u8; BLOCK_SIZE],
+        block_dev: Arc<dyn BlockDevice>,
+        dirty: Arc<DirtyCounter>,
+    ) -> Self {
         Self {
-            cache,
+            cache: AlignedBlock(cache),
             block_id,
             block_dev,
-            modified: false,
+            modified: None,
+            pinned: false,
+            dirty,
+        }
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    fn mark_modified(&mut self, range: Range<usize>) {
+        match &mut self.modified {
+            Some(modified) => {
+                modified.start = modified.start.min(range.start);
+                modified.end = modified.end.max(range.end);
+            }
+            None => {
+                self.modified = Some(range);
+                self.dirty.inc();
+            }
+        }
+    }
+
+    /// Throws away the changes not written back yet.
+    fn discard(&mut self) {
+        if self.modified.take().is_some() {
+            self.dirty.dec();
         }
     }
 
     pub fn clear(&mut self) {
-        self.modified = true;
-        self.cache.fill(0);
+        self.mark_modified(0..BLOCK_SIZE);
+        self.cache.0.fill(0);
     }
 
     fn get_addr(&self, offset: usize) -> usize {
-        &self.cache[offset] as *const _ as usize
+        &self.cache.0[offset] as *const _ as usize
+    }
+
+    /// Checks `size` bytes of `T` at `offset` are in this block and
+    /// aligned for `T`.
+    fn check_access<T>(&self, offset: usize, size: usize) {
+        assert!(offset + size <= BLOCK_SIZE, "offset: {}, size: {}", offset, size);
+        let align = align_of::<T>();
+        assert!(
+            self.get_addr(offset).is_multiple_of(align),
+            "misaligned offset: {}, align: {}",
+            offset,
+            align
+        );
     }
 
+    /// Gets a reference of `T` at `offset` in this block.
+    ///
+    /// # Safety
+    /// The caller must ensure the bytes at `offset` are a valid `T`.
     pub unsafe fn get_ref<T>(&self, offset: InBlockOffset) -> &T
     where
         T: Sized,
     {
         let offset = offset as usize;
-        let size = size_of::<T>();
-        assert!(offset + size <= BLOCK_SIZE, "offset: {}, size: {}", offset, size);
+        self.check_access::<T>(offset, size_of::<T>());
 
         &*(self.get_addr(offset) as *const T)
     }
 
+    /// Gets a mutable reference of `T` at `offset` in this block.
+    ///
+    /// # Safety
+    /// The caller must ensure the bytes at `offset` are a valid `T`.
     pub unsafe fn get_mut<T>(&mut self, offset: InBlockOffset) -> &mut T
     where
         T: Sized,
     {
         let offset = offset as usize;
-        let size = size_of::<T>();
-        assert!(offset + size <= BLOCK_SIZE, "offset: {}, size: {}", offset, size);
+        self.check_access::<T>(offset, size_of::<T>());
 
-        self.modified = true;
+        self.mark_modified(offset..offset + size_of::<T>());
         &mut *(self.get_addr(offset) as *mut T)
     }
 
@@ -68,14 +180,52 @@ impl BlockCache {
         unsafe { cb(self.get_mut(offset)) }
     }
 
+    /// Reads `len` items of `T` starting at `offset` in this block.
+    pub fn read_slice<T, V>(
+        &self,
+        offset: InBlockOffset,
+        len: usize,
+        cb: impl FnOnce(&[T]) -> V,
+    ) -> V {
+        let offset = offset as usize;
+        self.check_access::<T>(offset, len * size_of::<T>());
+
+        unsafe { cb(from_raw_parts(self.get_addr(offset) as *const T, len)) }
+    }
+
+    /// Writes `len` items of `T` starting at `offset` in this block.
+    pub fn write_slice<T, V>(
+        &mut self,
+        offset: InBlockOffset,
+        len: usize,
+        cb: impl FnOnce(&mut [T]) -> V,
+    ) -> V {
+        let offset = offset as usize;
+        self.check_access::<T>(offset, len * size_of::<T>());
+
+        self.mark_modified(offset..offset + len * size_of::<T>());
+        unsafe { cb(from_raw_parts_mut(self.get_addr(offset) as *mut T, len)) }
+    }
+
     /// Synchronize the cache back to disk.
+    ///
+    /// Only the modified bytes are written if the device supports
+    /// partial writes.
     pub fn sync(&mut self) {
-        if !self.modified {
+        let Some(modified) = self.modified.take() else {
             return;
-        }
+        };
 
-        self.modified = false;
-        self.block_dev.write(self.block_id, &self.cache);
+        self.dirty.dec();
+        let res = if modified.len() < BLOCK_SIZE && self.block_dev.supports_partial_write() {
+            self.block_dev
+                .write_partial(self.block_id, modified.start, &self.cache.0[modified])
+        } else {
+            self.block_dev.write(self.block_id, &self.cache.0)
+        };
+        if let Err(err) = res {
+            error!("block_cache: failed to write block {}: {}", self.block_id, err);
+        }
     }
 }
 
@@ -85,10 +235,55 @@ impl Drop for BlockCache {
     }
 }
 
+/// The default number of blocks to read ahead on sequential access.
+pub const READ_AHEAD_BLOCKS: usize = 4;
+
+/// Tracks the reads of a file, to read ahead once it's read sequentially.
+#[derive(Default)]
+pub struct ReadAhead {
+    /// The index in the file of the last block read.
+    last:  Option<usize>,
+    /// The blocks of the file before this index were read ahead.
+    until: usize,
+}
+
+impl ReadAhead {
+    /// Records a read of the block `idx` of the file.
+    ///
+    /// Returns the blocks to read ahead of it, `count` at most, if it
+    /// follows the last one and the next block was not read ahead yet.
+    pub fn note_read(&mut self, idx: usize, count: usize) -> Range<usize> {
+        let sequential = self.last.is_some_and(|last| last + 1 == idx);
+        self.last = Some(idx);
+        if !sequential {
+            self.until = 0;
+            return idx + 1..idx + 1;
+        }
+        if idx + 1 < self.until {
+            return idx + 1..idx + 1;
+        }
+        idx + 1..idx + 1 + count
+    }
+
+    /// Records the blocks before `end` as read ahead.
+    pub fn read_until(&mut self, end: usize) {
+        self.until = end;
+    }
+}
+
 /// Linked list of all buffers. Sorted by how recently the buffer used.
 pub struct BlockCacheBuffer {
-    buffer:   VecDeque<(BlockId, Arc<Mutex<BlockCache>>)>,
-    capacity: usize,
+    buffer:     VecDeque<(BlockId, Arc<Mutex<BlockCache>>)>,
+    capacity:   usize,
+    /// The number of blocks to read ahead, 0 disables read-ahead.
+    read_ahead: usize,
+    /// Counts the blocks loaded from disk synchronously by `get`.
+    misses:     u64,
+    /// Counts the blocks found in cache by `get`.
+    hits:       u64,
+    dirty:      Arc<DirtyCounter>,
+    #[cfg(any(test, feature = "cache-debug"))]
+    holders:    Holders<BlockId>,
 }
 
 impl BlockCacheBuffer {
@@ -96,6 +291,14 @@ impl BlockCacheBuffer {
         Self {
             buffer: VecDeque::new(),
             capacity,
+            read_ahead: READ_AHEAD_BLOCKS,
+            misses: 0,
+            hits: 0,
+            // With most of the buffers waiting to be written back, little
+            // room is left for the blocks being read.
+            dirty: Arc::new(DirtyCounter::new(capacity * 3 / 4)),
+            #[cfg(any(test, feature = "cache-debug"))]
+            holders: Holders::default(),
         }
     }
 
@@ -108,76 +311,223 @@ impl BlockCacheBuffer {
         block_dev: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
         if let Some((_, cache)) = self.buffer.iter().find(|&&(bid, _)| bid == block_id) {
+            #[cfg(any(test, feature = "cache-debug"))]
+            self.holders.take(block_id, Arc::strong_count(cache) > 1);
+            self.hits += 1;
             cache.clone()
         } else {
             // Not cached.
-            // Recycle the unused buffer by LRU.
-            if self.buffer.len() == self.capacity {
-                // front to back.
-                if let Some((idx, _)) = self
-                    .buffer
-                    .iter()
-                    .enumerate()
-                    .find(|(_, (_, cache))| Arc::strong_count(cache) == 1)
-                {
-                    self.buffer.remove(idx);
-                } else {
-                    // All buffers are busy, then too many processes are
-                    // simultaneously executing file system calls.
-                    // TODO: A more graceful response might to sleep until
-                    // a buffer became free, though there would then be
-                    // a possibility of deadlock.
-                    panic!("Out of block cache buffer.");
-                }
+            if self.buffer.len() == self.capacity && !self.recycle(self.capacity) {
+                // All buffers are busy, then too many processes are
+                // simultaneously executing file system calls.
+                // TODO: A more graceful response might to sleep until
+                // a buffer became free, though there would then be
+                // a possibility of deadlock.
+                panic!("Out of block cache buffer.{}", self.describe_holders());
             }
 
-            let block = Arc::new(Mutex::new(BlockCache::new(block_id, block_dev.clone())));
+            #[cfg(any(test, feature = "cache-debug"))]
+            self.holders.take(block_id, false);
+            self.misses += 1;
+            let block = BlockCache::load(block_id, block_dev.clone(), self.dirty.clone());
+            let block = Arc::new(Mutex::new(block));
             self.buffer.push_back((block_id, block.clone()));
 
             block
         }
     }
 
-    pub fn flush(&mut self) {
-        for (_, cache) in self.buffer.iter() {
-            cache.lock().sync()
+    /// Gets the block like `get`, and keeps it cached from now on.
+    ///
+    /// For the blocks that must never be re-read from the device in the
+    /// middle of an operation, e.g. the super block.
+    pub fn pin(
+        &mut self,
+        block_id: BlockId,
+        block_dev: Arc<dyn BlockDevice>,
+    ) -> Arc<Mutex<BlockCache>> {
+        let block = self.get(block_id, block_dev);
+        block.lock().pinned = true;
+        block
+    }
+
+    /// Loads blocks `[start, start + count)` into cache ahead of demand.
+    ///
+    /// It loads a quarter of the capacity at most and only recycles
+    /// the unused buffers, so the blocks in use are never evicted.
+    /// Each run of uncached blocks is read by one device request.
+    pub fn prefetch(&mut self, start: BlockId, count: usize, block_dev: Arc<dyn BlockDevice>) {
+        let count = count.min(self.capacity / 4) as u64;
+
+        let mut blocks = Vec::new();
+        for block_id in start..start + count {
+            if self.buffer.iter().any(|&(bid, _)| bid == block_id) {
+                continue;
+            }
+            // Keep a buffer for each block to be loaded.
+            if self.buffer.len() + blocks.len() >= self.capacity
+                && !self.recycle(self.buffer.len())
+            {
+                break;
+            }
+            blocks.push(block_id);
+        }
+
+        for run in blocks.chunk_by(|&a, &b| a + 1 == b) {
+            let mut data = vec![[0u8; BLOCK_SIZE]; run.len()];
+            let mut bufs: Vec<&mut [u8]> = data.iter_mut().map(|d| d.as_mut_slice()).collect();
+            if let Err(err) = block_dev.read_many(run[0], &mut bufs) {
+                error!("block_cache: failed to prefetch blocks from {}: {}", run[0], err);
+                continue;
+            }
+
+            for (&block_id, cache) in run.iter().zip(data) {
+                let block =
+                    BlockCache::with_data(block_id, cache, block_dev.clone(), self.dirty.clone());
+                self.buffer.push_back((block_id, Arc::new(Mutex::new(block))));
+            }
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use alloc::string::String;
+    pub fn read_ahead(&self) -> usize {
+        self.read_ahead
+    }
 
-    #[allow(unused_imports)]
-    use super::*;
+    pub fn set_read_ahead(&mut self, blocks: usize) {
+        self.read_ahead = blocks;
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Returns the number of modified blocks not written back yet.
+    pub fn dirty_count(&self) -> usize {
+        self.dirty.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the most blocks ever dirty at the same time.
+    pub fn dirty_high_water(&self) -> usize {
+        self.dirty.high_water.load(Ordering::Relaxed)
+    }
+
+    /// Returns the ids of the cached blocks, from least to most recently
+    /// loaded.
+    pub fn cached_blocks(&self) -> impl Iterator<Item = BlockId> + '_ {
+        self.buffer.iter().map(|&(block_id, _)| block_id)
+    }
+
+    #[cfg(any(test, feature = "cache-debug"))]
+    fn describe_holders(&self) -> String {
+        format!(" Holders: {}", self.holders.report(self.cached_blocks()))
+    }
 
-    struct MockBlockDevice {
-        pub data: [u8; BLOCK_SIZE],
+    #[cfg(not(any(test, feature = "cache-debug")))]
+    fn describe_holders(&self) -> &'static str {
+        ""
     }
 
-    impl MockBlockDevice {
-        pub fn new() -> Self {
-            Self {
-                data: [0; BLOCK_SIZE],
+    /// Recycles the unused buffer in the first `end` buffers by LRU, the
+    /// pinned ones are skipped.
+    ///
+    /// Returns `false` if all of them are busy.
+    fn recycle(&mut self, end: usize) -> bool {
+        // front to back.
+        match self
+            .buffer
+            .iter()
+            .take(end)
+            .position(|(_, cache)| Arc::strong_count(cache) == 1 && !cache.lock().pinned)
+        {
+            Some(idx) => {
+                let (_block_id, _) = self.buffer.remove(idx).unwrap();
+                #[cfg(any(test, feature = "cache-debug"))]
+                self.holders.remove(_block_id);
+                true
             }
+            None => false,
         }
     }
 
-    impl BlockDevice for MockBlockDevice {
-        fn read(&self, _block_id: BlockId, buf: &mut [u8])  -> Result<(), String>  {
-            buf.copy_from_slice(&self.data);
-            Ok(())
+    pub fn flush(&mut self) {
+        for (_, cache) in self.buffer.iter() {
+            cache.lock().sync()
+        }
+    }
+
+    /// Drops the cached block `block_id`, so the next `get` reloads it
+    /// from the device, e.g. after it was written outside of the cache.
+    ///
+    /// A dirty block is written back first, or its changes are thrown
+    /// away if `discard`. Returns `false` if the block is in use or
+    /// pinned, and it stays cached.
+    pub fn invalidate(&mut self, block_id: BlockId, discard: bool) -> bool {
+        let Some(idx) = self.buffer.iter().position(|&(bid, _)| bid == block_id) else {
+            return true;
+        };
+        if Arc::strong_count(&self.buffer[idx].1) > 1 {
+            warn!("block_cache: block {} is in use, not invalidated", block_id);
+            return false;
         }
+        if self.buffer[idx].1.lock().is_pinned() {
+            warn!("block_cache: block {} is pinned, not invalidated", block_id);
+            return false;
+        }
+
+        let (_, cache) = self.buffer.remove(idx).unwrap();
+        if discard {
+            cache.lock().discard();
+        }
+        #[cfg(any(test, feature = "cache-debug"))]
+        self.holders.remove(block_id);
+        true
+    }
 
-        fn write(&self, _block_id: BlockId, _buf: &[u8]) -> Result<(), String> {
-            Ok(())
+    /// Drops all the cached blocks not in use, see `invalidate`. The
+    /// pinned blocks are kept.
+    ///
+    /// Returns `false` if some are in use and stay cached.
+    pub fn invalidate_all(&mut self, discard: bool) -> bool {
+        let blocks: Vec<BlockId> = self
+            .buffer
+            .iter()
+            .filter(|(_, cache)| !cache.lock().is_pinned())
+            .map(|&(block_id, _)| block_id)
+            .collect();
+        let mut all = true;
+        for block_id in blocks {
+            all &= self.invalidate(block_id, discard);
         }
+        all
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use alloc::string::String;
+
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::{
+        block_dev::{DInode, InodeType, DINODE_SIZE, INODES_PER_BLOCK, N_DIRECT},
+        ram_disk::RamDisk,
+        recording::{Op, RecordingBlockDevice},
+    };
+
+    /// A disk with room for the block ids the tests use.
+    fn ram_disk() -> Arc<RamDisk> {
+        Arc::new(RamDisk::new(256))
     }
 
     #[test]
     fn test_block_cache_buffer() {
-        let dev = Arc::new(MockBlockDevice::new());
+        let dev = ram_disk();
         let mut block_cache = BlockCacheBuffer::new(2);
 
         let cache1 = block_cache.get(1, dev.clone());
@@ -199,4 +549,334 @@ mod tests {
         assert_eq!(block_cache.buffer[0].0, 2);
         assert_eq!(block_cache.buffer[1].0, 3);
     }
+
+    #[test]
+    fn test_read_slice() {
+        let dev = ram_disk();
+        let mut data = [0; BLOCK_SIZE];
+        for (i, chunk) in data.chunks_exact_mut(4).take(4).enumerate() {
+            chunk.copy_from_slice(&(i as u32 * 10).to_ne_bytes());
+        }
+        dev.write(0, &data).unwrap();
+        let mut cache = BlockCache::new(0, dev);
+
+        cache.read_slice(0, 4, |nums: &[u32]| assert_eq!(nums, [0, 10, 20, 30]));
+        cache.read_slice(8, 2, |nums: &[u32]| assert_eq!(nums, [20, 30]));
+
+        cache.write_slice(4, 1, |nums: &mut [u32]| nums[0] = 42);
+        cache.read_slice(0, 3, |nums: &[u32]| assert_eq!(nums, [0, 42, 20]));
+    }
+
+    #[test]
+    fn test_pinned_block_not_evicted() {
+        let dev = ram_disk();
+        let mut block_cache = BlockCacheBuffer::new(4);
+
+        drop(block_cache.pin(1, dev.clone()));
+        for block_id in 10..20 {
+            block_cache.get(block_id, dev.clone());
+        }
+        assert!(block_cache.cached_blocks().any(|block_id| block_id == 1));
+
+        let misses = block_cache.misses();
+        assert!(block_cache.get(1, dev.clone()).lock().is_pinned());
+        assert_eq!(block_cache.misses(), misses);
+    }
+
+    #[test]
+    fn test_pinned_block_not_invalidated() {
+        let dev = ram_disk();
+        let mut block_cache = BlockCacheBuffer::new(4);
+
+        block_cache
+            .pin(1, dev.clone())
+            .lock()
+            .write(0, |byte: &mut u8| *byte = 7);
+        block_cache.get(2, dev.clone());
+        assert!(!block_cache.invalidate(1, true));
+        assert!(block_cache.invalidate_all(true));
+        assert_eq!(block_cache.cached_blocks().collect::<Vec<_>>(), [1]);
+
+        // Its changes are kept too.
+        let block = block_cache.get(1, dev.clone());
+        assert_eq!(block.lock().read(0, |byte: &u8| *byte), 7);
+    }
+
+    #[test]
+    fn test_dirty_count() {
+        let dev = ram_disk();
+        let mut block_cache = BlockCacheBuffer::new(8);
+
+        for block_id in 0..5 {
+            let block = block_cache.get(block_id, dev.clone());
+            block.lock().write(0, |n: &mut u32| *n = 1);
+            // Modifying it again doesn't count.
+            block.lock().write(4, |n: &mut u32| *n = 2);
+        }
+        block_cache.get(5, dev.clone()).lock().read(0, |_: &u32| {});
+        assert_eq!(block_cache.dirty_count(), 5);
+
+        block_cache.get(0, dev.clone()).lock().sync();
+        assert_eq!(block_cache.dirty_count(), 4);
+        block_cache.flush();
+        assert_eq!(block_cache.dirty_count(), 0);
+        assert_eq!(block_cache.dirty_high_water(), 5);
+
+        block_cache.get(1, dev.clone()).lock().clear();
+        assert_eq!(block_cache.dirty_count(), 1);
+        assert_eq!(block_cache.dirty_high_water(), 5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_read_slice_out_of_block() {
+        let cache = BlockCache::new(0, ram_disk());
+        cache.read_slice(4, BLOCK_SIZE / 4, |_: &[u32]| {});
+    }
+
+    #[test]
+    #[should_panic(expected = "misaligned")]
+    fn test_read_misaligned() {
+        let cache = BlockCache::new(0, ram_disk());
+        cache.read(4, |_: &DInode| {});
+    }
+
+    #[test]
+    fn test_read_aligned_inodes() {
+        let cache = BlockCache::new(0, ram_disk());
+        for offset in (0..BLOCK_SIZE).step_by(DINODE_SIZE).take(INODES_PER_BLOCK) {
+            cache.read(offset as InBlockOffset, |dinode: &DInode| {
+                assert_eq!(dinode.size, 0)
+            });
+        }
+    }
+
+    /// Returns a file of `blocks` contiguous blocks from `start`.
+    fn contiguous_file(start: BlockId, blocks: usize) -> DInode {
+        let mut addresses = [0; N_DIRECT];
+        for (i, addr) in addresses.iter_mut().enumerate().take(blocks) {
+            *addr = start + i as BlockId;
+        }
+        DInode::new(InodeType::File, 0, 1, (blocks * BLOCK_SIZE) as u64, addresses)
+    }
+
+    /// Reads the `files` one block at a time, a block of each in turn.
+    ///
+    /// Returns the number of cache misses and device reads.
+    fn sequential_read(files: &[DInode], read_ahead: usize) -> (u64, usize) {
+        let dev = ram_disk();
+        let cache = Arc::new(Mutex::new(BlockCacheBuffer::new(BLOCK_BUFFER_SIZE)));
+        cache.lock().set_read_ahead(read_ahead);
+
+        let mut states: Vec<ReadAhead> = files.iter().map(|_| ReadAhead::default()).collect();
+        let blocks = files[0].size as usize / BLOCK_SIZE;
+        let mut buf = [0u8; BLOCK_SIZE];
+        for i in 0..blocks {
+            for (dinode, state) in files.iter().zip(states.iter_mut()) {
+                let get_bid = |idx| dinode.get_bid(idx, dev.clone(), cache.clone());
+                let read = dinode.read_data_with(
+                    i * BLOCK_SIZE,
+                    &mut buf,
+                    dev.clone(),
+                    cache.clone(),
+                    &get_bid,
+                    state,
+                );
+                assert_eq!(read, BLOCK_SIZE);
+            }
+        }
+
+        let misses = cache.lock().misses();
+        (misses, dev.reads())
+    }
+
+    #[test]
+    fn test_prefetch_sequential_read() {
+        let blocks = 16;
+        let file = [contiguous_file(100, blocks)];
+
+        let (misses, reads) = sequential_read(&file, 0);
+        assert_eq!(misses, blocks as u64);
+        assert_eq!(reads, blocks);
+
+        let (misses, reads) = sequential_read(&file, READ_AHEAD_BLOCKS);
+        assert!(misses < blocks as u64 / 2, "misses: {}", misses);
+        // Every block is still read from disk exactly once.
+        assert_eq!(reads, blocks);
+    }
+
+    #[test]
+    fn test_prefetch_interleaved_reads() {
+        let blocks = 12;
+        let files = [contiguous_file(100, blocks), contiguous_file(200, blocks)];
+
+        // Each file is still read sequentially.
+        let (misses, reads) = sequential_read(&files, READ_AHEAD_BLOCKS);
+        assert!(misses < blocks as u64, "misses: {}", misses);
+        assert_eq!(reads, 2 * blocks);
+    }
+
+    #[test]
+    fn test_read_ahead_window() {
+        let mut read_ahead = ReadAhead::default();
+        assert!(read_ahead.note_read(0, 4).is_empty());
+        assert_eq!(read_ahead.note_read(1, 4), 2..6);
+        read_ahead.read_until(6);
+        // The blocks ahead are not looked up again until the window ends.
+        for idx in 2..5 {
+            assert!(read_ahead.note_read(idx, 4).is_empty());
+        }
+        assert_eq!(read_ahead.note_read(5, 4), 6..10);
+
+        // A seek starts over.
+        assert!(read_ahead.note_read(2, 4).is_empty());
+        assert_eq!(read_ahead.note_read(3, 4), 4..8);
+    }
+
+    #[test]
+    fn test_prefetch_bounded() {
+        let dev = ram_disk();
+        let mut block_cache = BlockCacheBuffer::new(8);
+
+        let busy: alloc::vec::Vec<_> = (0..7).map(|i| block_cache.get(i, dev.clone())).collect();
+        block_cache.prefetch(100, 8, dev.clone());
+
+        // At most a quarter of capacity is prefetched, and busy buffers are kept.
+        assert_eq!(block_cache.buffer.len(), 8);
+        assert_eq!(block_cache.buffer[7].0, 100);
+        for (i, cache) in busy.iter().enumerate() {
+            assert!(Arc::ptr_eq(&block_cache.buffer[i].1, cache));
+        }
+    }
+
+    #[test]
+    fn test_prefetch_read_many() {
+        let disk = ram_disk();
+        for block_id in 100..110 {
+            disk.write(block_id, &[block_id as u8; BLOCK_SIZE]).unwrap();
+        }
+        let dev = Arc::new(RecordingBlockDevice::new(disk));
+        let runs = |dev: &RecordingBlockDevice| -> Vec<_> {
+            dev.take_log().iter().map(|r| (r.block_id, r.len)).collect()
+        };
+        let mut block_cache = BlockCacheBuffer::new(32);
+
+        block_cache.prefetch(100, 4, dev.clone());
+        assert_eq!(runs(&dev), [(100, 4)]);
+
+        // A cached block splits the run.
+        block_cache.prefetch(102, 6, dev.clone());
+        assert_eq!(runs(&dev), [(104, 4)]);
+
+        let _busy = block_cache.get(201, dev.clone());
+        dev.take_log();
+        block_cache.prefetch(200, 4, dev.clone());
+        assert_eq!(runs(&dev), [(200, 1), (202, 2)]);
+        assert!(dev.log().iter().all(|r| r.op == Op::Read));
+
+        let block = block_cache.get(105, dev.clone());
+        assert_eq!(block.lock().cache.0, [105; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn test_partial_sync() {
+        for (partial, extent) in [(true, 100..=200), (false, 0..=BLOCK_SIZE - 1)] {
+            let dev = ram_disk();
+            dev.set_partial_write(partial);
+            let mut block = BlockCache::new(1, dev.clone());
+            block.write(100, |byte: &mut u8| *byte = 1);
+            block.write(200, |byte: &mut u8| *byte = 2);
+            // Only the extent written over it is cleared.
+            dev.write(1, &[0xff; BLOCK_SIZE]).unwrap();
+            block.sync();
+            assert_eq!(dev.writes(), 2);
+
+            let mut data = [0; BLOCK_SIZE];
+            dev.read(1, &mut data).unwrap();
+            for (i, &byte) in data.iter().enumerate() {
+                let expected = match i {
+                    100 => 1,
+                    200 => 2,
+                    _ if extent.contains(&i) => 0,
+                    _ => 0xff,
+                };
+                assert_eq!(byte, expected, "byte {}", i);
+            }
+
+            // Clean again, nothing more to write.
+            block.sync();
+            assert_eq!(dev.writes(), 2);
+        }
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let dev = Arc::new(RamDisk::new(8));
+        let mut block_cache = BlockCacheBuffer::new(4);
+        let read = |block_cache: &mut BlockCacheBuffer, block_id| {
+            block_cache
+                .get(block_id, dev.clone())
+                .lock()
+                .read(0, |byte: &u8| *byte)
+        };
+
+        assert_eq!(read(&mut block_cache, 3), 0);
+        dev.write(3, &[7; BLOCK_SIZE]).unwrap();
+        assert_eq!(read(&mut block_cache, 3), 0, "served from cache");
+        assert!(block_cache.invalidate(3, false));
+        assert_eq!(read(&mut block_cache, 3), 7);
+
+        // Written back before it's dropped.
+        block_cache
+            .get(3, dev.clone())
+            .lock()
+            .write(0, |byte: &mut u8| *byte = 8);
+        assert!(block_cache.invalidate_all(false));
+        assert_eq!(block_cache.dirty_count(), 0);
+        assert_eq!(read(&mut block_cache, 3), 8);
+
+        // Or thrown away.
+        block_cache
+            .get(3, dev.clone())
+            .lock()
+            .write(0, |byte: &mut u8| *byte = 9);
+        assert!(block_cache.invalidate(3, true));
+        assert_eq!(block_cache.dirty_count(), 0);
+        assert_eq!(read(&mut block_cache, 3), 8);
+
+        let busy = block_cache.get(3, dev.clone());
+        read(&mut block_cache, 4);
+        assert!(!block_cache.invalidate(3, false));
+        assert!(!block_cache.invalidate_all(false));
+        assert_eq!(block_cache.cached_blocks().collect::<Vec<_>>(), [3]);
+        drop(busy);
+        assert!(block_cache.invalidate(3, false));
+        assert_eq!(block_cache.cached_blocks().count(), 0);
+    }
+
+    std::thread_local! {
+        static CURRENT_TASK: core::cell::Cell<Option<u64>> = const { core::cell::Cell::new(None) };
+    }
+
+    #[test]
+    fn test_exhaustion_names_holders() {
+        use crate::cache_debug::set_current_holder;
+
+        set_current_holder(|| CURRENT_TASK.with(|task| task.get()));
+        let dev = ram_disk();
+        let mut block_cache = BlockCacheBuffer::new(2);
+
+        CURRENT_TASK.with(|task| task.set(Some(7)));
+        let _a = block_cache.get(1, dev.clone());
+        CURRENT_TASK.with(|task| task.set(Some(8)));
+        let _b = block_cache.get(2, dev.clone());
+        let _c = block_cache.get(1, dev.clone());
+
+        let err = std::panic::catch_unwind(core::panic::AssertUnwindSafe(|| {
+            block_cache.get(3, dev.clone());
+        }))
+        .unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(msg.contains("1 by task 7, 8; 2 by task 8"), "{}", msg);
+    }
 }
@@ -1,10 +1,10 @@
-use core::mem::size_of;
+use core::mem::{size_of, MaybeUninit};
 
 use alloc::{string::String, sync::Arc};
-use log::debug;
+use log::{debug, warn};
 use spin::Mutex;
 
-use crate::block_cache::BlockCacheBuffer;
+use crate::block_cache::{BlockCacheBuffer, ReadAhead};
 
 /// The trait of block devices.
 ///
@@ -12,6 +12,60 @@ use crate::block_cache::BlockCacheBuffer;
 pub trait BlockDevice: Send + Sync {
     fn read(&self, block_id: u64, buf: &mut [u8]) -> Result<(), String>;
     fn write(&self, block_id: u64, buf: &[u8]) -> Result<(), String>;
+
+    /// Reads the contiguous blocks from `start` into `bufs`, one block
+    /// per buffer.
+    ///
+    /// Devices able to transfer a run of blocks in one request should
+    /// override it, the default reads them one by one.
+    fn read_many(&self, start: u64, bufs: &mut [&mut [u8]]) -> Result<(), String> {
+        for (block_id, buf) in (start..).zip(bufs.iter_mut()) {
+            self.read(block_id, buf)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `bufs` to the contiguous blocks from `start`, one block
+    /// per buffer.
+    fn write_many(&self, start: u64, bufs: &[&[u8]]) -> Result<(), String> {
+        for (block_id, buf) in (start..).zip(bufs.iter()) {
+            self.write(block_id, buf)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the device can write less than a block, see
+    /// `write_partial`.
+    fn supports_partial_write(&self) -> bool {
+        false
+    }
+
+    /// Writes `buf` at `offset` bytes into the block `block_id`, leaving
+    /// the rest of the block as it was.
+    ///
+    /// Only called on the devices `supports_partial_write`.
+    fn write_partial(&self, block_id: u64, offset: usize, buf: &[u8]) -> Result<(), String> {
+        let _ = (block_id, offset, buf);
+        Err(String::from("partial writes are not supported"))
+    }
+
+    /// Waits for the writes done to be durable.
+    ///
+    /// Devices writing through a volatile cache should override it, the
+    /// default has nothing to wait for.
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Tells the device the `count` blocks from `block_id` are unused,
+    /// their data may be dropped.
+    ///
+    /// Devices able to make use of it, e.g. by trimming flash, should
+    /// override it, the default ignores it.
+    fn discard(&self, block_id: u64, count: usize) -> Result<(), String> {
+        let _ = (block_id, count);
+        Ok(())
+    }
 }
 
 /// The size of one block.
@@ -28,6 +82,15 @@ pub const BLOCK_SIZE: usize = 4096; // Bytes
 /// File system magic number for sanity check.
 const FS_MAGIC: u64 = 0x102030;
 
+/// The on-disk format version written by `SuperBlock::new`.
+///
+/// The images made before the versioning read as version 0.
+/// Version 2 added the mode and the owner to `DInode`.
+/// Version 3 added the type of the inode to `DirEntry`.
+/// Version 4 added the extended attributes block to `DInode`.
+/// Version 5 counted `.` and `..` in the links of directories.
+pub const FS_VERSION: u32 = 5;
+
 /// Inode number in one block.
 pub const INODES_PER_BLOCK: usize = BLOCK_SIZE / DINODE_SIZE;
 
@@ -39,7 +102,7 @@ pub const BITMAP_PER_BLOCK: usize = BLOCK_SIZE * 8;
 /// We should keep every `DInode` to take up the most of space in
 /// 1/n of `BLOCK_SIZE` preferably.
 /// (i.e. DINODE_SIZE == BLOCK_SIZE / n)
-pub const N_DIRECT: usize = 28;
+pub const N_DIRECT: usize = 26;
 
 /// Indirect blocks per block.
 pub const N_INDIRECT: usize = BLOCK_SIZE / size_of::<BlockId>();
@@ -51,7 +114,7 @@ pub const MAX_BLOCKS_PER_INODE: usize = N_DIRECT + N_INDIRECT;
 pub const CAPACITY_PER_INODE: usize = MAX_BLOCKS_PER_INODE * BLOCK_SIZE;
 
 /// The size of directory name.
-pub const DIR_NAME_SIZE: usize = 24;
+pub const DIR_NAME_SIZE: usize = 23;
 
 /// The size of directory entry.
 pub const DIR_ENTRY_SIZE: usize = size_of::<DirEntry>();
@@ -80,25 +143,36 @@ pub type InBlockOffset = u64;
 /// Disk layout:
 /// [ boot block | super block | inode bit map | inode blocks
 ///                               | data bit map | data blocks ]
+///
+/// The fields are read only, a super block is only made by `new` when
+/// the file system is created:
+///
+/// ```compile_fail
+/// let mut sb = fs::block_dev::SuperBlock::new(1024, 2, 3, 16, 19, 20, 1004);
+/// sb.data_start = 0;
+/// ```
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct SuperBlock {
     /// Must be `FS_MAGIC`
-    magic:                u64,
+    magic:            u64,
     /// Size of file system image (blocks).
-    pub blocks:           u64,
+    blocks:           u64,
     /// Block number of first free inode map block.
-    pub inode_bmap_start: InodeId,
+    inode_bmap_start: InodeId,
     /// Block number of first inode block.
-    pub inode_start:      InodeId,
+    inode_start:      InodeId,
     /// Number of inodes.
-    pub inode_blocks:     u64,
+    inode_blocks:     u64,
     /// Block number of first free data map block.
-    pub data_bmap_start:  InodeId,
+    data_bmap_start:  InodeId,
     /// Block number of first data block.
-    pub data_start:       InodeId,
+    data_start:       InodeId,
     /// Number of data blocks.
-    pub data_blocks:      u64,
+    data_blocks:      u64,
+    /// On-disk format version.
+    version:          u32,
+    _reserved:        u32,
 }
 
 impl SuperBlock {
@@ -120,6 +194,8 @@ impl SuperBlock {
             data_bmap_start,
             data_start,
             data_blocks,
+            version: FS_VERSION,
+            _reserved: 0,
         }
     }
 
@@ -127,7 +203,50 @@ impl SuperBlock {
         self.magic == FS_MAGIC
     }
 
+    pub fn magic(&self) -> u64 {
+        self.magic
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    pub fn inode_bmap_start(&self) -> InodeId {
+        self.inode_bmap_start
+    }
+
+    pub fn inode_start(&self) -> InodeId {
+        self.inode_start
+    }
+
+    pub fn inode_blocks(&self) -> u64 {
+        self.inode_blocks
+    }
+
+    pub fn data_bmap_start(&self) -> InodeId {
+        self.data_bmap_start
+    }
+
+    pub fn data_start(&self) -> InodeId {
+        self.data_start
+    }
+
+    pub fn data_blocks(&self) -> u64 {
+        self.data_blocks
+    }
+
+    pub(crate) fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
     /// Gets block id and offset-in-block by inode-num.
+    ///
+    /// The offset is a multiple of `DINODE_SIZE`, so it's aligned for
+    /// `DInode`.
     pub fn find_inode(&self, inum: InodeId) -> (BlockId, InBlockOffset) {
         let block_id = inum / INODES_PER_BLOCK as u64 + self.inode_start;
         let offset = (inum % INODES_PER_BLOCK as u64) * DINODE_SIZE as u64;
@@ -142,6 +261,14 @@ pub struct BitmapBlock {
 }
 
 impl BitmapBlock {
+    /// Creates a bitmap with every bit free.
+    #[cfg(feature = "std")]
+    pub const fn empty() -> Self {
+        Self {
+            inner: [0; BLOCK_SIZE],
+        }
+    }
+
     pub fn allocate(&mut self) -> Option<usize> {
         for (i, &byte) in self.inner.iter().enumerate() {
             if byte == 0xff {
@@ -157,6 +284,19 @@ impl BitmapBlock {
         None
     }
 
+    /// Allocates the bit `idx`, returns false if it's taken.
+    pub fn allocate_at(&mut self, idx: usize) -> bool {
+        if self.is_allocated(idx) {
+            return false;
+        }
+        self.inner[idx / 8] |= 1 << (idx % 8);
+        true
+    }
+
+    pub fn is_allocated(&self, idx: usize) -> bool {
+        self.inner[idx / 8] & (1 << (idx % 8)) != 0
+    }
+
     pub fn free(&mut self, idx: usize) {
         let byte = idx / 8;
         let offset = idx % 8;
@@ -181,6 +321,9 @@ pub type DataBlock = [u8; BLOCK_SIZE];
 pub struct DirEntry {
     pub inode_num: InodeId,
     name:          [u8; DIR_NAME_SIZE],
+    /// A copy of the type of the inode, so listing a directory doesn't
+    /// load its children.
+    pub type_:     InodeType,
 }
 
 impl DirEntry {
@@ -188,15 +331,35 @@ impl DirEntry {
         Self {
             inode_num: 0,
             name:      [0; DIR_NAME_SIZE],
+            type_:     InodeType::Invalid,
         }
     }
 
-    pub fn new(name: &str, inum: InodeId) -> Self {
+    pub fn new(name: &str, inum: InodeId, type_: InodeType) -> Self {
         let mut bytes = [0; DIR_NAME_SIZE];
         bytes[..name.len()].copy_from_slice(name.as_bytes());
         Self {
             inode_num: inum,
-            name:      bytes,
+            name: bytes,
+            type_,
+        }
+    }
+
+    /// Returns the on-disk form of this entry.
+    pub fn as_bytes(&self) -> &[u8; DIR_ENTRY_SIZE] {
+        // SAFETY: `DirEntry` is `repr(C)` without padding, see the
+        // assertion below, so all of its bytes are initialized.
+        unsafe { &*(self as *const Self as *const [u8; DIR_ENTRY_SIZE]) }
+    }
+
+    /// Parses an entry from its on-disk form, an unknown type is read as
+    /// `InodeType::Invalid`.
+    pub fn from_bytes(bytes: &[u8; DIR_ENTRY_SIZE]) -> Self {
+        let (inum, rest) = bytes.split_at(size_of::<InodeId>());
+        Self {
+            inode_num: InodeId::from_ne_bytes(inum.try_into().unwrap()),
+            name:      rest[..DIR_NAME_SIZE].try_into().unwrap(),
+            type_:     InodeType::from_u8(rest[DIR_NAME_SIZE]),
         }
     }
 
@@ -208,6 +371,9 @@ impl DirEntry {
     }
 }
 
+const _: () =
+    assert!(DIR_ENTRY_SIZE == size_of::<InodeId>() + DIR_NAME_SIZE + size_of::<InodeType>());
+
 /// On-disk inode structure.
 ///
 /// The on-disk inodes are packed into a contiguous area of disk called
@@ -228,6 +394,14 @@ pub struct DInode {
     pub size:      u64,
     /// Data block addresses.
     pub addresses: [BlockId; N_DIRECT],
+    /// Permission bits, e.g. `0o644`.
+    pub mode:      u32,
+    /// Owner user id.
+    pub uid:       u32,
+    /// Owner group id.
+    pub gid:       u32,
+    /// Block of the extended attributes, 0 if none set.
+    pub xattr:     u32,
 }
 
 impl DInode {
@@ -244,17 +418,15 @@ impl DInode {
             links_num,
             size,
             addresses,
+            mode: type_.default_mode(),
+            uid: 0,
+            gid: 0,
+            xattr: 0,
         }
     }
 
     pub fn initialize(&mut self, type_: InodeType) {
-        *self = Self {
-            type_,
-            indirect: 0,
-            links_num: 0,
-            size: 0,
-            addresses: [0; N_DIRECT],
-        }
+        *self = Self::new(type_, 0, 0, 0, [0; N_DIRECT]);
     }
 
     pub fn is_valid(&self) -> bool {
@@ -268,16 +440,19 @@ impl DInode {
         block_dev: Arc<dyn BlockDevice>,
         cache: Arc<Mutex<BlockCacheBuffer>>,
     ) -> BlockId {
-        assert!(idx < MAX_BLOCKS_PER_INODE);
+        debug_assert!(idx < MAX_BLOCKS_PER_INODE);
 
         if idx < N_DIRECT {
             self.addresses[idx]
+        } else if self.indirect == 0 {
+            // No block past the direct ones is allocated yet.
+            0
         } else if idx < N_DIRECT + N_INDIRECT {
             cache
                 .lock()
                 .get(self.indirect, block_dev.clone())
                 .lock()
-                .read(0, |index_block: &IndexBlock| index_block[idx - N_DIRECT])
+                .read_slice(0, N_INDIRECT, |index: &[BlockId]| index[idx - N_DIRECT])
         } else {
             panic!("the block index is out of range: {}", idx)
         }
@@ -291,17 +466,20 @@ impl DInode {
         block_dev: Arc<dyn BlockDevice>,
         cache: Arc<Mutex<BlockCacheBuffer>>,
     ) {
-        assert!(idx < MAX_BLOCKS_PER_INODE);
+        debug_assert!(idx < MAX_BLOCKS_PER_INODE);
         debug!("dinode: map idx: {} to block id: {}", idx, block_id);
 
         if idx < N_DIRECT {
             self.addresses[idx] = block_id;
         } else if idx < N_DIRECT + N_INDIRECT {
+            assert!(self.indirect != 0, "the indirect block is not allocated");
             cache
                 .lock()
                 .get(self.indirect, block_dev.clone())
                 .lock()
-                .write(0, |index_block: &mut IndexBlock| index_block[idx - N_DIRECT] = block_id)
+                .write_slice(0, N_INDIRECT, |index: &mut [BlockId]| {
+                    index[idx - N_DIRECT] = block_id
+                })
         } else {
             panic!("the block index is out of range: {}", idx)
         }
@@ -317,6 +495,55 @@ impl DInode {
         block_dev: Arc<dyn BlockDevice>,
         cache: Arc<Mutex<BlockCacheBuffer>>,
     ) -> usize {
+        // SAFETY: Only initialized bytes are written to the buffer.
+        let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+        self.read_data_uninit(offset, buf, block_dev, cache)
+    }
+
+    /// Like `read_data`, but looks block ids up with `get_bid`, and
+    /// reads ahead as the earlier reads in `read_ahead` go.
+    pub(crate) fn read_data_with(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_dev: Arc<dyn BlockDevice>,
+        cache: Arc<Mutex<BlockCacheBuffer>>,
+        get_bid: &dyn Fn(usize) -> BlockId,
+        read_ahead: &mut ReadAhead,
+    ) -> usize {
+        // SAFETY: Only initialized bytes are written to the buffer.
+        let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+        self.read_uninit_with(offset, buf, block_dev, cache, get_bid, read_ahead)
+    }
+
+    /// Like `read_data`, but reads into a buffer not initialized yet, so
+    /// the caller doesn't have to zero it first.
+    ///
+    /// Returns the size of read data, the prefix of `buf` initialized.
+    pub fn read_data_uninit(
+        &self,
+        offset: usize,
+        buf: &mut [MaybeUninit<u8>],
+        block_dev: Arc<dyn BlockDevice>,
+        cache: Arc<Mutex<BlockCacheBuffer>>,
+    ) -> usize {
+        let get_bid = |idx| self.get_bid(idx, block_dev.clone(), cache.clone());
+        let read_ahead = &mut ReadAhead::default();
+        self.read_uninit_with(offset, buf, block_dev.clone(), cache.clone(), &get_bid, read_ahead)
+    }
+
+    fn read_uninit_with(
+        &self,
+        offset: usize,
+        buf: &mut [MaybeUninit<u8>],
+        block_dev: Arc<dyn BlockDevice>,
+        cache: Arc<Mutex<BlockCacheBuffer>>,
+        get_bid: &dyn Fn(usize) -> BlockId,
+        read_ahead: &mut ReadAhead,
+    ) -> usize {
+        if offset >= self.size as usize {
+            return 0;
+        }
         let mut start = offset;
         // Ensure the end address does not exceed the safe range.
         let end = start + buf.len().min(self.size as usize - offset);
@@ -328,15 +555,34 @@ impl DInode {
             let incr = end.min((start_block + 1) * BLOCK_SIZE) - start;
             let dst = &mut buf[completed..completed + incr];
 
-            cache
-                .lock()
-                .get(self.get_bid(start_block, block_dev.clone(), cache.clone()), block_dev.clone())
-                .lock()
-                .read(0, |data_block: &DataBlock| {
-                    // Copy data from this block.
-                    let src = &data_block[start % BLOCK_SIZE..start % BLOCK_SIZE + incr];
-                    dst.copy_from_slice(src);
-                });
+            let block_id = get_bid(start_block);
+            if block_id == 0 {
+                // A hole of a sparse file reads as zeros.
+                for dst in dst.iter_mut() {
+                    dst.write(0);
+                }
+            } else {
+                self.read_ahead(
+                    start_block,
+                    block_id,
+                    block_dev.clone(),
+                    cache.clone(),
+                    get_bid,
+                    read_ahead,
+                );
+
+                cache
+                    .lock()
+                    .get(block_id, block_dev.clone())
+                    .lock()
+                    .read(0, |data_block: &DataBlock| {
+                        // Copy data from this block.
+                        let src = &data_block[start % BLOCK_SIZE..start % BLOCK_SIZE + incr];
+                        for (dst, &src) in dst.iter_mut().zip(src) {
+                            dst.write(src);
+                        }
+                    });
+            }
 
             completed += incr;
             start += incr;
@@ -346,6 +592,38 @@ impl DInode {
         completed
     }
 
+    /// Prefetches the blocks following `idx` if it's read sequentially,
+    /// see `ReadAhead`.
+    ///
+    /// Only the run of blocks physically following `block_id` is loaded,
+    /// in one request with `block_id` itself if it's not cached yet, not
+    /// to seek back for it afterwards.
+    fn read_ahead(
+        &self,
+        idx: usize,
+        block_id: BlockId,
+        block_dev: Arc<dyn BlockDevice>,
+        cache: Arc<Mutex<BlockCacheBuffer>>,
+        get_bid: &dyn Fn(usize) -> BlockId,
+        read_ahead: &mut ReadAhead,
+    ) {
+        let count = cache.lock().read_ahead();
+        let ahead = read_ahead.note_read(idx, count);
+        if ahead.is_empty() {
+            return;
+        }
+
+        // The block ids ahead are only looked up once per window.
+        let blocks = (self.size as usize).div_ceil(BLOCK_SIZE);
+        let count = (ahead.start..ahead.end.min(blocks))
+            .take_while(|&i| get_bid(i) == block_id + (i - idx) as u64)
+            .count();
+        read_ahead.read_until(idx + 1 + count);
+        if count > 0 {
+            cache.lock().prefetch(block_id, count + 1, block_dev);
+        }
+    }
+
     /// Writes data from buffer to current disk inode.
     ///
     /// Returns the size of written data.
@@ -356,6 +634,22 @@ impl DInode {
         block_dev: Arc<dyn BlockDevice>,
         cache: Arc<Mutex<BlockCacheBuffer>>,
     ) -> usize {
+        let get_bid = |idx| self.get_bid(idx, block_dev.clone(), cache.clone());
+        self.write_data_with(offset, buf, block_dev.clone(), cache.clone(), &get_bid)
+    }
+
+    /// Like `write_data`, but looks block ids up with `get_bid`.
+    pub(crate) fn write_data_with(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        block_dev: Arc<dyn BlockDevice>,
+        cache: Arc<Mutex<BlockCacheBuffer>>,
+        get_bid: &dyn Fn(usize) -> BlockId,
+    ) -> usize {
+        if offset >= self.size as usize {
+            return 0;
+        }
         let mut start_addr = offset;
         // Ensure the end address does not exceed the safe range.
         let end_addr = start_addr + buf.len().min(self.size as usize - offset);
@@ -365,7 +659,11 @@ impl DInode {
         while start_addr < end_addr {
             // Growth value is the minimum of the end address or the block boundary.
             let incr = end_addr.min((start_block + 1) * BLOCK_SIZE) - start_addr;
-            let block_id = self.get_bid(start_block, block_dev.clone(), cache.clone());
+            let block_id = get_bid(start_block);
+            if block_id == 0 {
+                warn!("dinode: block {} is not allocated, write stops short", start_block);
+                break;
+            }
 
             cache.lock().get(block_id, block_dev.clone()).lock().write(
                 0,
@@ -386,11 +684,34 @@ impl DInode {
     }
 }
 
+#[repr(u8)]
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum InodeType {
     Invalid,
     File,
     Directory,
+    /// Named pipe.
+    Fifo,
+}
+
+impl InodeType {
+    /// Returns the type stored as `byte`, `Invalid` if it's unknown.
+    pub fn from_u8(byte: u8) -> Self {
+        match byte {
+            1 => InodeType::File,
+            2 => InodeType::Directory,
+            3 => InodeType::Fifo,
+            _ => InodeType::Invalid,
+        }
+    }
+
+    /// The mode of a new inode of this type.
+    pub fn default_mode(self) -> u32 {
+        match self {
+            InodeType::Directory => 0o755,
+            _ => 0o644,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -398,10 +719,11 @@ mod tests {
     extern crate std;
 
     use super::*;
+    use crate::ram_disk::RamDisk;
 
     #[test]
     fn test_super_block() {
-        let x = &mut [0u8; size_of::<SuperBlock>()];
+        let x = &mut [0u64; size_of::<SuperBlock>() / size_of::<u64>()];
         let sb = x as *mut _ as *mut SuperBlock;
 
         assert_eq!(
@@ -415,12 +737,14 @@ mod tests {
                 inode_start:      0,
                 data_bmap_start:  0,
                 data_start:       0,
+                version:          0,
+                _reserved:        0,
             }
         );
-        assert_eq!(unsafe { (*sb).is_valid() }, false);
+        assert!(!unsafe { (*sb).is_valid() });
 
         unsafe { (*sb).magic = FS_MAGIC }
-        assert_eq!(unsafe { (*sb).is_valid() }, true);
+        assert!(unsafe { (*sb).is_valid() });
     }
 
     #[test]
@@ -446,16 +770,128 @@ mod tests {
     #[test]
     fn dir_entry_test() {
         for name in ["test", &"1".repeat(DIR_NAME_SIZE), "😀"] {
-            let dirent = DirEntry::new(name, 2);
+            let dirent = DirEntry::new(name, 2, InodeType::File);
             assert_eq!(dirent.name(), name);
+            assert_eq!(dirent.type_, InodeType::File);
+        }
+        assert_eq!(DIR_ENTRY_SIZE, 32);
+    }
+
+    /// A disk whose bytes are their offset in the block plus the block id.
+    fn pattern_disk() -> Arc<dyn BlockDevice> {
+        let disk = RamDisk::new(16);
+        for block_id in 0..16 {
+            let block: std::vec::Vec<u8> = (0..BLOCK_SIZE)
+                .map(|i| (i as u64 + block_id) as u8)
+                .collect();
+            disk.write(block_id, &block).unwrap();
+        }
+        Arc::new(disk)
+    }
+
+    #[test]
+    fn test_read_data_uninit() {
+        let dev = pattern_disk();
+        let cache = Arc::new(Mutex::new(BlockCacheBuffer::new(4)));
+        let mut addresses = [0; N_DIRECT];
+        addresses[..2].copy_from_slice(&[7, 9]);
+        let size = BLOCK_SIZE + 100;
+        let dinode = DInode::new(InodeType::File, 0, 1, size as u64, addresses);
+
+        let mut expected = [0u8; BLOCK_SIZE];
+        let n = dinode.read_data(0, &mut expected, dev.clone(), cache.clone());
+        assert_eq!(n, BLOCK_SIZE);
+        let mut uninit = [MaybeUninit::<u8>::uninit(); BLOCK_SIZE];
+        let n = dinode.read_data_uninit(0, &mut uninit, dev.clone(), cache.clone());
+        assert_eq!(n, BLOCK_SIZE);
+        let read: std::vec::Vec<u8> = uninit.iter().map(|b| unsafe { b.assume_init() }).collect();
+        assert_eq!(read, expected);
+
+        // Only the bytes before the end of the file are initialized.
+        let mut uninit = [MaybeUninit::<u8>::uninit(); BLOCK_SIZE];
+        let n = dinode.read_data_uninit(BLOCK_SIZE, &mut uninit, dev, cache);
+        assert_eq!(n, 100);
+        let read: std::vec::Vec<u8> =
+            uninit[..n].iter().map(|b| unsafe { b.assume_init() }).collect();
+        assert_eq!(read, (9..109).collect::<std::vec::Vec<u8>>());
+    }
+
+    #[test]
+    fn test_read_hole() {
+        let dev = pattern_disk();
+        let cache = Arc::new(Mutex::new(BlockCacheBuffer::new(4)));
+        let mut addresses = [0; N_DIRECT];
+        addresses[..3].copy_from_slice(&[7, 0, 9]);
+        let dinode = DInode::new(InodeType::File, 0, 1, 3 * BLOCK_SIZE as u64, addresses);
+
+        // From the end of the first block to the start of the third.
+        let mut buf = [0xffu8; BLOCK_SIZE + 20];
+        let n = dinode.read_data(BLOCK_SIZE - 10, &mut buf, dev, cache);
+        assert_eq!(n, buf.len());
+        let pattern = |block_id: usize, offset: usize| (offset + block_id) as u8;
+        for (i, &byte) in buf[..10].iter().enumerate() {
+            assert_eq!(byte, pattern(7, BLOCK_SIZE - 10 + i));
+        }
+        assert!(buf[10..BLOCK_SIZE + 10].iter().all(|&byte| byte == 0));
+        for (i, &byte) in buf[BLOCK_SIZE + 10..].iter().enumerate() {
+            assert_eq!(byte, pattern(9, i));
+        }
+    }
+
+    #[test]
+    fn test_write_past_hole() {
+        let dev = pattern_disk();
+        let cache = Arc::new(Mutex::new(BlockCacheBuffer::new(4)));
+        let mut addresses = [0; N_DIRECT];
+        addresses[..3].copy_from_slice(&[7, 0, 9]);
+        let dinode = DInode::new(InodeType::File, 0, 1, 3 * BLOCK_SIZE as u64, addresses);
+
+        let n = dinode.write_data(2 * BLOCK_SIZE + 10, &[0xaa; 20], dev.clone(), cache.clone());
+        assert_eq!(n, 20);
+
+        let mut buf = [0xffu8; 3 * BLOCK_SIZE];
+        assert_eq!(dinode.read_data(0, &mut buf, dev, cache), buf.len());
+        let pattern = |block_id: usize, offset: usize| (offset + block_id) as u8;
+        let (first, rest) = buf.split_at(BLOCK_SIZE);
+        let (hole, last) = rest.split_at(BLOCK_SIZE);
+        for (i, &byte) in first.iter().enumerate() {
+            assert_eq!(byte, pattern(7, i));
+        }
+        assert!(hole.iter().all(|&byte| byte == 0));
+        for (i, &byte) in last.iter().enumerate() {
+            let expected = if (10..30).contains(&i) {
+                0xaa
+            } else {
+                pattern(9, i)
+            };
+            assert_eq!(byte, expected);
         }
     }
 
     #[test]
     fn dinode_test() {
-        let x = &mut [0u8; size_of::<DInode>()];
+        let x = &mut [0u64; size_of::<DInode>() / size_of::<u64>()];
         let inode = x as *mut _ as *mut DInode;
 
-        assert_eq!(unsafe { (*inode).is_valid() }, false);
+        assert!(!unsafe { (*inode).is_valid() });
+    }
+
+    #[test]
+    fn test_dir_entry_bytes() {
+        let dirent = DirEntry::new("hello.txt", 42, InodeType::Fifo);
+        let bytes = *dirent.as_bytes();
+        assert_eq!(bytes[..8], 42u64.to_ne_bytes());
+        assert_eq!(&bytes[8..17], b"hello.txt");
+
+        let parsed = DirEntry::from_bytes(&bytes);
+        assert_eq!(parsed.inode_num, 42);
+        assert_eq!(parsed.name(), "hello.txt");
+        assert_eq!(parsed.type_, InodeType::Fifo);
+        assert_eq!(parsed.as_bytes(), &bytes);
+
+        // A corrupt type isn't taken as is.
+        let mut corrupt = bytes;
+        corrupt[DIR_ENTRY_SIZE - 1] = 0xff;
+        assert_eq!(DirEntry::from_bytes(&corrupt).type_, InodeType::Invalid);
     }
 }
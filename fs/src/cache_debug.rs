@@ -0,0 +1,75 @@
+//! Tracks which task holds each pinned cache entry, so running out of
+//! cache buffers can be reported with the holders.
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::fmt::{Display, Write};
+
+use spin::Once;
+
+/// Identifies the holder of a cache entry, i.e. a task id.
+pub type HolderId = u64;
+
+static CURRENT_HOLDER: Once<fn() -> Option<HolderId>> = Once::new();
+
+/// Sets the function identifying the caller of the caches.
+///
+/// It takes effect only on the first call.
+pub fn set_current_holder(f: fn() -> Option<HolderId>) {
+    CURRENT_HOLDER.call_once(|| f);
+}
+
+fn current_holder() -> Option<HolderId> {
+    CURRENT_HOLDER.get().and_then(|f| f())
+}
+
+/// The holders of the cache entries keyed by `K`.
+pub struct Holders<K> {
+    holders: BTreeMap<K, Vec<HolderId>>,
+}
+
+impl<K> Default for Holders<K> {
+    fn default() -> Self {
+        Self {
+            holders: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Ord + Copy + Display> Holders<K> {
+    /// Records the caller takes the entry `key`, `pinned` tells whether
+    /// it was held by others already.
+    pub fn take(&mut self, key: K, pinned: bool) {
+        let holders = self.holders.entry(key).or_default();
+        if !pinned {
+            holders.clear();
+        }
+        if let Some(holder) = current_holder() {
+            if !holders.contains(&holder) {
+                holders.push(holder);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: K) {
+        self.holders.remove(&key);
+    }
+
+    /// Describes the holders of `keys`, e.g. `3 by task 1, 2; 4 by unknown`.
+    pub fn report(&self, keys: impl Iterator<Item = K>) -> String {
+        let mut report = String::new();
+        for key in keys {
+            if !report.is_empty() {
+                report.push_str("; ");
+            }
+            let holders = match self.holders.get(&key) {
+                Some(holders) if !holders.is_empty() => {
+                    let ids: Vec<_> = holders.iter().map(|id| format!("{}", id)).collect();
+                    format!("task {}", ids.join(", "))
+                }
+                _ => String::from("unknown"),
+            };
+            let _ = write!(report, "{} by {}", key, holders);
+        }
+        report
+    }
+}
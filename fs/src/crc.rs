@@ -0,0 +1,87 @@
+//! CRC-32 with the IEEE polynomial, the one of zlib and Ethernet, for
+//! checksums of the on-disk structures.
+
+/// The reversed IEEE polynomial.
+const POLY: u32 = 0xedb8_8320;
+
+/// The CRC of each byte value, built at compile time.
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// An incremental CRC-32, fed with the data piece by piece.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self { state: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state = TABLE[((self.state ^ byte as u32) & 0xff) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    /// Returns the CRC of the data fed so far.
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the CRC-32 of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_dev::BLOCK_SIZE;
+
+    #[test]
+    fn test_crc32_vectors() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"a"), 0xe8b7_be43);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+        assert_eq!(crc32(&[0; 32]), 0x190a_55ad);
+    }
+
+    #[test]
+    fn test_crc32_incremental() {
+        let data: [u8; BLOCK_SIZE] = core::array::from_fn(|i| (i * 7) as u8);
+        let mut crc = Crc32::new();
+        for chunk in data.chunks(100) {
+            crc.update(chunk);
+        }
+        assert_eq!(crc.finish(), crc32(&data));
+    }
+}
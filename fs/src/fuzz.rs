@@ -0,0 +1,17 @@
+//! Helpers to drive the file system from property tests and fuzz
+//! targets on the host, enabled by the `std` feature.
+
+use alloc::sync::Arc;
+
+use crate::{ram_disk::RamDisk, FileSystem};
+
+/// Splits the first name off `path`, see `FileSystem::get_inode_from_path`.
+pub fn skip(path: &str) -> Option<(&str, &str)> {
+    crate::skip(path)
+}
+
+/// Creates a file system of `total_blocks` in memory.
+pub fn mem_fs(total_blocks: u64, inode_blocks: u64) -> Arc<FileSystem> {
+    let dev = Arc::new(RamDisk::new(total_blocks as usize));
+    FileSystem::create(dev, total_blocks, inode_blocks).expect("failed to create the file system")
+}
@@ -1,17 +1,28 @@
 use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::{String, ToString},
     sync::{Arc, Weak},
     vec::Vec,
 };
+use core::ops::Bound;
+
 use log::{debug, warn};
 use spin::Mutex;
 
 use crate::{
-    block_dev::{BlockId, DInode, InBlockOffset, InodeId, InodeType, N_DIRECT},
+    block_cache::{BlockCacheBuffer, ReadAhead},
+    block_dev::{
+        BlockDevice, BlockId, DInode, InBlockOffset, IndexBlock, InodeId, InodeType, N_DIRECT,
+    },
     FileSystem,
 };
 
 pub const INODE_BUFFER_SIZE: usize = 64;
 
+/// The number of names cached per directory.
+pub const NAME_CACHE_SIZE: usize = 16;
+
 /// Inodes cache.
 ///
 /// Keeps a cache of in-use inodes in memory to provide a place
@@ -19,6 +30,8 @@ pub const INODE_BUFFER_SIZE: usize = 64;
 pub struct InodeCacheBuffer {
     cache:    Vec<(InodeId, Arc<Mutex<Inode>>)>,
     capacity: usize,
+    /// Counts the inodes loaded from the block cache by `get`.
+    misses:   u64,
 }
 
 impl InodeCacheBuffer {
@@ -26,9 +39,14 @@ impl InodeCacheBuffer {
         Self {
             cache: Vec::new(),
             capacity,
+            misses: 0,
         }
     }
 
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
     pub fn get(
         &mut self,
         inum: InodeId,
@@ -43,17 +61,28 @@ impl InodeCacheBuffer {
             return Err(InodeNotExists(inum));
         }
 
-        if self.cache.len() == self.capacity {
-            let (id, _) = self.cache.remove(self.capacity - 1);
-            debug!("remove inode {} from cache", id);
-        }
-
         let inode = match self.cache.iter().position(|&(id, _)| id == inum) {
             Some(pos) => {
                 let (_, inode) = self.cache.remove(pos);
                 inode
             }
             None => {
+                // Only the inodes not in use are evicted, the cache grows
+                // past its capacity otherwise. An inode loaded twice would
+                // have two copies, each locked on its own.
+                while self.cache.len() >= self.capacity {
+                    let Some(pos) = self
+                        .cache
+                        .iter()
+                        .rposition(|(_, inode)| Arc::strong_count(inode) == 1)
+                    else {
+                        break;
+                    };
+                    let (id, _) = self.cache.remove(pos);
+                    debug!("remove inode {} from cache", id);
+                }
+
+                self.misses += 1;
                 let (block_id, in_block_offset) = fs.sb.find_inode(inum);
 
                 // Acquire cache buffer block.
@@ -94,17 +123,26 @@ pub struct Inode {
     /// Inode number.
     pub inode_num:       InodeId,
 
-    // Copy of `DInode`.
     /// File type.
     pub type_: InodeType,
-    /// Indirect block number.
-    indirect:  InodeId,
-    /// Counts the number of directory entries that refer to this inode.
-    links_num: u64,
-    /// Size of file (bytes).
-    size:      u64,
-    /// Data block addresses.
-    addresses: [BlockId; N_DIRECT],
+    /// Copy of `DInode`.
+    dinode:    DInode,
+    /// Copy of the indirect block, loaded on the first lookup past the
+    /// direct blocks.
+    index:     Mutex<Option<Box<IndexBlock>>>,
+
+    /// Names looked up in this directory.
+    names: Mutex<NameCache>,
+
+    /// All the entries of this directory, built on the first lookup
+    /// missing `names`.
+    dir_index: Mutex<Option<DirIndex>>,
+
+    /// The sequential reads of this file.
+    read_ahead: Mutex<ReadAhead>,
+
+    /// Counts the `InodeHandle`s opened on this inode.
+    open_count: usize,
 }
 
 impl Inode {
@@ -121,10 +159,12 @@ impl Inode {
             in_block_offset,
             inode_num,
             type_: dinode.type_,
-            indirect: dinode.indirect,
-            links_num: dinode.links_num,
-            size: dinode.size,
-            addresses: dinode.addresses,
+            dinode: *dinode,
+            index: Mutex::new(None),
+            names: Mutex::new(NameCache::default()),
+            dir_index: Mutex::new(None),
+            read_ahead: Mutex::new(ReadAhead::default()),
+            open_count: 0,
         }
     }
 
@@ -133,23 +173,227 @@ impl Inode {
     }
 
     pub fn size(&self) -> usize {
-        self.size as usize
+        self.dinode.size as usize
+    }
+
+    pub fn links_num(&self) -> u64 {
+        self.dinode.links_num
+    }
+
+    pub fn open_count(&self) -> usize {
+        self.open_count
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.dinode.mode
+    }
+
+    /// Returns the owner as (uid, gid).
+    pub fn owner(&self) -> (u32, u32) {
+        (self.dinode.uid, self.dinode.gid)
     }
 
     pub fn dinode(&self) -> DInode {
-        DInode::new(self.type_, self.indirect, self.links_num, self.size, self.addresses)
+        self.dinode
+    }
+
+    /// Gets block id by inner index, like `DInode::get_bid` but the
+    /// indirect block is only loaded once.
+    pub fn get_bid(
+        &self,
+        idx: usize,
+        block_dev: Arc<dyn BlockDevice>,
+        cache: Arc<Mutex<BlockCacheBuffer>>,
+    ) -> BlockId {
+        if idx < N_DIRECT || self.dinode.indirect == 0 {
+            return self.dinode.get_bid(idx, block_dev, cache);
+        }
+        let mut index = self.index.lock();
+        let index = index.get_or_insert_with(|| {
+            cache
+                .lock()
+                .get(self.dinode.indirect, block_dev)
+                .lock()
+                .read(0, |block: &IndexBlock| Box::new(*block))
+        });
+        index[idx - N_DIRECT]
+    }
+
+    /// Reads data from this inode to buffer, see `DInode::read_data`.
+    pub fn read_data(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_dev: Arc<dyn BlockDevice>,
+        cache: Arc<Mutex<BlockCacheBuffer>>,
+    ) -> usize {
+        let get_bid = |idx| self.get_bid(idx, block_dev.clone(), cache.clone());
+        self.dinode.read_data_with(
+            offset,
+            buf,
+            block_dev.clone(),
+            cache.clone(),
+            &get_bid,
+            &mut self.read_ahead.lock(),
+        )
+    }
+
+    /// Writes data from buffer to this inode, see `DInode::write_data`.
+    pub fn write_data(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        block_dev: Arc<dyn BlockDevice>,
+        cache: Arc<Mutex<BlockCacheBuffer>>,
+    ) -> usize {
+        let get_bid = |idx| self.get_bid(idx, block_dev.clone(), cache.clone());
+        self.dinode
+            .write_data_with(offset, buf, block_dev.clone(), cache.clone(), &get_bid)
     }
 
     pub fn is_valid(&self) -> bool {
         self.type_ != InodeType::Invalid
     }
 
+    /// Looks up `name` in the names cached in this directory.
+    pub fn cached_name(&self, name: &str) -> Option<InodeId> {
+        self.names.lock().get(name)
+    }
+
+    pub fn cache_name(&self, name: &str, inum: InodeId) {
+        self.names.lock().insert(name, inum);
+    }
+
+    /// Drops the cached names, for the entries of this directory changed.
+    pub fn invalidate_names(&mut self) {
+        self.names.get_mut().clear();
+    }
+
+    pub(crate) fn dir_index(&self) -> &Mutex<Option<DirIndex>> {
+        &self.dir_index
+    }
+
+    /// Drops the index of the entries, for they are rewritten in place.
+    pub fn invalidate_dir_index(&mut self) {
+        *self.dir_index.get_mut() = None;
+    }
+
     pub fn update(&mut self, dinode: &DInode) {
         self.type_ = dinode.type_;
-        self.indirect = dinode.indirect;
-        self.links_num = dinode.links_num;
-        self.size = dinode.size;
-        self.addresses = dinode.addresses;
+        self.dinode = *dinode;
+        *self.index.get_mut() = None;
+    }
+}
+
+/// An opened inode.
+///
+/// Keeps an unlinked inode alive, the last handle dropped frees it.
+pub struct InodeHandle {
+    inode: Arc<Mutex<Inode>>,
+}
+
+impl InodeHandle {
+    pub fn open(inode: Arc<Mutex<Inode>>) -> Self {
+        inode.lock().open_count += 1;
+        Self { inode }
+    }
+
+    pub fn inode(&self) -> &Arc<Mutex<Inode>> {
+        &self.inode
+    }
+}
+
+impl Drop for InodeHandle {
+    fn drop(&mut self) {
+        let mut inode = self.inode.lock();
+        inode.open_count -= 1;
+        if inode.open_count == 0 && inode.links_num() == 0 && inode.is_valid() {
+            if let Some(fs) = inode.get_fs() {
+                debug!("free unlinked inode {} on the last close", inode.inode_num);
+                fs.release_inode(&mut inode);
+            }
+        }
+    }
+}
+
+/// The recently looked up names of a directory, most recent first.
+#[derive(Default)]
+struct NameCache {
+    entries: VecDeque<(String, InodeId)>,
+}
+
+impl NameCache {
+    fn get(&mut self, name: &str) -> Option<InodeId> {
+        let pos = self.entries.iter().position(|(n, _)| n == name)?;
+        let entry = self.entries.remove(pos)?;
+        let inum = entry.1;
+        self.entries.push_front(entry);
+        Some(inum)
+    }
+
+    fn insert(&mut self, name: &str, inum: InodeId) {
+        if self.get(name).is_some() {
+            return;
+        }
+        if self.entries.len() == NAME_CACHE_SIZE {
+            self.entries.pop_back();
+        }
+        self.entries.push_front((name.to_string(), inum));
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// The entries of a directory by name, so looking one up doesn't scan
+/// the directory.
+#[derive(Default)]
+pub(crate) struct DirIndex {
+    // The slot and the inode of each entry, the first one if a name
+    // is repeated.
+    entries: BTreeMap<String, (usize, InodeId)>,
+    // The number of zeroed slots.
+    zeroed:  usize,
+}
+
+impl DirIndex {
+    pub(crate) fn get(&self, name: &str) -> Option<(usize, InodeId)> {
+        self.entries.get(name).copied()
+    }
+
+    /// Adds the entry in `slot`, a zeroed one if `name` is empty.
+    pub(crate) fn insert(&mut self, name: &str, slot: usize, inum: InodeId) {
+        if name.is_empty() {
+            self.zeroed += 1;
+        } else {
+            self.entries.entry(name.to_string()).or_insert((slot, inum));
+        }
+    }
+
+    /// Returns the slot of the first entry sorted after `name`, the first
+    /// one if `None`.
+    pub(crate) fn next_after(&self, name: Option<&str>) -> Option<usize> {
+        let bound = name.map_or(Bound::Unbounded, Bound::Excluded);
+        self.entries
+            .range::<str, _>((bound, Bound::Unbounded))
+            .next()
+            .map(|(_, &(slot, _))| slot)
+    }
+
+    pub(crate) fn remove(&mut self, name: &str) -> Option<(usize, InodeId)> {
+        self.entries.remove(name)
+    }
+
+    /// Records the entry `name` moved to `slot`.
+    pub(crate) fn move_to(&mut self, name: &str, slot: usize) {
+        if let Some(entry) = self.entries.get_mut(name) {
+            entry.0 = slot;
+        }
+    }
+
+    pub(crate) fn zeroed(&self) -> usize {
+        self.zeroed
     }
 }
 
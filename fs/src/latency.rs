@@ -0,0 +1,111 @@
+//! A block device charging the time a rotational disk would take, for
+//! tests to compare the I/O patterns of the file system.
+
+use alloc::{string::String, sync::Arc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::block_dev::{BlockDevice, BlockId};
+
+/// Simulated cost of moving the head to another track, in microseconds.
+const SEEK_US: u64 = 4000;
+/// Simulated cost of waiting for the block to come under the head.
+const ROTATION_US: u64 = 2000;
+/// Simulated cost of issuing a request.
+const REQUEST_US: u64 = 100;
+/// Simulated cost of transferring a block.
+const TRANSFER_US: u64 = 40;
+
+/// Wraps a device to charge each request the simulated time a
+/// rotational disk would take, the blocks right after the previous
+/// request don't seek.
+pub struct LatencyBlockDevice {
+    dev:        Arc<dyn BlockDevice>,
+    /// The block under the head.
+    head:       AtomicU64,
+    elapsed_us: AtomicU64,
+}
+
+impl LatencyBlockDevice {
+    pub fn new(dev: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            dev,
+            head: AtomicU64::new(0),
+            elapsed_us: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the simulated time of the I/O so far, in microseconds.
+    pub fn elapsed_us(&self) -> u64 {
+        self.elapsed_us.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.elapsed_us.store(0, Ordering::Relaxed);
+    }
+
+    fn charge(&self, start: BlockId, blocks: u64) {
+        let mut cost = REQUEST_US + blocks * TRANSFER_US;
+        if self.head.swap(start + blocks, Ordering::Relaxed) != start {
+            cost += SEEK_US + ROTATION_US;
+        }
+        self.elapsed_us.fetch_add(cost, Ordering::Relaxed);
+    }
+}
+
+impl BlockDevice for LatencyBlockDevice {
+    fn read(&self, block_id: BlockId, buf: &mut [u8]) -> Result<(), String> {
+        self.charge(block_id, 1);
+        self.dev.read(block_id, buf)
+    }
+
+    fn write(&self, block_id: BlockId, buf: &[u8]) -> Result<(), String> {
+        self.charge(block_id, 1);
+        self.dev.write(block_id, buf)
+    }
+
+    fn read_many(&self, start: BlockId, bufs: &mut [&mut [u8]]) -> Result<(), String> {
+        self.charge(start, bufs.len() as u64);
+        self.dev.read_many(start, bufs)
+    }
+
+    fn write_many(&self, start: BlockId, bufs: &[&[u8]]) -> Result<(), String> {
+        self.charge(start, bufs.len() as u64);
+        self.dev.write_many(start, bufs)
+    }
+
+    fn supports_partial_write(&self) -> bool {
+        self.dev.supports_partial_write()
+    }
+
+    fn write_partial(&self, block_id: BlockId, offset: usize, buf: &[u8]) -> Result<(), String> {
+        self.charge(block_id, 1);
+        self.dev.write_partial(block_id, offset, buf)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.dev.flush()
+    }
+
+    fn discard(&self, block_id: BlockId, count: usize) -> Result<(), String> {
+        self.dev.discard(block_id, count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block_dev::BLOCK_SIZE, ram_disk::RamDisk};
+
+    #[test]
+    fn test_sequential_requests_dont_seek() {
+        let dev = LatencyBlockDevice::new(Arc::new(RamDisk::new(16)));
+        let mut buf = [0; BLOCK_SIZE];
+        dev.read(0, &mut buf).unwrap();
+        dev.reset();
+
+        dev.read(1, &mut buf).unwrap();
+        assert_eq!(dev.elapsed_us(), REQUEST_US + TRANSFER_US);
+        dev.read(8, &mut buf).unwrap();
+        assert_eq!(dev.elapsed_us(), 2 * (REQUEST_US + TRANSFER_US) + SEEK_US + ROTATION_US);
+    }
+}
@@ -1,36 +1,70 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 extern crate alloc;
 
 use alloc::{
     string::{String, ToString},
     sync::Arc,
+    vec,
     vec::Vec,
 };
 use block_cache::{BlockCacheBuffer, BLOCK_BUFFER_SIZE};
 use block_dev::{
-    BitmapBlock, BlockDevice, BlockId, DInode, DirEntry, InodeId, InodeType, SuperBlock,
-    BLOCK_SIZE, CAPACITY_PER_INODE, DINODE_SIZE, DIR_ENTRY_SIZE, INODES_PER_BLOCK,
-    MAX_BLOCKS_PER_INODE,
+    BitmapBlock, BlockDevice, BlockId, DInode, DataBlock, DirEntry, InBlockOffset, InodeId,
+    InodeType, SuperBlock, BITMAP_PER_BLOCK, BLOCK_SIZE, CAPACITY_PER_INODE, DINODE_SIZE,
+    DIR_ENTRY_SIZE, DIR_NAME_SIZE, FS_VERSION, INODES_PER_BLOCK, MAX_BLOCKS_PER_INODE, N_DIRECT,
 };
 use core::{
     cmp::min,
-    mem::size_of,
-    slice::{from_raw_parts, from_raw_parts_mut},
+    fmt,
+    mem::{size_of, MaybeUninit},
+    ops::Range,
 };
-use inode::{Inode, InodeCacheBuffer, InodeNotExists, INODE_BUFFER_SIZE};
+use inode::{DirIndex, Inode, InodeCacheBuffer, InodeNotExists, INODE_BUFFER_SIZE};
 use log::{debug, trace, warn};
+use metrics::{FsMetrics, IoCounters, MeteredDevice};
+use scoped::ScopedFs;
 use spin::{Mutex, MutexGuard};
+use xattr::XATTR_KEY_MAX;
 
+pub mod barrier;
 pub mod block_cache;
 pub mod block_dev;
+#[cfg(any(test, feature = "cache-debug"))]
+pub mod cache_debug;
+pub mod crc;
+#[cfg(feature = "std")]
+pub mod fuzz;
 pub mod inode;
+#[cfg(any(test, feature = "std", feature = "test-devices"))]
+pub mod latency;
+pub mod metrics;
+#[cfg(any(test, feature = "std", feature = "test-devices"))]
+pub mod ram_disk;
+#[cfg(any(test, feature = "std", feature = "test-devices"))]
+pub mod recording;
+pub mod scoped;
+pub mod xattr;
 
 /// The location of the super block.
 pub const SUPER_BLOCK_LOC: u64 = 1;
 
+/// The largest file `FileSystem::read_path` reads into memory.
+pub const READ_PATH_MAX: usize = CAPACITY_PER_INODE;
+
+/// A directory about to grow a block is compacted first if it has at
+/// least this many zeroed entries.
+const COMPACT_THRESHOLD: usize = BLOCK_SIZE / DIR_ENTRY_SIZE / 4;
+
+/// The blocks between the first ones of two files next to each other
+/// in the inode table, under `AllocPolicy::Contiguous`.
+const ALLOC_GROUP_BLOCKS: u64 = 64;
+
 pub struct FileSystem {
     dev: Arc<dyn BlockDevice>,
+    // The I/O done through this file system, the device counts the
+    // blocks into it.
+    counters: Arc<IoCounters>,
     // A copy of super block in memory.
     // We can't edit the data in super block on disk during the
     // file system running except when it creating. Therefor,
@@ -43,6 +77,8 @@ pub struct FileSystem {
     // This lock protects the invariant that an inode is present in the
     // cache at most once.
     inode_cache: Arc<Mutex<InodeCacheBuffer>>,
+    // Where the blocks of a growing file are placed.
+    alloc_policy: Mutex<AllocPolicy>,
 }
 
 impl FileSystem {
@@ -55,6 +91,16 @@ impl FileSystem {
         dev: Arc<dyn BlockDevice>,
         total_blocks: u64,
         inode_blocks: u64,
+    ) -> Result<Arc<Self>, FileSystemInitError> {
+        Self::create_with(dev, total_blocks, inode_blocks, FormatOptions::default())
+    }
+
+    /// Create file system on given block device, formatted as `options`.
+    pub fn create_with(
+        dev: Arc<dyn BlockDevice>,
+        total_blocks: u64,
+        inode_blocks: u64,
+        options: FormatOptions,
     ) -> Result<Arc<Self>, FileSystemInitError> {
         let mut rest_blocks = total_blocks;
 
@@ -113,47 +159,266 @@ impl FileSystem {
             data_blocks_num,
         );
         debug!("fs: init fs with super block: {:#?}", sb);
-        let root_inode = Self::init_fs(dev.clone(), sb).unwrap();
+        let root_inode = Self::init_fs(dev.clone(), sb, options).unwrap();
         assert_eq!(root_inode.lock().inode_num, 0);
 
         Ok(FileSystem::open(dev, true).expect("Failed to create file system."))
     }
 
+    /// Opens the file system on `dev`.
+    ///
+    /// If `validate`, the images of an older format are migrated to
+    /// `FS_VERSION` in place, and the ones can't be read are rejected.
     pub fn open(dev: Arc<dyn BlockDevice>, validate: bool) -> Result<Arc<Self>, FileSystemInvalid> {
+        if validate {
+            // The block cache only logs the errors, probe the device first.
+            let mut buf = [0u8; BLOCK_SIZE];
+            dev.read(SUPER_BLOCK_LOC, &mut buf)
+                .map_err(FileSystemInvalid::Unreadable)?;
+        }
+
+        let counters = Arc::new(IoCounters::default());
+        let dev: Arc<dyn BlockDevice> = Arc::new(MeteredDevice::new(dev, counters.clone()));
         let block_cache = Arc::new(Mutex::new(BlockCacheBuffer::new(BLOCK_BUFFER_SIZE)));
         let inode_cache = Arc::new(Mutex::new(InodeCacheBuffer::new(INODE_BUFFER_SIZE)));
 
-        let mut lock = block_cache.lock();
-        lock.get(SUPER_BLOCK_LOC, dev.clone())
+        // Pinned, so the super block is never re-read from the device.
+        let super_block = block_cache
             .lock()
-            .read(0, |super_block: &SuperBlock| {
-                if super_block.is_valid() || !validate {
-                    Ok(Arc::new(Self {
-                        dev: dev.clone(),
-                        sb: Arc::new(super_block.clone()),
-                        block_cache: block_cache.clone(),
-                        inode_cache: inode_cache.clone(),
-                    }))
-                } else {
-                    Err(FileSystemInvalid())
+            .pin(SUPER_BLOCK_LOC, dev.clone())
+            .lock()
+            .read(0, |super_block: &SuperBlock| *super_block);
+        let mut fs = Self {
+            dev,
+            counters,
+            sb: Arc::new(super_block),
+            block_cache,
+            inode_cache,
+            alloc_policy: Mutex::new(AllocPolicy::default()),
+        };
+
+        if validate {
+            if !super_block.is_valid() {
+                return Err(FileSystemInvalid::BadMagic(super_block.magic()));
+            }
+            if super_block.version() > FS_VERSION {
+                return Err(FileSystemInvalid::UnsupportedVersion(super_block.version()));
+            }
+            while fs.sb.version() < FS_VERSION {
+                fs.migrate(fs.sb.version())?;
+            }
+        }
+        Ok(Arc::new(fs))
+    }
+
+    /// Upgrades the image from `from_version` to the next version in place.
+    fn migrate(&mut self, from_version: u32) -> Result<(), FileSystemInvalid> {
+        let mut sb = *self.sb;
+        match from_version {
+            // Nothing changed on disk but the version itself.
+            0 => sb.set_version(1),
+            // The last two direct blocks became the mode and the owner,
+            // so only the inodes not using them can be kept.
+            1 => {
+                let inodes =
+                    self.read_bmap(sb.inode_bmap_start(), sb.inode_start(), self.max_inode_num());
+                let locations: Vec<_> = (0..inodes.len())
+                    .filter(|&inum| inodes[inum])
+                    .map(|inum| sb.find_inode(inum as InodeId))
+                    .collect();
+
+                let read_dinode = |&(block_id, offset): &(BlockId, InBlockOffset)| {
+                    self.block_cache
+                        .lock()
+                        .get(block_id, self.dev.clone())
+                        .lock()
+                        .read(offset, |dinode: &DInode| *dinode)
+                };
+                if locations
+                    .iter()
+                    .map(read_dinode)
+                    .any(|dinode| dinode.size as usize > N_DIRECT * BLOCK_SIZE)
+                {
+                    return Err(FileSystemInvalid::Incompatible(from_version));
                 }
-            })
+
+                for &(block_id, offset) in &locations {
+                    self.block_cache
+                        .lock()
+                        .get(block_id, self.dev.clone())
+                        .lock()
+                        .write(offset, |dinode: &mut DInode| {
+                            *dinode = DInode::new(
+                                dinode.type_,
+                                dinode.indirect,
+                                dinode.links_num,
+                                dinode.size,
+                                dinode.addresses,
+                            );
+                        });
+                }
+                sb.set_version(2);
+            }
+            // The last byte of the names became the type of the entries,
+            // so only the names shorter than that can be kept.
+            2 => {
+                let inodes =
+                    self.read_bmap(sb.inode_bmap_start(), sb.inode_start(), self.max_inode_num());
+                let read_dinode = |inum: InodeId| {
+                    let (block_id, offset) = sb.find_inode(inum);
+                    self.block_cache
+                        .lock()
+                        .get(block_id, self.dev.clone())
+                        .lock()
+                        .read(offset, |dinode: &DInode| *dinode)
+                };
+                let dirs: Vec<_> = (0..inodes.len())
+                    .filter(|&inum| inodes[inum])
+                    .map(|inum| read_dinode(inum as InodeId))
+                    .filter(|dinode| dinode.type_ == InodeType::Directory)
+                    .collect();
+                let read_dirents = |dir: &DInode| {
+                    let mut bytes = vec![0u8; dir.size as usize / DIR_ENTRY_SIZE * DIR_ENTRY_SIZE];
+                    dir.read_data(0, &mut bytes, self.dev.clone(), self.block_cache.clone());
+                    bytes
+                };
+
+                // Check all of them first, a rejected image is left as it was.
+                if dirs.iter().map(read_dirents).any(|bytes| {
+                    bytes
+                        .chunks_exact(DIR_ENTRY_SIZE)
+                        .any(|dirent| dirent[DIR_ENTRY_SIZE - 1] != 0)
+                }) {
+                    return Err(FileSystemInvalid::Incompatible(from_version));
+                }
+
+                for dir in &dirs {
+                    let mut bytes = read_dirents(dir);
+                    for dirent in bytes.chunks_exact_mut(DIR_ENTRY_SIZE) {
+                        let inum = InodeId::from_ne_bytes(dirent[..8].try_into().unwrap());
+                        let named = dirent[8] != 0;
+                        if named && inodes.get(inum as usize).copied().unwrap_or(false) {
+                            dirent[DIR_ENTRY_SIZE - 1] = read_dinode(inum).type_ as u8;
+                        }
+                    }
+                    dir.write_data(0, &bytes, self.dev.clone(), self.block_cache.clone());
+                }
+                sb.set_version(3);
+            }
+            // The extended attributes block took the reserved field, which
+            // was always zero.
+            3 => sb.set_version(4),
+            // The directories count their `.` and the `..` of their
+            // subdirectories in their links.
+            4 => {
+                let inodes =
+                    self.read_bmap(sb.inode_bmap_start(), sb.inode_start(), self.max_inode_num());
+                let links = self.count_links(&inodes);
+                for inum in (0..inodes.len()).filter(|&inum| inodes[inum]) {
+                    let (block_id, offset) = sb.find_inode(inum as InodeId);
+                    self.block_cache
+                        .lock()
+                        .get(block_id, self.dev.clone())
+                        .lock()
+                        .write(offset, |dinode: &mut DInode| {
+                            if dinode.type_ == InodeType::Directory {
+                                dinode.links_num = links[inum];
+                            }
+                        });
+                }
+                sb.set_version(5);
+            }
+            _ => return Err(FileSystemInvalid::Incompatible(from_version)),
+        }
+        debug!("fs: migrated image from version {} to {}", from_version, sb.version());
+
+        self.block_cache
+            .lock()
+            .get(SUPER_BLOCK_LOC, self.dev.clone())
+            .lock()
+            .write(0, |super_block: &mut SuperBlock| *super_block = sb);
+        self.sb = Arc::new(sb);
+        Ok(())
+    }
+
+    /// Writes all the cached blocks back to the device.
+    ///
+    /// The inodes are updated through the block cache, so this also
+    /// writes back them.
+    pub fn close(&self) {
+        self.block_cache.lock().flush();
+        if let Err(err) = self.dev.flush() {
+            warn!("fs: failed to flush the device: {}", err);
+        }
+    }
+
+    /// Returns the I/O done since this file system was opened.
+    pub fn metrics(&self) -> FsMetrics {
+        self.counters.snapshot()
+    }
+
+    /// Sets the blocks read ahead of a sequential read, 0 disables it.
+    pub fn set_read_ahead(&self, blocks: usize) {
+        self.block_cache.lock().set_read_ahead(blocks);
     }
 
+    /// Sets where the blocks of a growing file are placed.
+    pub fn set_alloc_policy(&self, policy: AllocPolicy) {
+        *self.alloc_policy.lock() = policy;
+    }
+
+    pub fn alloc_policy(&self) -> AllocPolicy {
+        *self.alloc_policy.lock()
+    }
+
+    /// Returns the data blocks of `inode` in order, the direct ones then
+    /// the indirect ones, with `None` for the holes.
+    pub fn block_map(&self, inode: &MutexGuard<Inode>) -> Vec<Option<BlockId>> {
+        (0..inode.size().div_ceil(BLOCK_SIZE))
+            .map(|idx| inode.get_bid(idx, self.dev.clone(), self.block_cache.clone()))
+            .map(|block_id| (block_id != 0).then_some(block_id))
+            .collect()
+    }
+
+    /// Formats the device of this file system as `sb` says.
+    ///
+    /// A device already holding a file system, i.e. a valid super block
+    /// and a root directory, is left as it is.
     pub fn init(self: &Arc<Self>, sb: SuperBlock) -> Result<(), FileSystemInitError> {
-        let _ = FileSystem::init_fs(self.dev.clone(), sb)?;
+        if self.is_initialized() {
+            return Err(FileSystemInitError::AlreadyInitialized);
+        }
+        let _ = FileSystem::init_fs(self.dev.clone(), sb, FormatOptions::default())?;
         Ok(())
     }
 
+    fn is_initialized(self: &Arc<Self>) -> bool {
+        self.sb.is_valid()
+            && self
+                .get_inode(0)
+                .is_ok_and(|root| root.lock().type_ == InodeType::Directory)
+    }
+
     /// Initialize the file system.
     pub fn init_fs(
         dev: Arc<dyn BlockDevice>,
         sb: SuperBlock,
+        options: FormatOptions,
     ) -> Result<Arc<Mutex<Inode>>, FileSystemInitError> {
         let block_cache = Arc::new(Mutex::new(BlockCacheBuffer::new(BLOCK_BUFFER_SIZE)));
 
-        // Clear all non-data blocks.
-        for i in sb.inode_bmap_start..sb.data_start {
+        // Clear all non-data blocks, or the ones read before being
+        // initialized: the bitmaps and the block of the root inode.
+        let (root_block, _) = sb.find_inode(0);
+        let blocks: Vec<BlockId> = if options.zero_metadata {
+            (sb.inode_bmap_start()..sb.data_start()).collect()
+        } else {
+            (sb.inode_bmap_start()..sb.inode_start())
+                .chain(root_block..root_block + 1)
+                .chain(sb.data_bmap_start()..sb.data_start())
+                .collect()
+        };
+        for i in blocks {
             block_cache.lock().get(i, dev.clone()).lock().write(
                 0,
                 |data_block: &mut [u8; BLOCK_SIZE]| {
@@ -185,13 +450,17 @@ impl FileSystem {
         let fs = FileSystem::open(dev, true).expect("Failed to create file system.");
 
         // Create the root inode and initialize it.
-        fs.allocate_inode(InodeType::Directory)
-            .ok_or_else(|| FileSystemInitError(String::from("Failed to create the root inode.")))
+        let root = fs.allocate_inode(InodeType::Directory).ok_or_else(|| {
+            FileSystemInitError::Failed(String::from("Failed to create the root inode."))
+        })?;
+        // No directory refers to the root, its `.` and `..` both do.
+        fs.update_dinode(&mut root.lock(), |dinode| dinode.links_num = 2);
+        Ok(root)
     }
 
     /// Allocates a new empty inode from current file system.
     pub fn allocate_inode(self: &Arc<Self>, type_: InodeType) -> Option<Arc<Mutex<Inode>>> {
-        match self.allocate_bmap(self.sb.inode_bmap_start, self.sb.inode_start) {
+        match self.allocate_bmap(self.sb.inode_bmap_start(), self.sb.inode_start()) {
             Some(inum) => {
                 if inum >= self.max_inode_num() {
                     warn!(
@@ -201,11 +470,23 @@ impl FileSystem {
                     );
                     None
                 } else {
+                    // Initialized before it's loaded through the cache, the
+                    // slot holds garbage rather than a valid type if the
+                    // inode blocks weren't zeroed by the format.
+                    let (block_id, offset) = self.sb.find_inode(inum);
+                    let block_lock = self.block_cache.lock().get(block_id, self.dev.clone());
+                    block_lock
+                        .lock()
+                        .write(offset, |dinode: &mut MaybeUninit<DInode>| {
+                            dinode.write(DInode::new(type_, 0, 0, 0, [0; N_DIRECT]));
+                        });
+
                     match self.inode_cache.lock().get(inum, self.clone()) {
                         Ok(inode_lock) => {
                             let inode_lock_clone = inode_lock.clone();
                             let mut inode_clone = inode_lock_clone.lock();
                             self.update_dinode(&mut inode_clone, |dinode| dinode.initialize(type_));
+                            inode_clone.invalidate_dir_index();
                             Some(inode_lock)
                         }
                         _ => panic!("Failed to access the inode just allocated: {}", inum),
@@ -221,16 +502,16 @@ impl FileSystem {
 
     /// Allocates a free space in data area.
     pub fn allocate_data_block(self: &Arc<Self>) -> Option<BlockId> {
-        match self.allocate_bmap(self.sb.data_bmap_start, self.sb.data_start) {
+        match self.allocate_bmap(self.sb.data_bmap_start(), self.sb.data_start()) {
             Some(allocate_id) => {
-                if allocate_id >= self.sb.data_blocks {
+                if allocate_id >= self.sb.data_blocks() {
                     warn!(
                         "fs: allocate_id exceeds the range of data blocks. {}",
                         allocate_id
                     );
                     None
                 } else {
-                    Some(self.sb.data_start + allocate_id)
+                    Some(self.sb.data_start() + allocate_id)
                 }
             }
             None => {
@@ -240,6 +521,39 @@ impl FileSystem {
         }
     }
 
+    /// Allocates a data block for `inode` after its block `prev`, 0 if
+    /// it's the first one, as `alloc_policy` says.
+    fn allocate_file_block(self: &Arc<Self>, inode: &Inode, prev: BlockId) -> Option<BlockId> {
+        if *self.alloc_policy.lock() == AllocPolicy::Contiguous {
+            // A file without blocks starts at a place picked by its
+            // inode number, so the files written together don't take
+            // the neighbors of each other.
+            let goal = if prev == 0 {
+                inode.inode_num * ALLOC_GROUP_BLOCKS % self.sb.data_blocks()
+            } else {
+                prev + 1 - self.sb.data_start()
+            };
+            if goal < self.sb.data_blocks() && self.allocate_bmap_at(self.sb.data_bmap_start(), goal)
+            {
+                return Some(self.sb.data_start() + goal);
+            }
+        }
+        self.allocate_data_block()
+    }
+
+    /// Sets the bit `id` in the bitmap starting at block `start`, returns
+    /// false if it's taken.
+    fn allocate_bmap_at(&self, start: BlockId, id: u64) -> bool {
+        let block_id = start + id / BITMAP_PER_BLOCK as u64;
+        self.block_cache
+            .lock()
+            .get(block_id, self.dev.clone())
+            .lock()
+            .write(0, |bmap: &mut BitmapBlock| {
+                bmap.allocate_at(id as usize % BITMAP_PER_BLOCK)
+            })
+    }
+
     fn allocate_bmap(self: &Arc<Self>, start: BlockId, end: BlockId) -> Option<u64> {
         for i in start..end {
             let block_offset = i - start;
@@ -256,10 +570,240 @@ impl FileSystem {
         None
     }
 
+    /// Frees the bit `id` in the bitmap starting at block `start`.
+    fn free_bmap(&self, start: BlockId, id: u64) {
+        let block_id = start + id / BITMAP_PER_BLOCK as u64;
+        self.block_cache
+            .lock()
+            .get(block_id, self.dev.clone())
+            .lock()
+            .write(0, |bmap: &mut BitmapBlock| {
+                bmap.free(id as usize % BITMAP_PER_BLOCK)
+            });
+    }
+
+    /// Frees a block allocated by `allocate_data_block`.
+    fn free_data_block(&self, block_id: BlockId) {
+        self.free_data_blocks(&[block_id]);
+    }
+
+    /// Frees the blocks allocated by `allocate_data_block`, and discards
+    /// them on the device with one request per run of contiguous blocks.
+    ///
+    /// The ids outside the data blocks are skipped, not to free a bit of
+    /// another block or discard the metadata.
+    fn free_data_blocks(&self, blocks: &[BlockId]) {
+        let mut runs: Vec<(BlockId, usize)> = Vec::new();
+        for &block_id in blocks {
+            if block_id < self.sb.data_start()
+                || block_id >= self.sb.data_start() + self.sb.data_blocks()
+            {
+                warn!("fs: skipped freeing block {} outside the data blocks", block_id);
+                continue;
+            }
+            self.free_bmap(self.sb.data_bmap_start(), block_id - self.sb.data_start());
+            // A cached copy would be written back over the discarded block.
+            if !self.block_cache.lock().invalidate(block_id, true) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some((start, count)) if *start + *count as BlockId == block_id => *count += 1,
+                _ => runs.push((block_id, 1)),
+            }
+        }
+
+        for (start, count) in runs {
+            if let Err(err) = self.dev.discard(start, count) {
+                warn!("fs: failed to discard {} blocks from {}: {}", count, start, err);
+            }
+        }
+    }
+
+    /// Frees an inode allocated by `allocate_inode`.
+    fn free_inode(self: &Arc<Self>, inode: &mut MutexGuard<Inode>) {
+        self.update_dinode(inode, |dinode| dinode.initialize(InodeType::Invalid));
+        self.free_bmap(self.sb.inode_bmap_start(), inode.inode_num);
+    }
+
+    /// Frees `inode` together with its data blocks.
+    fn release_inode(self: &Arc<Self>, inode: &mut MutexGuard<Inode>) {
+        let blocks = inode.size().div_ceil(BLOCK_SIZE);
+        self.release_blocks(inode, 0..blocks, false);
+        let xattr = inode.dinode().xattr;
+        if xattr != 0 {
+            self.free_data_block(xattr as BlockId);
+        }
+        self.free_inode(inode);
+    }
+
+    /// Counts the references to each of the allocated `inodes`, the links
+    /// it should have.
+    ///
+    /// Besides its entry in the parent, a directory is referred to by its
+    /// own `.` and the `..` of each subdirectory. The root has no entry,
+    /// its `..` refers to itself.
+    fn count_links(&self, inodes: &[bool]) -> Vec<u64> {
+        let read_dinode = |inum: usize| {
+            let (block_id, offset) = self.sb.find_inode(inum as InodeId);
+            self.block_cache
+                .lock()
+                .get(block_id, self.dev.clone())
+                .lock()
+                .read(offset, |dinode: &DInode| *dinode)
+        };
+        let is_dir = |inum: usize| read_dinode(inum).type_ == InodeType::Directory;
+
+        let mut links = vec![0; inodes.len()];
+        links[0] = 1;
+        for dir in (0..inodes.len()).filter(|&inum| inodes[inum] && is_dir(inum)) {
+            links[dir] += 1;
+
+            let dinode = read_dinode(dir);
+            let mut bytes = vec![0u8; dinode.size as usize / DIR_ENTRY_SIZE * DIR_ENTRY_SIZE];
+            dinode.read_data(0, &mut bytes, self.dev.clone(), self.block_cache.clone());
+            for dirent in bytes.chunks_exact(DIR_ENTRY_SIZE) {
+                let inum = InodeId::from_ne_bytes(dirent[..8].try_into().unwrap()) as usize;
+                let named = dirent[8] != 0;
+                if !named || !inodes.get(inum).copied().unwrap_or(false) {
+                    continue;
+                }
+                links[inum] += 1;
+                if is_dir(inum) {
+                    links[dir] += 1;
+                }
+            }
+        }
+        links
+    }
+
+    /// Reads the bitmap in blocks `[start, end)`, returns whether each of
+    /// the first `count` bits is allocated.
+    fn read_bmap(&self, start: BlockId, end: BlockId, count: u64) -> Vec<bool> {
+        let count = count as usize;
+        let mut ret = Vec::with_capacity(count);
+        for i in start..end {
+            self.block_cache
+                .lock()
+                .get(i, self.dev.clone())
+                .lock()
+                .read(0, |bmap: &BitmapBlock| {
+                    let n = BITMAP_PER_BLOCK.min(count - ret.len());
+                    ret.extend((0..n).map(|idx| bmap.is_allocated(idx)));
+                });
+        }
+        ret
+    }
+
+    /// Iterates over the allocated inodes, the root included, in the
+    /// order of their inode numbers.
+    ///
+    /// The bitmap is read up front, and each inode is loaded when it's
+    /// yielded.
+    pub fn iter_inodes(
+        self: &Arc<Self>,
+    ) -> impl Iterator<Item = (InodeId, Arc<Mutex<Inode>>)> + '_ {
+        let sb = &self.sb;
+        let inodes = self.read_bmap(sb.inode_bmap_start(), sb.inode_start(), self.max_inode_num());
+        (0..inodes.len() as InodeId)
+            .filter(move |&inum| inodes[inum as usize])
+            .filter_map(|inum| Some((inum, self.get_inode(inum).ok()?)))
+    }
+
+    /// Checks that the bitmaps agree with the inodes, like `fsck`.
+    ///
+    /// Walks every allocated inode, collects the data blocks it refers to,
+    /// and compares them with the data bitmap. Also checks that directory
+    /// entries refer to allocated inodes.
+    pub fn verify(self: &Arc<Self>) -> FsckReport {
+        let sb = &self.sb;
+        let inodes = self.read_bmap(sb.inode_bmap_start(), sb.inode_start(), self.max_inode_num());
+        let data = self.read_bmap(sb.data_bmap_start(), sb.data_start(), sb.data_blocks());
+        let mut referenced = vec![false; data.len()];
+        let links = self.count_links(&inodes);
+        let mut report = FsckReport::default();
+
+        for inum in (0..inodes.len()).filter(|&inum| inodes[inum]) {
+            let inum = inum as InodeId;
+            let (block_id, offset) = sb.find_inode(inum);
+            let dinode = self
+                .block_cache
+                .lock()
+                .get(block_id, self.dev.clone())
+                .lock()
+                .read(offset, |dinode: &DInode| *dinode);
+            if dinode.links_num != links[inum as usize] {
+                report.bad_links.push((inum, links[inum as usize]));
+            }
+
+            let mut mark = |block_id: BlockId| {
+                // A hole of a sparse file refers to no block.
+                if block_id == 0 {
+                    return;
+                }
+                if block_id < sb.data_start() || block_id >= sb.data_start() + sb.data_blocks() {
+                    report.bad_blocks.push((inum, block_id));
+                } else {
+                    referenced[(block_id - sb.data_start()) as usize] = true;
+                }
+            };
+
+            let blocks = (dinode.size as usize).div_ceil(BLOCK_SIZE);
+            if blocks > N_DIRECT {
+                mark(dinode.indirect);
+            }
+            if dinode.xattr != 0 {
+                mark(dinode.xattr as BlockId);
+            }
+            let block_ids: Vec<BlockId> = (0..blocks)
+                .map(|idx| dinode.get_bid(idx, self.dev.clone(), self.block_cache.clone()))
+                .collect();
+            block_ids.iter().for_each(|&block_id| mark(block_id));
+
+            if dinode.type_ == InodeType::Directory {
+                let files_num = dinode.size as usize / DIR_ENTRY_SIZE;
+                let per_block = BLOCK_SIZE / DIR_ENTRY_SIZE;
+                for (idx, first) in (0..files_num).step_by(per_block).enumerate() {
+                    // The entries in a hole are zeros, not read from the super block.
+                    if block_ids[idx] == 0 {
+                        continue;
+                    }
+                    self.block_cache
+                        .lock()
+                        .get(block_ids[idx], self.dev.clone())
+                        .lock()
+                        .read_slice(
+                            0,
+                            per_block.min(files_num - first),
+                            |dirents: &[[u8; DIR_ENTRY_SIZE]]| {
+                                // Parsed from bytes, the type may be corrupt.
+                                for dirent in dirents.iter().map(DirEntry::from_bytes) {
+                                    let inode_num = dirent.inode_num;
+                                    if !inodes.get(inode_num as usize).copied().unwrap_or(false) {
+                                        report.dangling_entries.push((inum, inode_num));
+                                    }
+                                }
+                            },
+                        );
+                }
+            }
+        }
+
+        for (i, (&used, &referenced)) in data.iter().zip(referenced.iter()).enumerate() {
+            let block_id = sb.data_start() + i as BlockId;
+            if used && !referenced {
+                report.leaked_blocks.push(block_id);
+            } else if !used && referenced {
+                report.double_used_blocks.push(block_id);
+            }
+        }
+
+        report
+    }
+
     pub fn max_blocks_num(self: &Arc<Self>) -> u64 {
         min(
-            self.sb.data_blocks,
-            self.sb.inode_blocks * MAX_BLOCKS_PER_INODE as u64,
+            self.sb.data_blocks(),
+            self.sb.inode_blocks() * MAX_BLOCKS_PER_INODE as u64,
         )
     }
 
@@ -275,8 +819,8 @@ impl FileSystem {
         self.inode_cache.lock().get(inum, self.clone())
     }
 
-    fn max_inode_num(self: &Arc<Self>) -> InodeId {
-        self.sb.inode_blocks * (INODES_PER_BLOCK as u64)
+    fn max_inode_num(&self) -> InodeId {
+        self.sb.inode_blocks() * (INODES_PER_BLOCK as u64)
     }
 
     fn update_dinode<V>(
@@ -300,6 +844,77 @@ impl FileSystem {
         dinode_cache.write(offset, execute_then_update)
     }
 
+    /// Sets the permission bits of `inode`.
+    pub fn set_mode(self: &Arc<Self>, inode: &mut MutexGuard<Inode>, mode: u32) {
+        self.update_dinode(inode, |dinode| dinode.mode = mode);
+    }
+
+    /// Sets the extended attribute `key` of `inode` to `value`.
+    ///
+    /// The attributes are kept in a block allocated on the first set, and
+    /// all of them together have to fit in it.
+    pub fn set_xattr(
+        self: &Arc<Self>,
+        inode: &mut MutexGuard<Inode>,
+        key: &str,
+        value: &[u8],
+    ) -> Result<(), FileSystemAllocationError> {
+        if key.is_empty() || key.len() > XATTR_KEY_MAX {
+            return Err(FileSystemAllocationError::InvalidName(key.to_string()));
+        }
+
+        let block_id = inode.dinode().xattr as BlockId;
+        if block_id != 0 {
+            return self
+                .block_cache
+                .lock()
+                .get(block_id, self.dev.clone())
+                .lock()
+                .write(0, |block: &mut DataBlock| xattr::set(block, key, value))
+                .map_err(FileSystemAllocationError::XattrFull);
+        }
+
+        let mut table = [0; BLOCK_SIZE];
+        xattr::set(&mut table, key, value).map_err(FileSystemAllocationError::XattrFull)?;
+        let Some(block_id) = self.allocate_data_block() else {
+            return Err(FileSystemAllocationError::Exhausted(BLOCK_SIZE));
+        };
+        let Ok(xattr) = u32::try_from(block_id) else {
+            self.free_data_block(block_id);
+            return Err(FileSystemAllocationError::Exhausted(BLOCK_SIZE));
+        };
+        self.block_cache
+            .lock()
+            .get(block_id, self.dev.clone())
+            .lock()
+            .write(0, |block: &mut DataBlock| *block = table);
+        self.update_dinode(inode, |dinode| dinode.xattr = xattr);
+        Ok(())
+    }
+
+    /// Gets the extended attribute `key` of `inode`.
+    pub fn get_xattr(&self, inode: &MutexGuard<Inode>, key: &str) -> Option<Vec<u8>> {
+        let block_id = inode.dinode().xattr as BlockId;
+        if block_id == 0 {
+            return None;
+        }
+        self.block_cache
+            .lock()
+            .get(block_id, self.dev.clone())
+            .lock()
+            .read(0, |block: &DataBlock| xattr::get(block, key))
+    }
+
+    /// Sets the owner of `inode`.
+    ///
+    /// Nothing checks the owner yet, it's kept for `stat`.
+    pub fn set_owner(self: &Arc<Self>, inode: &mut MutexGuard<Inode>, uid: u32, gid: u32) {
+        self.update_dinode(inode, |dinode| {
+            dinode.uid = uid;
+            dinode.gid = gid;
+        });
+    }
+
     fn set_inode_size(self: &Arc<Self>, inode: &mut MutexGuard<Inode>, size: usize) {
         self.update_dinode(inode, |dinode| {
             dinode.size = size as u64;
@@ -311,33 +926,54 @@ impl FileSystem {
         inode: &MutexGuard<Inode>,
         name: &str,
     ) -> Option<Arc<Mutex<Inode>>> {
-        assert_eq!(
-            inode.type_,
-            InodeType::Directory,
-            "Only directories can look up files."
-        );
+        if inode.type_ != InodeType::Directory {
+            return None;
+        }
 
-        let files_num = inode.size() / DIR_ENTRY_SIZE;
-        let dirent = &mut DirEntry::empty();
+        if let Some(inode_num) = inode.cached_name(name) {
+            return self.get_inode(inode_num).ok();
+        }
 
-        // TODO: Looking up a file by name will be slow when files_num
-        // more and more bigger.
-        for i in 0..files_num {
-            let read_size = self.read_inode(&inode, DIR_ENTRY_SIZE * i, unsafe {
-                from_raw_parts_mut(dirent as *mut _ as *mut u8, DIR_ENTRY_SIZE)
-            });
+        let (_, inode_num) = self.with_dir_index(inode, |index| index.get(name))?;
+        inode.cache_name(name, inode_num);
+        let inode = self
+            .get_inode(inode_num)
+            .expect("failed to get an inode from the directory entry.");
+        Some(inode)
+    }
 
-            assert_eq!(read_size, DIR_ENTRY_SIZE);
+    /// Runs `f` on the index of the entries of the directory `dir`,
+    /// built first if it's not.
+    fn with_dir_index<T>(&self, dir: &Inode, f: impl FnOnce(&mut DirIndex) -> T) -> T {
+        let mut index = dir.dir_index().lock();
+        f(index.get_or_insert_with(|| self.build_dir_index(dir)))
+    }
 
-            if dirent.name() == name {
-                let inode = self
-                    .get_inode(dirent.inode_num)
-                    .expect("failed to get an inode from the directory entry.");
-                return Some(inode);
+    fn build_dir_index(&self, dir: &Inode) -> DirIndex {
+        let mut index = DirIndex::default();
+        let files_num = dir.size() / DIR_ENTRY_SIZE;
+        let per_block = BLOCK_SIZE / DIR_ENTRY_SIZE;
+        for (idx, first) in (0..files_num).step_by(per_block).enumerate() {
+            let block_id = dir.get_bid(idx, self.dev.clone(), self.block_cache.clone());
+            if block_id == 0 {
+                warn!("fs: directory {} is truncated at block {}", dir.inode_num, idx);
+                break;
             }
+            self.block_cache
+                .lock()
+                .get(block_id, self.dev.clone())
+                .lock()
+                .read_slice(
+                    0,
+                    per_block.min(files_num - first),
+                    |dirents: &[[u8; DIR_ENTRY_SIZE]]| {
+                        for (i, dirent) in dirents.iter().map(DirEntry::from_bytes).enumerate() {
+                            index.insert(dirent.name(), first + i, dirent.inode_num);
+                        }
+                    },
+                );
         }
-
-        None
+        index
     }
 
     pub fn list_children(self: &Arc<Self>, inode: &MutexGuard<Inode>) -> Vec<String> {
@@ -349,22 +985,47 @@ impl FileSystem {
 
         let files_num = inode.size() / DIR_ENTRY_SIZE;
         let mut ret = Vec::new();
-        let dirent = &mut DirEntry::empty();
+        let mut bytes = [0u8; DIR_ENTRY_SIZE];
 
         for i in 0..files_num {
-            let read_size = self.read_inode(&inode, DIR_ENTRY_SIZE * i, unsafe {
-                from_raw_parts_mut(dirent as *mut _ as *mut u8, DIR_ENTRY_SIZE)
-            });
+            let read_size = self.read_inode_data(inode, DIR_ENTRY_SIZE * i, &mut bytes);
 
-            assert_eq!(read_size, DIR_ENTRY_SIZE);
+            if read_size != DIR_ENTRY_SIZE {
+                warn!("fs: directory {} is truncated at entry {}", inode.inode_num, i);
+                break;
+            }
 
-            ret.push(dirent.name().to_string());
+            // The entries in a hole are zeros, without a name.
+            let dirent = DirEntry::from_bytes(&bytes);
+            if !dirent.name().is_empty() {
+                ret.push(dirent.name().to_string());
+            }
         }
 
         ret
     }
 
+    /// Lists the entries of the directory `inode` with their types, the
+    /// children are not loaded. The zeroed entries are skipped.
+    pub fn read_dir(&self, inode: &MutexGuard<Inode>) -> Vec<DirItem> {
+        assert_eq!(inode.type_, InodeType::Directory, "Only directories can be read.");
+
+        (0..inode.size() / DIR_ENTRY_SIZE)
+            .map(|i| self.read_dirent(inode, i))
+            .filter(|dirent| !dirent.name().is_empty())
+            .map(|dirent| DirItem {
+                name:      dirent.name().to_string(),
+                inode_num: dirent.inode_num,
+                type_:     dirent.type_,
+            })
+            .collect()
+    }
+
     /// Creates a new empty inode under this inode directory.
+    ///
+    /// Nothing changes if it fails: the entry is the last thing that can
+    /// run out of space, and the links are only counted once it's written.
+    /// A directory's `.` and `..` are not stored, just counted in links.
     pub fn create_inode(
         self: &Arc<Self>,
         inode: &mut MutexGuard<Inode>,
@@ -377,56 +1038,362 @@ impl FileSystem {
             "New files only can be created in directories."
         );
 
-        if !name.is_empty() && name.starts_with("/") {
+        // Checked before anything is allocated, the entry can't hold a
+        // longer name.
+        if name.starts_with("/") || name.len() > DIR_NAME_SIZE {
             return Err(FileSystemAllocationError::InvalidName(name.to_string()));
         }
 
-        if let Some(_) = self.look_up(inode, name) {
+        if self.look_up(inode, name).is_some() {
             return Err(FileSystemAllocationError::AlreadyExist(
                 name.to_string(),
                 type_,
             ));
         }
 
+        if inode.size().is_multiple_of(BLOCK_SIZE)
+            && self.zeroed_dirents(inode) >= COMPACT_THRESHOLD
+        {
+            self.compact_dir(inode);
+        }
+
         let new_inode_lock = self
             .allocate_inode(type_)
-            .ok_or_else(|| FileSystemAllocationError::InodeExhausted)?;
+            .ok_or(FileSystemAllocationError::InodeExhausted)?;
 
+        let mut new_inode = new_inode_lock.lock();
         let base_offset = inode.size();
-        self.resize_inode(inode, base_offset + DIR_ENTRY_SIZE)?;
-        assert_eq!(inode.size(), base_offset + DIR_ENTRY_SIZE);
+        if let Err(err) = self.resize_inode(inode, base_offset + DIR_ENTRY_SIZE) {
+            // The directory is left as it was, release the new inode too.
+            self.free_inode(&mut new_inode);
+            return Err(err);
+        }
+        debug_assert_eq!(inode.size(), base_offset + DIR_ENTRY_SIZE);
 
-        let mut new_inode = new_inode_lock.lock();
         {
-            let dirent = &DirEntry::new(name, new_inode.inode_num);
+            let dirent = DirEntry::new(name, new_inode.inode_num, type_);
 
-            let written = self.write_inode(inode, base_offset, unsafe {
-                from_raw_parts(dirent as *const _ as *const u8, DIR_ENTRY_SIZE)
-            });
-            assert_eq!(written, DIR_ENTRY_SIZE);
+            let written = self.write_inode_data(inode, base_offset, dirent.as_bytes());
+            debug_assert_eq!(written.ok(), Some(DIR_ENTRY_SIZE));
+            inode.invalidate_names();
+            if let Some(index) = inode.dir_index().lock().as_mut() {
+                index.insert(name, base_offset / DIR_ENTRY_SIZE, new_inode.inode_num);
+            }
 
-            self.update_dinode(&mut new_inode, |dinode| dinode.links_num += 1);
+            if type_ == InodeType::Directory {
+                // Its own `.` and the entry, and its `..` refers to the parent.
+                self.update_dinode(&mut new_inode, |dinode| dinode.links_num += 2);
+                self.update_dinode(inode, |dinode| dinode.links_num += 1);
+            } else {
+                self.update_dinode(&mut new_inode, |dinode| dinode.links_num += 1);
+            }
         }
 
         Ok(new_inode_lock.clone())
     }
 
+    /// Creates a new inode under this inode directory like `create_inode`,
+    /// and writes `data` to it.
+    ///
+    /// Nothing is left behind if it fails, the entry and the inode are
+    /// removed again.
+    pub fn create_inode_with_data(
+        self: &Arc<Self>,
+        dir: &mut MutexGuard<Inode>,
+        name: &str,
+        type_: InodeType,
+        data: &[u8],
+    ) -> Result<Arc<Mutex<Inode>>, FileSystemAllocationError> {
+        let inode_lock = self.create_inode(dir, name, type_)?;
+        let mut inode = inode_lock.lock();
+
+        let res = self.resize_inode(&mut inode, data.len()).and_then(|()| {
+            self.write_inode_all(&mut inode, 0, data)
+                .map_err(|_| FileSystemAllocationError::Exhausted(data.len()))
+        });
+        if let Err(err) = res {
+            self.remove_dirent(dir, name);
+            self.update_dinode(&mut inode, |dinode| dinode.links_num = 0);
+            if type_ == InodeType::Directory {
+                self.update_dinode(dir, |dinode| dinode.links_num -= 1);
+            }
+            self.release_inode(&mut inode);
+            return Err(err);
+        }
+
+        drop(inode);
+        Ok(inode_lock)
+    }
+
+    /// Removes the entry `name` from the directory `dir`, and drops a link
+    /// of the inode it refers to.
+    ///
+    /// The inode is freed once it has no links left and no `InodeHandle`
+    /// opened on it, so an opened file stays readable after unlinked.
+    pub fn unlink(
+        self: &Arc<Self>,
+        dir: &mut MutexGuard<Inode>,
+        name: &str,
+    ) -> Result<(), FileSystemAllocationError> {
+        let inode_lock = self
+            .look_up(dir, name)
+            .ok_or_else(|| FileSystemAllocationError::NotFound(name.to_string()))?;
+        let mut inode = inode_lock.lock();
+        if inode.type_ == InodeType::Directory {
+            return Err(FileSystemAllocationError::IsADirectory);
+        }
+
+        self.remove_dirent(dir, name);
+        self.update_dinode(&mut inode, |dinode| dinode.links_num -= 1);
+        if inode.links_num() == 0 && inode.open_count() == 0 {
+            self.release_inode(&mut inode);
+        }
+        Ok(())
+    }
+
+    /// Removes the entry `name` from the directory `dir`, the last entry
+    /// is moved into its slot.
+    fn remove_dirent(self: &Arc<Self>, dir: &mut MutexGuard<Inode>, name: &str) {
+        let files_num = dir.size() / DIR_ENTRY_SIZE;
+        let Some((pos, _)) = self.with_dir_index(dir, |index| index.remove(name)) else {
+            return;
+        };
+        let last = files_num - 1;
+        if pos != last {
+            let moved = self.read_dirent(dir, last);
+            self.write_dirent(dir, pos, &moved);
+            self.with_dir_index(dir, |index| index.move_to(moved.name(), pos));
+        }
+
+        let new_size = DIR_ENTRY_SIZE * last;
+        let old_blocks = dir.size().div_ceil(BLOCK_SIZE);
+        let new_blocks = new_size.div_ceil(BLOCK_SIZE);
+        self.release_blocks(dir, new_blocks..old_blocks, new_blocks > N_DIRECT);
+        self.set_inode_size(dir, new_size);
+        dir.invalidate_names();
+    }
+
+    /// Removes the zeroed entries of the directory `dir` and shrinks it,
+    /// the other entries keep their order.
+    ///
+    /// `unlink` keeps a directory packed, but a slot grown by
+    /// `create_inode` reads as zeros if its entry never got written,
+    /// e.g. the system crashed in between.
+    ///
+    /// Returns the number of entries removed.
+    pub fn compact_dir(self: &Arc<Self>, dir: &mut MutexGuard<Inode>) -> usize {
+        assert_eq!(dir.type_, InodeType::Directory, "Only directories can be compacted.");
+
+        let files_num = dir.size() / DIR_ENTRY_SIZE;
+        let mut kept = 0;
+        for i in 0..files_num {
+            let dirent = self.read_dirent(dir, i);
+            if dirent.name().is_empty() {
+                continue;
+            }
+            if kept != i {
+                self.write_dirent(dir, kept, &dirent);
+            }
+            kept += 1;
+        }
+
+        let removed = files_num - kept;
+        if removed > 0 {
+            debug!("fs: compacted {} entries of directory {}", removed, dir.inode_num);
+            self.resize_inode(dir, kept * DIR_ENTRY_SIZE)
+                .expect("shrinking a directory never fails");
+            dir.invalidate_names();
+            dir.invalidate_dir_index();
+        }
+        removed
+    }
+
+    /// Counts the zeroed entries of the directory `dir`.
+    fn zeroed_dirents(&self, dir: &MutexGuard<Inode>) -> usize {
+        self.with_dir_index(dir, |index| index.zeroed())
+    }
+
+    fn read_dirent(&self, dir: &MutexGuard<Inode>, i: usize) -> DirEntry {
+        let mut bytes = [0u8; DIR_ENTRY_SIZE];
+        self.read_inode_data(dir, DIR_ENTRY_SIZE * i, &mut bytes);
+        DirEntry::from_bytes(&bytes)
+    }
+
+    fn write_dirent(self: &Arc<Self>, dir: &mut MutexGuard<Inode>, i: usize, dirent: &DirEntry) {
+        // Within the directory, it doesn't grow.
+        let written = self.write_inode_data(dir, DIR_ENTRY_SIZE * i, dirent.as_bytes());
+        debug_assert_eq!(written.ok(), Some(DIR_ENTRY_SIZE));
+    }
+
     /// Reads data from this inode to buffer.
     ///
-    /// Returns the size of read data.
-    pub fn read_inode(&self, inode: &MutexGuard<Inode>, offset: usize, buf: &mut [u8]) -> usize {
-        inode
-            .dinode()
-            .read_data(offset, buf, self.dev.clone(), self.block_cache.clone())
+    /// Returns the size of read data. Directories are read through
+    /// `read_dir`, their entries are not exposed as bytes.
+    ///
+    /// An inode is loaded once however it's looked up, so the reads and
+    /// writes of a file are ordered by the lock of its `Inode`: a read
+    /// sees a `write_inode` either whole or not at all.
+    pub fn read_inode(
+        &self,
+        inode: &MutexGuard<Inode>,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, FileSystemAllocationError> {
+        if inode.type_ == InodeType::Directory {
+            return Err(FileSystemAllocationError::IsADirectory);
+        }
+        Ok(self.read_inode_data(inode, offset, buf))
+    }
+
+    /// Like `read_inode`, but reads the entries of a directory too.
+    fn read_inode_data(&self, inode: &MutexGuard<Inode>, offset: usize, buf: &mut [u8]) -> usize {
+        let n = inode.read_data(offset, buf, self.dev.clone(), self.block_cache.clone());
+        self.counters.add_read(n);
+        n
     }
 
     /// Writes data from buffer to inode.
     ///
-    /// Returns the size of written data.
-    pub fn write_inode(&self, inode: &MutexGuard<Inode>, offset: usize, buf: &[u8]) -> usize {
-        inode
-            .dinode()
-            .write_data(offset, buf, self.dev.clone(), self.block_cache.clone())
+    /// The inode grows if the data goes past its end, and the hole
+    /// between the old end and `offset` reads as zeros.
+    ///
+    /// Returns the size of written data, short if the inode can't grow to
+    /// hold all of it. Fails with the error growing it if nothing can be
+    /// written, e.g. `TooLarge` or `Exhausted`. Directories are rejected,
+    /// their entries are only changed by `create_inode` and `unlink`.
+    pub fn write_inode(
+        self: &Arc<Self>,
+        inode: &mut MutexGuard<Inode>,
+        offset: usize,
+        buf: &[u8],
+    ) -> Result<usize, FileSystemAllocationError> {
+        if inode.type_ == InodeType::Directory {
+            return Err(FileSystemAllocationError::IsADirectory);
+        }
+        self.write_inode_data(inode, offset, buf)
+    }
+
+    /// Like `write_inode`, but writes the entries of a directory too.
+    fn write_inode_data(
+        self: &Arc<Self>,
+        inode: &mut MutexGuard<Inode>,
+        offset: usize,
+        buf: &[u8],
+    ) -> Result<usize, FileSystemAllocationError> {
+        let end = offset + buf.len();
+        if end > inode.size() {
+            if let Err(err) = self.resize_inode(inode, end) {
+                warn!("fs: failed to grow inode {}: {}", inode.inode_num, err);
+                // The part before the old end is still written.
+                if offset >= inode.size() {
+                    return Err(err);
+                }
+            }
+        }
+        if offset >= inode.size() {
+            return Ok(0);
+        }
+
+        let n = inode.write_data(offset, buf, self.dev.clone(), self.block_cache.clone());
+        self.counters.add_written(n);
+        Ok(n)
+    }
+
+    /// Writes the whole `buf` to `inode` at `offset`.
+    ///
+    /// Keeps calling `write_inode` until everything is written, fails
+    /// with the bytes left when it can't make progress, e.g. the file
+    /// system is full or `inode` is a directory.
+    pub fn write_inode_all(
+        self: &Arc<Self>,
+        inode: &mut MutexGuard<Inode>,
+        offset: usize,
+        buf: &[u8],
+    ) -> Result<(), ShortWrite> {
+        let mut written = 0;
+        while written < buf.len() {
+            let n = self
+                .write_inode(inode, offset + written, &buf[written..])
+                .unwrap_or(0);
+            if n == 0 {
+                return Err(ShortWrite {
+                    written,
+                    short: buf.len() - written,
+                });
+            }
+            written += n;
+        }
+        Ok(())
+    }
+
+    /// Copies `len` bytes of `src` at `src_off` to `dst` at `dst_off`.
+    ///
+    /// Blocks at the same offset in both files are copied in the cache
+    /// without a bounce buffer, only the unaligned head and tail go
+    /// through bytes. The copy stops at the end of `src`, and `dst` grows
+    /// to hold it.
+    ///
+    /// Returns the size of copied data.
+    pub fn copy_range(
+        self: &Arc<Self>,
+        src: &MutexGuard<Inode>,
+        src_off: usize,
+        dst: &mut MutexGuard<Inode>,
+        dst_off: usize,
+        len: usize,
+    ) -> Result<usize, FileSystemAllocationError> {
+        if src.type_ == InodeType::Directory || dst.type_ == InodeType::Directory {
+            return Err(FileSystemAllocationError::IsADirectory);
+        }
+        let len = len.min(src.size().saturating_sub(src_off));
+        let end = dst_off
+            .checked_add(len)
+            .ok_or(FileSystemAllocationError::TooLarge(usize::MAX))?;
+        if end > dst.size() {
+            self.resize_inode(dst, end)?;
+        }
+
+        let mut copied = 0;
+        let mut buf = [0; BLOCK_SIZE];
+        while copied < len {
+            let (s, d) = (src_off + copied, dst_off + copied);
+            let left = len - copied;
+            if s % BLOCK_SIZE == 0 && d % BLOCK_SIZE == 0 && left >= BLOCK_SIZE {
+                let src_bid =
+                    src.get_bid(s / BLOCK_SIZE, self.dev.clone(), self.block_cache.clone());
+                let dst_bid =
+                    dst.get_bid(d / BLOCK_SIZE, self.dev.clone(), self.block_cache.clone());
+                // A hole reads as zeros, not as the super block.
+                let data = if src_bid == 0 {
+                    [0; BLOCK_SIZE]
+                } else {
+                    self.block_cache
+                        .lock()
+                        .get(src_bid, self.dev.clone())
+                        .lock()
+                        .read(0, |block: &DataBlock| *block)
+                };
+                let dst_block = self.block_cache.lock().get(dst_bid, self.dev.clone());
+                dst_block
+                    .lock()
+                    .write(0, |block: &mut DataBlock| *block = data);
+                self.counters.add_read(BLOCK_SIZE);
+                self.counters.add_written(BLOCK_SIZE);
+                copied += BLOCK_SIZE;
+                continue;
+            }
+
+            let n = left
+                .min(BLOCK_SIZE - s % BLOCK_SIZE)
+                .min(BLOCK_SIZE - d % BLOCK_SIZE);
+            let n = self.read_inode(src, s, &mut buf[..n])?;
+            let n = self.write_inode(dst, d, &buf[..n])?;
+            if n == 0 {
+                break;
+            }
+            copied += n;
+        }
+        Ok(copied)
     }
 
     pub fn resize_inode(
@@ -438,7 +1405,13 @@ impl FileSystem {
             return Err(FileSystemAllocationError::TooLarge(new_size));
         }
 
+        // The block math below trusts the old size, a corrupt one would
+        // free or map blocks out of the inode's range.
         let old_size = inode.size();
+        if old_size > CAPACITY_PER_INODE {
+            warn!("inode: inode {} has a corrupt size {}", inode.inode_num, old_size);
+            return Err(FileSystemAllocationError::TooLarge(old_size));
+        }
         debug!(
             "inode: resize inode {} from {} Bytes to {} Bytes ({:.6} MBytes)",
             inode.inode_num,
@@ -460,20 +1433,39 @@ impl FileSystem {
                 }
             }
 
-            let base_idx = (old_size + BLOCK_SIZE - 1) / BLOCK_SIZE;
-            let needed_blocks = (increment + BLOCK_SIZE - 1) / BLOCK_SIZE;
+            let base_idx = old_size.div_ceil(BLOCK_SIZE);
+            let needed_blocks = increment.div_ceil(BLOCK_SIZE);
             debug!("inode: allocate new blocks, needs {}", needed_blocks);
 
+            let had_indirect = inode.dinode().indirect != 0;
+            let mut prev = match base_idx {
+                0 => 0,
+                idx => inode.get_bid(idx - 1, self.dev.clone(), self.block_cache.clone()),
+            };
             for i in 0..needed_blocks {
-                let block_id = self
-                    .allocate_data_block()
-                    .ok_or_else(|| FileSystemAllocationError::Exhausted(new_size))?;
+                let idx = base_idx + i;
+                if idx >= N_DIRECT && inode.dinode().indirect == 0 {
+                    let Some(indirect) = self.allocate_file_block(inode, prev) else {
+                        self.release_blocks(inode, base_idx..idx, had_indirect);
+                        return Err(FileSystemAllocationError::Exhausted(new_size));
+                    };
+                    debug!("inode: resize: allocated indirect block_id: {}", indirect);
+                    clear_block(indirect, self.clone());
+                    self.update_dinode(inode, |dinode| dinode.indirect = indirect);
+                    prev = indirect;
+                }
+
+                let Some(block_id) = self.allocate_file_block(inode, prev) else {
+                    self.release_blocks(inode, base_idx..idx, had_indirect);
+                    return Err(FileSystemAllocationError::Exhausted(new_size));
+                };
                 debug!("inode: resize: allocated block_id: {}", block_id);
                 clear_block(block_id, self.clone());
+                prev = block_id;
 
                 self.update_dinode(inode, |dinode| {
                     dinode.set_bid(
-                        base_idx + i,
+                        idx,
                         block_id,
                         self.dev.clone(),
                         self.block_cache.clone(),
@@ -484,12 +1476,60 @@ impl FileSystem {
             self.set_inode_size(inode, new_size);
             Ok(())
         } else if new_size < old_size {
-            unimplemented!()
+            // Zero the dropped tail of the last block kept, a later grow
+            // within that block has to read zeros.
+            let tail_end = old_size.min(new_size.next_multiple_of(BLOCK_SIZE));
+            if tail_end > new_size {
+                inode.dinode().write_data(
+                    new_size,
+                    &[0; BLOCK_SIZE][..tail_end - new_size],
+                    self.dev.clone(),
+                    self.block_cache.clone(),
+                );
+            }
+
+            let old_blocks = old_size.div_ceil(BLOCK_SIZE);
+            let new_blocks = new_size.div_ceil(BLOCK_SIZE);
+            self.release_blocks(inode, new_blocks..old_blocks, new_blocks > N_DIRECT);
+            self.set_inode_size(inode, new_size);
+            Ok(())
         } else {
             Ok(()) // invariant size
         }
     }
 
+    /// Releases the blocks `idxs` of `inode` allocated by a failed
+    /// `resize_inode`, so the inode keeps its old size and blocks.
+    ///
+    /// The indirect block goes too unless the inode `had_indirect`.
+    fn release_blocks(
+        self: &Arc<Self>,
+        inode: &mut MutexGuard<Inode>,
+        idxs: Range<usize>,
+        had_indirect: bool,
+    ) {
+        let mut freed = Vec::with_capacity(idxs.len() + 1);
+        for idx in idxs {
+            let block_id = inode
+                .dinode()
+                .get_bid(idx, self.dev.clone(), self.block_cache.clone());
+            self.update_dinode(inode, |dinode| {
+                dinode.set_bid(idx, 0, self.dev.clone(), self.block_cache.clone())
+            });
+            // A hole has no block to free.
+            if block_id != 0 {
+                freed.push(block_id);
+            }
+        }
+
+        let indirect = inode.dinode().indirect;
+        if !had_indirect && indirect != 0 {
+            self.update_dinode(inode, |dinode| dinode.indirect = 0);
+            freed.push(indirect);
+        }
+        self.free_data_blocks(&freed);
+    }
+
     pub fn get_inode_from_path(
         self: &Arc<Self>,
         path: &str,
@@ -499,30 +1539,268 @@ impl FileSystem {
             return Some(start_at.clone());
         }
 
-        while let Some((name, next_path)) = skip(path) {
-            trace!("get_inode_from_path: name: {}, path: {}", name, next_path);
+        let (name, next_path) = skip(path)?;
+        trace!("get_inode_from_path: name: {}, path: {}", name, next_path);
+
+        let next_ip = {
             let ip = start_at.lock();
             if ip.type_ != InodeType::Directory {
                 return None;
             }
+            self.look_up(&ip, name)?
+        };
+        self.get_inode_from_path(next_path, &next_ip)
+    }
 
-            if let Some(next_ip) = self.look_up(&ip, name) {
-                return self.get_inode_from_path(next_path, &next_ip);
-            } else {
-                return None;
+    /// Returns a view of the file system where the directory at `path`
+    /// is the root.
+    pub fn scoped_root(
+        self: &Arc<Self>,
+        path: &str,
+    ) -> Result<ScopedFs, FileSystemAllocationError> {
+        let path = canonicalize(path);
+        let root = self
+            .get_inode_from_path(path.trim_start_matches('/'), &self.root())
+            .filter(|inode| inode.lock().type_ == InodeType::Directory)
+            .ok_or(FileSystemAllocationError::NotFound(path))?;
+        Ok(ScopedFs::new(self.clone(), root))
+    }
+
+    /// Reads the whole file at `path` from the root.
+    ///
+    /// Fails if the file is larger than `READ_PATH_MAX`.
+    pub fn read_path(self: &Arc<Self>, path: &str) -> Result<Vec<u8>, FileSystemAllocationError> {
+        let path = canonicalize(path);
+        let inode_lock = self
+            .get_inode_from_path(&path, &self.root())
+            .ok_or_else(|| FileSystemAllocationError::NotFound(path.clone()))?;
+        let inode = inode_lock.lock();
+        if inode.type_ == InodeType::Directory {
+            return Err(FileSystemAllocationError::InvalidName(path));
+        }
+        if inode.size() > READ_PATH_MAX {
+            return Err(FileSystemAllocationError::TooLarge(inode.size()));
+        }
+
+        let mut data = vec![0; inode.size()];
+        let n = self.read_inode(&inode, 0, &mut data)?;
+        data.truncate(n);
+        Ok(data)
+    }
+
+    /// Replaces the content of the file at `path` from the root with
+    /// `data`, the file is created first if it doesn't exist and `create`.
+    pub fn write_path(
+        self: &Arc<Self>,
+        path: &str,
+        data: &[u8],
+        create: bool,
+    ) -> Result<(), FileSystemAllocationError> {
+        let path = canonicalize(path);
+        let (parent, name) = path.rsplit_once('/').unwrap();
+        if name.is_empty() {
+            return Err(FileSystemAllocationError::InvalidName(path));
+        }
+        let not_found = || FileSystemAllocationError::NotFound(path.clone());
+
+        let dir_lock = self
+            .get_inode_from_path(parent, &self.root())
+            .ok_or_else(not_found)?;
+        let inode_lock = {
+            let mut dir = dir_lock.lock();
+            if dir.type_ != InodeType::Directory {
+                return Err(not_found());
             }
+            match self.look_up(&dir, name) {
+                Some(inode_lock) => inode_lock,
+                None if create => self.create_inode(&mut dir, name, InodeType::File)?,
+                None => return Err(not_found()),
+            }
+        };
+
+        let mut inode = inode_lock.lock();
+        if inode.type_ == InodeType::Directory {
+            return Err(FileSystemAllocationError::InvalidName(path));
         }
+        self.resize_inode(&mut inode, data.len())?;
+        self.write_inode_all(&mut inode, 0, data)
+            .map_err(|_| FileSystemAllocationError::Exhausted(data.len()))
+    }
+}
 
-        None
+impl Drop for FileSystem {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// The inconsistencies found by `FileSystem::verify`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// Blocks marked used in the data bitmap but not referenced by any inode.
+    pub leaked_blocks:      Vec<BlockId>,
+    /// Blocks referenced by inodes but marked free in the data bitmap, so
+    /// they may be allocated again.
+    pub double_used_blocks: Vec<BlockId>,
+    /// Block ids out of the data area, as (inode, block id).
+    pub bad_blocks:         Vec<(InodeId, BlockId)>,
+    /// Directory entries referring to free inodes, as (directory, inode).
+    pub dangling_entries:   Vec<(InodeId, InodeId)>,
+    /// Inodes whose link count doesn't match the references to them, as
+    /// (inode, references).
+    pub bad_links:          Vec<(InodeId, u64)>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.leaked_blocks.is_empty()
+            && self.double_used_blocks.is_empty()
+            && self.bad_blocks.is_empty()
+            && self.dangling_entries.is_empty()
+            && self.bad_links.is_empty()
     }
 }
 
-#[allow(dead_code)]
 #[derive(Debug)]
-pub struct FileSystemInitError(String);
+pub enum FileSystemInitError {
+    Failed(String),
+    /// The device already holds a file system.
+    AlreadyInitialized,
+}
+
+impl fmt::Display for FileSystemInitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileSystemInitError::Failed(err) => {
+                write!(f, "failed to initialize file system: {}", err)
+            }
+            FileSystemInitError::AlreadyInitialized => {
+                write!(f, "failed to initialize file system: already initialized")
+            }
+        }
+    }
+}
 
+/// The reason why a block device doesn't hold a valid file system.
 #[derive(Debug)]
-pub struct FileSystemInvalid();
+pub enum FileSystemInvalid {
+    /// The magic number in super block mismatched.
+    BadMagic(u64),
+    /// The image is of a newer version than `FS_VERSION`.
+    UnsupportedVersion(u32),
+    /// The image of this version can't be migrated to `FS_VERSION`.
+    Incompatible(u32),
+    /// The device failed to read the super block.
+    Unreadable(String),
+}
+
+impl fmt::Display for FileSystemInvalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileSystemInvalid::BadMagic(magic) => {
+                write!(f, "invalid file system: bad super block magic: {:#x}", magic)
+            }
+            FileSystemInvalid::UnsupportedVersion(version) => write!(
+                f,
+                "invalid file system: version {} is newer than the supported version {}",
+                version, FS_VERSION
+            ),
+            FileSystemInvalid::Incompatible(version) => write!(
+                f,
+                "invalid file system: can't migrate version {} to version {}",
+                version, FS_VERSION
+            ),
+            FileSystemInvalid::Unreadable(err) => {
+                write!(f, "invalid file system: can't read the super block: {}", err)
+            }
+        }
+    }
+}
+
+/// An entry listed by `FileSystem::read_dir`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirItem {
+    pub name:      String,
+    pub inode_num: InodeId,
+    pub type_:     InodeType,
+}
+
+/// Reads the entries of a directory one at a time in the order of their
+/// names, keeping the last name read between calls so a large directory
+/// can be listed in pieces.
+///
+/// `unlink` moves the last entry into the slot it frees, a position in
+/// the slots would skip that entry. The names keep their order, so each
+/// entry left in the directory is read once. The zeroed entries are
+/// skipped like `FileSystem::read_dir`.
+pub struct DirStream {
+    dir:   Arc<Mutex<Inode>>,
+    /// The name of the last entry read.
+    after: Option<String>,
+}
+
+impl DirStream {
+    pub fn new(dir: Arc<Mutex<Inode>>) -> Self {
+        Self::after(dir, None)
+    }
+
+    /// Opens a stream after the entry `name`, the last one an earlier
+    /// stream read.
+    pub fn after(dir: Arc<Mutex<Inode>>, name: Option<String>) -> Self {
+        Self { dir, after: name }
+    }
+
+    /// Returns the name of the last entry read, see `after`.
+    pub fn cursor(&self) -> Option<&str> {
+        self.after.as_deref()
+    }
+}
+
+impl Iterator for DirStream {
+    type Item = DirItem;
+
+    fn next(&mut self) -> Option<DirItem> {
+        let dir = self.dir.lock();
+        assert_eq!(dir.type_, InodeType::Directory, "Only directories can be read.");
+        let fs = dir.get_fs().expect("file system has been dropped");
+
+        let slot = fs.with_dir_index(&dir, |index| index.next_after(self.after.as_deref()))?;
+        let dirent = fs.read_dirent(&dir, slot);
+        self.after = Some(dirent.name().to_string());
+        Some(DirItem {
+            name:      dirent.name().to_string(),
+            inode_num: dirent.inode_num,
+            type_:     dirent.type_,
+        })
+    }
+}
+
+/// Where `FileSystem` places the blocks of a growing file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AllocPolicy {
+    /// The lowest free block.
+    Lowest,
+    /// The block after the last one of the file if it's free, keeping
+    /// the file contiguous on the device.
+    #[default]
+    Contiguous,
+}
+
+/// How `FileSystem::create_with` formats the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// Zeroes all the blocks before the data area. Otherwise the inodes
+    /// but the root are left as they were, and initialized when allocated.
+    pub zero_metadata: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            zero_metadata: true,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum FileSystemAllocationError {
@@ -531,6 +1809,58 @@ pub enum FileSystemAllocationError {
     AlreadyExist(String, InodeType),
     TooLarge(usize),
     InvalidName(String),
+    NotFound(String),
+    /// The extended attributes would take this many bytes, more than
+    /// one block.
+    XattrFull(usize),
+    /// Reading or writing the bytes of a directory.
+    IsADirectory,
+}
+
+impl fmt::Display for FileSystemAllocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileSystemAllocationError::Exhausted(size) => {
+                write!(f, "no more data blocks to hold {} bytes", size)
+            }
+            FileSystemAllocationError::InodeExhausted => write!(f, "no more free inodes"),
+            FileSystemAllocationError::AlreadyExist(name, type_) => {
+                write!(f, "{:?} `{}` already exists", type_, name)
+            }
+            FileSystemAllocationError::TooLarge(size) => write!(
+                f,
+                "size {} bytes exceeds the inode capacity {} bytes",
+                size, CAPACITY_PER_INODE
+            ),
+            FileSystemAllocationError::InvalidName(name) => write!(f, "invalid name: `{}`", name),
+            FileSystemAllocationError::NotFound(name) => write!(f, "`{}` not found", name),
+            FileSystemAllocationError::XattrFull(size) => write!(
+                f,
+                "extended attributes of {} bytes exceed the block of {} bytes",
+                size, BLOCK_SIZE
+            ),
+            FileSystemAllocationError::IsADirectory => write!(f, "is a directory"),
+        }
+    }
+}
+
+/// A write that `write_inode_all` could not complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShortWrite {
+    /// Bytes written before it stopped.
+    pub written: usize,
+    /// Bytes not written.
+    pub short:   usize,
+}
+
+impl fmt::Display for ShortWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "short write: {} bytes written, {} bytes short",
+            self.written, self.short
+        )
+    }
 }
 
 fn clear_block(bid: BlockId, fs: Arc<FileSystem>) {
@@ -581,13 +1911,85 @@ fn skip(path: &str) -> Option<(&str, &str)> {
     Some((&path[name_start..name_start + len], &path[p..]))
 }
 
+/// Normalizes `path` into a clean absolute path.
+///
+/// Collapses repeated slashes, drops `.` and the trailing slash, and
+/// resolves `..` by popping the previous name, which stops at the root.
+/// A relative path is taken from the root.
+///
+/// ```
+/// assert_eq!(fs::canonicalize("/a/./b/../c"), "/a/c");
+/// assert_eq!(fs::canonicalize("/../x"), "/x");
+/// ```
+pub fn canonicalize(path: &str) -> String {
+    let mut names = Vec::new();
+    let mut rest = path;
+    while let Some((name, next)) = skip(rest) {
+        match name {
+            "." => {}
+            ".." => {
+                names.pop();
+            }
+            _ => names.push(name),
+        }
+        rest = next;
+    }
+
+    if names.is_empty() {
+        return String::from("/");
+    }
+    names.iter().fold(String::new(), |mut ret, name| {
+        ret.push('/');
+        ret.push_str(name);
+        ret
+    })
+}
+
 pub fn calc_blocks_num(total_bytes: u64) -> u64 {
-    (total_bytes + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64
+    total_bytes.div_ceil(BLOCK_SIZE as u64)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ram_disk::RamDisk;
+    use recording::{Op, RecordingBlockDevice};
+
+    #[test]
+    fn test_error_display() {
+        use alloc::format;
+
+        let err = FileSystemAllocationError::Exhausted(4097);
+        assert!(format!("{}", err).contains("4097"));
+
+        let err = FileSystemAllocationError::AlreadyExist("foo".to_string(), InodeType::File);
+        let msg = format!("{}", err);
+        assert!(msg.contains("foo"));
+        assert!(msg.contains("File"));
+
+        let err = FileSystemAllocationError::TooLarge(CAPACITY_PER_INODE + 1);
+        let msg = format!("{}", err);
+        assert!(msg.contains(&(CAPACITY_PER_INODE + 1).to_string()));
+        assert!(msg.contains(&CAPACITY_PER_INODE.to_string()));
+
+        let err = FileSystemAllocationError::InvalidName("/bin".to_string());
+        assert!(format!("{}", err).contains("/bin"));
+
+        let err = FileSystemAllocationError::NotFound("etc".to_string());
+        assert!(format!("{}", err).contains("etc"));
+
+        let err = FileSystemAllocationError::IsADirectory;
+        assert_eq!(format!("{}", err), "is a directory");
+
+        let err = FileSystemInvalid::BadMagic(0xdead);
+        assert!(format!("{}", err).contains("0xdead"));
+
+        let err = FileSystemInvalid::UnsupportedVersion(FS_VERSION + 1);
+        assert!(format!("{}", err).contains(&(FS_VERSION + 1).to_string()));
+
+        let err = FileSystemInitError::Failed(String::from("no root inode"));
+        assert!(format!("{}", err).contains("no root inode"));
+    }
 
     #[test]
     fn test_skip() {
@@ -596,4 +1998,539 @@ mod tests {
         assert_eq!(skip("a"), Some(("a", "")));
         assert_eq!(skip(""), None);
     }
+
+    #[test]
+    fn test_canonicalize() {
+        assert_eq!(canonicalize("/a/./b/../c"), "/a/c");
+        assert_eq!(canonicalize("/../x"), "/x");
+        assert_eq!(canonicalize("//a///b"), "/a/b");
+        assert_eq!(canonicalize("/a/b/"), "/a/b");
+        assert_eq!(canonicalize("/a/b/.."), "/a");
+        assert_eq!(canonicalize("/a/../../.."), "/");
+        assert_eq!(canonicalize("a/b"), "/a/b");
+        assert_eq!(canonicalize("/"), "/");
+        assert_eq!(canonicalize(""), "/");
+    }
+
+    fn mem_device(blocks: usize) -> Arc<RamDisk> {
+        Arc::new(RamDisk::new(blocks))
+    }
+
+    #[test]
+    fn test_open_dead_device() {
+        let dev = Arc::new(RecordingBlockDevice::new(mem_device(1024)));
+        // The first request, reading the super block, times out.
+        dev.fail_at(0, "timed out");
+        let err = FileSystem::open(dev, true).err().unwrap();
+        assert!(matches!(err, FileSystemInvalid::Unreadable(ref msg) if msg == "timed out"));
+    }
+
+    #[test]
+    fn test_init_formatted_device() {
+        let dev = mem_device(1024);
+        let fs = FileSystem::create(dev.clone(), 1024, 16).unwrap();
+        let root_lock = fs.root();
+        let file_lock = fs
+            .create_inode(&mut root_lock.lock(), "file", InodeType::File)
+            .unwrap();
+        fs.write_inode_all(&mut file_lock.lock(), 0, b"kept")
+            .unwrap();
+
+        assert!(matches!(fs.init(*fs.sb), Err(FileSystemInitError::AlreadyInitialized)));
+        fs.close();
+
+        let fs = FileSystem::open(dev, true).unwrap();
+        let file_lock = fs.look_up(&fs.root().lock(), "file").unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(fs.read_inode(&file_lock.lock(), 0, &mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"kept");
+        assert!(fs.verify().is_clean());
+
+        // A blank device is formatted.
+        let blank = mem_device(1024);
+        FileSystem::open(blank.clone(), false)
+            .unwrap()
+            .init(*fs.sb)
+            .unwrap();
+        let fs = FileSystem::open(blank, true).unwrap();
+        assert!(fs.list_children(&fs.root().lock()).is_empty());
+    }
+
+    #[test]
+    fn test_create_long_name() {
+        let dev = mem_device(1024);
+        let fs = FileSystem::create(dev.clone(), 1024, 16).unwrap();
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+
+        let name = "x".repeat(DIR_NAME_SIZE + 1);
+        let err = fs.create_inode(&mut root, &name, InodeType::File).err();
+        assert!(matches!(err, Some(FileSystemAllocationError::InvalidName(_))));
+        assert_eq!(root.size(), 0);
+
+        // Nothing was allocated for it, the next inode is the first after
+        // the root.
+        let file_lock = fs
+            .create_inode(&mut root, &name[..DIR_NAME_SIZE], InodeType::File)
+            .unwrap();
+        assert_eq!(file_lock.lock().inode_num, 1);
+        assert_eq!(fs.list_children(&root), [&name[..DIR_NAME_SIZE]]);
+    }
+
+    #[test]
+    fn test_corrupt_dirent_type() {
+        let dev = mem_device(1024);
+        let fs = FileSystem::create(dev.clone(), 1024, 16).unwrap();
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        fs.create_inode(&mut root, "file", InodeType::File).unwrap();
+
+        // An unknown type in the only entry of the root.
+        let block_id = root.get_bid(0, fs.dev.clone(), fs.block_cache.clone());
+        fs.block_cache
+            .lock()
+            .get(block_id, fs.dev.clone())
+            .lock()
+            .write(DIR_ENTRY_SIZE as InBlockOffset - 1, |type_: &mut u8| *type_ = 0xff);
+        root.invalidate_dir_index();
+
+        assert!(fs.look_up(&root, "file").is_some());
+        assert_eq!(fs.read_dir(&root)[0].type_, InodeType::Invalid);
+        drop(root);
+        assert!(fs.verify().is_clean());
+    }
+
+    #[test]
+    fn test_fast_format() {
+        let inode_blocks = 64;
+        let format = |zero_metadata| {
+            // Leftovers of a previous image.
+            let dev = mem_device(1024);
+            dev.fill(0xff);
+
+            let options = FormatOptions { zero_metadata };
+            let fs = FileSystem::create_with(dev.clone(), 1024, inode_blocks, options).unwrap();
+            let writes = dev.writes();
+
+            let root_lock = fs.root();
+            let mut root = root_lock.lock();
+            assert_eq!(root.size(), 0);
+            let file_lock = fs.create_inode(&mut root, "file", InodeType::File).unwrap();
+            assert_eq!(file_lock.lock().size(), 0);
+            assert_eq!(file_lock.lock().type_, InodeType::File);
+            assert_eq!(fs.list_children(&root), ["file"]);
+            assert!(fs.verify().is_clean());
+            writes
+        };
+
+        let full = format(true);
+        let fast = format(false);
+        // All but the block of the root inode are skipped.
+        assert_eq!(full - fast, inode_blocks as usize - 1);
+    }
+
+    #[test]
+    fn test_look_up_cached_name() {
+        let dev = mem_device(1024);
+        let fs = FileSystem::create(dev.clone(), 1024, 16).unwrap();
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        for name in ["a", "b", "hot"] {
+            fs.create_inode(&mut root, name, InodeType::File).unwrap();
+        }
+
+        let first = fs.look_up(&root, "hot").unwrap();
+        let reads = dev.reads();
+        let hits = fs.block_cache.lock().hits();
+
+        // Neither the device nor the block cache is touched.
+        let second = fs.look_up(&root, "hot").unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(dev.reads(), reads);
+        assert_eq!(fs.block_cache.lock().hits(), hits);
+
+        // Creating an entry invalidates the cache, but the names
+        // still resolve.
+        fs.create_inode(&mut root, "new", InodeType::File).unwrap();
+        assert!(root.cached_name("hot").is_none());
+        assert!(Arc::ptr_eq(&fs.look_up(&root, "hot").unwrap(), &first));
+        assert!(fs.look_up(&root, "missing").is_none());
+    }
+
+    #[test]
+    fn test_indirect_block_loaded_once() {
+        let blocks = N_DIRECT + 2 * BLOCK_BUFFER_SIZE;
+        let dev = Arc::new(RecordingBlockDevice::new(mem_device(1024)));
+        let inum = {
+            let fs = FileSystem::create(dev.clone(), 1024, 16).unwrap();
+            let root_lock = fs.root();
+            let file_lock = fs
+                .create_inode(&mut root_lock.lock(), "file", InodeType::File)
+                .unwrap();
+            let mut file = file_lock.lock();
+            fs.write_inode_all(&mut file, 0, &vec![7; blocks * BLOCK_SIZE])
+                .unwrap();
+            fs.close();
+            file.inode_num
+        };
+
+        // The file is larger than the block cache, the indirect block
+        // would be evicted and read again while reading it.
+        let fs = FileSystem::open(dev.clone(), true).unwrap();
+        let file_lock = fs.get_inode(inum).unwrap();
+        let file = file_lock.lock();
+        let indirect = file.dinode().indirect;
+        dev.take_log();
+        let mut buf = vec![0; blocks * BLOCK_SIZE];
+        assert_eq!(fs.read_inode(&file, 0, &mut buf).unwrap(), buf.len());
+        assert!(buf.iter().all(|&b| b == 7));
+
+        let loads = dev
+            .log()
+            .iter()
+            .filter(|r| r.op == Op::Read && r.block_id == indirect)
+            .count();
+        assert_eq!(loads, 1);
+    }
+
+    #[test]
+    fn test_truncate_discards_blocks() {
+        let blocks = N_DIRECT + 2;
+        let dev = Arc::new(RecordingBlockDevice::new(mem_device(1024)));
+        let fs = FileSystem::create(dev.clone(), 1024, 16).unwrap();
+        let root_lock = fs.root();
+        let file_lock = fs
+            .create_inode(&mut root_lock.lock(), "file", InodeType::File)
+            .unwrap();
+        let mut file = file_lock.lock();
+        fs.write_inode_all(&mut file, 0, &vec![7; blocks * BLOCK_SIZE])
+            .unwrap();
+        let mut freed: Vec<BlockId> = fs.block_map(&file)[1..]
+            .iter()
+            .map(|block_id| block_id.unwrap())
+            .collect();
+        freed.push(file.dinode().indirect);
+        freed.sort();
+
+        dev.take_log();
+        fs.resize_inode(&mut file, BLOCK_SIZE).unwrap();
+        fs.close();
+        let log = dev.take_log();
+        let mut discarded: Vec<BlockId> = log
+            .iter()
+            .filter(|r| r.op == Op::Discard)
+            .flat_map(|r| r.block_id..r.block_id + r.len as BlockId)
+            .collect();
+        discarded.sort();
+        assert_eq!(discarded, freed);
+        // Their cached copies are not written back over them.
+        assert!(!log
+            .iter()
+            .any(|r| r.op == Op::Write && freed.contains(&r.block_id)));
+    }
+
+    #[test]
+    fn test_block_map() {
+        let blocks = N_DIRECT + 3;
+        let new_fs = || {
+            let fs = FileSystem::create(mem_device(1024), 1024, 16).unwrap();
+            fs.set_alloc_policy(AllocPolicy::Lowest);
+            let root_lock = fs.root();
+            let file_lock = fs
+                .create_inode(&mut root_lock.lock(), "file", InodeType::File)
+                .unwrap();
+            (fs, file_lock)
+        };
+
+        // The blocks the write takes from `allocate_data_block`, on a
+        // twin of the file system, the indirect one among them.
+        let (twin, _) = new_fs();
+        let mut allocated: Vec<_> = (0..=blocks)
+            .map(|_| twin.allocate_data_block().unwrap())
+            .collect();
+        let indirect = allocated.remove(N_DIRECT);
+
+        let (fs, file_lock) = new_fs();
+        let mut file = file_lock.lock();
+        fs.write_inode_all(&mut file, 0, &vec![1; blocks * BLOCK_SIZE - 10])
+            .unwrap();
+        assert_eq!(file.dinode().indirect, indirect);
+        let map = fs.block_map(&file);
+        assert_eq!(map, allocated.iter().copied().map(Some).collect::<Vec<_>>());
+
+        // A block missing in the middle is a hole.
+        fs.update_dinode(&mut file, |dinode| dinode.addresses[1] = 0);
+        let map = fs.block_map(&file);
+        assert_eq!(map.len(), blocks);
+        assert_eq!(map[1], None);
+        assert_eq!(map[2], Some(allocated[2]));
+    }
+
+    #[test]
+    fn test_copy_range_hole() {
+        let fs = FileSystem::create(mem_device(1024), 1024, 16).unwrap();
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        let src_lock = fs.create_inode(&mut root, "src", InodeType::File).unwrap();
+        let mut src = src_lock.lock();
+        fs.write_inode_all(&mut src, 0, &vec![1; 3 * BLOCK_SIZE])
+            .unwrap();
+        fs.update_dinode(&mut src, |dinode| dinode.addresses[1] = 0);
+
+        let dst_lock = fs.create_inode(&mut root, "dst", InodeType::File).unwrap();
+        let mut dst = dst_lock.lock();
+        fs.write_inode_all(&mut dst, 0, &vec![7; 3 * BLOCK_SIZE])
+            .unwrap();
+        let copied = fs.copy_range(&src, 0, &mut dst, 0, 3 * BLOCK_SIZE);
+        assert_eq!(copied.unwrap(), 3 * BLOCK_SIZE);
+        let mut buf = vec![0; 3 * BLOCK_SIZE];
+        fs.read_inode(&dst, 0, &mut buf).unwrap();
+        assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == 1));
+        assert!(buf[BLOCK_SIZE..2 * BLOCK_SIZE].iter().all(|&b| b == 0));
+        assert!(buf[2 * BLOCK_SIZE..].iter().all(|&b| b == 1));
+
+        let res = fs.copy_range(&src, 0, &mut dst, usize::MAX - 10, BLOCK_SIZE);
+        assert!(matches!(res, Err(FileSystemAllocationError::TooLarge(_))));
+        assert_eq!(dst.size(), 3 * BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_resize_corrupt_size() {
+        let fs = FileSystem::create(mem_device(1024), 1024, 16).unwrap();
+        let root_lock = fs.root();
+        let file_lock = fs
+            .create_inode(&mut root_lock.lock(), "file", InodeType::File)
+            .unwrap();
+        let mut file = file_lock.lock();
+
+        // The block count of the old size would overflow.
+        let corrupt = usize::MAX - 1;
+        let mut dinode = file.dinode();
+        dinode.size = corrupt as u64;
+        file.update(&dinode);
+        for new_size in [0, BLOCK_SIZE, CAPACITY_PER_INODE] {
+            assert!(matches!(
+                fs.resize_inode(&mut file, new_size),
+                Err(FileSystemAllocationError::TooLarge(size)) if size == corrupt
+            ));
+        }
+        assert_eq!(file.size(), corrupt);
+    }
+
+    #[test]
+    fn test_read_dir_types() {
+        let dev = mem_device(1024);
+        let entries = [
+            ("bin", InodeType::Directory),
+            ("init", InodeType::File),
+            ("fifo", InodeType::Fifo),
+            ("home", InodeType::Directory),
+        ];
+        {
+            let fs = FileSystem::create(dev.clone(), 1024, 16).unwrap();
+            let root_lock = fs.root();
+            let mut root = root_lock.lock();
+            for (name, type_) in entries {
+                fs.create_inode(&mut root, name, type_).unwrap();
+            }
+            fs.close();
+        }
+
+        // Nothing but the root is cached after reopened.
+        let fs = FileSystem::open(dev, true).unwrap();
+        let root_lock = fs.root();
+        let root = root_lock.lock();
+        let misses = fs.inode_cache.lock().misses();
+        let items = fs.read_dir(&root);
+        assert_eq!(fs.inode_cache.lock().misses(), misses);
+
+        let listed: Vec<_> = items
+            .iter()
+            .map(|item| (item.name.as_str(), item.type_))
+            .collect();
+        assert_eq!(listed, entries);
+        for item in &items {
+            let child = fs.look_up(&root, &item.name).unwrap();
+            assert_eq!(child.lock().inode_num, item.inode_num);
+        }
+    }
+
+    #[test]
+    fn test_look_up_truncated_directory() {
+        let fs = FileSystem::create(mem_device(1024), 1024, 16).unwrap();
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        let file = fs.create_inode(&mut root, "kept", InodeType::File).unwrap();
+        let children = fs.list_children(&root);
+
+        // The size claims a second block that was never allocated.
+        let per_block = BLOCK_SIZE / DIR_ENTRY_SIZE;
+        let size = (per_block + 1) * DIR_ENTRY_SIZE;
+        fs.update_dinode(&mut root, |dinode| dinode.size = size as u64);
+        assert_eq!(root.dinode().addresses[1], 0);
+
+        assert!(fs.look_up(&root, "missing").is_none());
+        assert!(Arc::ptr_eq(&fs.look_up(&root, "kept").unwrap(), &file));
+        // The missing block is a hole, its zeroed entries are no children.
+        assert_eq!(fs.list_children(&root), children);
+
+        let mut buf = [0xffu8; DIR_ENTRY_SIZE];
+        let n = fs.read_inode_data(&root, per_block * DIR_ENTRY_SIZE, &mut buf);
+        assert_eq!(n, DIR_ENTRY_SIZE);
+        assert_eq!(buf, [0; DIR_ENTRY_SIZE]);
+        assert_eq!(fs.read_inode_data(&root, size, &mut buf), 0);
+        assert_eq!(fs.read_inode_data(&root, size + BLOCK_SIZE, &mut buf), 0);
+        assert!(fs.look_up(&file.lock(), "kept").is_none());
+    }
+
+    #[test]
+    fn test_verify_sparse() {
+        let fs = FileSystem::create(mem_device(1024), 1024, 16).unwrap();
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+
+        let file_lock = fs.create_inode(&mut root, "file", InodeType::File).unwrap();
+        let mut file = file_lock.lock();
+        fs.write_inode_all(&mut file, 0, &vec![1; 3 * BLOCK_SIZE])
+            .unwrap();
+        let block_id = file.dinode().addresses[1];
+        fs.update_dinode(&mut file, |dinode| dinode.addresses[1] = 0);
+        fs.free_data_block(block_id);
+        assert!(fs.verify().is_clean());
+
+        let dir_lock = fs
+            .create_inode(&mut root, "dir", InodeType::Directory)
+            .unwrap();
+        let mut dir = dir_lock.lock();
+        fs.create_inode(&mut dir, "child", InodeType::File).unwrap();
+        // The second block of entries is a hole.
+        let size = (BLOCK_SIZE / DIR_ENTRY_SIZE + 1) * DIR_ENTRY_SIZE;
+        fs.update_dinode(&mut dir, |dinode| dinode.size = size as u64);
+        assert_eq!(dir.dinode().addresses[1], 0);
+        let report = fs.verify();
+        assert!(report.is_clean(), "{:?}", report);
+    }
+
+    #[test]
+    fn test_create_inode_out_of_inodes() {
+        // One inode block holds 16 inodes.
+        let fs = FileSystem::create(mem_device(256), 256, 1).unwrap();
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+
+        let mut created = 0;
+        let err = loop {
+            match fs.create_inode(&mut root, &created.to_string(), InodeType::File) {
+                Ok(_) => created += 1,
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(err, FileSystemAllocationError::InodeExhausted));
+        assert_eq!(root.size(), created * DIR_ENTRY_SIZE);
+        assert_eq!(fs.list_children(&root).len(), created);
+    }
+
+    #[test]
+    fn test_create_inode_out_of_blocks() {
+        let fs = FileSystem::create(mem_device(256), 256, 1).unwrap();
+        let mut blocks = Vec::new();
+        while let Some(block_id) = fs.allocate_data_block() {
+            blocks.push(block_id);
+        }
+
+        // The empty root directory needs a block for the entry.
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        let res = fs.create_inode(&mut root, "full", InodeType::File);
+        assert!(matches!(res, Err(FileSystemAllocationError::Exhausted(_))));
+        assert_eq!(root.size(), 0);
+        // The inode allocated for it is released.
+        let file_lock = fs.allocate_inode(InodeType::File).unwrap();
+        let mut file = file_lock.lock();
+        assert_eq!(file.inode_num, 1);
+
+        // A failed resize releases the blocks it got.
+        let block_id = blocks.pop().unwrap();
+        fs.free_data_block(block_id);
+        assert!(fs.resize_inode(&mut file, 2 * BLOCK_SIZE).is_err());
+        assert_eq!(file.size(), 0);
+        assert_eq!(file.dinode().addresses[0], 0);
+        assert_eq!(fs.allocate_data_block(), Some(block_id));
+    }
+
+    #[test]
+    fn test_free_blocks_outside_data() {
+        let dev = Arc::new(RecordingBlockDevice::new(mem_device(256)));
+        let fs = FileSystem::create(dev.clone(), 256, 1).unwrap();
+        while fs.allocate_data_block().is_some() {}
+
+        let end = fs.sb.data_start() + fs.sb.data_blocks();
+        dev.take_log();
+        fs.free_data_blocks(&[0, fs.sb.data_start() - 1, end, end + 8]);
+        assert!(!dev.take_log().iter().any(|r| r.op == Op::Discard));
+        // No bit of the bitmap was freed for them.
+        assert_eq!(fs.allocate_data_block(), None);
+
+        fs.free_data_block(end - 1);
+        assert_eq!(fs.allocate_data_block(), Some(end - 1));
+    }
+
+    #[test]
+    fn test_create_dir_out_of_blocks() {
+        let fs = FileSystem::create(mem_device(256), 256, 9).unwrap();
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        // Fill the first block of the root, the next entry needs another.
+        for i in 0..BLOCK_SIZE / DIR_ENTRY_SIZE {
+            fs.create_inode(&mut root, &i.to_string(), InodeType::File)
+                .unwrap();
+        }
+        let (size, links) = (root.size(), root.links_num());
+        let mut blocks = Vec::new();
+        while let Some(block_id) = fs.allocate_data_block() {
+            blocks.push(block_id);
+        }
+
+        let res = fs.create_inode(&mut root, "dir", InodeType::Directory);
+        assert!(matches!(res, Err(FileSystemAllocationError::Exhausted(_))));
+        assert_eq!(root.size(), size);
+        assert_eq!(root.links_num(), links);
+        assert!(fs.look_up(&root, "dir").is_none());
+        for block_id in blocks {
+            fs.free_data_block(block_id);
+        }
+        assert!(fs.verify().is_clean());
+    }
+
+    #[test]
+    fn test_create_inode_with_data() {
+        let fs = FileSystem::create(mem_device(256), 256, 4).unwrap();
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+
+        let data: Vec<u8> = (0..10 * 1024).map(|i| (i % 251) as u8).collect();
+        let file_lock = fs
+            .create_inode_with_data(&mut root, "copy", InodeType::File, &data)
+            .unwrap();
+        let mut buf = vec![0; data.len()];
+        assert_eq!(fs.read_inode(&file_lock.lock(), 0, &mut buf).unwrap(), data.len());
+        assert_eq!(buf, data);
+
+        // Leave fewer blocks than the data needs.
+        let mut blocks = Vec::new();
+        while let Some(block_id) = fs.allocate_data_block() {
+            blocks.push(block_id);
+        }
+        for block_id in blocks.drain(..data.len().div_ceil(BLOCK_SIZE) - 1) {
+            fs.free_data_block(block_id);
+        }
+        let res = fs.create_inode_with_data(&mut root, "partial", InodeType::File, &data);
+        assert!(matches!(res, Err(FileSystemAllocationError::Exhausted(_))));
+        assert_eq!(fs.list_children(&root), ["copy"]);
+        assert!(fs.look_up(&root, "partial").is_none());
+        for block_id in blocks {
+            fs.free_data_block(block_id);
+        }
+        assert!(fs.verify().is_clean());
+    }
 }
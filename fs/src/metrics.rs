@@ -0,0 +1,127 @@
+//! Counters of the I/O done by a `FileSystem`, to compare the bytes
+//! asked for with the blocks transferred.
+
+use alloc::{string::String, sync::Arc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::block_dev::BlockDevice;
+
+/// A snapshot of the I/O done by a file system since it was opened.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FsMetrics {
+    /// Bytes read by `read_inode`.
+    pub bytes_read:     u64,
+    /// Bytes written by `write_inode`.
+    pub bytes_written:  u64,
+    /// Blocks read from the device.
+    pub blocks_read:    u64,
+    /// Blocks written to the device.
+    pub blocks_written: u64,
+}
+
+impl FsMetrics {
+    /// Returns the I/O done since the snapshot `earlier`.
+    pub fn since(&self, earlier: &FsMetrics) -> FsMetrics {
+        FsMetrics {
+            bytes_read:     self.bytes_read - earlier.bytes_read,
+            bytes_written:  self.bytes_written - earlier.bytes_written,
+            blocks_read:    self.blocks_read - earlier.blocks_read,
+            blocks_written: self.blocks_written - earlier.blocks_written,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct IoCounters {
+    bytes_read:     AtomicU64,
+    bytes_written:  AtomicU64,
+    blocks_read:    AtomicU64,
+    blocks_written: AtomicU64,
+}
+
+impl IoCounters {
+    pub fn add_read(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_written(&self, bytes: usize) {
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> FsMetrics {
+        FsMetrics {
+            bytes_read:     self.bytes_read.load(Ordering::Relaxed),
+            bytes_written:  self.bytes_written.load(Ordering::Relaxed),
+            blocks_read:    self.blocks_read.load(Ordering::Relaxed),
+            blocks_written: self.blocks_written.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Wraps a device to count the blocks transferred.
+pub(crate) struct MeteredDevice {
+    dev:      Arc<dyn BlockDevice>,
+    counters: Arc<IoCounters>,
+}
+
+impl MeteredDevice {
+    pub fn new(dev: Arc<dyn BlockDevice>, counters: Arc<IoCounters>) -> Self {
+        Self { dev, counters }
+    }
+
+    fn count_read(&self, blocks: usize) {
+        self.counters
+            .blocks_read
+            .fetch_add(blocks as u64, Ordering::Relaxed);
+    }
+
+    fn count_written(&self, blocks: usize) {
+        self.counters
+            .blocks_written
+            .fetch_add(blocks as u64, Ordering::Relaxed);
+    }
+}
+
+impl BlockDevice for MeteredDevice {
+    fn read(&self, block_id: u64, buf: &mut [u8]) -> Result<(), String> {
+        self.dev.read(block_id, buf)?;
+        self.count_read(1);
+        Ok(())
+    }
+
+    fn write(&self, block_id: u64, buf: &[u8]) -> Result<(), String> {
+        self.dev.write(block_id, buf)?;
+        self.count_written(1);
+        Ok(())
+    }
+
+    fn read_many(&self, start: u64, bufs: &mut [&mut [u8]]) -> Result<(), String> {
+        self.dev.read_many(start, bufs)?;
+        self.count_read(bufs.len());
+        Ok(())
+    }
+
+    fn write_many(&self, start: u64, bufs: &[&[u8]]) -> Result<(), String> {
+        self.dev.write_many(start, bufs)?;
+        self.count_written(bufs.len());
+        Ok(())
+    }
+
+    fn supports_partial_write(&self) -> bool {
+        self.dev.supports_partial_write()
+    }
+
+    fn write_partial(&self, block_id: u64, offset: usize, buf: &[u8]) -> Result<(), String> {
+        self.dev.write_partial(block_id, offset, buf)?;
+        self.count_written(1);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.dev.flush()
+    }
+
+    fn discard(&self, block_id: u64, count: usize) -> Result<(), String> {
+        self.dev.discard(block_id, count)
+    }
+}
@@ -0,0 +1,134 @@
+//! A block device in memory, for tests and benchmarks.
+
+use alloc::{format, string::String, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::block_dev::{BlockDevice, BLOCK_SIZE};
+
+/// A block device of a fixed number of blocks in memory.
+///
+/// Counts the blocks read and written, the out-of-range accesses fail.
+pub struct RamDisk {
+    blocks:  Mutex<Vec<[u8; BLOCK_SIZE]>>,
+    reads:   AtomicUsize,
+    writes:  AtomicUsize,
+    /// Whether it takes partial writes, off by default like most disks.
+    partial: AtomicBool,
+}
+
+impl RamDisk {
+    /// Creates a disk of `capacity` blocks of zeros.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            blocks:  Mutex::new(vec![[0; BLOCK_SIZE]; capacity]),
+            reads:   AtomicUsize::new(0),
+            writes:  AtomicUsize::new(0),
+            partial: AtomicBool::new(false),
+        }
+    }
+
+    /// Makes the disk take partial writes, see
+    /// `BlockDevice::write_partial`.
+    pub fn set_partial_write(&self, partial: bool) {
+        self.partial.store(partial, Ordering::Relaxed);
+    }
+
+    /// Returns the number of blocks.
+    pub fn capacity(&self) -> usize {
+        self.blocks.lock().len()
+    }
+
+    /// Sets every byte to `byte`, e.g. to leave garbage behind.
+    pub fn fill(&self, byte: u8) {
+        self.blocks
+            .lock()
+            .iter_mut()
+            .for_each(|block| block.fill(byte));
+    }
+
+    pub fn reads(&self) -> usize {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    pub fn writes(&self) -> usize {
+        self.writes.load(Ordering::Relaxed)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn read(&self, block_id: u64, buf: &mut [u8]) -> Result<(), String> {
+        let blocks = self.blocks.lock();
+        let block = blocks
+            .get(block_id as usize)
+            .ok_or_else(|| format!("block {} out of range {}", block_id, blocks.len()))?;
+        buf.copy_from_slice(block);
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn write(&self, block_id: u64, buf: &[u8]) -> Result<(), String> {
+        let mut blocks = self.blocks.lock();
+        let len = blocks.len();
+        let block = blocks
+            .get_mut(block_id as usize)
+            .ok_or_else(|| format!("block {} out of range {}", block_id, len))?;
+        block.copy_from_slice(buf);
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn supports_partial_write(&self) -> bool {
+        self.partial.load(Ordering::Relaxed)
+    }
+
+    fn write_partial(&self, block_id: u64, offset: usize, buf: &[u8]) -> Result<(), String> {
+        let mut blocks = self.blocks.lock();
+        let len = blocks.len();
+        let block = blocks
+            .get_mut(block_id as usize)
+            .ok_or_else(|| format!("block {} out of range {}", block_id, len))?;
+        block[offset..offset + buf.len()].copy_from_slice(buf);
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use super::*;
+    use crate::{block_dev::InodeType, FileSystem};
+
+    #[test]
+    fn test_out_of_range() {
+        let disk = RamDisk::new(4);
+        let mut buf = [0; BLOCK_SIZE];
+        assert!(disk.read(3, &mut buf).is_ok());
+        assert!(disk.read(4, &mut buf).is_err());
+        assert!(disk.write(4, &buf).is_err());
+        assert_eq!((disk.reads(), disk.writes()), (1, 0));
+    }
+
+    #[test]
+    fn test_round_trip_file() {
+        let disk = Arc::new(RamDisk::new(1024));
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 7).map(|i| i as u8).collect();
+        {
+            let fs = FileSystem::create(disk.clone(), 1024, 16).unwrap();
+            let root_lock = fs.root();
+            let mut root = root_lock.lock();
+            let file_lock = fs.create_inode(&mut root, "file", InodeType::File).unwrap();
+            fs.write_inode_all(&mut file_lock.lock(), 0, &data).unwrap();
+        }
+
+        let fs = FileSystem::open(disk, true).unwrap();
+        let root_lock = fs.root();
+        let file_lock = fs.look_up(&root_lock.lock(), "file").unwrap();
+        let mut buf = vec![0; data.len()];
+        assert_eq!(fs.read_inode(&file_lock.lock(), 0, &mut buf).unwrap(), data.len());
+        assert_eq!(buf, data);
+    }
+}
@@ -0,0 +1,181 @@
+//! A block device logging the requests it gets, for tests to assert the
+//! I/O done by an operation.
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use spin::Mutex;
+
+use crate::block_dev::{BlockDevice, BlockId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+    Flush,
+    Discard,
+}
+
+/// A request to the device, `len` blocks from `block_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub op:       Op,
+    pub block_id: BlockId,
+    pub len:      usize,
+}
+
+/// Wraps a device to log every request, in the order they come.
+///
+/// It can fail a chosen request to test the error paths.
+pub struct RecordingBlockDevice {
+    dev:     Arc<dyn BlockDevice>,
+    log:     Mutex<Vec<Record>>,
+    /// Fails the request at this index of the log with the message.
+    fail_at: Mutex<Option<(usize, String)>>,
+}
+
+impl RecordingBlockDevice {
+    pub fn new(dev: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            dev,
+            log: Mutex::new(Vec::new()),
+            fail_at: Mutex::new(None),
+        }
+    }
+
+    /// Returns the requests logged so far.
+    pub fn log(&self) -> Vec<Record> {
+        self.log.lock().clone()
+    }
+
+    /// Returns the requests logged so far and clears the log.
+    pub fn take_log(&self) -> Vec<Record> {
+        core::mem::take(&mut self.log.lock())
+    }
+
+    /// Fails the `n`th request from now, counted from 0, with `err`.
+    pub fn fail_at(&self, n: usize, err: &str) {
+        *self.fail_at.lock() = Some((self.log.lock().len() + n, err.to_string()));
+    }
+
+    /// Logs the request, returns the error injected for it if any.
+    fn record(&self, op: Op, block_id: BlockId, len: usize) -> Result<(), String> {
+        let mut log = self.log.lock();
+        let idx = log.len();
+        log.push(Record { op, block_id, len });
+
+        let mut fail_at = self.fail_at.lock();
+        match fail_at.take() {
+            Some((at, err)) if at == idx => Err(err),
+            other => {
+                *fail_at = other;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl BlockDevice for RecordingBlockDevice {
+    fn read(&self, block_id: BlockId, buf: &mut [u8]) -> Result<(), String> {
+        self.record(Op::Read, block_id, 1)?;
+        self.dev.read(block_id, buf)
+    }
+
+    fn write(&self, block_id: BlockId, buf: &[u8]) -> Result<(), String> {
+        self.record(Op::Write, block_id, 1)?;
+        self.dev.write(block_id, buf)
+    }
+
+    fn read_many(&self, start: BlockId, bufs: &mut [&mut [u8]]) -> Result<(), String> {
+        self.record(Op::Read, start, bufs.len())?;
+        self.dev.read_many(start, bufs)
+    }
+
+    fn write_many(&self, start: BlockId, bufs: &[&[u8]]) -> Result<(), String> {
+        self.record(Op::Write, start, bufs.len())?;
+        self.dev.write_many(start, bufs)
+    }
+
+    fn supports_partial_write(&self) -> bool {
+        self.dev.supports_partial_write()
+    }
+
+    fn write_partial(&self, block_id: BlockId, offset: usize, buf: &[u8]) -> Result<(), String> {
+        self.record(Op::Write, block_id, 1)?;
+        self.dev.write_partial(block_id, offset, buf)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.record(Op::Flush, 0, 0)?;
+        self.dev.flush()
+    }
+
+    fn discard(&self, block_id: BlockId, count: usize) -> Result<(), String> {
+        self.record(Op::Discard, block_id, count)?;
+        self.dev.discard(block_id, count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_dev::{InodeType, BLOCK_SIZE},
+        ram_disk::RamDisk,
+        FileSystem,
+    };
+
+    #[test]
+    fn test_fail_at() {
+        let dev = RecordingBlockDevice::new(Arc::new(RamDisk::new(4)));
+        let mut buf = [0; BLOCK_SIZE];
+        dev.read(0, &mut buf).unwrap();
+
+        dev.fail_at(1, "injected");
+        dev.write(1, &buf).unwrap();
+        assert_eq!(dev.read(2, &mut buf), Err(String::from("injected")));
+        dev.read(2, &mut buf).unwrap();
+
+        let ops: Vec<_> = dev.log().iter().map(|r| (r.op, r.block_id)).collect();
+        assert_eq!(ops, [(Op::Read, 0), (Op::Write, 1), (Op::Read, 2), (Op::Read, 2)]);
+    }
+
+    #[test]
+    fn test_create_inode_writes() {
+        let dev = Arc::new(RecordingBlockDevice::new(Arc::new(RamDisk::new(1024))));
+        let fs = FileSystem::create(dev.clone(), 1024, 16).unwrap();
+        let sb = fs.sb.clone();
+        fs.close();
+        dev.take_log();
+
+        let root_lock = fs.root();
+        fs.create_inode(&mut root_lock.lock(), "file", InodeType::File)
+            .unwrap();
+        fs.close();
+
+        // The blocks are read once into the cache, the new directory
+        // block is zeroed on the device right away, and the rest are
+        // written back by `close` in the order they were loaded.
+        let ops: Vec<_> = dev.take_log().iter().map(|r| (r.op, r.block_id)).collect();
+        let (inode_bmap, inodes) = (sb.inode_bmap_start(), sb.inode_start());
+        let (data_bmap, dir_block) = (sb.data_bmap_start(), sb.data_start());
+        assert_eq!(
+            ops,
+            [
+                (Op::Read, inodes),
+                (Op::Read, inode_bmap),
+                (Op::Read, data_bmap),
+                (Op::Read, dir_block),
+                (Op::Write, dir_block),
+                (Op::Write, inodes),
+                (Op::Write, inode_bmap),
+                (Op::Write, data_bmap),
+                (Op::Write, dir_block),
+                (Op::Flush, 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//! A view of the file system rooted at one of its directories.
+
+use alloc::sync::Arc;
+
+use spin::Mutex;
+
+use crate::{canonicalize, inode::Inode, FileSystem};
+
+/// Resolves the paths from a directory taken as `/`, see
+/// `FileSystem::scoped_root`.
+///
+/// The paths are canonicalized first, so `..` stops at the scoped root
+/// and nothing outside of it can be reached.
+pub struct ScopedFs {
+    fs:   Arc<FileSystem>,
+    root: Arc<Mutex<Inode>>,
+}
+
+impl ScopedFs {
+    pub(crate) fn new(fs: Arc<FileSystem>, root: Arc<Mutex<Inode>>) -> Self {
+        Self { fs, root }
+    }
+
+    pub fn fs(&self) -> &Arc<FileSystem> {
+        &self.fs
+    }
+
+    /// Returns the directory taken as `/`.
+    pub fn root(&self) -> Arc<Mutex<Inode>> {
+        self.root.clone()
+    }
+
+    pub fn get_inode_from_path(&self, path: &str) -> Option<Arc<Mutex<Inode>>> {
+        let path = canonicalize(path);
+        self.fs
+            .get_inode_from_path(path.trim_start_matches('/'), &self.root)
+    }
+}
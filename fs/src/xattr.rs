@@ -0,0 +1,110 @@
+//! Extended attributes, the small key/value pairs attached to an inode.
+//!
+//! They are packed into one block referenced by `DInode::xattr`, each as
+//! the length of the key (1 byte), the length of the value (2 bytes),
+//! the key and the value. A zero key length ends the table.
+
+use alloc::vec::Vec;
+
+use crate::block_dev::{DataBlock, BLOCK_SIZE};
+
+/// The maximum length of a key.
+pub const XATTR_KEY_MAX: usize = u8::MAX as usize;
+
+const HEADER_SIZE: usize = 3;
+
+/// Iterates over the (key, value) pairs in `block`.
+fn entries(block: &DataBlock) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 0;
+    core::iter::from_fn(move || {
+        if pos + HEADER_SIZE > BLOCK_SIZE || block[pos] == 0 {
+            return None;
+        }
+        let key_len = block[pos] as usize;
+        let value_len = u16::from_le_bytes([block[pos + 1], block[pos + 2]]) as usize;
+        let key = pos + HEADER_SIZE;
+        let value = key + key_len;
+        let end = value + value_len;
+        // A corrupt length runs past the block, the table ends there.
+        if end > BLOCK_SIZE {
+            pos = BLOCK_SIZE;
+            return None;
+        }
+        pos = end;
+        Some((&block[key..value], &block[value..end]))
+    })
+}
+
+/// Gets the value of `key` in `block`.
+pub(crate) fn get(block: &DataBlock, key: &str) -> Option<Vec<u8>> {
+    entries(block)
+        .find(|&(k, _)| k == key.as_bytes())
+        .map(|(_, value)| value.to_vec())
+}
+
+/// Sets `key` to `value` in `block`, replacing the old value.
+///
+/// Fails with the size the table would take if it doesn't fit in the
+/// block, and the block is left as it was.
+pub(crate) fn set(block: &mut DataBlock, key: &str, value: &[u8]) -> Result<(), usize> {
+    debug_assert!(!key.is_empty() && key.len() <= XATTR_KEY_MAX);
+
+    let mut table: Vec<(Vec<u8>, Vec<u8>)> = entries(block)
+        .filter(|&(k, _)| k != key.as_bytes())
+        .map(|(k, v)| (k.to_vec(), v.to_vec()))
+        .collect();
+    table.push((key.as_bytes().to_vec(), value.to_vec()));
+
+    let size = table
+        .iter()
+        .map(|(k, v)| HEADER_SIZE + k.len() + v.len())
+        .sum();
+    if size > BLOCK_SIZE {
+        return Err(size);
+    }
+
+    block.fill(0);
+    let mut pos = 0;
+    for (k, v) in table {
+        block[pos] = k.len() as u8;
+        block[pos + 1..pos + HEADER_SIZE].copy_from_slice(&(v.len() as u16).to_le_bytes());
+        pos += HEADER_SIZE;
+        block[pos..pos + k.len()].copy_from_slice(&k);
+        pos += k.len();
+        block[pos..pos + v.len()].copy_from_slice(&v);
+        pos += v.len();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_replaces() {
+        let mut block = [0; BLOCK_SIZE];
+        set(&mut block, "user.a", b"1").unwrap();
+        set(&mut block, "user.b", b"22").unwrap();
+        set(&mut block, "user.a", b"333").unwrap();
+
+        assert_eq!(get(&block, "user.a").as_deref(), Some(&b"333"[..]));
+        assert_eq!(get(&block, "user.b").as_deref(), Some(&b"22"[..]));
+        assert_eq!(get(&block, "user.c"), None);
+        assert_eq!(entries(&block).count(), 2);
+    }
+
+    #[test]
+    fn test_corrupt_length() {
+        let mut block = [0; BLOCK_SIZE];
+        set(&mut block, "user.a", b"1").unwrap();
+        let pos = HEADER_SIZE + "user.a".len() + 1;
+        // The value of the second entry runs past the block.
+        block[pos] = 1;
+        block[pos + 1..pos + HEADER_SIZE].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+
+        assert_eq!(entries(&block).count(), 1);
+        assert_eq!(get(&block, "user.a").as_deref(), Some(&b"1"[..]));
+        assert_eq!(get(&block, "user.b"), None);
+    }
+}
@@ -0,0 +1,70 @@
+use std::{collections::HashSet, sync::Arc, thread};
+
+use fs::{
+    block_cache::BlockCacheBuffer,
+    block_dev::{BlockDevice, BlockId, BLOCK_SIZE},
+    ram_disk::RamDisk,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use spin::Mutex;
+
+extern crate alloc;
+extern crate std;
+
+const THREADS: usize = 4;
+const BLOCKS: usize = 16;
+const ROUNDS: usize = 2000;
+
+/// Checks there is at most one cache entry per block id.
+fn assert_unique(cache: &BlockCacheBuffer) {
+    let mut seen = HashSet::new();
+    for block_id in cache.cached_blocks() {
+        assert!(seen.insert(block_id), "block {} is cached twice", block_id);
+    }
+}
+
+#[test]
+fn test_concurrent_access() {
+    let dev = Arc::new(RamDisk::new(BLOCKS));
+    // Fewer buffers than blocks, so the threads keep evicting each
+    // other's blocks.
+    let cache = Arc::new(Mutex::new(BlockCacheBuffer::new(BLOCKS / 2)));
+
+    let counts: Vec<Vec<u64>> = thread::scope(|s| {
+        let handles: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let dev = dev.clone();
+                let cache = cache.clone();
+                s.spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(t as u64);
+                    let mut counts = vec![0u64; BLOCKS];
+                    for _ in 0..ROUNDS {
+                        let block_id = rng.gen_range(0..BLOCKS) as BlockId;
+                        let block = {
+                            let mut cache = cache.lock();
+                            let block = cache.get(block_id, dev.clone());
+                            assert_unique(&cache);
+                            block
+                        };
+
+                        // Each thread bumps its own counter in the block.
+                        block.lock().write((t * 8) as u64, |count: &mut u64| *count += 1);
+                        counts[block_id as usize] += 1;
+                    }
+                    counts
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    cache.lock().flush();
+    for block_id in 0..BLOCKS {
+        let mut buf = [0u8; BLOCK_SIZE];
+        dev.read(block_id as u64, &mut buf).unwrap();
+        for (t, counts) in counts.iter().enumerate() {
+            let count = u64::from_ne_bytes(buf[t * 8..t * 8 + 8].try_into().unwrap());
+            assert_eq!(count, counts[block_id], "block {}, thread {}", block_id, t);
+        }
+    }
+}
@@ -1,6 +1,14 @@
-use std::io::Read;
+use std::{io::Read, thread};
 
-use fs::block_dev::{self, InodeType, BLOCK_SIZE, CAPACITY_PER_INODE};
+use fs::{
+    block_cache::READ_AHEAD_BLOCKS,
+    block_dev::{
+        self, InodeType, BLOCK_SIZE, CAPACITY_PER_INODE, DIR_ENTRY_SIZE, FS_VERSION, N_DIRECT,
+    },
+    inode::{InodeHandle, INODE_BUFFER_SIZE},
+    ram_disk::RamDisk,
+    AllocPolicy, DirStream, FileSystem, FileSystemAllocationError, SUPER_BLOCK_LOC,
+};
 use log::debug;
 
 extern crate alloc;
@@ -25,7 +33,7 @@ fn test_allocate_block() {
     debug!("fs: max blocks num: {}", fs.max_blocks_num());
     for i in 0..fs.max_blocks_num() {
         let block_id = fs.allocate_data_block();
-        assert_eq!(block_id, Some(fs.sb.data_start + i), "Failed to allocate the {}th block", i);
+        assert_eq!(block_id, Some(fs.sb.data_start() + i), "Failed to allocate the {}th block", i);
     }
     assert_eq!(fs.allocate_data_block(), None, "Exceeding the max blocks num.");
 }
@@ -58,9 +66,10 @@ fn test_nested_dir() {
                 fs.resize_inode(&mut file, 10).unwrap();
                 assert_eq!(file.size(), 10);
 
-                fs.write_inode(&file, 0, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+                fs.write_inode_all(&mut file, 0, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10])
+                    .unwrap();
                 let mut buffer = [0u8; 10];
-                fs.read_inode(&file, 0, &mut buffer);
+                fs.read_inode(&file, 0, &mut buffer).unwrap();
                 assert_eq!(buffer, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
             }
         }
@@ -87,25 +96,44 @@ fn test_single_large_file() {
 }
 
 #[test]
-#[ignore = "This test will take a very long time to run"]
-fn test_amounts_of_directories() {
-    let fs = helpers::init_fs();
+fn test_large_directory() {
+    let entries = block_dev::MAX_DIRENTS_PER_INODE;
+    let fs = helpers::init_fs_with(&helpers::new_disk(), entries as u64 + 16);
     let root_lock = fs.root();
     let mut root = root_lock.lock();
 
     let dir_lock = fs
-        .create_inode(&mut root, "amounts_of_directories", InodeType::Directory)
+        .create_inode(&mut root, "large_directory", InodeType::Directory)
         .unwrap();
     let mut dir = dir_lock.lock();
 
-    for i in 0..block_dev::MAX_DIRENTS_PER_INODE {
+    let before = fs.metrics();
+    let mut inums = Vec::with_capacity(entries);
+    for i in 0..entries {
         let d_lock = fs
             .create_inode(&mut dir, &i.to_string(), InodeType::Directory)
             .unwrap();
         let d = d_lock.lock();
 
         assert_eq!(d.type_, InodeType::Directory);
+        inums.push(d.inode_num);
     }
+    assert_eq!(dir.size(), CAPACITY_PER_INODE);
+    assert!(matches!(
+        fs.create_inode(&mut dir, "full", InodeType::File),
+        Err(FileSystemAllocationError::TooLarge(_))
+    ));
+
+    // The entries are never read back to find a name, a scan per
+    // entry would read them over and over.
+    for (i, &inum) in inums.iter().enumerate() {
+        let d_lock = fs.look_up(&dir, &i.to_string()).unwrap();
+        assert_eq!(d_lock.lock().inode_num, inum);
+    }
+    assert!(fs.look_up(&dir, "missing").is_none());
+    let read = fs.metrics().since(&before);
+    assert_eq!(read.bytes_read, 0);
+    assert!(read.blocks_read < entries as u64 / 4, "{:?}", read);
 }
 
 #[test]
@@ -138,7 +166,8 @@ fn test_read_write() {
             break;
         }
 
-        fs.write_inode(&dst_file, read_count, &buffer);
+        fs.write_inode_all(&mut dst_file, read_count, &buffer[..offset])
+            .unwrap();
         read_count += offset;
 
         if read_count >= fs::block_dev::CAPACITY_PER_INODE {
@@ -146,3 +175,874 @@ fn test_read_write() {
         }
     }
 }
+
+#[test]
+fn test_verify_leaked_block() {
+    let fs = helpers::init_fs();
+    {
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        let file_lock = fs
+            .create_inode(&mut root, "file", InodeType::File)
+            .unwrap();
+        fs.resize_inode(&mut file_lock.lock(), 3 * BLOCK_SIZE)
+            .unwrap();
+    }
+    assert!(fs.verify().is_clean());
+
+    // Allocated but never referenced by any inode.
+    let leaked = fs.allocate_data_block().unwrap();
+    let report = fs.verify();
+    assert_eq!(report.leaked_blocks, [leaked]);
+    assert!(report.double_used_blocks.is_empty());
+    assert!(report.dangling_entries.is_empty());
+}
+
+#[test]
+fn test_sparse_write() {
+    let fs = helpers::init_fs();
+    let root_lock = fs.root();
+    let mut root = root_lock.lock();
+
+    let file_lock = fs
+        .create_inode(&mut root, "sparse", InodeType::File)
+        .unwrap();
+    let mut file = file_lock.lock();
+
+    let offset = 1024 * 1024;
+    let data = [0xabu8; 100];
+    assert_eq!(fs.write_inode(&mut file, offset, &data).unwrap(), data.len());
+    assert_eq!(file.size(), offset + data.len());
+
+    // The hole before the written region reads as zeros.
+    let mut buffer = alloc::vec![0xffu8; offset];
+    assert_eq!(fs.read_inode(&file, 0, &mut buffer).unwrap(), offset);
+    assert!(buffer.iter().all(|&b| b == 0));
+
+    let mut buffer = [0u8; 100];
+    assert_eq!(fs.read_inode(&file, offset, &mut buffer).unwrap(), data.len());
+    assert_eq!(buffer, data);
+}
+
+#[test]
+fn test_grow_past_direct_blocks() {
+    let fs = helpers::init_fs();
+    let root_lock = fs.root();
+    let mut root = root_lock.lock();
+
+    let file_lock = fs
+        .create_inode(&mut root, "indirect", InodeType::File)
+        .unwrap();
+    let mut file = file_lock.lock();
+
+    fs.resize_inode(&mut file, N_DIRECT * BLOCK_SIZE).unwrap();
+    assert_eq!(file.dinode().indirect, 0);
+
+    let data = [0x5au8; 100];
+    let offset = N_DIRECT * BLOCK_SIZE;
+    assert_eq!(fs.write_inode(&mut file, offset, &data).unwrap(), data.len());
+    let indirect = file.dinode().indirect;
+    assert!(indirect >= fs.sb.data_start(), "indirect block: {}", indirect);
+
+    let mut buffer = [0u8; 100];
+    assert_eq!(fs.read_inode(&file, offset, &mut buffer).unwrap(), data.len());
+    assert_eq!(buffer, data);
+    assert!(fs.verify().is_clean());
+}
+
+#[test]
+fn test_write_inode_all_short() {
+    let fs = helpers::init_fs();
+    let root_lock = fs.root();
+    let mut root = root_lock.lock();
+
+    let file_lock = fs
+        .create_inode(&mut root, "short", InodeType::File)
+        .unwrap();
+    let mut file = file_lock.lock();
+    fs.resize_inode(&mut file, BLOCK_SIZE).unwrap();
+    while fs.allocate_data_block().is_some() {}
+
+    // Only the allocated block can be filled.
+    let data = alloc::vec![0x42u8; 3 * BLOCK_SIZE];
+    let err = fs.write_inode_all(&mut file, 100, &data).unwrap_err();
+    assert_eq!(err.written, BLOCK_SIZE - 100);
+    assert_eq!(err.short, data.len() - err.written);
+    assert_eq!(file.size(), BLOCK_SIZE);
+
+    let mut buffer = alloc::vec![0u8; BLOCK_SIZE - 100];
+    assert_eq!(fs.read_inode(&file, 100, &mut buffer).unwrap(), buffer.len());
+    assert!(buffer.iter().all(|&b| b == 0x42));
+
+    assert_eq!(fs.write_inode_all(&mut file, 0, &data[..100]), Ok(()));
+}
+
+#[test]
+fn test_write_inode_errors() {
+    let fs = helpers::init_fs();
+    let root_lock = fs.root();
+    let mut root = root_lock.lock();
+
+    let file_lock = fs
+        .create_inode(&mut root, "big", InodeType::File)
+        .unwrap();
+    let mut file = file_lock.lock();
+    assert!(matches!(
+        fs.write_inode(&mut file, CAPACITY_PER_INODE, b"x"),
+        Err(FileSystemAllocationError::TooLarge(_))
+    ));
+
+    while fs.allocate_data_block().is_some() {}
+    assert!(matches!(
+        fs.write_inode(&mut file, 0, b"x"),
+        Err(FileSystemAllocationError::Exhausted(_))
+    ));
+    assert_eq!(file.size(), 0);
+}
+
+#[test]
+fn test_alloc_policy() {
+    // The blocks not right after the previous one of the file.
+    let breaks = |blocks: &[u64]| blocks.windows(2).filter(|w| w[1] != w[0] + 1).count();
+
+    let mut results = Vec::new();
+    for policy in [AllocPolicy::Lowest, AllocPolicy::Contiguous] {
+        let fs = helpers::init_fs();
+        fs.set_alloc_policy(policy);
+        assert_eq!(fs.alloc_policy(), policy);
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        let a_lock = fs.create_inode(&mut root, "a", InodeType::File).unwrap();
+        let b_lock = fs.create_inode(&mut root, "b", InodeType::File).unwrap();
+        let mut a = a_lock.lock();
+        let mut b = b_lock.lock();
+
+        // Appends a block to each file in turn, past the direct blocks.
+        let block = [0x5au8; BLOCK_SIZE];
+        for i in 0..N_DIRECT + 4 {
+            fs.write_inode_all(&mut a, i * BLOCK_SIZE, &block).unwrap();
+            fs.write_inode_all(&mut b, i * BLOCK_SIZE, &block).unwrap();
+        }
+        let blocks = |inode| {
+            fs.block_map(inode)
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+        };
+        results.push((breaks(&blocks(&a)), breaks(&blocks(&b))));
+        assert!(fs.verify().is_clean());
+    }
+
+    let (lowest, contiguous) = (results[0], results[1]);
+    assert!(contiguous.0 < lowest.0, "{:?} vs {:?}", contiguous, lowest);
+    assert!(contiguous.1 < lowest.1, "{:?} vs {:?}", contiguous, lowest);
+}
+
+#[test]
+fn test_read_ahead_latency() {
+    let disk = helpers::new_disk();
+    let fs = helpers::init_fs_on(&disk);
+    let blocks = N_DIRECT + 38;
+    {
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        let file_lock = fs.create_inode(&mut root, "file", InodeType::File).unwrap();
+        fs.write_inode_all(&mut file_lock.lock(), 0, &vec![0x5au8; blocks * BLOCK_SIZE])
+            .unwrap();
+    }
+    fs.close();
+
+    // Reads the file a block at a time on a cold cache.
+    let sequential_read = |read_ahead: usize| {
+        let (fs, dev) = helpers::open_fs_with_latency(&disk);
+        fs.set_read_ahead(read_ahead);
+        let file_lock = fs.get_inode_from_path("/file", &fs.root()).unwrap();
+        let file = file_lock.lock();
+        dev.reset();
+
+        let mut buf = [0u8; BLOCK_SIZE];
+        for i in 0..blocks {
+            assert_eq!(fs.read_inode(&file, i * BLOCK_SIZE, &mut buf).unwrap(), BLOCK_SIZE);
+        }
+        dev.elapsed_us()
+    };
+
+    let without = sequential_read(0);
+    let with = sequential_read(READ_AHEAD_BLOCKS);
+    debug!("simulated read time: {}us without read ahead, {}us with", without, with);
+    assert!(with < without, "{} vs {}", with, without);
+}
+
+#[test]
+fn test_copy_range() {
+    let fs = helpers::init_fs();
+    let root_lock = fs.root();
+    let mut root = root_lock.lock();
+
+    // Past the direct blocks, so the indirect block is used too.
+    let data: Vec<u8> = (0..(N_DIRECT + 10) * BLOCK_SIZE + 123)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let src_lock = fs.create_inode(&mut root, "src", InodeType::File).unwrap();
+    let mut src = src_lock.lock();
+    fs.write_inode_all(&mut src, 0, &data).unwrap();
+
+    // The same offset in a block, and a different one.
+    for (name, src_off, dst_off) in [
+        ("aligned", 2 * BLOCK_SIZE + 100, 100),
+        ("unaligned", 100, 7),
+    ] {
+        let dst_lock = fs.create_inode(&mut root, name, InodeType::File).unwrap();
+        let mut dst = dst_lock.lock();
+        let old = alloc::vec![0xaau8; 3 * BLOCK_SIZE];
+        fs.write_inode_all(&mut dst, 0, &old).unwrap();
+
+        let len = data.len() - src_off;
+        let copied = fs
+            .copy_range(&src, src_off, &mut dst, dst_off, len + 1000)
+            .unwrap();
+        assert_eq!(copied, len);
+
+        let mut expected = old.clone();
+        expected.resize(dst_off + len, 0);
+        expected[dst_off..].copy_from_slice(&data[src_off..]);
+        assert_eq!(dst.size(), expected.len());
+        let mut buffer = alloc::vec![0u8; expected.len()];
+        assert_eq!(fs.read_inode(&dst, 0, &mut buffer).unwrap(), expected.len());
+        assert!(buffer == expected, "{} copy differs", name);
+    }
+    assert!(fs.verify().is_clean());
+}
+
+#[test]
+fn test_close_persists() {
+    let disk = helpers::new_disk();
+    let fs = helpers::init_fs_on(&disk);
+    {
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        let file_lock = fs
+            .create_inode(&mut root, "persisted", InodeType::File)
+            .unwrap();
+        fs.write_inode_all(&mut file_lock.lock(), 0, b"still here")
+            .unwrap();
+    }
+    fs.close();
+
+    // Reopen while `fs` is still alive, so nothing is flushed by drop.
+    let reopened = helpers::open_fs(&disk);
+    let root_lock = reopened.root();
+    let file_lock = reopened.look_up(&root_lock.lock(), "persisted").unwrap();
+    let file = file_lock.lock();
+    let mut buffer = [0u8; 10];
+    assert_eq!(reopened.read_inode(&file, 0, &mut buffer).unwrap(), 10);
+    assert_eq!(&buffer, b"still here");
+
+    drop(fs);
+}
+
+/// Overwrites the version in the super block on `disk`.
+fn set_image_version(disk: &RamDisk, version: u32) {
+    // The version follows the eight u64 fields of the super block.
+    helpers::patch_disk(disk, SUPER_BLOCK_LOC, 8 * 8, &version.to_ne_bytes());
+}
+
+#[test]
+fn test_open_migrates_old_version() {
+    let disk = helpers::new_disk();
+    drop(helpers::init_fs_on(&disk));
+
+    // An image made before the versioning.
+    set_image_version(&disk, 0);
+    let fs = helpers::open_fs(&disk);
+    assert_eq!(fs.sb.version(), FS_VERSION);
+    drop(fs);
+
+    // The migration is written back.
+    assert_eq!(helpers::open_fs(&disk).sb.version(), FS_VERSION);
+}
+
+#[test]
+fn test_open_migrates_dirent_types() {
+    let disk = helpers::new_disk();
+    let block = {
+        let fs = helpers::init_fs_on(&disk);
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        fs.create_inode(&mut root, "dir", InodeType::Directory)
+            .unwrap();
+        fs.create_inode(&mut root, "file", InodeType::File).unwrap();
+        fs.close();
+        fs.block_map(&root)[0].unwrap()
+    };
+    // The version 2 entries have no type.
+    for i in 0..2 {
+        helpers::patch_disk(&disk, block, (i + 1) * DIR_ENTRY_SIZE - 1, &[0]);
+    }
+    set_image_version(&disk, 2);
+
+    let fs = helpers::open_fs(&disk);
+    assert_eq!(fs.sb.version(), FS_VERSION);
+    let root_lock = fs.root();
+    let types: Vec<_> = fs
+        .read_dir(&root_lock.lock())
+        .into_iter()
+        .map(|item| item.type_)
+        .collect();
+    assert_eq!(types, [InodeType::Directory, InodeType::File]);
+}
+
+#[test]
+fn test_scoped_root() {
+    let fs = helpers::init_fs();
+    let (jail_inum, a_inum) = {
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        let jail_lock = fs
+            .create_inode(&mut root, "jail", InodeType::Directory)
+            .unwrap();
+        let a_lock = fs
+            .create_inode(&mut jail_lock.lock(), "a", InodeType::File)
+            .unwrap();
+        let etc_lock = fs
+            .create_inode(&mut root, "etc", InodeType::Directory)
+            .unwrap();
+        fs.create_inode(&mut etc_lock.lock(), "passwd", InodeType::File)
+            .unwrap();
+        let jail_inum = jail_lock.lock().inode_num;
+        let a_inum = a_lock.lock().inode_num;
+        (jail_inum, a_inum)
+    };
+
+    let jail = fs.scoped_root("/jail").unwrap();
+    let inum = |path| {
+        jail.get_inode_from_path(path)
+            .map(|inode| inode.lock().inode_num)
+    };
+    assert_eq!(jail.root().lock().inode_num, jail_inum);
+    assert_eq!(inum("/"), Some(jail_inum));
+    assert_eq!(inum("/a"), Some(a_inum));
+    assert_eq!(inum("a"), Some(a_inum));
+    assert_eq!(inum("/../a"), Some(a_inum));
+
+    assert!(fs.get_inode_from_path("etc/passwd", &fs.root()).is_some());
+    assert_eq!(inum("/../etc"), None);
+    assert_eq!(inum("/../etc/passwd"), None);
+    assert_eq!(inum("../../jail/a"), None);
+
+    assert!(matches!(fs.scoped_root("/jail/a"), Err(FileSystemAllocationError::NotFound(_))));
+    assert!(matches!(fs.scoped_root("/nope"), Err(FileSystemAllocationError::NotFound(_))));
+}
+
+#[test]
+fn test_dir_stream_chunks() {
+    let fs = helpers::init_fs();
+    let dir_lock = fs
+        .create_inode(&mut fs.root().lock(), "big", InodeType::Directory)
+        .unwrap();
+    let mut expected = Vec::new();
+    {
+        let mut dir = dir_lock.lock();
+        // Over two blocks of entries.
+        for i in 0..300 {
+            let name = i.to_string();
+            fs.create_inode(&mut dir, &name, InodeType::File).unwrap();
+            expected.push(name);
+        }
+        for i in (0..300).step_by(10) {
+            fs.unlink(&mut dir, &i.to_string()).unwrap();
+        }
+        expected.retain(|name| name.parse::<usize>().unwrap() % 10 != 0);
+    }
+
+    let mut seen = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut stream = DirStream::after(dir_lock.clone(), cursor);
+        let chunk: Vec<_> = stream.by_ref().take(7).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        cursor = stream.cursor().map(String::from);
+        seen.extend(chunk.into_iter().map(|item| item.name));
+    }
+    expected.sort();
+    assert_eq!(seen, expected);
+}
+
+#[test]
+fn test_dir_stream_unlink_between_chunks() {
+    let fs = helpers::init_fs();
+    let dir_lock = fs
+        .create_inode(&mut fs.root().lock(), "dir", InodeType::Directory)
+        .unwrap();
+    let mut dir = dir_lock.lock();
+    for name in ["a", "b", "c", "d", "e"] {
+        fs.create_inode(&mut dir, name, InodeType::File).unwrap();
+    }
+    drop(dir);
+
+    let mut stream = DirStream::new(dir_lock.clone());
+    let first: Vec<_> = stream.by_ref().take(2).map(|item| item.name).collect();
+    assert_eq!(first, ["a", "b"]);
+    let cursor = stream.cursor().map(String::from);
+
+    // "e" moves into the slot of "a", before where the stream stopped.
+    fs.unlink(&mut dir_lock.lock(), "a").unwrap();
+    let rest: Vec<_> = DirStream::after(dir_lock, cursor)
+        .map(|item| item.name)
+        .collect();
+    assert_eq!(rest, ["c", "d", "e"]);
+}
+
+#[test]
+fn test_open_rejects_long_dirent_names() {
+    let disk = helpers::new_disk();
+    let block = {
+        let fs = helpers::init_fs_on(&disk);
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        fs.create_inode(&mut root, "file", InodeType::File).unwrap();
+        fs.close();
+        fs.block_map(&root)[0].unwrap()
+    };
+    // A name filling the version 2 entry, its last byte is taken by
+    // the type now.
+    helpers::patch_disk(&disk, block, 8, &[b'x'; DIR_ENTRY_SIZE - 8]);
+    set_image_version(&disk, 2);
+    let err = FileSystem::open(disk, true).err().unwrap();
+    assert!(err.to_string().contains("can't migrate version 2"), "{}", err);
+}
+
+#[test]
+fn test_open_rejects_newer_version() {
+    let disk = helpers::new_disk();
+    drop(helpers::init_fs_on(&disk));
+
+    set_image_version(&disk, FS_VERSION + 1);
+    let err = FileSystem::open(disk, true).err().unwrap();
+    let msg = err.to_string();
+    assert!(msg.contains(&(FS_VERSION + 1).to_string()), "{}", msg);
+}
+
+#[test]
+fn test_open_rejects_unmigratable_version() {
+    let disk = helpers::new_disk();
+    {
+        let fs = helpers::init_fs_on(&disk);
+        let root_lock = fs.root();
+        let file_lock = fs
+            .create_inode(&mut root_lock.lock(), "large", InodeType::File)
+            .unwrap();
+        fs.resize_inode(&mut file_lock.lock(), (block_dev::N_DIRECT + 1) * BLOCK_SIZE)
+            .unwrap();
+    }
+
+    // A version 1 inode using the direct blocks replaced by the owner.
+    set_image_version(&disk, 1);
+    let err = FileSystem::open(disk, true).err().unwrap();
+    assert!(err.to_string().contains("can't migrate version 1"), "{}", err);
+}
+
+#[test]
+fn test_mode_and_owner_persist() {
+    let disk = helpers::new_disk();
+    let fs = helpers::init_fs_on(&disk);
+    {
+        let root_lock = fs.root();
+        let file_lock = fs
+            .create_inode(&mut root_lock.lock(), "owned", InodeType::File)
+            .unwrap();
+        let mut file = file_lock.lock();
+        assert_eq!(file.mode(), 0o644);
+        assert_eq!(file.owner(), (0, 0));
+
+        fs.set_mode(&mut file, 0o600);
+        fs.set_owner(&mut file, 1000, 100);
+    }
+    fs.close();
+
+    let reopened = helpers::open_fs(&disk);
+    let root_lock = reopened.root();
+    let file_lock = reopened.look_up(&root_lock.lock(), "owned").unwrap();
+    let file = file_lock.lock();
+    assert_eq!(file.mode(), 0o600);
+    assert_eq!(file.owner(), (1000, 100));
+
+    drop(fs);
+}
+
+#[test]
+fn test_iter_inodes() {
+    let fs = helpers::init_fs();
+    let root_lock = fs.root();
+    let mut root = root_lock.lock();
+
+    let mut expected = vec![root.inode_num];
+    for i in 0..3 {
+        let dir_lock = fs
+            .create_inode(&mut root, &format!("dir{}", i), InodeType::Directory)
+            .unwrap();
+        let mut dir = dir_lock.lock();
+        expected.push(dir.inode_num);
+        for j in 0..4 {
+            let file_lock = fs
+                .create_inode(&mut dir, &j.to_string(), InodeType::File)
+                .unwrap();
+            expected.push(file_lock.lock().inode_num);
+        }
+    }
+    drop(root);
+
+    let inodes: Vec<_> = fs.iter_inodes().collect();
+    assert_eq!(inodes.len(), 1 + 3 * 5);
+    for (inum, inode_lock) in &inodes {
+        assert_eq!(inode_lock.lock().inode_num, *inum);
+    }
+    let inums: Vec<_> = inodes.iter().map(|(inum, _)| *inum).collect();
+    assert_eq!(inums, expected);
+}
+
+#[test]
+fn test_unlink_directory() {
+    let fs = helpers::init_fs();
+    let root_lock = fs.root();
+    let mut root = root_lock.lock();
+    fs.create_inode(&mut root, "dir", InodeType::Directory).unwrap();
+
+    let res = fs.unlink(&mut root, "dir");
+    assert!(matches!(res, Err(FileSystemAllocationError::IsADirectory)));
+    assert!(fs.look_up(&root, "dir").is_some());
+}
+
+#[test]
+fn test_unlink_while_open() {
+    let fs = helpers::init_fs();
+    let root_lock = fs.root();
+    let mut root = root_lock.lock();
+
+    let data: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| i as u8).collect();
+    let file_lock = fs.create_inode(&mut root, "unlinked", InodeType::File).unwrap();
+    fs.write_inode_all(&mut file_lock.lock(), 0, &data).unwrap();
+    fs.create_inode(&mut root, "kept", InodeType::File).unwrap();
+    let inum = file_lock.lock().inode_num;
+
+    let handle = InodeHandle::open(file_lock);
+    fs.unlink(&mut root, "unlinked").unwrap();
+    assert!(fs.look_up(&root, "unlinked").is_none());
+    assert_eq!(fs.list_children(&root), ["kept"]);
+    drop(root);
+
+    {
+        let file = handle.inode().lock();
+        assert_eq!(file.links_num(), 0);
+        let mut buf = vec![0; data.len()];
+        assert_eq!(fs.read_inode(&file, 0, &mut buf).unwrap(), data.len());
+        assert_eq!(buf, data);
+    }
+    assert!(fs.iter_inodes().any(|(i, _)| i == inum));
+
+    drop(handle);
+    assert!(!fs.iter_inodes().any(|(i, _)| i == inum));
+    assert!(fs.verify().is_clean());
+}
+
+#[test]
+fn test_small_write_amplification() {
+    let disk = helpers::new_disk();
+    let fs = helpers::init_fs_on(&disk);
+    {
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        let file_lock = fs.create_inode(&mut root, "file", InodeType::File).unwrap();
+        fs.write_inode_all(&mut file_lock.lock(), 0, &[0; BLOCK_SIZE])
+            .unwrap();
+    }
+    fs.close();
+    drop(fs);
+
+    // Nothing cached, the block written has to be read first.
+    let fs = helpers::open_fs(&disk);
+    let root_lock = fs.root();
+    let file_lock = fs.look_up(&root_lock.lock(), "file").unwrap();
+    let mut file = file_lock.lock();
+
+    let before = fs.metrics();
+    assert_eq!(fs.write_inode(&mut file, 10, &[1]).unwrap(), 1);
+    fs.close();
+    let metrics = fs.metrics().since(&before);
+    assert_eq!(metrics.bytes_written, 1);
+    assert_eq!(metrics.blocks_read, 1);
+    assert_eq!(metrics.blocks_written, 1);
+
+    let mut buf = [0; 1];
+    assert_eq!(fs.read_inode(&file, 10, &mut buf).unwrap(), 1);
+    let metrics = fs.metrics().since(&before);
+    assert_eq!(metrics.bytes_read, 1);
+    // Still cached.
+    assert_eq!(metrics.blocks_read, 1);
+}
+
+#[test]
+fn test_read_write_path() {
+    let fs = helpers::init_fs();
+    let root_lock = fs.root();
+    fs.create_inode(&mut root_lock.lock(), "dir", InodeType::Directory)
+        .unwrap();
+
+    let data: Vec<u8> = (0..BLOCK_SIZE * (N_DIRECT + 2)).map(|i| i as u8).collect();
+    fs.write_path("/dir/file", &data, true).unwrap();
+    assert_eq!(fs.read_path("/dir/file").unwrap(), data);
+
+    // Shrinking drops the blocks past the new end.
+    fs.write_path("dir//file", b"short", false).unwrap();
+    assert_eq!(fs.read_path("/dir/./file").unwrap(), b"short");
+    assert!(fs.verify().is_clean());
+
+    assert!(matches!(
+        fs.read_path("/dir/missing"),
+        Err(FileSystemAllocationError::NotFound(_))
+    ));
+    assert!(matches!(
+        fs.write_path("/dir/missing", b"data", false),
+        Err(FileSystemAllocationError::NotFound(_))
+    ));
+    assert!(matches!(fs.read_path("/dir"), Err(FileSystemAllocationError::InvalidName(_))));
+}
+
+#[test]
+fn test_compact_dir() {
+    let disk = helpers::new_disk();
+    let names: Vec<_> = (0..100).map(|i| format!("file{}", i)).collect();
+    let (peak, block) = {
+        let fs = helpers::init_fs_on(&disk);
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        for name in &names {
+            fs.create_inode(&mut root, name, InodeType::File).unwrap();
+        }
+        let peak = root.size();
+        for name in &names[10..] {
+            fs.unlink(&mut root, name).unwrap();
+        }
+        fs.close();
+        (peak, fs.block_map(&root)[0].unwrap())
+    };
+
+    // Zero two of the slots, as if their entries were never written.
+    let zeroed = [3, 7];
+    for i in zeroed {
+        helpers::patch_disk(&disk, block, i * DIR_ENTRY_SIZE, &[0; DIR_ENTRY_SIZE]);
+    }
+    let fs = helpers::open_fs(&disk);
+    let root_lock = fs.root();
+    let mut root = root_lock.lock();
+    assert_eq!(fs.compact_dir(&mut root), zeroed.len());
+    assert_eq!(fs.compact_dir(&mut root), 0);
+    assert!(root.size() < peak);
+    assert_eq!(root.size(), (10 - zeroed.len()) * DIR_ENTRY_SIZE);
+
+    let kept: Vec<_> = names[..10]
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !zeroed.contains(i))
+        .map(|(_, name)| name.clone())
+        .collect();
+    assert_eq!(fs.list_children(&root), kept);
+    for name in &kept {
+        assert!(fs.look_up(&root, name).is_some(), "{} is lost", name);
+    }
+}
+
+#[test]
+fn test_create_compacts_full_dir() {
+    let disk = helpers::new_disk();
+    let per_block = BLOCK_SIZE / DIR_ENTRY_SIZE;
+    let block = {
+        let fs = helpers::init_fs_on(&disk);
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        for i in 0..per_block {
+            fs.create_inode(&mut root, &format!("file{}", i), InodeType::File)
+                .unwrap();
+        }
+        fs.close();
+        fs.block_map(&root)[0].unwrap()
+    };
+    for i in 0..per_block / 2 {
+        helpers::patch_disk(&disk, block, i * 2 * DIR_ENTRY_SIZE, &[0; DIR_ENTRY_SIZE]);
+    }
+
+    let fs = helpers::open_fs(&disk);
+    let root_lock = fs.root();
+    let mut root = root_lock.lock();
+
+    // The block is full, but half of it is reclaimed instead of growing.
+    fs.create_inode(&mut root, "new", InodeType::File).unwrap();
+    assert_eq!(root.size(), (per_block - per_block / 2 + 1) * DIR_ENTRY_SIZE);
+    assert!(fs.look_up(&root, "new").is_some());
+    assert!(fs.look_up(&root, "file1").is_some());
+}
+
+#[test]
+fn test_write_directory_rejected() {
+    let fs = helpers::init_fs();
+    let root_lock = fs.root();
+    let mut root = root_lock.lock();
+    fs.create_inode(&mut root, "file", InodeType::File).unwrap();
+    let size = root.size();
+
+    assert!(matches!(
+        fs.write_inode(&mut root, 0, &[0; DIR_ENTRY_SIZE]),
+        Err(FileSystemAllocationError::IsADirectory)
+    ));
+    assert!(fs.write_inode_all(&mut root, 0, b"x").is_err());
+    let mut buf = [0u8; DIR_ENTRY_SIZE];
+    assert!(matches!(
+        fs.read_inode(&root, 0, &mut buf),
+        Err(FileSystemAllocationError::IsADirectory)
+    ));
+    assert_eq!(root.size(), size);
+
+    // The entries are still changed through the directory operations.
+    fs.create_inode(&mut root, "new", InodeType::File).unwrap();
+    assert_eq!(fs.list_children(&root), ["file", "new"]);
+    assert!(fs.look_up(&root, "file").is_some());
+}
+
+#[test]
+fn test_xattr_persist() {
+    let disk = helpers::new_disk();
+    let fs = helpers::init_fs_on(&disk);
+    {
+        let root_lock = fs.root();
+        let file_lock = fs
+            .create_inode(&mut root_lock.lock(), "file", InodeType::File)
+            .unwrap();
+        let mut file = file_lock.lock();
+        assert_eq!(fs.get_xattr(&file, "host.mode"), None);
+
+        fs.set_xattr(&mut file, "host.mode", b"0755").unwrap();
+        fs.set_xattr(&mut file, "host.owner", b"alice").unwrap();
+    }
+    fs.close();
+
+    let reopened = helpers::open_fs(&disk);
+    let root_lock = reopened.root();
+    let file_lock = reopened.look_up(&root_lock.lock(), "file").unwrap();
+    let file = file_lock.lock();
+    assert_eq!(reopened.get_xattr(&file, "host.mode"), Some(b"0755".to_vec()));
+    assert_eq!(reopened.get_xattr(&file, "host.owner"), Some(b"alice".to_vec()));
+    assert!(reopened.verify().is_clean());
+
+    drop(fs);
+}
+
+#[test]
+fn test_xattr_exceeds_block() {
+    let fs = helpers::init_fs();
+    let root_lock = fs.root();
+    let file_lock = fs
+        .create_inode(&mut root_lock.lock(), "file", InodeType::File)
+        .unwrap();
+    let mut file = file_lock.lock();
+
+    let res = fs.set_xattr(&mut file, "big", &[0; BLOCK_SIZE]);
+    assert!(matches!(res, Err(FileSystemAllocationError::XattrFull(_))));
+    assert_eq!(file.dinode().xattr, 0);
+
+    fs.set_xattr(&mut file, "half", &[1; BLOCK_SIZE / 2])
+        .unwrap();
+    let res = fs.set_xattr(&mut file, "more", &[2; BLOCK_SIZE / 2]);
+    assert!(matches!(res, Err(FileSystemAllocationError::XattrFull(_))));
+    assert_eq!(fs.get_xattr(&file, "half"), Some(vec![1; BLOCK_SIZE / 2]));
+    assert_eq!(fs.get_xattr(&file, "more"), None);
+}
+
+#[test]
+fn test_dir_links() {
+    let disk = helpers::new_disk();
+    let fs = helpers::init_fs_on(&disk);
+    let inums = {
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        let a_lock = fs
+            .create_inode(&mut root, "a", InodeType::Directory)
+            .unwrap();
+        let mut a = a_lock.lock();
+        let b_lock = fs.create_inode(&mut a, "b", InodeType::Directory).unwrap();
+        fs.create_inode(&mut b_lock.lock(), "c", InodeType::Directory)
+            .unwrap();
+        let file_lock = fs.create_inode(&mut a, "file", InodeType::File).unwrap();
+
+        // `.` and `..` of the root, and `..` of `a`.
+        assert_eq!(root.links_num(), 3);
+        // The entry, `.`, and `..` of `b`.
+        assert_eq!(a.links_num(), 3);
+        assert_eq!(b_lock.lock().links_num(), 3);
+        assert_eq!(file_lock.lock().links_num(), 1);
+
+        let b_inum = b_lock.lock().inode_num;
+        [a.inode_num, b_inum]
+    };
+    assert!(fs.verify().is_clean());
+    fs.close();
+
+    // A version 4 image counted only the entry.
+    for inum in inums {
+        let (block_id, offset) = fs.sb.find_inode(inum);
+        // `links_num` follows the type and the indirect block.
+        helpers::patch_disk(&disk, block_id, offset as usize + 16, &1u64.to_ne_bytes());
+    }
+    set_image_version(&disk, 4);
+    drop(fs);
+
+    let fs = helpers::open_fs(&disk);
+    assert_eq!(fs.sb.version(), FS_VERSION);
+    assert!(fs.verify().is_clean());
+}
+
+#[test]
+fn test_concurrent_read_write_not_torn() {
+    const ROUNDS: u8 = 100;
+    let fs = helpers::init_fs();
+    let root_lock = fs.root();
+    let file_lock = {
+        let mut root = root_lock.lock();
+        let file_lock = fs
+            .create_inode(&mut root, "shared", InodeType::File)
+            .unwrap();
+        fs.write_inode_all(&mut file_lock.lock(), 0, &[0; 2 * BLOCK_SIZE])
+            .unwrap();
+        file_lock
+    };
+    // Fill the inode cache with inodes in use, the file in use is still
+    // loaded once.
+    let _held: Vec<_> = (0..INODE_BUFFER_SIZE)
+        .map(|i| {
+            fs.create_inode(&mut root_lock.lock(), &format!("held{}", i), InodeType::File)
+                .unwrap()
+        })
+        .collect();
+    let found = fs.get_inode_from_path("shared", &root_lock).unwrap();
+    assert!(alloc::sync::Arc::ptr_eq(&found, &file_lock));
+    drop((found, file_lock));
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            for round in 1..=ROUNDS {
+                let file_lock = fs.get_inode_from_path("shared", &root_lock).unwrap();
+                fs.write_inode_all(&mut file_lock.lock(), 0, &[round; 2 * BLOCK_SIZE])
+                    .unwrap();
+            }
+        });
+        s.spawn(|| {
+            let mut buf = vec![0; 2 * BLOCK_SIZE];
+            for _ in 0..ROUNDS {
+                let file_lock = fs.get_inode_from_path("shared", &root_lock).unwrap();
+                assert_eq!(fs.read_inode(&file_lock.lock(), 0, &mut buf).unwrap(), buf.len());
+                assert!(buf.iter().all(|&b| b == buf[0]), "torn read of round {}", buf[0]);
+            }
+        });
+    });
+}
@@ -1,34 +1,17 @@
-use alloc::{format, sync::Arc};
-use spin::Mutex;
-use std::io::{Read, Seek, SeekFrom, Write};
+use alloc::sync::Arc;
 
 use fs::{
     block_dev::{BlockDevice, BLOCK_SIZE},
+    latency::LatencyBlockDevice,
+    ram_disk::RamDisk,
     FileSystem,
 };
 
 extern crate alloc;
 extern crate std;
 
-pub struct BlockFile(pub Mutex<std::fs::File>);
-
-impl BlockDevice for BlockFile {
-    fn read(&self, block_id: u64, buf: &mut [u8])  -> Result<(), String>  {
-        let mut file = self.0.lock();
-        file.seek(SeekFrom::Start(block_id * BLOCK_SIZE as u64))
-            .unwrap();
-        assert_eq!(file.read(buf).unwrap(), BLOCK_SIZE);
-        Ok(())
-    }
-
-    fn write(&self, block_id: u64, buf: &[u8]) -> Result<(), String>  {
-        let mut file = self.0.lock();
-        file.seek(SeekFrom::Start(block_id * BLOCK_SIZE as u64))
-            .unwrap();
-        assert_eq!(file.write(buf).unwrap(), BLOCK_SIZE);
-        Ok(())
-    }
-}
+/// The number of blocks of the disks.
+const DISK_BLOCKS: usize = 100 * 1024;
 
 pub fn init_test_logger() {
     let _ = env_logger::builder()
@@ -38,21 +21,42 @@ pub fn init_test_logger() {
 }
 
 pub fn init_fs() -> Arc<FileSystem> {
+    init_fs_on(&new_disk())
+}
+
+/// Returns a disk of zeros, for a file system to be created and opened
+/// again on it.
+pub fn new_disk() -> Arc<RamDisk> {
+    Arc::new(RamDisk::new(DISK_BLOCKS))
+}
+
+/// Creates a file system on `disk`.
+pub fn init_fs_on(disk: &Arc<RamDisk>) -> Arc<FileSystem> {
+    init_fs_with(disk, FileSystem::calc_inodes_num(DISK_BLOCKS as u64, 0.1))
+}
+
+/// Creates a file system with `inodes` inodes on `disk`.
+pub fn init_fs_with(disk: &Arc<RamDisk>, inodes: u64) -> Arc<FileSystem> {
     init_test_logger();
+    FileSystem::create(disk.clone(), DISK_BLOCKS as u64, inodes).unwrap()
+}
+
+/// Opens the file system on `disk` with a fresh block cache.
+pub fn open_fs(disk: &Arc<RamDisk>) -> Arc<FileSystem> {
+    FileSystem::open(disk.clone(), true).unwrap()
+}
+
+/// Opens the file system on `disk` on a device simulating its latency.
+pub fn open_fs_with_latency(disk: &Arc<RamDisk>) -> (Arc<FileSystem>, Arc<LatencyBlockDevice>) {
+    let dev = Arc::new(LatencyBlockDevice::new(disk.clone()));
+    (FileSystem::open(dev.clone(), true).unwrap(), dev)
+}
 
-    let path = format!("target/fs-{}.img", rand::prelude::random::<u64>());
-    let file = std::fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(path)
-        .unwrap();
-    file.set_len(100 * 1024 * BLOCK_SIZE as u64).unwrap();
-
-    FileSystem::create(
-        Arc::new(BlockFile(Mutex::new(file))),
-        100 * 1024,
-        FileSystem::calc_inodes_num(100 * 1024, 0.1),
-    )
-    .unwrap()
+/// Writes `data` at `offset` in the block `block_id` of `disk`, e.g. over
+/// the entries of a directory.
+pub fn patch_disk(disk: &RamDisk, block_id: u64, offset: usize, data: &[u8]) {
+    let mut block = [0; BLOCK_SIZE];
+    disk.read(block_id, &mut block).unwrap();
+    block[offset..offset + data.len()].copy_from_slice(data);
+    disk.write(block_id, &block).unwrap();
 }
@@ -0,0 +1,102 @@
+//! Random sequences of directory operations against a file system in
+//! memory, run with `cargo test --features std --test proptest`.
+
+use std::collections::BTreeMap;
+
+use fs::{
+    block_dev::{BitmapBlock, InodeType, DIR_ENTRY_SIZE},
+    fuzz::{mem_fs, skip},
+};
+use proptest::prelude::*;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Create(String),
+    Unlink(String),
+    LookUp(String),
+}
+
+fn name() -> impl Strategy<Value = String> {
+    "[a-e]{1,2}"
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        name().prop_map(Op::Create),
+        name().prop_map(Op::Unlink),
+        name().prop_map(Op::LookUp),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn test_directory_ops(ops in prop::collection::vec(op(), 1..64)) {
+        let fs = mem_fs(1024, 16);
+        let root_lock = fs.root();
+        let mut root = root_lock.lock();
+        // The expected entries, by name.
+        let mut entries = BTreeMap::new();
+
+        for op in ops {
+            match op {
+                Op::Create(name) => {
+                    let created = fs.create_inode(&mut root, &name, InodeType::File);
+                    prop_assert_eq!(created.is_ok(), !entries.contains_key(&name));
+                    if let Ok(inode) = created {
+                        entries.insert(name, inode.lock().inode_num);
+                    }
+                }
+                Op::Unlink(name) => {
+                    let unlinked = fs.unlink(&mut root, &name);
+                    prop_assert_eq!(unlinked.is_ok(), entries.remove(&name).is_some());
+                }
+                Op::LookUp(name) => {
+                    let found = fs.look_up(&root, &name).map(|inode| inode.lock().inode_num);
+                    prop_assert_eq!(found, entries.get(&name).copied());
+                }
+            }
+
+            let mut children = fs.list_children(&root);
+            children.sort();
+            let names: Vec<_> = entries.keys().cloned().collect();
+            prop_assert_eq!(children, names);
+            prop_assert_eq!(root.size(), entries.len() * DIR_ENTRY_SIZE);
+        }
+
+        drop(root);
+        prop_assert!(fs.verify().is_clean());
+    }
+
+    #[test]
+    fn test_skip(names in prop::collection::vec("[a-z]{1,8}", 0..8), slashes in 1..4usize) {
+        let sep = "/".repeat(slashes);
+        let mut path = format!("{}{}", sep, names.join(&sep));
+        let mut split = Vec::new();
+        while let Some((name, rest)) = skip(&path) {
+            split.push(name.to_string());
+            path = rest.to_string();
+        }
+        prop_assert_eq!(split, names);
+    }
+
+    #[test]
+    fn test_bitmap(allocated in 1..512usize, frees in prop::collection::vec(any::<usize>(), 0..32)) {
+        let mut bmap = BitmapBlock::empty();
+        for i in 0..allocated {
+            prop_assert_eq!(bmap.allocate(), Some(i));
+        }
+
+        let mut freed: Vec<_> = frees.into_iter().map(|i| i % allocated).collect();
+        freed.sort();
+        freed.dedup();
+        for &i in &freed {
+            bmap.free(i);
+            prop_assert!(!bmap.is_allocated(i));
+        }
+        // The lowest bits freed are allocated again first.
+        for &i in &freed {
+            prop_assert_eq!(bmap.allocate(), Some(i));
+        }
+        prop_assert_eq!(bmap.allocate(), Some(allocated));
+    }
+}
@@ -1,12 +1,89 @@
+use alloc::vec::Vec;
 use core::fmt::{self, Write};
 
-use crate::syscall::console_putchar;
+use crate::{sync::intr_lock::IntrMutex, syscall::console_putchar};
 
-struct Stdout;
+/// Bytes handed to `console_putchar`, so tests can tell whether a print
+/// went through.
+#[cfg(test)]
+static WRITTEN: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+/// The bytes of the recent output kept by `LOG`.
+pub const LOG_SIZE: usize = 16 * 1024;
+
+/// The recent output, the oldest bytes overwritten when it's full.
+struct LogRing {
+    buf:   [u8; LOG_SIZE],
+    // The index of the oldest byte.
+    start: usize,
+    len:   usize,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        Self {
+            buf:   [0; LOG_SIZE],
+            start: 0,
+            len:   0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let end = (self.start + self.len) % LOG_SIZE;
+            self.buf[end] = byte;
+            if self.len == LOG_SIZE {
+                self.start = (self.start + 1) % LOG_SIZE;
+            } else {
+                self.len += 1;
+            }
+        }
+    }
+
+    fn to_vec(&self) -> Vec<u8> {
+        let end = self.start + self.len;
+        let mut ret = Vec::with_capacity(self.len);
+        ret.extend_from_slice(&self.buf[self.start..end.min(LOG_SIZE)]);
+        if end > LOG_SIZE {
+            ret.extend_from_slice(&self.buf[..end - LOG_SIZE]);
+        }
+        ret
+    }
+}
+
+/// Taken by the interrupt handlers printing too.
+static LOG: IntrMutex<LogRing> = IntrMutex::new(LogRing::new());
+
+struct Stdout {
+    // Skips `LOG` if it's held rather than waiting for it, the holder
+    // may be the panicking code.
+    panicking: bool,
+    putchar:   fn(u8) -> Result<(), isize>,
+}
+
+/// Serializes `print!`s so lines from different harts don't interleave.
+///
+/// The interrupt handlers print too, one taking it while this hart holds
+/// it would spin forever.
+static WRITER: IntrMutex<Stdout> = IntrMutex::new(Stdout {
+    panicking: false,
+    putchar:   console_putchar,
+});
 
 impl fmt::Write for Stdout {
     /// Prints a string, which can contain non-ASCII characters.
+    ///
+    /// Stops at the first character the SBI fails to print, the whole
+    /// string is kept in `LOG` all the same.
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.panicking {
+            if let Some(mut log) = LOG.try_lock() {
+                log.push(s.as_bytes());
+            }
+        } else {
+            LOG.lock().push(s.as_bytes());
+        }
+
         let mut buffer = [0u8; 4];
 
         // The `console_putchar` sbi call accepts one 'u8` to print
@@ -15,7 +92,9 @@ impl fmt::Write for Stdout {
         // call `console_putchar` once for each `u8`.
         for c in s.chars() {
             for code_point in c.encode_utf8(&mut buffer).as_bytes().iter() {
-                console_putchar(*code_point);
+                (self.putchar)(*code_point).map_err(|_| fmt::Error)?;
+                #[cfg(test)]
+                WRITTEN.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
             }
         }
         Ok(())
@@ -23,8 +102,33 @@ impl fmt::Write for Stdout {
 }
 
 /// Prints formatted string by [`core::format_args!`].
+///
+/// The output failed to print is dropped, a panic here would be in the
+/// middle of logging.
 pub fn _print(args: fmt::Arguments) {
-    Stdout.write_fmt(args).unwrap();
+    let _ = WRITER.lock().write_fmt(args);
+}
+
+/// Prints from the panic handler.
+///
+/// If the panic happened while `WRITER` was held, its holder never gets to
+/// release it and `_print` would spin forever. `console_putchar` is a bare
+/// SBI call that needs no lock, so fall back to writing around `WRITER`.
+pub fn _print_panic(args: fmt::Arguments) {
+    // Held if we can, so the other harts don't interleave.
+    let _writer = WRITER.try_lock();
+    let _ = Stdout {
+        panicking: true,
+        putchar:   console_putchar,
+    }
+    .write_fmt(args);
+}
+
+/// Returns the recent output, at most `LOG_SIZE` bytes, oldest first.
+///
+/// The first line can be cut, its start overwritten.
+pub fn dump_log() -> Vec<u8> {
+    LOG.lock().to_vec()
 }
 
 #[macro_export]
@@ -54,3 +158,81 @@ impl<'a> fmt::Display for HexDump<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test_case]
+    fn test_print_panic_while_locked() {
+        // Pretend the panicking code was in the middle of a `println!`.
+        let guard = WRITER.lock();
+        let before = WRITTEN.load(Ordering::Relaxed);
+        _print_panic(format_args!("[panic] while locked\n"));
+        assert_eq!(WRITTEN.load(Ordering::Relaxed) - before, 21);
+        drop(guard);
+
+        let before = WRITTEN.load(Ordering::Relaxed);
+        _print_panic(format_args!("[panic] unlocked\n"));
+        assert_eq!(WRITTEN.load(Ordering::Relaxed) - before, 17);
+        assert!(!WRITER.is_locked());
+    }
+
+    /// Characters the SBI prints before `failing_putchar` fails.
+    static PUTCHAR_BUDGET: AtomicUsize = AtomicUsize::new(0);
+
+    fn failing_putchar(c: u8) -> Result<(), isize> {
+        match PUTCHAR_BUDGET.fetch_sub(1, Ordering::Relaxed) {
+            0 => {
+                PUTCHAR_BUDGET.store(0, Ordering::Relaxed);
+                Err(-1)
+            }
+            _ => console_putchar(c),
+        }
+    }
+
+    #[test_case]
+    fn test_print_sbi_failure() {
+        PUTCHAR_BUDGET.store(4, Ordering::Relaxed);
+        WRITER.lock().putchar = failing_putchar;
+        let before = WRITTEN.load(Ordering::Relaxed);
+        _print(format_args!("sbi {} fails\n", "mid-string"));
+        WRITER.lock().putchar = console_putchar;
+
+        // The rest of the arguments are dropped after the failure, the
+        // string failed to print is kept in the log all the same.
+        assert_eq!(WRITTEN.load(Ordering::Relaxed) - before, 4);
+        assert!(dump_log().ends_with(b"sbi mid-string"));
+
+        let before = WRITTEN.load(Ordering::Relaxed);
+        println!("\nprinted after the failure");
+        assert_eq!(WRITTEN.load(Ordering::Relaxed) - before, 27);
+    }
+
+    #[test_case]
+    fn test_dump_log() {
+        let lines = LOG_SIZE / 10 + 100;
+        for i in 0..lines {
+            println!("log {:05}", i);
+        }
+
+        let log = dump_log();
+        assert_eq!(log.len(), LOG_SIZE);
+        let log = core::str::from_utf8(&log).unwrap();
+        // The first line may be cut.
+        let kept: Vec<&str> = log.lines().skip(1).collect();
+        let first = lines - kept.len();
+        for (i, line) in kept.iter().enumerate() {
+            assert_eq!(*line, alloc::format!("log {:05}", first + i));
+        }
+        assert_eq!(*kept.last().unwrap(), alloc::format!("log {:05}", lines - 1));
+
+        // Nothing is kept while the panicking code holds the log.
+        let guard = LOG.lock();
+        _print_panic(format_args!("[panic] lost\n"));
+        drop(guard);
+        assert!(!core::str::from_utf8(&dump_log()).unwrap().contains("lost"));
+    }
+}
@@ -0,0 +1,77 @@
+use core::ptr::NonNull;
+
+use crate::{
+    mem::{
+        address::{PhysicalAddress, VirtualAddress},
+        allocator::{alloc_frames, free_frames},
+        PAGE_SIZE,
+    },
+    pa2va,
+};
+
+/// Physically contiguous pages shared with a device.
+///
+/// The driver accesses them at `va`, and the device at `pa`, which is
+/// what goes to its registers and descriptors. The device must be
+/// stopped before they are dropped.
+pub struct Dma {
+    pa:    PhysicalAddress,
+    pages: usize,
+}
+
+impl Dma {
+    /// Allocates `pages` zeroed pages.
+    pub fn new(pages: usize) -> Option<Self> {
+        let pa = alloc_frames(pages)?;
+        Some(Self { pa, pages })
+    }
+
+    pub fn pa(&self) -> PhysicalAddress {
+        self.pa
+    }
+
+    pub fn va(&self) -> VirtualAddress {
+        pa2va!(self.pa)
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Returns the page `idx` as a `T` for the driver.
+    pub fn page<T>(&self, idx: usize) -> NonNull<T> {
+        assert!(idx < self.pages, "dma: page {} of {}", idx, self.pages);
+        assert!(size_of::<T>() <= PAGE_SIZE);
+        unsafe { NonNull::new_unchecked((self.va() + idx * PAGE_SIZE) as *mut T) }
+    }
+
+    /// Returns the device address of the page `idx`.
+    pub fn page_pa(&self, idx: usize) -> PhysicalAddress {
+        assert!(idx < self.pages, "dma: page {} of {}", idx, self.pages);
+        self.pa + idx * PAGE_SIZE
+    }
+}
+
+impl Drop for Dma {
+    fn drop(&mut self) {
+        unsafe { free_frames(self.pa, self.pages) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::va2pa;
+
+    #[test_case]
+    fn test_dma() {
+        let dma = Dma::new(3).unwrap();
+        assert_eq!(dma.pa() % PAGE_SIZE, 0);
+        assert_eq!(va2pa!(dma.va()), dma.pa());
+        for idx in 0..dma.pages() {
+            let page = dma.page::<[u8; PAGE_SIZE]>(idx);
+            assert_eq!(va2pa!(page.as_ptr() as usize), dma.page_pa(idx));
+            assert!(unsafe { page.as_ref() }.iter().all(|&b| b == 0));
+        }
+    }
+}
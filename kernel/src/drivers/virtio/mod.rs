@@ -1,15 +1,18 @@
+mod dma;
 pub mod virtio_blk;
 
-use alloc::boxed::Box;
-use core::ptr::NonNull;
+use alloc::sync::{Arc, Weak};
+use core::{array::from_fn, ptr::NonNull};
 
 use bitflags::bitflags;
+use dma::Dma;
+use spin::Mutex;
 use virtio_blk::VIRTIO_BLK_DEVICES;
 
 use super::{ReadOnly, ReadWrite, Volatile, WriteOnly};
 
-/// Virtqueue size.
-const QUEUE_SIZE: usize = 16;
+/// Virtqueue size asked for, the device may take a smaller one.
+const QUEUE_SIZE: usize = 64;
 
 /// Device-specific configuration space starts at the offset 0x100 and is ac-
 /// cessed with byte alignment. Its meaning and size depend on the device
@@ -56,6 +59,7 @@ bitflags! {
         const BLK_F_SCSI = 1 << 7;	/* Supports scsi command passthru */
         const BLK_F_CONFIG_WCE = 1 << 11;	/* Writeback mode available in config */
         const BLK_F_MQ = 1 << 12;	/* support more than one vq */
+        const BLK_F_DISCARD = 1 << 13;	/* Supports discard requests */
         const F_ANY_LAYOUT = 1 << 27;
         const RING_F_INDIRECT_DESC = 1 << 28;
         const RING_F_EVENT_IDX = 1 << 29;
@@ -146,47 +150,53 @@ pub struct VirtIORegs {
     config:             [u8; 0],      // Configuration space placeholder
 }
 
+/// A virtqueue of `size` descriptors.
+///
+/// The rings are allocated for `QUEUE_SIZE` entries, the device only
+/// uses the first `size` of them. Each ring takes a page of `dma`, all
+/// zeroes being their initial state.
 struct VirtQueue {
-    desc:  NonNull<[VirtqDesc; QUEUE_SIZE]>,
+    desc:  NonNull<[VirtqDesc]>,
     avail: NonNull<VirtqAvail>,
     used:  NonNull<VirtqUsed>,
+    size:  usize,
+    dma:   Dma,
 }
 
+/// The pages of `VirtQueue::dma` holding the rings.
+const DESC_PAGE: usize = 0;
+const AVAIL_PAGE: usize = 1;
+const USED_PAGE: usize = 2;
+
 impl VirtQueue {
-    pub fn new() -> Self {
-        let desc = Box::new(core::array::from_fn(|_| VirtqDesc {
-            addr:  0,
-            len:   0,
-            flags: 0,
-            next:  0,
-        }));
-        let desc_ptr = unsafe { NonNull::new_unchecked(Box::into_raw(desc)) };
-
-        let avail = Box::new(VirtqAvail {
-            flags:      Volatile::from(0),
-            idx:        Volatile::from(0),
-            ring:       core::array::from_fn(|_| Volatile::from(0)),
-            used_event: Volatile::from(0),
-        });
-        let avail_ptr = unsafe { NonNull::new_unchecked(Box::into_raw(avail)) };
-
-        let used = Box::new(VirtqUsed {
-            flags:       Volatile::from(0),
-            idx:         Volatile::from(0),
-            ring:        core::array::from_fn(|_| VirtqUsedElem {
-                id:  Volatile::from(0),
-                len: Volatile::from(0),
-            }),
-            avail_event: Volatile::from(0),
-        });
-        let used_ptr = unsafe { NonNull::new_unchecked(Box::into_raw(used)) };
+    pub fn new(size: usize) -> Self {
+        assert!(size <= QUEUE_SIZE, "queue size {} over {}", size, QUEUE_SIZE);
+        let dma = Dma::new(3).expect("virtio: no memory for the queue");
+        let desc = dma.page::<[VirtqDesc; QUEUE_SIZE]>(DESC_PAGE);
 
         Self {
-            desc:  desc_ptr,
-            avail: avail_ptr,
-            used:  used_ptr,
+            desc: NonNull::slice_from_raw_parts(desc.cast(), size),
+            avail: dma.page(AVAIL_PAGE),
+            used: dma.page(USED_PAGE),
+            size,
+            dma,
         }
     }
+
+    /// Returns the device address of the descriptor table.
+    pub fn desc_pa(&self) -> u64 {
+        self.dma.page_pa(DESC_PAGE) as u64
+    }
+
+    /// Returns the device address of the available ring.
+    pub fn avail_pa(&self) -> u64 {
+        self.dma.page_pa(AVAIL_PAGE) as u64
+    }
+
+    /// Returns the device address of the used ring.
+    pub fn used_pa(&self) -> u64 {
+        self.dma.page_pa(USED_PAGE) as u64
+    }
 }
 
 #[repr(C, align(16))]
@@ -226,6 +236,9 @@ pub enum VirtIOInitError {
 
     /// Invalid or unsupported virtio version.
     InvalidVersion(u32),
+
+    /// The device's maximum queue size, too small for a request.
+    QueueTooSmall(u32),
 }
 
 #[derive(Debug)]
@@ -235,6 +248,18 @@ pub enum VirtIOError {
 
     /// Read/Write request beyond capacity.
     OutOfCapacity(u64),
+
+    /// The device didn't complete the request of the block in time.
+    Timeout(u64),
+
+    /// The device reported an error status for the request of the block.
+    IoError(u64, u8),
+
+    /// Another request is in flight.
+    Busy,
+
+    /// The device was reset after a request timed out, it takes no more.
+    Failed,
 }
 
 impl core::fmt::Display for VirtIOError {
@@ -242,19 +267,110 @@ impl core::fmt::Display for VirtIOError {
         match self {
             VirtIOError::InvalidBufferSize(len) => write!(f, "Invalid buffer size: {}", len),
             VirtIOError::OutOfCapacity(sector) => write!(f, "Out of capacity: {}", sector),
+            VirtIOError::Timeout(block_id) => write!(f, "Timed out on block: {}", block_id),
+            VirtIOError::IoError(block_id, status) => {
+                write!(f, "I/O error on block: {}, status: {}", block_id, status)
+            }
+            VirtIOError::Busy => write!(f, "Device busy"),
+            VirtIOError::Failed => write!(f, "Device failed"),
         }
     }
 }
 
-pub fn handle_virtio_interrupt() {
-    // SAFETY: interrupt handler guarantee that only one thread running this
-    // function at the same time
-    #[allow(static_mut_refs)]
-    for device in unsafe { VIRTIO_BLK_DEVICES.iter_mut() } {
-        if let Some(block_dev) = device {
-            if let Some(block_dev) = block_dev.upgrade() {
-                block_dev.handle_interrupt();
+/// The registered devices of a kind, looked up by the interrupt handler.
+///
+/// Holds weak references, a device dropped leaves its slot to be reused.
+pub struct DeviceSlots<T, const N: usize> {
+    slots: Mutex<[Option<Weak<T>>; N]>,
+}
+
+impl<T, const N: usize> DeviceSlots<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: Mutex::new([const { None }; N]),
+        }
+    }
+
+    /// Registers `device` in a free slot, returns the slot or `None` if
+    /// all of them are taken.
+    pub fn register(&self, device: &Arc<T>) -> Option<usize> {
+        let mut slots = self.slots.lock();
+        let (i, slot) = slots
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| slot.as_ref().is_none_or(|dev| dev.strong_count() == 0))?;
+        *slot = Some(Arc::downgrade(device));
+        Some(i)
+    }
+
+    /// Clears the slots of the devices dropped.
+    pub fn release_dropped(&self) {
+        for slot in self.slots.lock().iter_mut() {
+            if slot.as_ref().is_some_and(|dev| dev.strong_count() == 0) {
+                *slot = None;
             }
         }
     }
+
+    /// Calls `f` on each device alive.
+    ///
+    /// The devices are taken out before `f` runs, so it never runs with
+    /// the slots locked, even if it drops the last reference of a device.
+    pub fn for_each(&self, mut f: impl FnMut(&T)) {
+        let devices: [Option<Arc<T>>; N] = {
+            let slots = self.slots.lock();
+            from_fn(|i| slots[i].as_ref().and_then(Weak::upgrade))
+        };
+        devices.iter().flatten().for_each(|dev| f(dev));
+    }
+}
+
+pub fn handle_virtio_interrupt() {
+    VIRTIO_BLK_DEVICES.for_each(|block_dev| block_dev.handle_interrupt());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mem::PAGE_SIZE, va2pa};
+
+    #[test_case]
+    fn test_queue_dma() {
+        let queue = VirtQueue::new(QUEUE_SIZE);
+        let regions = [
+            (queue.desc.as_ptr() as *const u8 as usize, queue.desc_pa()),
+            (queue.avail.as_ptr() as usize, queue.avail_pa()),
+            (queue.used.as_ptr() as usize, queue.used_pa()),
+        ];
+        for (va, pa) in regions {
+            assert_eq!(va % PAGE_SIZE, 0);
+            assert_eq!(pa as usize % PAGE_SIZE, 0);
+            assert_eq!(va2pa!(va) as u64, pa);
+        }
+        assert_eq!(unsafe { queue.used.as_ref() }.idx.read_volatile(), 0);
+    }
+
+    #[test_case]
+    fn test_device_slots() {
+        let slots: DeviceSlots<usize, 2> = DeviceSlots::new();
+        let first = Arc::new(1);
+        let second = Arc::new(2);
+        assert_eq!(slots.register(&first), Some(0));
+        assert_eq!(slots.register(&second), Some(1));
+        assert_eq!(slots.register(&Arc::new(3)), None);
+
+        let mut seen = [0; 2];
+        slots.for_each(|&dev| seen[dev - 1] += 1);
+        assert_eq!(seen, [1, 1]);
+
+        // Registered while the slots are walked, it doesn't wait for them.
+        drop(first);
+        slots.release_dropped();
+        let third = Arc::new(3);
+        slots.for_each(|_| assert_eq!(slots.register(&third), Some(0)));
+
+        let mut seen = [0; 3];
+        slots.for_each(|&dev| seen[dev - 1] += 1);
+        assert_eq!(seen, [0, 1, 1]);
+    }
 }
@@ -1,29 +1,57 @@
 use alloc::{
     boxed::Box,
     string::{String, ToString},
-    sync::{Arc, Weak},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    array::from_fn,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
 };
-use core::array::from_fn;
 
 use fs::block_dev::{BlockDevice, BLOCK_SIZE};
-use log::{debug, info, trace};
-use spin::Mutex;
+use log::{debug, info, trace, warn};
 
-use super::{VirtIOError, VirtIOInitError, VirtIORegs, VirtQueue, VirtqDesc, VirtqDescFlags};
+use super::{
+    DeviceSlots, VirtIOError, VirtIOInitError, VirtIORegs, VirtQueue, VirtqDesc, VirtqDescFlags,
+};
 use crate::{
     drivers::{
         virtio::{VirtIODeviceType, VirtIOFeatures, VirtIOStatus, CONFIG_SPACE_OFFSET, QUEUE_SIZE},
         Volatile,
     },
+    intr::plic::{IrqControl, Plic, IRQ},
+    sync::intr_lock::IntrMutex,
     va2pa,
 };
 
 const MAX_BLK_DEVICES: usize = 16;
 
+/// Descriptors of a request besides its blocks, the header and the status.
+const REQ_EXTRA_DESCS: usize = 2;
+
+/// Polls of the used ring before a request is given up.
+const MAX_POLLS: usize = 1 << 20;
+
+const SECTORS_PER_BLOCK: u64 = BLOCK_SIZE as u64 / 512;
+
 #[derive(Clone, Copy, Debug)]
 enum VirtIOBlockReqType {
-    Read  = 0,
-    Write = 1,
+    Read    = 0,
+    Write   = 1,
+    Discard = 11,
+}
+
+impl VirtIOBlockReqType {
+    /// The bytes of each buffer of the request.
+    fn buf_len(self) -> usize {
+        match self {
+            Self::Read | Self::Write => BLOCK_SIZE,
+            Self::Discard => size_of::<VirtIOBlockDiscard>(),
+        }
+    }
 }
 
 /// Virtio block device configuration.
@@ -72,12 +100,65 @@ struct VirtIOBlockReq {
     sector:   u64,
 }
 
+/// The buffer of a discard request, the range of sectors to discard.
+#[repr(C)]
+struct VirtIOBlockDiscard {
+    sector:      u64,
+    num_sectors: u32,
+    flags:       u32,
+}
+
 struct InnerVirtIOBlock {
     regs:        *mut VirtIORegs,
     queue:       Box<VirtQueue>,
     used_idx:    u16,
     sectors_num: u64,
     status:      [Volatile<VirtIORequestStatus>; QUEUE_SIZE],
+    /// Woken when the request in flight is done.
+    waker:       Option<Waker>,
+    /// A request holds the descriptors, its chain always starts at the
+    /// first one.
+    in_flight:   bool,
+    /// Reset after a request timed out.
+    failed:      bool,
+}
+
+impl InnerVirtIOBlock {
+    /// Marks the requests in the used ring done, returns the waker of
+    /// the request in flight if it was among them.
+    fn reap(&mut self) -> Option<Waker> {
+        let mut done = false;
+        let used = unsafe { self.queue.used.as_ref() };
+        while self.used_idx != used.idx.read_volatile() {
+            let id = used.ring[self.used_idx as usize % self.queue.size]
+                .id
+                .read_volatile();
+            trace!("virtio: finished operation id: {}", id);
+
+            self.status[id as usize] = Volatile::from(VirtIORequestStatus::Done);
+            self.used_idx = self.used_idx.wrapping_add(1);
+            done = true;
+        }
+        if done {
+            self.waker.take()
+        } else {
+            None
+        }
+    }
+
+    /// Resets the device, so it no longer touches the queue or the
+    /// buffers of the request in flight, and fails the later requests.
+    fn reset(&mut self) {
+        let regs = unsafe { &mut *self.regs };
+        regs.status.write_volatile(0);
+        // The reset is done once the status reads back as 0.
+        for _ in 0..MAX_POLLS {
+            if regs.status.read_volatile() == 0 {
+                break;
+            }
+        }
+        self.failed = true;
+    }
 }
 
 #[repr(u32)]
@@ -88,12 +169,25 @@ enum VirtIORequestStatus {
 }
 
 pub struct VirtIOBlock {
-    inner:    Mutex<InnerVirtIOBlock>,
-    capacity: u64, // bytes
+    /// Taken by `handle_interrupt` in the interrupt context.
+    inner:       IntrMutex<InnerVirtIOBlock>,
+    capacity:    u64, // bytes
+    /// Maximum number of blocks in one request, the rest of the queue
+    /// holds the header and the status.
+    segments:    usize,
+    /// Maximum number of blocks in one discard request, `None` if the
+    /// device doesn't support discard.
+    discard_max: Option<u64>,
 }
 
 impl VirtIOBlock {
     pub fn init(header: usize) -> Result<Arc<Self>, VirtIOInitError> {
+        Self::init_with(header, &Plic)
+    }
+
+    /// Sets up the device at `header`, its interrupt is masked by `irq`
+    /// until the device is ready to service it.
+    fn init_with(header: usize, irq: &dyn IrqControl) -> Result<Arc<Self>, VirtIOInitError> {
         let regs = unsafe { &mut *(header as *mut VirtIORegs) };
 
         if regs.magic.read_volatile() != 0x74726976 {
@@ -108,6 +202,7 @@ impl VirtIOBlock {
             unsafe { &*((header + CONFIG_SPACE_OFFSET) as *const VirtIOBlockConfig) };
         info!("Device capacity: {} sectors", block_config.capacity);
 
+        irq.disable(IRQ::VIRTIO);
         regs.status.write_volatile(VirtIOStatus::empty().bits());
         regs.status.write_volatile(VirtIOStatus::ACKNOWLEDGE.bits());
         regs.status.write_volatile(VirtIOStatus::DRIVER.bits());
@@ -126,160 +221,250 @@ impl VirtIOBlock {
         regs.driver_features.write_volatile(features.bits());
         regs.status.write_volatile(VirtIOStatus::FEATURES_OK.bits());
 
-        let queue = Box::new(VirtQueue::new());
         regs.queue_sel.write_volatile(0);
         assert_eq!(regs.queue_ready.read_volatile(), 0, "virtio disk should not be ready");
 
-        regs.queue_num.write_volatile(va2pa!(QUEUE_SIZE as u32));
-        regs.queue_desc_low
-            .write_volatile(va2pa!(queue.desc.as_ptr() as u32));
+        let queue_num_max = regs.queue_num_max.read_volatile();
+        // Rounded down to a power of two, so `idx % size` keeps in step
+        // with the ring indices wrapping at 2^16.
+        let size = QUEUE_SIZE
+            .min(queue_num_max as usize)
+            .checked_ilog2()
+            .map_or(0, |log| 1 << log);
+        if size <= REQ_EXTRA_DESCS {
+            return Err(VirtIOInitError::QueueTooSmall(queue_num_max));
+        }
+        let queue = Box::new(VirtQueue::new(size));
+        debug!("virtio: queue size: {}, device maximum: {}", queue.size, queue_num_max);
+
+        regs.queue_num.write_volatile(queue.size as u32);
+        regs.queue_desc_low.write_volatile(queue.desc_pa() as u32);
         regs.queue_desc_high
-            .write_volatile(va2pa!(((queue.desc.as_ptr() as u64) >> 32) as u32));
+            .write_volatile((queue.desc_pa() >> 32) as u32);
         regs.queue_driver_low
-            .write_volatile(va2pa!(queue.avail.as_ptr() as u32));
+            .write_volatile(queue.avail_pa() as u32);
         regs.queue_driver_high
-            .write_volatile(va2pa!(((queue.avail.as_ptr() as u64) >> 32) as u32));
-        regs.queue_device_low
-            .write_volatile(va2pa!(queue.used.as_ptr() as u32));
+            .write_volatile((queue.avail_pa() >> 32) as u32);
+        regs.queue_device_low.write_volatile(queue.used_pa() as u32);
         regs.queue_device_high
-            .write_volatile(va2pa!(((queue.used.as_ptr() as u64) >> 32) as u32));
+            .write_volatile((queue.used_pa() >> 32) as u32);
 
         regs.queue_ready.write_volatile(1);
         regs.status.write_volatile(VirtIOStatus::DRIVER_OK.bits());
 
+        let segments = queue.size - REQ_EXTRA_DESCS;
+        let discard_max = Some(block_config.max_discard_sectors as u64 / SECTORS_PER_BLOCK)
+            .filter(|&blocks| features.contains(VirtIOFeatures::BLK_F_DISCARD) && blocks > 0);
+
         let block = Arc::new(VirtIOBlock {
-            inner:    Mutex::new(InnerVirtIOBlock {
+            inner: IntrMutex::new(InnerVirtIOBlock {
                 regs,
                 queue,
                 used_idx: 0,
                 sectors_num: block_config.capacity,
                 status: from_fn(|_| Volatile::from(VirtIORequestStatus::Pending)),
+                waker: None,
+                in_flight: false,
+                failed: false,
             }),
             capacity: block_config.capacity * 512,
+            segments,
+            discard_max,
         });
 
-        // SAFETY: We only register device at this os startup.
-        unsafe { VIRTIO_BLK_DEVICES[0] = Some(Arc::downgrade(&block)) };
+        if VIRTIO_BLK_DEVICES.register(&block).is_none() {
+            warn!("virtio: too many block devices, interrupts of this one are ignored");
+        }
+        irq.enable(IRQ::VIRTIO);
         Ok(block)
     }
 
     pub fn read_block(&self, block_id: u64, buf: &mut [u8]) -> Result<(), VirtIOError> {
-        if buf.len() != BLOCK_SIZE {
-            return Err(VirtIOError::InvalidBufferSize(buf.len()));
+        self.read_blocks(block_id, &mut [buf])
+    }
+
+    /// Submits a read of the block into a buffer of its own, the returned
+    /// future is ready with it once `handle_interrupt` marks the request
+    /// done.
+    ///
+    /// One request is in flight at a time, submitting fails with `Busy`
+    /// while the future of another one is alive, or was forgotten.
+    pub fn read_block_async(&self, block_id: u64) -> BlockFuture<'_> {
+        let buf = Box::new([0u8; BLOCK_SIZE]);
+        let request = self.submit(block_id, &[buf.as_ptr()], VirtIOBlockReqType::Read);
+        BlockFuture {
+            request,
+            buf: Some(buf),
         }
-        self.send(block_id, buf.as_ptr(), VirtIOBlockReqType::Read)
     }
 
     pub fn write_block(&self, block_id: u64, buf: &[u8]) -> Result<(), VirtIOError> {
-        if buf.len() != BLOCK_SIZE {
+        self.write_blocks(block_id, &[buf])
+    }
+
+    /// Reads the contiguous blocks from `block_id` into `bufs`, with one
+    /// request per `segments` blocks.
+    pub fn read_blocks(&self, block_id: u64, bufs: &mut [&mut [u8]]) -> Result<(), VirtIOError> {
+        if let Some(buf) = bufs.iter().find(|buf| buf.len() != BLOCK_SIZE) {
+            return Err(VirtIOError::InvalidBufferSize(buf.len()));
+        }
+        let ptrs: Vec<_> = bufs.iter().map(|buf| buf.as_ptr()).collect();
+        self.send_all(block_id, &ptrs, VirtIOBlockReqType::Read)
+    }
+
+    /// Writes `bufs` to the contiguous blocks from `block_id`, with one
+    /// request per `segments` blocks.
+    pub fn write_blocks(&self, block_id: u64, bufs: &[&[u8]]) -> Result<(), VirtIOError> {
+        if let Some(buf) = bufs.iter().find(|buf| buf.len() != BLOCK_SIZE) {
             return Err(VirtIOError::InvalidBufferSize(buf.len()));
         }
-        self.send(block_id, buf.as_ptr(), VirtIOBlockReqType::Write)
+        let ptrs: Vec<_> = bufs.iter().map(|buf| buf.as_ptr()).collect();
+        self.send_all(block_id, &ptrs, VirtIOBlockReqType::Write)
     }
 
+    /// Discards the `count` blocks from `block_id`, with one request per
+    /// `discard_max` blocks. Does nothing if the device doesn't support
+    /// discard.
+    pub fn discard_blocks(&self, block_id: u64, count: usize) -> Result<(), VirtIOError> {
+        let Some(discard_max) = self.discard_max else {
+            return Ok(());
+        };
+        let end = block_id + count as u64;
+        let sector_end = end * SECTORS_PER_BLOCK;
+        if sector_end >= self.inner.lock().sectors_num {
+            return Err(VirtIOError::OutOfCapacity(sector_end));
+        }
+
+        for start in (block_id..end).step_by(discard_max as usize) {
+            let range = VirtIOBlockDiscard {
+                sector:      start * SECTORS_PER_BLOCK,
+                num_sectors: ((end - start).min(discard_max) * SECTORS_PER_BLOCK) as u32,
+                flags:       0,
+            };
+            let ptr = &range as *const _ as *const u8;
+            self.send(start, &[ptr], VirtIOBlockReqType::Discard)?;
+        }
+        Ok(())
+    }
+
+    fn send_all(
+        &self,
+        block_id: u64,
+        bufs: &[*const u8],
+        op: VirtIOBlockReqType,
+    ) -> Result<(), VirtIOError> {
+        for (i, chunk) in bufs.chunks(self.segments).enumerate() {
+            self.send(block_id + (i * self.segments) as u64, chunk, op)?;
+        }
+        Ok(())
+    }
+
+    /// Sends one request transferring a block to or from each buffer,
+    /// and waits for the device to complete it.
     fn send(
         &self,
         block_id: u64,
-        buf_ptr: *const u8,
+        bufs: &[*const u8],
         op: VirtIOBlockReqType,
     ) -> Result<(), VirtIOError> {
+        self.submit(block_id, bufs, op).block_on()
+    }
+
+    /// Hands a request to the device as a chain of the header, the
+    /// buffers and the status, the buffers must outlive the request.
+    fn submit(&self, block_id: u64, bufs: &[*const u8], op: VirtIOBlockReqType) -> Request<'_> {
         assert_eq!(BLOCK_SIZE % 512, 0);
+        assert!(!bufs.is_empty() && bufs.len() <= self.segments);
 
         let mut inner = self.inner.lock();
-        {
-            let sector = block_id * (BLOCK_SIZE as u64 / 512);
-            let sector_end = sector + (BLOCK_SIZE as u64 / 512);
-            if sector_end >= inner.sectors_num {
-                return Err(VirtIOError::OutOfCapacity(sector_end));
-            };
+        if inner.failed {
+            return Request::failed(self, block_id, VirtIOError::Failed);
+        }
+        if inner.in_flight {
+            return Request::failed(self, block_id, VirtIOError::Busy);
+        }
 
-            trace!("virtio: reading/writing block: {}, sector: {}, op: {:?}", block_id, sector, op);
+        let sector = block_id * SECTORS_PER_BLOCK;
+        let sector_end = sector + bufs.len() as u64 * SECTORS_PER_BLOCK;
+        if sector_end >= inner.sectors_num {
+            return Request::failed(self, block_id, VirtIOError::OutOfCapacity(sector_end));
+        };
+
+        trace!(
+            "virtio: reading/writing blocks: {}-{}, sector: {}, op: {:?}",
+            block_id,
+            block_id + bufs.len() as u64,
+            sector,
+            op
+        );
 
-            // build request header
-            let header = Box::new(VirtIOBlockReq {
-                type_:    op as u32,
-                reserved: 0,
-                sector:   sector as u64,
-            });
+        // build request header
+        let header = Box::new(VirtIOBlockReq {
+            type_:    op as u32,
+            reserved: 0,
+            sector:   sector as u64,
+        });
 
-            let status: Box<u8> = Box::new(0xff); // device writes 0 on success
-            let status_ptr = &*status as *const u8;
+        let status: Box<u8> = Box::new(0xff); // device writes 0 on success
 
-            let desc = unsafe { inner.queue.desc.as_mut() };
-            desc[0] = VirtqDesc {
-                addr:  va2pa!(&*header as *const _ as u64),
-                len:   core::mem::size_of::<VirtIOBlockReq>() as u32,
-                flags: VirtqDescFlags::NEXT.bits(),
-                next:  1,
-            };
+        let desc = unsafe { inner.queue.desc.as_mut() };
+        desc[0] = VirtqDesc {
+            addr:  va2pa!(&*header as *const _ as u64),
+            len:   core::mem::size_of::<VirtIOBlockReq>() as u32,
+            flags: VirtqDescFlags::NEXT.bits(),
+            next:  1,
+        };
 
-            desc[1] = VirtqDesc {
+        for (i, &buf_ptr) in bufs.iter().enumerate() {
+            desc[i + 1] = VirtqDesc {
                 addr:  va2pa!(buf_ptr as u64),
-                len:   BLOCK_SIZE as u32,
+                len:   op.buf_len() as u32,
                 flags: match op {
                     VirtIOBlockReqType::Read => {
                         (VirtqDescFlags::NEXT | VirtqDescFlags::WRITE).bits()
                     }
-                    VirtIOBlockReqType::Write => VirtqDescFlags::NEXT.bits(),
+                    VirtIOBlockReqType::Write | VirtIOBlockReqType::Discard => {
+                        VirtqDescFlags::NEXT.bits()
+                    }
                 },
-                next:  2,
+                next:  (i + 2) as u16,
             };
+        }
 
-            desc[2] = VirtqDesc {
-                addr:  va2pa!(status_ptr as u64),
-                len:   1,
-                flags: VirtqDescFlags::WRITE.bits(),
-                next:  0,
-            };
+        desc[bufs.len() + 1] = VirtqDesc {
+            addr:  va2pa!(&*status as *const u8 as u64),
+            len:   1,
+            flags: VirtqDescFlags::WRITE.bits(),
+            next:  0,
+        };
 
-            // notify device
-            let avail = unsafe { inner.queue.avail.as_mut() };
+        inner.status[0] = Volatile::from(VirtIORequestStatus::Pending);
+        inner.waker = None;
+        inner.in_flight = true;
 
-            let avail_idx = avail.idx.read_volatile();
-            avail.ring[avail_idx as usize % QUEUE_SIZE] = Volatile::from(0);
-            avail.idx.write_volatile(avail_idx + 1);
+        // notify device
+        let avail = unsafe { inner.queue.avail.as_mut() };
 
-            unsafe {
-                (*inner.regs).queue_notify.write_volatile(0);
-            }
+        let avail_idx = avail.idx.read_volatile();
+        avail.ring[avail_idx as usize % inner.queue.size] = Volatile::from(0);
+        avail.idx.write_volatile(avail_idx + 1);
 
-            // TODO: move to interrupt handler
-            // wait device
-            loop {
-                let used = unsafe { inner.queue.used.read_volatile() };
-                if used.idx.read_volatile() != inner.used_idx {
-                    let id = used.ring[inner.used_idx as usize % QUEUE_SIZE]
-                        .id
-                        .read_volatile();
-                    trace!("virtio: finished operation id: {}", id);
-                    break;
-                }
-            }
-            inner.used_idx = inner.used_idx.wrapping_add(1);
-            assert_eq!(unsafe { status_ptr.read_volatile() }, 0);
+        unsafe {
+            (*inner.regs).queue_notify.write_volatile(0);
+        }
 
-            // TODO: change loop to sleep
-            // inner.status[0] = Volatile::from(VirtIORequestStatus::Pending);
-            // while inner.status[0].read_volatile() == VirtIORequestStatus::Pending {}
+        Request {
+            dev: self,
+            block_id,
+            state: RequestState::Submitted { header, status },
         }
-        Ok(())
     }
 
     pub fn handle_interrupt(&self) {
         debug!("virtio: handling interrupt");
-        let mut inner = self.inner.lock();
-        {
-            let used = unsafe { inner.queue.used.read_volatile() };
-            while inner.used_idx != used.idx.read_volatile() {
-                let queue_used = unsafe { inner.queue.used.read() };
-                let id = queue_used.ring[inner.used_idx as usize % QUEUE_SIZE]
-                    .id
-                    .read_volatile();
-                trace!("virtio: finished operation id: {}", id);
-
-                inner.status[id as usize] = Volatile::from(VirtIORequestStatus::Done);
-                inner.used_idx = inner.used_idx.wrapping_add(1);
-            }
+        let waker = self.inner.lock().reap();
+        if let Some(waker) = waker {
+            waker.wake();
         }
     }
 
@@ -291,15 +476,141 @@ impl VirtIOBlock {
 impl Drop for VirtIOBlock {
     fn drop(&mut self) {
         debug!("virtio: dropping block device");
-        unsafe { VIRTIO_BLK_DEVICES[0] = None };
+        // Stops the device before its queue is freed.
+        unsafe { (*self.inner.get_mut().regs).status.write_volatile(0) };
+        VIRTIO_BLK_DEVICES.release_dropped();
+    }
+}
+
+enum RequestState {
+    /// Handed to the device, which writes the header and the status
+    /// until the request is done.
+    Submitted {
+        header: Box<VirtIOBlockReq>,
+        status: Box<u8>,
+    },
+    Failed(VirtIOError),
+    Finished,
+}
+
+/// A request in flight, done when the device completes it.
+///
+/// Dropping it before then waits for the device, which may still be
+/// using the buffers.
+struct Request<'a> {
+    dev:      &'a VirtIOBlock,
+    block_id: u64,
+    state:    RequestState,
+}
+
+impl<'a> Request<'a> {
+    fn failed(dev: &'a VirtIOBlock, block_id: u64, err: VirtIOError) -> Self {
+        Self {
+            dev,
+            block_id,
+            state: RequestState::Failed(err),
+        }
+    }
+
+    /// Busy-waits on the used ring for the request, for the callers
+    /// without an executor.
+    fn block_on(mut self) -> Result<(), VirtIOError> {
+        self.wait()
+    }
+
+    fn wait(&mut self) -> Result<(), VirtIOError> {
+        for _ in 0..MAX_POLLS {
+            // Interrupts may be off, reap the used ring here instead.
+            let waker = self.dev.inner.lock().reap();
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+            if let Poll::Ready(result) = self.check() {
+                return result;
+            }
+        }
+
+        warn!("virtio: request of block {} timed out, resetting the device", self.block_id);
+        // The device may still complete it later, in the buffers given
+        // back to the caller or credited to the next request.
+        self.dev.inner.lock().reset();
+        self.state = RequestState::Finished;
+        Err(VirtIOError::Timeout(self.block_id))
+    }
+
+    /// Checks the status slot of the request.
+    fn check(&mut self) -> Poll<Result<(), VirtIOError>> {
+        if let RequestState::Submitted { .. } = self.state {
+            let mut inner = self.dev.inner.lock();
+            if inner.status[0].read_volatile() == VirtIORequestStatus::Pending {
+                return Poll::Pending;
+            }
+            inner.in_flight = false;
+        }
+        match core::mem::replace(&mut self.state, RequestState::Finished) {
+            RequestState::Submitted { status, .. } => {
+                match unsafe { core::ptr::read_volatile(&*status) } {
+                    0 => Poll::Ready(Ok(())),
+                    status => Poll::Ready(Err(VirtIOError::IoError(self.block_id, status))),
+                }
+            }
+            RequestState::Failed(err) => Poll::Ready(Err(err)),
+            RequestState::Finished => panic!("virtio: request polled after completion"),
+        }
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), VirtIOError>> {
+        if let RequestState::Submitted { .. } = self.state {
+            // Registered before checking, not to miss an interrupt in between.
+            self.dev.inner.lock().waker = Some(cx.waker().clone());
+        }
+        self.check()
+    }
+}
+
+impl Drop for Request<'_> {
+    fn drop(&mut self) {
+        if let RequestState::Submitted { .. } = self.state {
+            let _ = self.wait();
+        }
+    }
+}
+
+/// A read of a block in flight, ready with the block when the device
+/// completes it.
+///
+/// The future owns the buffer the device writes to, so forgetting it
+/// only leaks the buffer, and the device takes no more requests.
+pub struct BlockFuture<'a> {
+    // Dropped first, waiting for the device to be done with `buf`.
+    request: Request<'a>,
+    buf:     Option<Box<[u8; BLOCK_SIZE]>>,
+}
+
+impl BlockFuture<'_> {
+    /// Busy-waits on the used ring for the read, for the callers without
+    /// an executor.
+    pub fn block_on(mut self) -> Result<Box<[u8; BLOCK_SIZE]>, VirtIOError> {
+        self.request.wait()?;
+        Ok(self.buf.take().unwrap())
+    }
+}
+
+impl Future for BlockFuture<'_> {
+    type Output = Result<Box<[u8; BLOCK_SIZE]>, VirtIOError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        this.request
+            .poll(cx)
+            .map(|result| result.map(|()| this.buf.take().unwrap()))
     }
 }
 
 unsafe impl Sync for VirtIOBlock {}
 unsafe impl Send for VirtIOBlock {}
 
-pub static mut VIRTIO_BLK_DEVICES: [Option<Weak<VirtIOBlock>>; MAX_BLK_DEVICES] =
-    [const { None }; MAX_BLK_DEVICES];
+pub static VIRTIO_BLK_DEVICES: DeviceSlots<VirtIOBlock, MAX_BLK_DEVICES> = DeviceSlots::new();
 
 impl BlockDevice for VirtIOBlock {
     fn read(&self, block_id: u64, buf: &mut [u8]) -> Result<(), String> {
@@ -311,4 +622,267 @@ impl BlockDevice for VirtIOBlock {
         self.write_block(block_id, buf)
             .map_err(|err| err.to_string())
     }
+
+    fn read_many(&self, start: u64, bufs: &mut [&mut [u8]]) -> Result<(), String> {
+        self.read_blocks(start, bufs).map_err(|err| err.to_string())
+    }
+
+    fn write_many(&self, start: u64, bufs: &[&[u8]]) -> Result<(), String> {
+        self.write_blocks(start, bufs)
+            .map_err(|err| err.to_string())
+    }
+
+    fn discard(&self, block_id: u64, count: usize) -> Result<(), String> {
+        self.discard_blocks(block_id, count)
+            .map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::task::Wake;
+    use core::{
+        mem::offset_of,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use spin::Mutex;
+
+    use super::*;
+    use crate::pa2va;
+
+    /// The registers and the config space of a device never completing
+    /// any request.
+    #[repr(C, align(8))]
+    struct SilentDevice([u8; CONFIG_SPACE_OFFSET + size_of::<VirtIOBlockConfig>()]);
+
+    impl SilentDevice {
+        fn new() -> Box<Self> {
+            Self::with_queue_num_max(QUEUE_SIZE as u32)
+        }
+
+        fn with_queue_num_max(queue_num_max: u32) -> Box<Self> {
+            let mut mmio =
+                Box::new(SilentDevice([0; CONFIG_SPACE_OFFSET + size_of::<VirtIOBlockConfig>()]));
+            mmio.0[0..4].copy_from_slice(&0x74726976u32.to_le_bytes());
+            mmio.0[4..8].copy_from_slice(&(VirtIODeviceType::BlockDevice as u32).to_le_bytes());
+            mmio.0[0x34..0x38].copy_from_slice(&queue_num_max.to_le_bytes());
+            let capacity = CONFIG_SPACE_OFFSET..CONFIG_SPACE_OFFSET + 8;
+            mmio.0[capacity].copy_from_slice(&1024u64.to_le_bytes());
+            mmio
+        }
+
+        /// Offers discard, `max_sectors` at most in one request.
+        fn offer_discard(&mut self, max_sectors: u32) {
+            let features = VirtIOFeatures::BLK_F_DISCARD.bits();
+            self.0[0x10..0x14].copy_from_slice(&features.to_le_bytes());
+            let offset = CONFIG_SPACE_OFFSET + offset_of!(VirtIOBlockConfig, max_discard_sectors);
+            self.0[offset..offset + 4].copy_from_slice(&max_sectors.to_le_bytes());
+        }
+
+        fn header(&mut self) -> usize {
+            self.0.as_mut_ptr() as usize
+        }
+    }
+
+    /// Records the device status each time the IRQ is masked or unmasked.
+    struct MockPlic {
+        header: usize,
+        events: Mutex<Vec<(IRQ, bool, u32)>>,
+    }
+
+    impl MockPlic {
+        fn record(&self, irq: IRQ, enabled: bool) {
+            let regs = unsafe { &*(self.header as *const VirtIORegs) };
+            self.events
+                .lock()
+                .push((irq, enabled, regs.status.read_volatile()));
+        }
+    }
+
+    impl IrqControl for MockPlic {
+        fn enable(&self, irq: IRQ) {
+            self.record(irq, true);
+        }
+
+        fn disable(&self, irq: IRQ) {
+            self.record(irq, false);
+        }
+    }
+
+    #[test_case]
+    fn test_send_timeout() {
+        let mut mmio = SilentDevice::new();
+        let header = mmio.header();
+        let plic = MockPlic {
+            header,
+            events: Mutex::new(Vec::new()),
+        };
+
+        let dev = VirtIOBlock::init_with(header, &plic).unwrap();
+        let mut buf = [0u8; BLOCK_SIZE];
+        assert!(matches!(dev.read_block(2, &mut buf), Err(VirtIOError::Timeout(2))));
+        // The device is reset, its ring is not reused.
+        assert_eq!(mmio.0[0x70..0x74], [0; 4]);
+        assert!(matches!(dev.read_block(3, &mut buf), Err(VirtIOError::Failed)));
+    }
+
+    /// Counts the wakes of a future.
+    struct WakeCounter(AtomicUsize);
+
+    impl Wake for WakeCounter {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Completes the request in flight as the device would, filling its
+    /// only block with `byte` and reporting `status`.
+    fn complete_read(dev: &VirtIOBlock, byte: u8, status: u8) {
+        let inner = dev.inner.lock();
+        let desc = unsafe { inner.queue.desc.as_ref() };
+        unsafe {
+            core::ptr::write_bytes(pa2va!(desc[1].addr) as *mut u8, byte, BLOCK_SIZE);
+            core::ptr::write_volatile(pa2va!(desc[2].addr) as *mut u8, status);
+        }
+
+        let used = unsafe { &mut *inner.queue.used.as_ptr() };
+        let idx = used.idx.read_volatile();
+        used.ring[idx as usize % inner.queue.size]
+            .id
+            .write_volatile(0);
+        used.idx.write_volatile(idx.wrapping_add(1));
+    }
+
+    #[test_case]
+    fn test_read_block_async() {
+        let mut mmio = SilentDevice::new();
+        let header = mmio.header();
+        let plic = MockPlic {
+            header,
+            events: Mutex::new(Vec::new()),
+        };
+
+        let dev = VirtIOBlock::init_with(header, &plic).unwrap();
+        let counter = Arc::new(WakeCounter(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut fut = dev.read_block_async(2);
+        assert!(Pin::new(&mut fut).poll(&mut cx).is_pending());
+        dev.handle_interrupt();
+        assert!(Pin::new(&mut fut).poll(&mut cx).is_pending());
+        // Another request fails rather than waiting for this one.
+        assert!(matches!(dev.read_block_async(3).block_on(), Err(VirtIOError::Busy)));
+
+        // Done only once the interrupt is handled.
+        complete_read(&dev, 0xab, 0);
+        assert!(Pin::new(&mut fut).poll(&mut cx).is_pending());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+
+        dev.handle_interrupt();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        let Poll::Ready(Ok(buf)) = Pin::new(&mut fut).poll(&mut cx) else {
+            panic!("the read is not done");
+        };
+        drop(fut);
+        assert!(buf.iter().all(|&byte| byte == 0xab));
+
+        // An error status fails the request.
+        let fut = dev.read_block_async(2);
+        complete_read(&dev, 0, 1);
+        assert!(matches!(fut.block_on(), Err(VirtIOError::IoError(2, 1))));
+    }
+
+    #[test_case]
+    fn test_discard_blocks() {
+        let mut mmio = SilentDevice::new();
+        let header = mmio.header();
+        let plic = MockPlic {
+            header,
+            events: Mutex::new(Vec::new()),
+        };
+
+        // Nothing is sent to a device without discard.
+        let dev = VirtIOBlock::init_with(header, &plic).unwrap();
+        assert!(dev.discard_blocks(2, 4).is_ok());
+        assert_eq!(
+            unsafe { dev.inner.lock().queue.avail.as_ref() }
+                .idx
+                .read_volatile(),
+            0
+        );
+        drop(dev);
+
+        mmio.offer_discard(2 * SECTORS_PER_BLOCK as u32);
+        let dev = VirtIOBlock::init_with(header, &plic).unwrap();
+        assert_eq!(dev.discard_max, Some(2));
+        assert!(matches!(dev.discard_blocks(1022, 4), Err(VirtIOError::OutOfCapacity(_))));
+
+        let range = VirtIOBlockDiscard {
+            sector:      2 * SECTORS_PER_BLOCK,
+            num_sectors: 2 * SECTORS_PER_BLOCK as u32,
+            flags:       0,
+        };
+        let ptr = &range as *const _ as *const u8;
+        let request = dev.submit(2, &[ptr], VirtIOBlockReqType::Discard);
+        let inner = dev.inner.lock();
+        let desc = unsafe { inner.queue.desc.as_ref() };
+        let req = unsafe { &*(pa2va!(desc[0].addr) as *const VirtIOBlockReq) };
+        assert_eq!(req.type_, VirtIOBlockReqType::Discard as u32);
+        assert_eq!(desc[1].len as usize, size_of::<VirtIOBlockDiscard>());
+        assert_eq!(desc[1].flags, VirtqDescFlags::NEXT.bits());
+        drop(inner);
+        assert!(matches!(request.block_on(), Err(VirtIOError::Timeout(2))));
+    }
+
+    #[test_case]
+    fn test_irq_enabled_when_ready() {
+        let mut mmio = SilentDevice::new();
+        let header = mmio.header();
+        let plic = MockPlic {
+            header,
+            events: Mutex::new(Vec::new()),
+        };
+
+        VirtIOBlock::init_with(header, &plic).unwrap();
+        let driver_ok = VirtIOStatus::DRIVER_OK.bits();
+        assert_eq!(*plic.events.lock(), [(IRQ::VIRTIO, false, 0), (IRQ::VIRTIO, true, driver_ok)]);
+    }
+
+    #[test_case]
+    fn test_queue_size_clamped() {
+        let mut mmio = SilentDevice::with_queue_num_max(4);
+        let header = mmio.header();
+        let plic = MockPlic {
+            header,
+            events: Mutex::new(Vec::new()),
+        };
+
+        let dev = VirtIOBlock::init_with(header, &plic).unwrap();
+        assert_eq!(dev.inner.lock().queue.size, 4);
+        assert_eq!(dev.segments, 2);
+        assert_eq!(mmio.0[0x38..0x3c], 4u32.to_le_bytes());
+
+        // Split to fit in the queue, the first request times out.
+        let mut bufs = alloc::vec![[0u8; BLOCK_SIZE]; 3];
+        let mut bufs: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| &mut buf[..]).collect();
+        assert!(matches!(dev.read_blocks(2, &mut bufs), Err(VirtIOError::Timeout(2))));
+
+        // Rounded down to a power of two.
+        let mut mmio = SilentDevice::with_queue_num_max(7);
+        let header = mmio.header();
+        let dev = VirtIOBlock::init_with(header, &plic).unwrap();
+        assert_eq!(dev.inner.lock().queue.size, 4);
+        assert_eq!(mmio.0[0x38..0x3c], 4u32.to_le_bytes());
+
+        for queue_num_max in [2, 3] {
+            let mut mmio = SilentDevice::with_queue_num_max(queue_num_max);
+            let header = mmio.header();
+            assert!(matches!(
+                VirtIOBlock::init_with(header, &plic),
+                Err(VirtIOInitError::QueueTooSmall(n)) if n == queue_num_max
+            ));
+        }
+    }
 }
@@ -0,0 +1,29 @@
+use alloc::string::String;
+
+use super::FileError;
+use crate::{print, syscall::console_getchar};
+
+/// Reads the bytes typed so far into `buf`, fails with `WouldBlock` if
+/// there are none.
+pub fn read_console(buf: &mut [u8]) -> Result<usize, FileError> {
+    let mut n = 0;
+    while n < buf.len() {
+        // The SBI returns -1 if nothing was typed.
+        let c = console_getchar() as isize;
+        if c < 0 {
+            break;
+        }
+        buf[n] = c as u8;
+        n += 1;
+    }
+    if n == 0 && !buf.is_empty() {
+        return Err(FileError::WouldBlock);
+    }
+    Ok(n)
+}
+
+/// Prints `buf`, the bytes that are not UTF-8 are replaced.
+pub fn write_console(buf: &[u8]) -> Result<usize, FileError> {
+    print!("{}", String::from_utf8_lossy(buf));
+    Ok(buf.len())
+}
@@ -0,0 +1,146 @@
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+};
+
+use fs::block_dev::InodeId;
+use spin::Mutex;
+
+use super::{FileError, OpenFile, Pipe};
+use crate::sync::wait_channel::Wait;
+
+/// The pipes of the opened FIFOs, keyed by the inode number.
+///
+/// A pipe lives as long as any end of it is open, so the data is
+/// discarded once all ends are closed.
+static FIFOS: Mutex<BTreeMap<InodeId, Weak<Pipe>>> = Mutex::new(BTreeMap::new());
+
+/// Opens the FIFO `inum` for reading, writing or both, on the same pipe
+/// as other opens of it.
+///
+/// Opening one end returns the wait for the other end if none is open,
+/// the task sleeps on it before the open returns to the user. Unless
+/// `nonblock`, then opening for writing fails if there are no readers.
+/// Opening both ends never waits.
+pub fn open_fifo(
+    inum: InodeId,
+    readable: bool,
+    writable: bool,
+    nonblock: bool,
+) -> Result<(OpenFile, Option<Wait>), FileError> {
+    let pipe = {
+        let mut fifos = FIFOS.lock();
+        match fifos.get(&inum).and_then(Weak::upgrade) {
+            Some(pipe) => pipe,
+            None => {
+                fifos.retain(|_, pipe| pipe.strong_count() > 0);
+                let pipe = Pipe::empty();
+                fifos.insert(inum, Arc::downgrade(&pipe));
+                pipe
+            }
+        }
+    };
+
+    if readable && writable {
+        return Ok((pipe.open_both(), None));
+    }
+    if writable && nonblock && pipe.peers(true) == 0 {
+        return Err(FileError::WouldBlock);
+    }
+
+    // Taken before checking for the other end, not to miss it opened in
+    // between.
+    let wait = pipe.opened_channel(writable).wait();
+    let file = if writable {
+        pipe.open_writer()
+    } else {
+        pipe.open_reader()
+    };
+    let wait = (!nonblock && pipe.peers(writable) == 0).then_some(wait);
+    Ok((file, wait))
+}
+
+#[cfg(test)]
+mod tests {
+    use ::syscall::{O_NONBLOCK, O_RDONLY, O_WRONLY};
+    use fs::block_dev::InodeType;
+
+    use super::*;
+    use crate::{
+        file::open,
+        proc::{State, TaskList},
+        ROOT_FS,
+    };
+
+    #[test_case]
+    fn test_fifo_between_tasks() {
+        let fs = ROOT_FS.get().expect("file system is not initialized");
+        let root = fs.root();
+        {
+            let mut root = root.lock();
+            if fs.look_up(&root, "fifo_test").is_none() {
+                fs.create_inode(&mut root, "fifo_test", InodeType::Fifo)
+                    .unwrap();
+            }
+        }
+
+        let mut tasks = TaskList::new();
+        let reader_lock = tasks.new_task().unwrap().clone();
+        let writer_lock = tasks.new_task().unwrap().clone();
+
+        // Open the read end first without blocking, so opening the write
+        // end finds a reader.
+        let (reader, wait) = open("/fifo_test", O_RDONLY | O_NONBLOCK).unwrap();
+        assert!(wait.is_none());
+        let rfd = reader_lock.write().alloc_fd(Arc::new(reader)).unwrap();
+        let (writer, wait) = open("/fifo_test", O_WRONLY).unwrap();
+        assert!(wait.is_none());
+        let wfd = writer_lock.write().alloc_fd(Arc::new(writer)).unwrap();
+
+        let written = writer_lock.read().file(wfd).unwrap().write(b"fifo");
+        assert_eq!(written, Ok(4));
+        writer_lock.write().close_fd(wfd).unwrap();
+
+        let reader = reader_lock.read();
+        let file = reader.file(rfd).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(file.read(&mut buf), Ok(4));
+        assert_eq!(&buf[..4], b"fifo");
+        assert_eq!(file.read(&mut buf), Ok(0));
+
+        fs.unlink(&mut root.lock(), "fifo_test").unwrap();
+    }
+
+    #[test_case]
+    fn test_fifo_nonblock_write_without_reader() {
+        let result = open_fifo(u64::MAX, false, true, true);
+        assert!(matches!(result, Err(FileError::WouldBlock)));
+    }
+
+    #[test_case]
+    fn test_fifo_open_waits_for_peer() {
+        let inum = u64::MAX - 1;
+        let mut tasks = TaskList::new();
+        let reader_lock = tasks.new_task().unwrap().clone();
+
+        // The reader sleeps until a writer opens the FIFO.
+        let (reader, wait) = open_fifo(inum, true, false, false).unwrap();
+        tasks.sleep_on(&mut reader_lock.write(), wait.expect("no writer to wait for"));
+        assert!(tasks.next_runnable().is_none());
+
+        let (writer, wait) = open_fifo(inum, false, true, false).unwrap();
+        assert!(wait.is_none());
+        assert!(tasks.next_runnable().is_some());
+        assert!(reader_lock.read().state == State::Runnable);
+
+        // Both ends in one file, it neither waits nor takes the ends of
+        // the others.
+        let (both, wait) = open_fifo(inum, true, true, false).unwrap();
+        assert!(wait.is_none());
+        assert_eq!(both.write(b"rw"), Ok(2));
+        let mut buf = [0u8; 4];
+        assert_eq!(reader.read(&mut buf), Ok(2));
+        drop((writer, both));
+        assert_eq!(reader.read(&mut buf), Ok(0));
+    }
+}
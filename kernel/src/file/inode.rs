@@ -0,0 +1,106 @@
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use fs::{
+    block_dev::InodeType,
+    inode::{Inode, InodeHandle},
+    DirItem, DirStream, FileSystemAllocationError,
+};
+use spin::Mutex;
+
+use super::FileError;
+
+/// A file in the file system, opened with an offset.
+pub struct InodeFile {
+    inode:      InodeHandle,
+    offset:     Mutex<usize>,
+    /// The name of the last entry `read_dir` returned.
+    dir_cursor: Mutex<Option<String>>,
+    readable:   bool,
+    writable:   bool,
+}
+
+impl InodeFile {
+    pub fn new(inode: Arc<Mutex<Inode>>, readable: bool, writable: bool) -> Self {
+        Self {
+            inode: InodeHandle::open(inode),
+            offset: Mutex::new(0),
+            dir_cursor: Mutex::new(None),
+            readable,
+            writable,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        *self.offset.lock()
+    }
+
+    pub fn inode(&self) -> &Arc<Mutex<Inode>> {
+        self.inode.inode()
+    }
+
+    pub fn readable(&self) -> bool {
+        self.readable
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Reads from the current offset into `buf`, and advances the offset.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FileError> {
+        if !self.readable {
+            return Err(FileError::NotReadable);
+        }
+
+        let mut offset = self.offset.lock();
+        let inode = self.inode().lock();
+        if *offset >= inode.size() {
+            return Ok(0);
+        }
+
+        let fs = inode.get_fs().expect("file system has been dropped");
+        let n = fs
+            .read_inode(&inode, *offset, buf)
+            .map_err(|_| FileError::IsDirectory)?;
+        *offset += n;
+        Ok(n)
+    }
+
+    /// Writes `buf` at the current offset, and advances the offset.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FileError> {
+        if !self.writable {
+            return Err(FileError::NotWritable);
+        }
+
+        let mut offset = self.offset.lock();
+        let mut inode = self.inode().lock();
+        let fs = inode.get_fs().expect("file system has been dropped");
+        let n = fs
+            .write_inode(&mut inode, *offset, buf)
+            .map_err(|err| match err {
+                FileSystemAllocationError::IsADirectory => FileError::IsDirectory,
+                FileSystemAllocationError::TooLarge(_) => FileError::TooLarge,
+                _ => FileError::NoSpace,
+            })?;
+
+        *offset += n;
+        Ok(n)
+    }
+
+    /// Reads at most `max` entries after the ones read last time, see
+    /// `DirStream`.
+    pub fn read_dir(&self, max: usize) -> Result<Vec<DirItem>, FileError> {
+        if !self.readable {
+            return Err(FileError::NotReadable);
+        }
+        if self.inode().lock().type_ != InodeType::Directory {
+            return Err(FileError::NotDirectory);
+        }
+
+        let mut cursor = self.dir_cursor.lock();
+        let mut stream = DirStream::after(self.inode().clone(), cursor.take());
+        let items = stream.by_ref().take(max).collect();
+        *cursor = stream.cursor().map(String::from);
+        Ok(items)
+    }
+}
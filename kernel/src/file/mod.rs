@@ -0,0 +1,188 @@
+//! Open files of tasks.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt;
+
+use ::syscall::{Errno, O_CREATE, O_NONBLOCK, O_RDWR, O_WRONLY};
+use fs::{block_dev::InodeType, inode::Inode, DirItem, FileSystem};
+use log::warn;
+use spin::Mutex;
+
+pub use self::{console::*, fifo::*, inode::*, pipe::*};
+use crate::{
+    sync::wait_channel::{Wait, WaitChannel},
+    ROOT_FS,
+};
+
+mod console;
+mod fifo;
+mod inode;
+mod pipe;
+
+/// The maximum number of open files per task.
+pub const NOFILE: usize = 16;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FileError {
+    NotFound,
+    NotReadable,
+    NotWritable,
+    /// Writing to a pipe whose read ends are all closed.
+    BrokenPipe,
+    /// No space left in the file system.
+    NoSpace,
+    /// Growing a file past the maximum size of an inode.
+    TooLarge,
+    /// The operation would block but blocking is not allowed.
+    WouldBlock,
+    /// Listing the entries of a file which is not a directory.
+    NotDirectory,
+    /// Reading or writing the bytes of a directory.
+    IsDirectory,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::NotFound => write!(f, "no such file"),
+            FileError::NotReadable => write!(f, "file is not readable"),
+            FileError::NotWritable => write!(f, "file is not writable"),
+            FileError::BrokenPipe => write!(f, "broken pipe"),
+            FileError::NoSpace => write!(f, "no space left"),
+            FileError::TooLarge => write!(f, "file too large"),
+            FileError::WouldBlock => write!(f, "operation would block"),
+            FileError::NotDirectory => write!(f, "not a directory"),
+            FileError::IsDirectory => write!(f, "is a directory"),
+        }
+    }
+}
+
+impl From<FileError> for Errno {
+    fn from(err: FileError) -> Self {
+        match err {
+            FileError::NotFound => Errno::ENOENT,
+            FileError::NotReadable | FileError::NotWritable => Errno::EBADF,
+            FileError::BrokenPipe => Errno::EPIPE,
+            FileError::NoSpace => Errno::ENOSPC,
+            FileError::TooLarge => Errno::EFBIG,
+            FileError::WouldBlock => Errno::EAGAIN,
+            FileError::NotDirectory => Errno::ENOTDIR,
+            FileError::IsDirectory => Errno::EISDIR,
+        }
+    }
+}
+
+/// Opens the file at `path`, `flags` are the `O_*` flags.
+///
+/// FIFOs are opened for writing with `O_WRONLY`, for both reading and
+/// writing with `O_RDWR`, otherwise for reading. The task sleeps on the
+/// returned wait, if any, until the other end of the FIFO is opened, see
+/// `open_fifo`.
+pub fn open(path: &str, flags: u32) -> Result<(OpenFile, Option<Wait>), FileError> {
+    let fs = ROOT_FS.get().ok_or(FileError::NotFound)?;
+    let inode = match fs.get_inode_from_path(path, &fs.root()) {
+        Some(inode) => inode,
+        None if flags & O_CREATE != 0 => create(fs, path)?,
+        None => return Err(FileError::NotFound),
+    };
+
+    let readable = flags & O_WRONLY == 0;
+    let writable = flags & (O_WRONLY | O_RDWR) != 0;
+    let (inum, type_) = {
+        let inode = inode.lock();
+        (inode.inode_num, inode.type_)
+    };
+    match type_ {
+        // `O_TRUNC` is ignored, a FIFO has no data on the disk.
+        InodeType::Fifo => open_fifo(inum, readable, writable, flags & O_NONBLOCK != 0),
+        _ => Ok((OpenFile::Inode(InodeFile::new(inode, readable, writable)), None)),
+    }
+}
+
+/// Creates a regular file at `path`.
+fn create(fs: &Arc<FileSystem>, path: &str) -> Result<Arc<Mutex<Inode>>, FileError> {
+    let (dir_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let dir = fs
+        .get_inode_from_path(dir_path, &fs.root())
+        .ok_or(FileError::NotFound)?;
+
+    let mut dir = dir.lock();
+    if dir.type_ != InodeType::Directory {
+        return Err(FileError::NotFound);
+    }
+    fs.create_inode(&mut dir, name, InodeType::File)
+        .map_err(|err| {
+            warn!("file: failed to create {}: {}", path, err);
+            FileError::NoSpace
+        })
+}
+
+/// A file opened by tasks, the fds refer to it.
+///
+/// Fds duplicated from each other share the same `OpenFile`, and
+/// so the offset.
+pub enum OpenFile {
+    /// The console, at the fds 0, 1 and 2 of every task.
+    Console,
+    Inode(InodeFile),
+    PipeReader(Arc<Pipe>),
+    PipeWriter(Arc<Pipe>),
+    /// A FIFO opened for both reading and writing.
+    Fifo(Arc<Pipe>),
+}
+
+impl OpenFile {
+    /// Reads into `buf`, returns the number of bytes read.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FileError> {
+        match self {
+            OpenFile::Console => read_console(buf),
+            OpenFile::Inode(file) => file.read(buf),
+            OpenFile::PipeReader(pipe) | OpenFile::Fifo(pipe) => pipe.read(buf),
+            _ => Err(FileError::NotReadable),
+        }
+    }
+
+    /// Writes `buf`, returns the number of bytes written.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FileError> {
+        match self {
+            OpenFile::Console => write_console(buf),
+            OpenFile::Inode(file) => file.write(buf),
+            OpenFile::PipeWriter(pipe) | OpenFile::Fifo(pipe) => pipe.write(buf),
+            _ => Err(FileError::NotWritable),
+        }
+    }
+
+    /// Returns the channel woken up when a write if `writing`, otherwise
+    /// a read, that failed with `WouldBlock` may go on, `None` if the file
+    /// can't be waited for.
+    pub fn wait_channel(&self, writing: bool) -> Option<&WaitChannel> {
+        match self {
+            OpenFile::PipeReader(pipe) | OpenFile::PipeWriter(pipe) | OpenFile::Fifo(pipe) => {
+                Some(pipe.channel(writing))
+            }
+            _ => None,
+        }
+    }
+
+    /// Reads at most `max` entries of a directory, see `InodeFile::read_dir`.
+    pub fn read_dir(&self, max: usize) -> Result<Vec<DirItem>, FileError> {
+        match self {
+            OpenFile::Inode(file) => file.read_dir(max),
+            _ => Err(FileError::NotDirectory),
+        }
+    }
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        match self {
+            OpenFile::PipeReader(pipe) => pipe.close(false),
+            OpenFile::PipeWriter(pipe) => pipe.close(true),
+            OpenFile::Fifo(pipe) => {
+                pipe.close(false);
+                pipe.close(true);
+            }
+            OpenFile::Console | OpenFile::Inode(_) => {}
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use alloc::sync::Arc;
+
+use spin::Mutex;
+
+use super::{FileError, OpenFile};
+use crate::sync::wait_channel::WaitChannel;
+
+/// The size of the pipe buffer.
+pub const PIPE_SIZE: usize = 512;
+
+pub struct Pipe {
+    inner:         Mutex<PipeInner>,
+    /// Readers wait on it for data.
+    readable:      WaitChannel,
+    /// Writers wait on it for space.
+    writable:      WaitChannel,
+    /// FIFO opens for writing wait on it for a read end.
+    reader_opened: WaitChannel,
+    /// FIFO opens for reading wait on it for a write end.
+    writer_opened: WaitChannel,
+}
+
+struct PipeInner {
+    data:    [u8; PIPE_SIZE],
+    /// Number of bytes read.
+    nread:   usize,
+    /// Number of bytes written.
+    nwrite:  usize,
+    /// Number of open read ends.
+    readers: usize,
+    /// Number of open write ends.
+    writers: usize,
+}
+
+impl Pipe {
+    /// Creates a pipe, returns its read end and write end.
+    pub fn new() -> (OpenFile, OpenFile) {
+        let pipe = Self::empty();
+        (pipe.open_reader(), pipe.open_writer())
+    }
+
+    /// Creates a pipe without any end opened.
+    pub(super) fn empty() -> Arc<Self> {
+        Arc::new(Pipe {
+            inner:         Mutex::new(PipeInner {
+                data:    [0; PIPE_SIZE],
+                nread:   0,
+                nwrite:  0,
+                readers: 0,
+                writers: 0,
+            }),
+            readable:      WaitChannel::new(),
+            writable:      WaitChannel::new(),
+            reader_opened: WaitChannel::new(),
+            writer_opened: WaitChannel::new(),
+        })
+    }
+
+    pub(super) fn open_reader(self: &Arc<Self>) -> OpenFile {
+        self.inner.lock().readers += 1;
+        self.reader_opened.wakeup();
+        OpenFile::PipeReader(self.clone())
+    }
+
+    pub(super) fn open_writer(self: &Arc<Self>) -> OpenFile {
+        self.inner.lock().writers += 1;
+        self.writer_opened.wakeup();
+        OpenFile::PipeWriter(self.clone())
+    }
+
+    /// Opens a read end and a write end as one file, for a FIFO opened
+    /// with `O_RDWR`.
+    pub(super) fn open_both(self: &Arc<Self>) -> OpenFile {
+        {
+            let mut inner = self.inner.lock();
+            inner.readers += 1;
+            inner.writers += 1;
+        }
+        self.reader_opened.wakeup();
+        self.writer_opened.wakeup();
+        OpenFile::Fifo(self.clone())
+    }
+
+    /// Returns the number of open read ends if `writer`, otherwise the
+    /// number of open write ends.
+    pub(super) fn peers(&self, writer: bool) -> usize {
+        let inner = self.inner.lock();
+        if writer {
+            inner.readers
+        } else {
+            inner.writers
+        }
+    }
+
+    /// Returns the channel woken up when a read end is opened if
+    /// `writer`, otherwise a write end.
+    pub(super) fn opened_channel(&self, writer: bool) -> &WaitChannel {
+        if writer {
+            &self.reader_opened
+        } else {
+            &self.writer_opened
+        }
+    }
+
+    /// Returns the channel woken up when the pipe has room if `writer`,
+    /// otherwise when it has data or all write ends are closed.
+    pub fn channel(&self, writer: bool) -> &WaitChannel {
+        if writer {
+            &self.writable
+        } else {
+            &self.readable
+        }
+    }
+
+    /// Reads the available bytes into `buf`, fails with `WouldBlock` if
+    /// the pipe is empty.
+    ///
+    /// Returns 0 if the pipe is empty and all write ends are closed.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FileError> {
+        let mut inner = self.inner.lock();
+        if inner.nread == inner.nwrite && inner.writers > 0 {
+            return Err(FileError::WouldBlock);
+        }
+
+        let mut n = 0;
+        while n < buf.len() && inner.nread != inner.nwrite {
+            buf[n] = inner.data[inner.nread % PIPE_SIZE];
+            inner.nread += 1;
+            n += 1;
+        }
+        self.writable.wakeup();
+        Ok(n)
+    }
+
+    /// Writes as much of `buf` as the pipe has room for, fails with
+    /// `WouldBlock` if the pipe is full.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FileError> {
+        let mut inner = self.inner.lock();
+        if inner.readers == 0 {
+            return Err(FileError::BrokenPipe);
+        }
+
+        let mut n = 0;
+        while n < buf.len() && inner.nwrite != inner.nread + PIPE_SIZE {
+            let idx = inner.nwrite % PIPE_SIZE;
+            inner.data[idx] = buf[n];
+            inner.nwrite += 1;
+            n += 1;
+        }
+        if n == 0 && !buf.is_empty() {
+            return Err(FileError::WouldBlock);
+        }
+        self.readable.wakeup();
+        Ok(n)
+    }
+
+    /// Closes a write end if `writable`, otherwise a read end.
+    pub fn close(&self, writable: bool) {
+        let mut inner = self.inner.lock();
+        if writable {
+            inner.writers -= 1;
+            self.readable.wakeup();
+        } else {
+            inner.readers -= 1;
+            self.writable.wakeup();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proc::{State, TaskList};
+
+    #[test_case]
+    fn test_pipe_between_tasks() {
+        let mut tasks = TaskList::new();
+        let writer_lock = tasks.new_task().unwrap().clone();
+        let reader_lock = tasks.new_task().unwrap().clone();
+
+        let (reader, writer) = Pipe::new();
+        let wfd = writer_lock.write().alloc_fd(Arc::new(writer)).unwrap();
+        let rfd = reader_lock.write().alloc_fd(Arc::new(reader)).unwrap();
+
+        let written = writer_lock
+            .read()
+            .file(wfd)
+            .unwrap()
+            .write(b"hello, pipe")
+            .unwrap();
+        assert_eq!(written, 11);
+        writer_lock.write().close_fd(wfd).unwrap();
+
+        let reader = reader_lock.read();
+        let file = reader.file(rfd).unwrap();
+        let mut buf = [0u8; 32];
+        assert_eq!(file.read(&mut buf[..5]), Ok(5));
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(file.read(&mut buf), Ok(6));
+        assert_eq!(&buf[..6], b", pipe");
+        // All write ends are closed.
+        assert_eq!(file.read(&mut buf), Ok(0));
+    }
+
+    #[test_case]
+    fn test_pipe_blocks_task() {
+        let mut tasks = TaskList::new();
+        let reader_lock = tasks.new_task().unwrap().clone();
+        let (reader, writer) = Pipe::new();
+
+        // Reading the empty pipe puts the reader to sleep.
+        let wait = reader.wait_channel(false).unwrap().wait();
+        let mut buf = [0u8; PIPE_SIZE + 1];
+        assert_eq!(reader.read(&mut buf), Err(FileError::WouldBlock));
+        tasks.sleep_on(&mut reader_lock.write(), wait);
+        assert!(tasks.next_runnable().is_none());
+
+        // Writing wakes it up, as much as fits is written.
+        let data = [0xa5u8; PIPE_SIZE + 1];
+        assert_eq!(writer.write(&data), Ok(PIPE_SIZE));
+        let next = tasks.next_runnable().expect("reader not woken up");
+        assert!(Arc::ptr_eq(next, &reader_lock));
+        assert!(reader_lock.read().state == State::Runnable);
+
+        // The writer blocks on the full pipe until the reader reads.
+        let wait = writer.wait_channel(true).unwrap().wait();
+        assert_eq!(writer.write(&data), Err(FileError::WouldBlock));
+        assert!(!wait.woken());
+        assert_eq!(reader.read(&mut buf), Ok(PIPE_SIZE));
+        assert!(wait.woken());
+        assert_eq!(writer.write(&data[..1]), Ok(1));
+    }
+
+    #[test_case]
+    fn test_pipe_broken() {
+        let (reader, writer) = Pipe::new();
+        drop(reader);
+        assert_eq!(writer.write(b"lost"), Err(FileError::BrokenPipe));
+        assert_eq!(writer.read(&mut [0u8; 4]), Err(FileError::NotReadable));
+    }
+}
@@ -1,7 +1,10 @@
-use core::arch::{asm, global_asm};
+use core::{
+    arch::{asm, global_asm},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
 use log::info;
-use plic::{handle_plic, plic_init};
+use plic::{external_interrupts, handle_plic, plic_init};
 use riscv::{
     interrupt::{supervisor::Interrupt, Exception},
     register::{
@@ -13,7 +16,10 @@ use riscv::{
 };
 
 use self::timer::{set_next_timer, tick};
-pub use self::trap::{usertrapret, TrapFrame};
+pub use self::{
+    timer::{ms_to_ticks, set_timer_interval, ticks, timer_delta, uptime_ms, TIMER_FREQ},
+    trap::{usertrapret, TrapFrame},
+};
 
 pub mod plic;
 mod timer;
@@ -37,30 +43,116 @@ extern "C" {
     fn kernelvec();
 }
 
+/// Harts with a saved interrupt state.
+const MAX_HARTS: usize = 8;
+
+/// The interrupt state of a hart before its outermost `push_off`, like
+/// `noff` and `intena` of `struct cpu` in xv6.
+struct SavedIntr {
+    depth: AtomicUsize,
+    sie:   AtomicBool,
+    sext:  AtomicBool,
+}
+
+impl SavedIntr {
+    const fn new() -> Self {
+        Self {
+            depth: AtomicUsize::new(0),
+            sie:   AtomicBool::new(false),
+            sext:  AtomicBool::new(false),
+        }
+    }
+}
+
+static SAVED_INTR: [SavedIntr; MAX_HARTS] = [const { SavedIntr::new() }; MAX_HARTS];
+
+/// Disables the interrupts of this hart until the matching `pop_off`.
+///
+/// The calls nest, only the outermost `pop_off` restores the state from
+/// before the outermost `push_off`.
+pub fn push_off() {
+    let sie = sstatus::read().sie();
+    let sext = sie::read().sext();
+    unsafe {
+        disable_supervisor_interrupt();
+        disable_supervisor_external_interrupt();
+    }
+
+    let saved = &SAVED_INTR[cpu_id()];
+    if saved.depth.load(Ordering::Relaxed) == 0 {
+        saved.sie.store(sie, Ordering::Relaxed);
+        saved.sext.store(sext, Ordering::Relaxed);
+    }
+    saved.depth.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Undoes one `push_off`, see `push_off`.
+pub fn pop_off() {
+    assert!(!sstatus::read().sie(), "pop_off: interruptible");
+    let saved = &SAVED_INTR[cpu_id()];
+    let depth = saved.depth.load(Ordering::Relaxed);
+    assert!(depth > 0, "pop_off: not pushed");
+    saved.depth.store(depth - 1, Ordering::Relaxed);
+
+    if depth == 1 {
+        unsafe {
+            if saved.sext.load(Ordering::Relaxed) {
+                enable_supervisor_external_interrupt();
+            }
+            if saved.sie.load(Ordering::Relaxed) {
+                enable_supervisor_interrupt();
+            }
+        }
+    }
+}
+
+/// Returns the number of interrupts handled since boot, the timer ticks
+/// and the external interrupts.
+pub fn interrupts() -> usize {
+    ticks() + external_interrupts()
+}
+
 /// Handles all traps from user or kernel process.
 pub unsafe fn handle(cause: scause::Scause, context: &mut TrapFrame) {
-    disable_supervisor_external_interrupt();
-    disable_supervisor_interrupt();
+    // The hart clears `sie` on the trap, `sret` sets it back.
+    push_off();
 
     let stval = stval::read();
     match cause.cause() {
         Trap::Exception(exception) => match Exception::from_number(exception) {
             Err(err) => panic!("{}", err),
             Ok(Exception::LoadPageFault) | Ok(Exception::StorePageFault) => {
-                panic!("pagefault: bad addr = {:#x}, instruction = {:#x}", stval, context.epc,);
+                panic!(
+                    "pagefault: bad addr = {:#x}, instruction = {:#x}\n{}",
+                    stval, context.epc, context
+                );
             }
-            Ok(e) => unimplemented!("{:?}", e),
-        },
-        Trap::Interrupt(intr) => match Interrupt::from_number(intr) {
-            Err(err) => panic!("{}", err),
-            Ok(Interrupt::SupervisorTimer) => tick(),
-            Ok(Interrupt::SupervisorExternal) => handle_plic(),
-            Ok(e) => unimplemented!("{:?}", e),
+            Ok(Exception::InstructionPageFault) => {
+                panic!("instruction pagefault: bad pc = {:#x}\n{}", stval, context);
+            }
+            Ok(e) => panic!("unhandled exception: {:?}, stval = {:#x}\n{}", e, stval, context),
         },
+        Trap::Interrupt(intr) => handle_interrupt(intr),
+    }
+
+    pop_off();
+}
+
+/// Handles the interrupt `intr` from user or kernel process.
+///
+/// The timer takes the task list and the tasks it wakes up, so the
+/// caller must not hold any task.
+pub unsafe fn handle_interrupt(intr: usize) {
+    push_off();
+
+    match Interrupt::from_number(intr) {
+        Err(err) => panic!("{}", err),
+        Ok(Interrupt::SupervisorTimer) => tick(),
+        Ok(Interrupt::SupervisorExternal) => handle_plic(),
+        Ok(e) => unimplemented!("{:?}", e),
     }
 
-    enable_supervisor_interrupt();
-    enable_supervisor_external_interrupt();
+    pop_off();
 }
 
 pub fn init() {
@@ -82,6 +174,27 @@ pub fn init() {
     set_next_timer();
 }
 
+/// Enables interrupts and stalls the hart until one is pending, the
+/// interrupts are disabled again afterwards if they were before.
+///
+/// Must not be called between `push_off` and `pop_off`, the interrupt
+/// would be taken while the hart holds an interrupt-disabling lock.
+pub fn wait_for_interrupt() {
+    assert_eq!(
+        SAVED_INTR[cpu_id()].depth.load(Ordering::Relaxed),
+        0,
+        "wait_for_interrupt: interrupts pushed off"
+    );
+    let sie = sstatus::read().sie();
+    unsafe {
+        enable_supervisor_interrupt();
+        asm!("wfi");
+        if !sie {
+            disable_supervisor_interrupt();
+        }
+    }
+}
+
 #[inline(always)]
 pub fn cpu_id() -> usize {
     // let id: usize;
@@ -114,3 +227,58 @@ unsafe fn enable_supervisor_external_interrupt() {
 unsafe fn disable_supervisor_external_interrupt() {
     sie::clear_sext();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_nested_push_off() {
+        unsafe {
+            enable_supervisor_interrupt();
+            enable_supervisor_external_interrupt();
+        }
+
+        push_off();
+        push_off();
+        assert!(!sstatus::read().sie());
+        assert!(!sie::read().sext());
+
+        pop_off();
+        assert!(!sstatus::read().sie());
+        assert!(!sie::read().sext());
+
+        pop_off();
+        assert!(sstatus::read().sie());
+        assert!(sie::read().sext());
+
+        // Stays off when it was off before the outermost push_off.
+        unsafe { disable_supervisor_interrupt() };
+        push_off();
+        push_off();
+        pop_off();
+        pop_off();
+        assert!(!sstatus::read().sie());
+        assert!(sie::read().sext());
+        unsafe { enable_supervisor_interrupt() };
+    }
+
+    #[test_case]
+    fn test_wait_for_interrupt_restores_sie() {
+        let was_enabled = sstatus::read().sie();
+
+        unsafe { disable_supervisor_interrupt() };
+        let before = interrupts();
+        wait_for_interrupt();
+        assert!(interrupts() > before);
+        assert!(!sstatus::read().sie());
+
+        unsafe { enable_supervisor_interrupt() };
+        wait_for_interrupt();
+        assert!(sstatus::read().sie());
+
+        if !was_enabled {
+            unsafe { disable_supervisor_interrupt() };
+        }
+    }
+}
@@ -1,10 +1,12 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use log::{debug, info};
 
 use super::cpu_id;
 use crate::{drivers::virtio::handle_virtio_interrupt, mem::PLIC_BASE};
 
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IRQ {
     UART0  = 10,
     VIRTIO = 1,
@@ -16,6 +18,28 @@ impl From<u32> for IRQ {
     }
 }
 
+/// The number of PLIC sources counted.
+pub const NR_IRQS: usize = 64;
+
+/// The external interrupts handled, per IRQ.
+static IRQ_COUNTS: [AtomicUsize; NR_IRQS] = [const { AtomicUsize::new(0) }; NR_IRQS];
+
+/// Returns the number of interrupts handled from `irq`.
+pub fn irq_count(irq: usize) -> usize {
+    IRQ_COUNTS.get(irq).map_or(0, |n| n.load(Ordering::Relaxed))
+}
+
+/// Returns the number of external interrupts handled.
+pub fn external_interrupts() -> usize {
+    IRQ_COUNTS.iter().map(|n| n.load(Ordering::Relaxed)).sum()
+}
+
+fn count_irq(irq: u32) {
+    if let Some(n) = IRQ_COUNTS.get(irq as usize) {
+        n.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 macro_rules! plic_irq_senable {
     ($hart_id:expr) => {
         *((crate::mem::PLIC_BASE + 0x2080 + ($hart_id * 0x100)) as *mut u32)
@@ -35,21 +59,52 @@ macro_rules! plic_sclaim {
 }
 
 pub unsafe fn plic_init() {
-    // let hart = cpu_id();
-    let hart = 0;
+    let hart = cpu_id();
 
     debug!("init plic hart: {}", hart);
 
     // TODO: enable virtio interrupt
     // set_irq(IRQ::VIRTIO, 1);
 
-    // enable irq for this hart in S-mode
-    plic_irq_senable!(hart) |= 1 << IRQ::VIRTIO as u32;
+    // The drivers enable their sources with `plic_enable` once their
+    // devices are ready.
 
     // set this hart's S-mode threshold to 0
     plic_irq_spriority!(hart) = 0;
 }
 
+/// Lets `irq` interrupt this hart in S-mode.
+pub fn plic_enable(irq: IRQ) {
+    let hart = cpu_id();
+    unsafe { plic_irq_senable!(hart) |= 1 << irq as u32 };
+}
+
+/// Stops `irq` interrupting this hart in S-mode.
+pub fn plic_disable(irq: IRQ) {
+    let hart = cpu_id();
+    unsafe { plic_irq_senable!(hart) &= !(1 << irq as u32) };
+}
+
+/// Masks the interrupt sources, so drivers can be tested without the
+/// real PLIC.
+pub trait IrqControl {
+    fn enable(&self, irq: IRQ);
+    fn disable(&self, irq: IRQ);
+}
+
+/// The PLIC of this hart.
+pub struct Plic;
+
+impl IrqControl for Plic {
+    fn enable(&self, irq: IRQ) {
+        plic_enable(irq);
+    }
+
+    fn disable(&self, irq: IRQ) {
+        plic_disable(irq);
+    }
+}
+
 unsafe fn set_irq(irq: IRQ, value: u32) {
     *((PLIC_BASE + (irq as usize * 4)) as *mut u32) = value;
 }
@@ -59,6 +114,7 @@ pub fn handle_plic() {
     let irq = unsafe { plic_sclaim!(hart_id) };
 
     info!("Received PLIC interrupt: irq: {}, hart_id: {}", irq, hart_id);
+    count_irq(irq);
     match IRQ::from(irq) {
         IRQ::VIRTIO => handle_virtio_interrupt(),
         _ => unimplemented!(),
@@ -3,20 +3,89 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use log::debug;
 use riscv::register::time;
 
-use crate::syscall::set_timer;
+use crate::{proc::TASKS, syscall::set_timer};
 
-pub const INTERVAL: usize = 100_000;
+/// The frequency of the `time` csr on the QEMU virt machine (Hz).
+pub const TIMER_FREQ: usize = 10_000_000;
+
+/// The default interval between two ticks (ms).
+pub const DEFAULT_INTERVAL_MS: usize = 10;
+
+/// The interval between two ticks, in `time` counts.
+static INTERVAL: AtomicUsize = AtomicUsize::new(timer_delta(DEFAULT_INTERVAL_MS, TIMER_FREQ));
 
 pub static TICKS: AtomicUsize = AtomicUsize::new(0);
 
+/// Converts `ms` milliseconds to the `mtimecmp` delta of a timer
+/// running at `freq` Hz.
+pub const fn timer_delta(ms: usize, freq: usize) -> usize {
+    ms * freq / 1000
+}
+
+/// Sets the interval between two ticks, from the next tick on.
+pub fn set_timer_interval(ms: usize) {
+    assert!(ms > 0, "timer interval must be positive");
+    INTERVAL.store(timer_delta(ms, TIMER_FREQ), Ordering::Relaxed);
+}
+
+/// Returns the number of ticks since boot.
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the milliseconds since boot.
+pub fn uptime_ms() -> usize {
+    time::read() / timer_delta(1, TIMER_FREQ)
+}
+
+/// Returns the number of ticks covering at least `ms` milliseconds.
+pub fn ms_to_ticks(ms: usize) -> usize {
+    timer_delta(ms, TIMER_FREQ).div_ceil(INTERVAL.load(Ordering::Relaxed))
+}
+
 pub fn set_next_timer() {
-    set_timer(time::read() + INTERVAL);
+    set_timer(time::read() + INTERVAL.load(Ordering::Relaxed));
 }
 
 pub fn tick() {
     set_next_timer();
-    TICKS.fetch_add(1, Ordering::Relaxed);
-    if TICKS.load(Ordering::Relaxed) % 100 == 0 {
-        debug!("ticks: {}", TICKS.load(Ordering::Relaxed));
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    if now % 100 == 0 {
+        debug!("ticks: {}", now);
+    }
+    // The task list is never held by this hart here, the interrupts are
+    // disabled while it is, and the traps release the tasks before
+    // handling an interrupt.
+    TASKS.write().wake_sleepers(now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_timer_delta() {
+        // The interval used before it was configurable.
+        assert_eq!(timer_delta(DEFAULT_INTERVAL_MS, TIMER_FREQ), 100_000);
+        assert_eq!(timer_delta(1, 10_000_000), 10_000);
+        assert_eq!(timer_delta(250, 1_000_000), 250_000);
+        assert_eq!(timer_delta(3, 1_000), 3);
+    }
+
+    #[test_case]
+    fn test_set_timer_interval() {
+        set_timer_interval(5);
+        assert_eq!(INTERVAL.load(Ordering::Relaxed), 50_000);
+        set_timer_interval(DEFAULT_INTERVAL_MS);
+        assert_eq!(INTERVAL.load(Ordering::Relaxed), 100_000);
+    }
+
+    #[test_case]
+    fn test_ms_to_ticks() {
+        assert_eq!(ms_to_ticks(0), 0);
+        assert_eq!(ms_to_ticks(1), 1);
+        assert_eq!(ms_to_ticks(DEFAULT_INTERVAL_MS), 1);
+        assert_eq!(ms_to_ticks(DEFAULT_INTERVAL_MS + 1), 2);
+        assert_eq!(ms_to_ticks(1000), 100);
     }
 }
@@ -1,15 +1,26 @@
-use riscv::register::{scause, sepc, sstatus, stvec};
+use core::fmt;
 
-use super::handle;
+use log::warn;
+use riscv::{
+    interrupt::Exception,
+    register::{
+        scause::{self, Trap},
+        sepc, sstatus, stval, stvec,
+    },
+    ExceptionNumber,
+};
+
+use super::{handle, handle_interrupt};
 use crate::{
     intr::{disable_supervisor_interrupt, trampoline, userret, uservec},
     mem::{TRAMPOLINE, TRAPFRAME},
     println,
-    proc::TASKS,
+    proc::{schedule, State, Task, TaskId, TASKS},
+    syscall::dispatch,
 };
 
 #[repr(C)]
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct TrapFrame {
     /*   0 */ pub kernel_satp:   usize, // kernel page table
     /*   8 */ pub kernel_sp:     usize, // top of process's kernel stack
@@ -49,6 +60,35 @@ pub struct TrapFrame {
     /* 280 */ pub t6:            usize,
 }
 
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        #[rustfmt::skip]
+        let regs = [
+            ("ra", self.ra), ("sp", self.sp), ("gp", self.gp), ("tp", self.tp),
+            ("t0", self.t0), ("t1", self.t1), ("t2", self.t2), ("s0", self.s0),
+            ("s1", self.s1), ("a0", self.a0), ("a1", self.a1), ("a2", self.a2),
+            ("a3", self.a3), ("a4", self.a4), ("a5", self.a5), ("a6", self.a6),
+            ("a7", self.a7), ("s2", self.s2), ("s3", self.s3), ("s4", self.s4),
+            ("s5", self.s5), ("s6", self.s6), ("s7", self.s7), ("s8", self.s8),
+            ("s9", self.s9), ("s10", self.s10), ("s11", self.s11), ("t3", self.t3),
+            ("t4", self.t4), ("t5", self.t5), ("t6", self.t6),
+        ];
+
+        writeln!(
+            f,
+            "epc: {:#018x} kernel_sp: {:#018x} kernel_satp: {:#018x}",
+            self.epc, self.kernel_sp, self.kernel_satp
+        )?;
+        for row in regs.chunks(4) {
+            for (name, value) in row {
+                write!(f, "{:>4}: {:#018x} ", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
 /// Handles interrupt, exception or system call from user space.
 #[no_mangle]
 pub fn usertrap() {
@@ -59,18 +99,122 @@ pub fn usertrap() {
     // TODO:
     // stvec::write(kernelvec)
 
-    let tasks = TASKS.write();
-    let proc = tasks
+    let epc = sepc::read();
+    let cause = scause::read();
+    // Handled before the task is taken, the timer takes the tasks it
+    // wakes up.
+    if let Trap::Interrupt(intr) = cause.cause() {
+        unsafe { handle_interrupt(intr) };
+    }
+
+    let proc = TASKS
+        .read()
         .current()
-        .expect("usertrap: failed to get current process");
-    {
+        .expect("usertrap: failed to get current process")
+        .clone();
+    let killed = {
         let mut proc_lock = proc.write();
 
         // Save user program counter.
-        proc_lock.trap_frame.epc = sepc::read();
+        proc_lock.trap_frame.epc = epc;
+
+        match cause.cause() {
+            Trap::Exception(e)
+                if matches!(Exception::from_number(e), Ok(Exception::UserEnvCall)) =>
+            {
+                // Return to the next instruction of `ecall`.
+                proc_lock.trap_frame.epc += 4;
 
-        unsafe { handle(scause::read(), &mut proc_lock.trap_frame) };
+                let tf = &proc_lock.trap_frame;
+                let (id, args) = (tf.a7, [tf.a0, tf.a1, tf.a2, tf.a3, tf.a4, tf.a5]);
+                let ret = dispatch(&mut proc_lock, id, args);
+                proc_lock.trap_frame.a0 = ret as usize;
+            }
+            Trap::Exception(e)
+                if matches!(
+                    Exception::from_number(e),
+                    Ok(Exception::LoadPageFault | Exception::StorePageFault)
+                ) =>
+            {
+                let va = stval::read();
+                if !proc_lock.handle_page_fault(va) {
+                    warn!(
+                        "usertrap: task {} page fault at 0x{:x}, epc: 0x{:x}",
+                        proc_lock.pid, va, proc_lock.trap_frame.epc
+                    );
+                    proc_lock.killed = true;
+                }
+            }
+            Trap::Exception(e)
+                if matches!(Exception::from_number(e), Ok(Exception::InstructionPageFault)) =>
+            {
+                let pc = stval::read();
+                if !proc_lock.handle_page_fault(pc) {
+                    kill_on_instruction_fault(&mut proc_lock, pc);
+                }
+            }
+            Trap::Interrupt(_) => {}
+            _ => unsafe { handle(cause, &mut proc_lock.trap_frame) },
+        }
+
+        proc_lock.killed && !matches!(proc_lock.state, State::Exited(_))
+    };
+    if killed {
+        // The task list is taken before the task, like `pick_next`.
+        let mut tasks = TASKS.write();
+        tasks.exit(&mut proc.write(), -1);
     }
+    let descheduled = matches!(proc.read().state, State::Exited(_) | State::Sleeping);
+
+    // Give up the CPU until the task is runnable again.
+    if descheduled {
+        schedule();
+    }
+    unsafe { usertrapret() }
+}
+
+/// An instruction fetch a task is not allowed to make.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InstructionFault {
+    pub pid:    TaskId,
+    /// The address fetched from.
+    pub pc:     usize,
+    /// The page is mapped, but not executable from user mode.
+    pub mapped: bool,
+}
+
+impl fmt::Display for InstructionFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let page = if self.mapped {
+            "not executable"
+        } else {
+            "not mapped"
+        };
+        write!(
+            f,
+            "task {} instruction page fault at pc 0x{:x}, page {}",
+            self.pid, self.pc, page
+        )
+    }
+}
+
+/// Reports the instruction fetch from `pc` and kills `task` for it, it
+/// exits once `usertrap` releases it.
+fn kill_on_instruction_fault(task: &mut Task, pc: usize) -> InstructionFault {
+    // Not walked, `pc` may be past `MAX_VA`.
+    let mapped = task
+        .page_table
+        .as_ref()
+        .and_then(|page_table| page_table.translate(pc))
+        .is_some();
+    let fault = InstructionFault {
+        pid: task.pid,
+        pc,
+        mapped,
+    };
+    warn!("usertrap: {}, epc: 0x{:x}", fault, task.trap_frame.epc);
+    task.killed = true;
+    fault
 }
 
 /// Returns to user space when `usertrap` is done.
@@ -79,7 +223,12 @@ pub unsafe fn usertrapret() {
     let satp: usize;
 
     {
-        let tasks = TASKS.write();
+        // Released before turning off interrupts, which releasing it
+        // would turn on again.
+        let current_task = match TASKS.read().current() {
+            Ok(current_task) => current_task.clone(),
+            Err(_) => panic!("get current process failed."),
+        };
 
         // We're about to switch the destination of traps from `kerneltrap()`
         // to `usertrap()`, so turn off interrupts until we're back in
@@ -91,10 +240,6 @@ pub unsafe fn usertrapret() {
         stvec::write(entry, stvec::TrapMode::Direct);
 
         {
-            let current_task = match tasks.current() {
-                Ok(current_task) => current_task,
-                Err(_) => panic!("get current process failed."),
-            };
             let proc = current_task.write();
 
             // // Set up trapframe values that `uservec` will need when the
@@ -140,13 +285,86 @@ pub unsafe fn usertrapret() {
 
 #[no_mangle]
 pub fn kerneltrap() {
-    let lock = TASKS.write();
-    let proc = lock
+    let cause = scause::read();
+    // Neither the task list nor the task is held, the timer takes them.
+    if let Trap::Interrupt(intr) = cause.cause() {
+        unsafe { handle_interrupt(intr) };
+        return;
+    }
+
+    let proc = TASKS
+        .read()
         .current()
-        .expect("usertrap: failed to get current process");
-    {
-        let mut proc_lock = proc.write();
+        .expect("usertrap: failed to get current process")
+        .clone();
+    let mut proc_lock = proc.write();
+    unsafe { handle(cause, &mut proc_lock.trap_frame) };
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use super::*;
+
+    #[test_case]
+    fn test_trap_frame_display() {
+        let tf = TrapFrame {
+            epc: 0x8000_1234,
+            sp: 0x3f_ffff_f000,
+            a0: 42,
+            t6: 0xdead_beef,
+            ..Default::default()
+        };
+
+        let dump = format!("{}", tf);
+        assert!(dump.contains("epc: 0x0000000080001234"));
+        assert!(dump.contains("  sp: 0x0000003ffffff000"));
+        assert!(dump.contains("  a0: 0x000000000000002a"));
+        assert!(dump.contains("  t6: 0x00000000deadbeef"));
+        // 31 registers in rows of four, after the epc line.
+        assert_eq!(dump.lines().count(), 9);
+    }
+
+    #[test_case]
+    fn test_instruction_fault() {
+        use crate::{
+            mem::{address::MAX_VA, TRAPFRAME},
+            proc::TaskList,
+        };
+
+        for (pc, mapped) in [(0x10_0000, false), (TRAPFRAME, true), (MAX_VA, false)] {
+            let mut tasks = TaskList::new();
+            tasks.user_init();
+            let mut task = tasks.get(&0).unwrap().write();
+            task.trap_frame.epc = pc;
+
+            let fault = kill_on_instruction_fault(&mut task, pc);
+            assert_eq!(fault, InstructionFault { pid: 0, pc, mapped });
+            assert!(format!("{}", fault).contains("instruction page fault"));
+            assert!(task.killed);
+        }
+    }
 
-        unsafe { handle(scause::read(), &mut proc_lock.trap_frame) };
+    #[test_case]
+    fn test_tick_wakes_current_task() {
+        use crate::intr::{ticks, wait_for_interrupt};
+
+        let task_lock = TASKS.read().current().unwrap().clone();
+        let state = task_lock.read().state;
+        {
+            let mut tasks = TASKS.write();
+            tasks.sleep(&mut task_lock.write(), ticks() + 1);
+        }
+
+        // The ticks are taken by `kerneltrap`, with the task asleep.
+        for _ in 0..10 {
+            if task_lock.read().state == State::Runnable {
+                break;
+            }
+            wait_for_interrupt();
+        }
+        assert!(task_lock.read().state == State::Runnable);
+        task_lock.write().state = state;
     }
 }
@@ -7,7 +7,6 @@
 // the #[no_main] attribute and provide our own entry point.
 #![reexport_test_harness_main = "test_main"]
 #![feature(alloc_error_handler)]
-#![feature(new_zeroed_alloc)]
 
 extern crate alloc;
 
@@ -17,18 +16,19 @@ use core::{arch::global_asm, panic::PanicInfo};
 use console::HexDump;
 use drivers::virtio::virtio_blk::VirtIOBlock;
 use fs::FileSystem;
-use log::{info, LevelFilter};
+use log::{error, info, LevelFilter};
 use mem::VIRTIO_MMIO_BASE;
 use sync::once_cell::OnceCell;
-use syscall;
 
 pub mod console;
 mod drivers;
+pub mod file;
 pub mod intr;
 pub mod logger;
 pub mod mem;
 pub mod proc;
 mod sync;
+pub mod syscall;
 
 // The entry point for this OS
 global_asm!(include_str!("boot/entry.S"));
@@ -59,9 +59,25 @@ pub fn init(hart_id: usize, _dtb_addr: usize) {
 }
 
 fn init_fs() {
+    // Name the task holding each block buffer if the cache runs out.
+    #[cfg(feature = "cache-debug")]
+    fs::cache_debug::set_current_holder(|| {
+        let tasks = proc::TASKS.try_read()?;
+        let task = tasks.current().ok()?.try_read()?;
+        Some(task.pid)
+    });
+
     match VirtIOBlock::init(VIRTIO_MMIO_BASE) {
         Ok(dev) => {
-            let fs = FileSystem::open(dev, true).expect("failed to open file system");
+            // Keep booting without the root file system, the files just
+            // can't be opened.
+            let fs = match FileSystem::open(dev, true) {
+                Ok(fs) => fs,
+                Err(err) => {
+                    error!("failed to mount the root file system: {}", err);
+                    return;
+                }
+            };
 
             let bin_file = fs
                 .get_inode_from_path("/bin/hello", &fs.root())
@@ -71,7 +87,9 @@ fn init_fs() {
                 let mut buf = [0u8; 4096];
                 let mut offset = 0;
                 loop {
-                    let size = fs.read_inode(&bin_file_guard, offset, &mut buf);
+                    let size = fs
+                        .read_inode(&bin_file_guard, offset, &mut buf)
+                        .expect("failed to read file");
                     println!("{}", HexDump(&buf[0..size]));
 
                     if size != buf.len() {
@@ -88,7 +106,7 @@ fn init_fs() {
     }
 }
 
-static ROOT_FS: OnceCell<Arc<FileSystem>> = OnceCell::new();
+pub(crate) static ROOT_FS: OnceCell<Arc<FileSystem>> = OnceCell::new();
 
 #[cfg(test)]
 #[no_mangle]
@@ -136,9 +154,14 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     if let Some(location) = info.location() {
-        println!("\n[panic] at {}:{} {}", location.file(), location.line(), info.message());
+        console::_print_panic(format_args!(
+            "\n[panic] at {}:{} {}\n",
+            location.file(),
+            location.line(),
+            info.message()
+        ));
     } else {
-        println!("[panic] {}", info.message());
+        console::_print_panic(format_args!("[panic] {}\n", info.message()));
     }
     syscall::shutdown()
 }
@@ -146,6 +169,6 @@ fn panic(info: &PanicInfo) -> ! {
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("\x1b[31m[test] failed\x1b[0m: {}\n", &info);
+    console::_print_panic(format_args!("\x1b[31m[test] failed\x1b[0m: {}\n\n", &info));
     syscall::shutdown()
 }
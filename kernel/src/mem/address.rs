@@ -1,4 +1,7 @@
-use core::slice::from_raw_parts_mut;
+use core::{mem::size_of, slice::from_raw_parts_mut};
+
+use super::PAGE_SIZE;
+use crate::{is_aligned, pa2va};
 
 /// A 64-bit physical address is split into three fields:
 ///
@@ -30,6 +33,10 @@ pub const PG_SHIFT: usize = 12;
 #[derive(Debug)]
 pub struct AddressNotAlignedError();
 
+/// The user virtual address is not mapped, or not accessible from user.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AddressNotMappedError(pub VirtualAddress);
+
 #[macro_export]
 macro_rules! pg_round_up {
     ($sz:expr, $pg_size:expr) => {{
@@ -60,6 +67,13 @@ macro_rules! memset {
     }};
 }
 
+/// Zeroes the page at `pa` a word at a time.
+pub unsafe fn zero_page(pa: PhysicalAddress) {
+    debug_assert!(is_aligned!(pa, PAGE_SIZE));
+    let words = from_raw_parts_mut(pa2va!(pa) as *mut u64, PAGE_SIZE / size_of::<u64>());
+    words.fill(0);
+}
+
 /// Converts the address to type `&'static mut T`.
 pub unsafe fn as_mut<T>(addr: Address) -> &'static mut T {
     (addr as *mut T).as_mut().expect("type cast error")
@@ -102,4 +116,21 @@ mod tests {
 
     #[test_case]
     fn test_px() {}
+
+    #[test_case]
+    fn test_zero_page() {
+        use alloc::boxed::Box;
+
+        let page = Box::into_raw(Box::new([0xffu8; PAGE_SIZE]));
+        let expected = Box::into_raw(Box::new([0xffu8; PAGE_SIZE]));
+        assert_eq!(page as usize % PAGE_SIZE, 0);
+
+        unsafe { zero_page(page as PhysicalAddress) };
+        crate::memset!(expected, 0, PAGE_SIZE);
+        unsafe {
+            assert_eq!(*page, *expected);
+            drop(Box::from_raw(page));
+            drop(Box::from_raw(expected));
+        }
+    }
 }
@@ -22,6 +22,8 @@ pub struct BuddyAllocator {
     free_lists: [Option<NonNull<FreeBlock>>; MAX_ORDER],
     start_addr: usize,
     end_addr:   usize,
+    /// The number of free pages.
+    free_count: usize,
 }
 
 impl BuddyAllocator {
@@ -30,6 +32,7 @@ impl BuddyAllocator {
             free_lists: [None; MAX_ORDER],
             start_addr: 0,
             end_addr:   0,
+            free_count: 0,
         }
     }
 
@@ -64,6 +67,7 @@ impl BuddyAllocator {
                 (*block).next = self.free_lists[order];
                 self.free_lists[order] = NonNull::new(block);
             }
+            self.free_count += current_size;
 
             addr += current_size * PAGE_SIZE;
         }
@@ -100,6 +104,11 @@ impl BuddyAllocator {
 
         Some(block)
     }
+
+    /// Returns the number of free pages.
+    pub fn free_pages_num(&self) -> usize {
+        self.free_count
+    }
 }
 
 impl FrameAllocator for BuddyAllocator {
@@ -121,6 +130,7 @@ impl FrameAllocator for BuddyAllocator {
             .and_then(|o| self.split_block(o, order));
 
         block_opt.map(|block| {
+            self.free_count -= pages;
             trace!(
                 "buddy_allocator: alloc {} pages: 0x{:x} - 0x{:x}",
                 pages,
@@ -144,6 +154,8 @@ impl FrameAllocator for BuddyAllocator {
         assert!(is_aligned!(addr, PAGE_SIZE), "addr must be page aligned");
 
         let mut order = order(pages);
+        // The block allocated is rounded up to a power of two.
+        self.free_count += 1 << order;
 
         // 尝试合并伙伴块
         let mut block_addr = addr;
@@ -247,10 +259,13 @@ mod tests {
         let mock_mem = MockMemory::new();
         let mut allocator = BuddyAllocator::new();
         allocator.init(mock_mem.start_addr(), mock_mem.end_addr());
+        let free = allocator.free_pages_num();
+        assert_eq!(free, mock_mem.data.len() / PAGE_SIZE);
 
         let addr1 = allocator.alloc_pages(1).unwrap();
         let addr2 = allocator.alloc_pages(2).unwrap();
         let addr4 = allocator.alloc_pages(4).unwrap();
+        assert_eq!(allocator.free_pages_num(), free - 7);
 
         assert_eq!(addr1 & (PAGE_SIZE - 1), 0);
         assert_eq!(addr2 & (PAGE_SIZE - 1), 0);
@@ -264,6 +279,7 @@ mod tests {
         allocator.free_pages(addr1, 1);
         allocator.free_pages(addr2, 2);
         allocator.free_pages(addr4, 4);
+        assert_eq!(allocator.free_pages_num(), free);
     }
 
     #[test_case]
@@ -0,0 +1,67 @@
+use core::alloc::Layout;
+
+use crate::{mem::PAGE_SIZE, pg_round_up};
+
+/// Hands out the memory of a range in order and never frees it.
+///
+/// Serves the allocations of early boot, e.g. the kernel page table,
+/// before the buddy allocator is set up.
+pub struct BumpAllocator {
+    start: usize,
+    next:  usize,
+    end:   usize,
+}
+
+impl BumpAllocator {
+    pub const fn new() -> Self {
+        Self {
+            start: 0,
+            next:  0,
+            end:   0,
+        }
+    }
+
+    pub fn init(&mut self, start: usize, end: usize) {
+        self.start = start;
+        self.next = start;
+        self.end = end;
+    }
+
+    pub fn alloc(&mut self, layout: Layout) -> Option<usize> {
+        let addr = self.next.checked_next_multiple_of(layout.align())?;
+        let next = addr.checked_add(layout.size())?;
+        if next > self.end {
+            return None;
+        }
+        self.next = next;
+        Some(addr)
+    }
+
+    /// Returns the end of the memory handed out, rounded up to a page.
+    pub fn watermark(&self) -> usize {
+        pg_round_up!(self.next, PAGE_SIZE)
+    }
+
+    /// Returns the number of pages handed out, partly or fully.
+    pub fn used_pages(&self) -> usize {
+        (self.watermark() - self.start) / PAGE_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_bump_alloc() {
+        let mut bump = BumpAllocator::new();
+        bump.init(0x8000_0000, 0x8000_0000 + 2 * PAGE_SIZE);
+
+        assert_eq!(bump.alloc(Layout::from_size_align(3, 1).unwrap()), Some(0x8000_0000));
+        let page = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+        assert_eq!(bump.alloc(page), Some(0x8000_1000));
+        assert_eq!(bump.used_pages(), 2);
+        assert_eq!(bump.alloc(page), None);
+        assert_eq!(bump.watermark(), 0x8000_2000);
+    }
+}
@@ -1,17 +1,27 @@
 use alloc::boxed::Box;
 use core::{
     alloc::{GlobalAlloc, Layout},
+    mem::size_of,
     ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
 use buddy_allocator::BuddyAllocator;
-use log::trace;
+use bump_allocator::BumpAllocator;
+use log::{info, trace};
 use slab_allocator::{SlabAllocator, MAX_SLAB_ORDER};
 use spin::Mutex;
 
-use crate::mem::{address::PhysicalAddress, PAGE_SIZE};
+use crate::{
+    mem::{
+        address::{zero_page, PhysicalAddress},
+        PAGE_SIZE,
+    },
+    va2pa,
+};
 
 mod buddy_allocator;
+mod bump_allocator;
 mod slab_allocator;
 
 pub trait FrameAllocator {
@@ -28,10 +38,26 @@ static FRAME_ALLOCATOR: Mutex<BuddyAllocator> = Mutex::new(BuddyAllocator::new()
 
 static SLAB_ALLOCATOR: SlabAllocator = SlabAllocator::new(&FRAME_ALLOCATOR);
 
+/// Serves the allocations from `init_early_allocator` until
+/// `init_allocator` hands over to the buddy allocator.
+static EARLY_ALLOCATOR: Mutex<BumpAllocator> = Mutex::new(BumpAllocator::new());
+
+static EARLY: AtomicBool = AtomicBool::new(false);
+
+/// The memory below it is the early allocator's, it's never freed.
+static EARLY_END: AtomicUsize = AtomicUsize::new(0);
+
 pub struct GlobalAllocator {}
 
 unsafe impl GlobalAlloc for GlobalAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if EARLY.load(Ordering::Acquire) {
+            return EARLY_ALLOCATOR
+                .lock()
+                .alloc(layout)
+                .map_or(null_mut(), |addr| addr as *mut u8);
+        }
+
         let order = order(layout.size());
         let result = if order > MAX_SLAB_ORDER {
             let pages = (layout.size() + (PAGE_SIZE - 1)) / PAGE_SIZE;
@@ -59,6 +85,10 @@ unsafe impl GlobalAlloc for GlobalAllocator {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if (ptr as usize) < EARLY_END.load(Ordering::Acquire) {
+            return;
+        }
+
         let order = order(layout.size());
         if order > MAX_SLAB_ORDER {
             let pages = (layout.size() + (PAGE_SIZE - 1)) / PAGE_SIZE;
@@ -74,20 +104,99 @@ unsafe impl GlobalAlloc for GlobalAllocator {
 #[global_allocator]
 static GLOBAL_ALLOCATOR: GlobalAllocator = GlobalAllocator {};
 
+/// Zeroes the user pages as they are freed, so that no process data
+/// outlives the process in the kernel heap.
+static SCRUB_USER_FRAMES: AtomicBool = AtomicBool::new(true);
+
+/// Sets whether `FromRawPage::free_user` zeroes the pages it frees.
+pub fn set_scrub_user_frames(scrub: bool) {
+    SCRUB_USER_FRAMES.store(scrub, Ordering::Release);
+}
+
+/// Returns the number of free pages in the frame allocator.
+///
+/// Pages cached by the slab allocator are not free here.
+pub fn free_pages_num() -> usize {
+    FRAME_ALLOCATOR.lock().free_pages_num()
+}
+
+/// Allocates `pages` physically contiguous zeroed pages, aligned to their
+/// size rounded up to a power of two.
+pub fn alloc_frames(pages: usize) -> Option<PhysicalAddress> {
+    let addr = FRAME_ALLOCATOR.lock().alloc_pages(pages)?;
+    for i in 0..pages {
+        unsafe { zero_page(addr + i * PAGE_SIZE) };
+    }
+    Some(addr)
+}
+
+/// Frees the pages allocated by `alloc_frames`.
+pub unsafe fn free_frames(addr: PhysicalAddress, pages: usize) {
+    FRAME_ALLOCATOR.lock().free_pages(addr, pages);
+}
+
+/// Serves the allocations from `[mem_start, mem_end)` until
+/// `init_allocator`.
+pub unsafe fn init_early_allocator(mem_start: PhysicalAddress, mem_end: PhysicalAddress) {
+    EARLY_ALLOCATOR.lock().init(mem_start, mem_end);
+    EARLY_END.store(mem_end, Ordering::Release);
+    EARLY.store(true, Ordering::Release);
+}
+
+/// Sets up the buddy allocator over `[mem_start, mem_end)`, taking over
+/// from the early allocator if it was used.
 pub unsafe fn init_allocator(mem_start: PhysicalAddress, mem_end: PhysicalAddress) {
-    FRAME_ALLOCATOR.lock().init(mem_start, mem_end);
+    let early = EARLY_ALLOCATOR.lock();
+    if EARLY.swap(false, Ordering::AcqRel) {
+        info!("allocator: {} pages allocated in early boot", early.used_pages());
+        EARLY_END.store(early.watermark(), Ordering::Release);
+    }
+    hand_off(&early, &mut FRAME_ALLOCATOR.lock(), mem_start, mem_end);
+}
+
+/// Sets up `buddy` over `[mem_start, mem_end)` but the memory `early`
+/// handed out, which stays allocated.
+fn hand_off(
+    early: &BumpAllocator,
+    buddy: &mut BuddyAllocator,
+    mem_start: PhysicalAddress,
+    mem_end: PhysicalAddress,
+) {
+    let start = if (mem_start..mem_end).contains(&early.watermark()) {
+        early.watermark()
+    } else {
+        mem_start
+    };
+    buddy.init(start, mem_end);
 }
 
 /// FromPage trait allocates a raw page from memory.
 /// The page must be freed manually.
 pub trait FromRawPage: Sized {
     unsafe fn new_zeroed() -> usize {
-        let boxed_page = Box::<Self>::new_zeroed().assume_init();
-        assert_eq!((&*boxed_page as *const _ as usize) % 4096, 0);
+        assert_eq!(size_of::<Self>(), PAGE_SIZE);
+        let ptr = Box::into_raw(Box::<Self>::new_uninit()) as usize;
+        assert_eq!(ptr % PAGE_SIZE, 0);
 
-        let ptr = Box::into_raw(boxed_page) as usize;
+        zero_page(va2pa!(ptr));
         ptr
     }
+
+    /// Frees the page allocated by `new_zeroed`.
+    unsafe fn free(addr: usize) {
+        drop(Box::from_raw(addr as *mut Self));
+    }
+
+    /// Frees the page a process used, zeroing it first unless scrubbing
+    /// is turned off by `set_scrub_user_frames`.
+    ///
+    /// `addr` is the kernel virtual address of the page, like `free`.
+    unsafe fn free_user(addr: usize) {
+        if SCRUB_USER_FRAMES.load(Ordering::Acquire) {
+            zero_page(va2pa!(addr));
+        }
+        Self::free(addr);
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -104,6 +213,67 @@ pub fn order(size: usize) -> usize {
 mod tests {
     use alloc::{boxed::Box, vec, vec::Vec};
 
+    use super::*;
+
+    #[test_case]
+    fn test_early_hand_off() {
+        let pages = 64;
+        let mem_start = alloc_frames(pages).unwrap();
+        let mem_end = mem_start + pages * PAGE_SIZE;
+
+        let mut early = BumpAllocator::new();
+        early.init(mem_start, mem_start + 16 * PAGE_SIZE);
+        let page = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+        let early_pages: Vec<usize> = (0..3).map(|_| early.alloc(page).unwrap()).collect();
+        early.alloc(Layout::new::<u64>()).unwrap();
+        assert_eq!(early.used_pages(), 4);
+
+        let mut buddy = BuddyAllocator::new();
+        hand_off(&early, &mut buddy, mem_start, mem_end);
+        assert_eq!(buddy.free_pages_num(), pages - 4);
+
+        // Freeing an early page doesn't make it free.
+        buddy.free_pages(early_pages[0], 1);
+        assert_eq!(buddy.free_pages_num(), pages - 4);
+
+        let mut allocated = Vec::new();
+        while let Some(addr) = buddy.alloc_pages(1) {
+            assert!(addr >= early.watermark() && addr < mem_end, "0x{:x}", addr);
+            allocated.push(addr);
+        }
+        assert_eq!(allocated.len(), pages - 4);
+
+        unsafe { free_frames(mem_start, pages) };
+    }
+
+    #[test_case]
+    fn test_alloc_frames_zeroed() {
+        let zeroed = |pa: PhysicalAddress| unsafe {
+            core::slice::from_raw_parts(pa as *const u64, PAGE_SIZE / 8)
+                .iter()
+                .all(|&w| w == 0)
+        };
+
+        let pa = alloc_frames(1).unwrap();
+        assert!(zeroed(pa));
+        crate::memset!(pa, 0xa5, PAGE_SIZE);
+        unsafe { free_frames(pa, 1) };
+
+        // The freed frame comes back zeroed, whenever it does.
+        let mut allocated = Vec::new();
+        while let Some(addr) = alloc_frames(1) {
+            assert!(zeroed(addr), "0x{:x}", addr);
+            allocated.push(addr);
+            if addr == pa {
+                break;
+            }
+        }
+        assert_eq!(allocated.last(), Some(&pa));
+        for addr in allocated {
+            unsafe { free_frames(addr, 1) };
+        }
+    }
+
     #[test_case]
     fn test_heap_alloc() {
         let a = Box::new(42);
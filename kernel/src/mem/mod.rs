@@ -1,4 +1,4 @@
-use allocator::{init_allocator, FromRawPage};
+use allocator::{init_allocator, init_early_allocator, FromRawPage};
 use log::info;
 
 use self::{
@@ -27,6 +27,10 @@ pub const TRAMPOLINE: Address = MAX_VA - PAGE_SIZE;
 /// The address of trap frame.
 pub const TRAPFRAME: Address = TRAMPOLINE - PAGE_SIZE;
 
+/// The memory after the kernel for the allocations before the buddy
+/// allocator is set up.
+const EARLY_MEM_SIZE: usize = 256 * PAGE_SIZE;
+
 /// MMIO base address.
 pub const VIRTIO_MMIO_BASE: Address = 0x1000_1000;
 
@@ -106,12 +110,13 @@ unsafe fn kvm_make() -> &'static mut PageTable {
 }
 
 pub unsafe fn init() {
-    assert_eq!(size_of::<PageTable>(), PAGE_SIZE);
-
     info!("Initializing memory...");
+    // The kernel page table is built before the buddy allocator, which
+    // takes the memory after it.
+    init_early_allocator(lp2addr!(end), lp2addr!(end) + EARLY_MEM_SIZE);
+    let kernel_pagetable = kvm_make();
     init_allocator(lp2addr!(end), MEM_END);
 
-    let kernel_pagetable = kvm_make();
     enable_paging(kernel_pagetable);
     info!("page_table: initialized.");
 }
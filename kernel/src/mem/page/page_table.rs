@@ -1,3 +1,4 @@
+use alloc::vec::Vec;
 use core::{
     arch::asm,
     fmt,
@@ -12,7 +13,9 @@ use riscv::register::satp;
 
 use crate::{
     mem::{
-        address::{as_mut, px, PhysicalAddress, VirtualAddress, MAX_VA, PG_SHIFT},
+        address::{
+            as_mut, px, AddressNotMappedError, PhysicalAddress, VirtualAddress, MAX_VA, PG_SHIFT,
+        },
         allocator::FromRawPage,
         PAGE_SIZE,
     },
@@ -108,6 +111,25 @@ impl PTE {
     pub fn is_executable(&self) -> bool {
         (self.flags() & PTEFlags::X) != PTEFlags::empty()
     }
+
+    /// Whether the page has been read, written or fetched since the
+    /// accessed bit was cleared.
+    pub fn is_accessed(&self) -> bool {
+        (self.flags() & PTEFlags::A) != PTEFlags::empty()
+    }
+
+    /// Whether the page has been written since the dirty bit was cleared.
+    pub fn is_dirty(&self) -> bool {
+        (self.flags() & PTEFlags::D) != PTEFlags::empty()
+    }
+
+    pub fn set_flags(&mut self, flags: PTEFlags) {
+        self.0 |= flags.bits();
+    }
+
+    pub fn clear_flags(&mut self, flags: PTEFlags) {
+        self.0 &= !flags.bits();
+    }
 }
 
 impl fmt::Display for PTE {
@@ -120,10 +142,43 @@ impl fmt::Display for PTE {
     }
 }
 
+/// A run of contiguous pages mapped to contiguous physical pages with
+/// the same permissions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MappedRegion {
+    pub va:    VirtualAddress,
+    pub pa:    PhysicalAddress,
+    pub size:  usize,
+    pub flags: PTEFlags,
+}
+
+impl fmt::Display for MappedRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let perm = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "[{:#x}..{:#x}] -> [{:#x}..{:#x}] {}{}{}{}",
+            self.va,
+            self.va + self.size,
+            self.pa,
+            self.pa + self.size,
+            perm(PTEFlags::R, 'R'),
+            perm(PTEFlags::W, 'W'),
+            perm(PTEFlags::X, 'X'),
+            perm(PTEFlags::U, 'U'),
+        )
+    }
+}
+
 #[repr(C, align(4096))]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct PageTable([PTE; PAGE_SIZE / size_of::<usize>()]);
 
+// Sv39 takes a page table as 512 entries of 8 bytes, a build of another
+// pointer width would walk them wrong.
+const _: () = assert!(size_of::<PTE>() == 8);
+const _: () = assert!(size_of::<PageTable>() == PAGE_SIZE);
+
 impl PageTable {
     pub const fn empty() -> Self {
         PageTable([PTE::empty(); PAGE_SIZE / size_of::<usize>()])
@@ -145,6 +200,228 @@ impl PageTable {
         };
     }
 
+    /// Copies the user memory `[0, size)` into `dst`, both the page table
+    /// entries and the physical pages.
+    pub fn user_vm_copy(&mut self, dst: &mut PageTable, size: usize) {
+        for va in (0..size).step_by(PAGE_SIZE) {
+            let pte = *self
+                .walk(va, false)
+                .expect("user_vm_copy: pte should exist");
+            assert!(pte.is_valid(), "user_vm_copy: page not present at 0x{:x}", va);
+
+            unsafe {
+                let page = RawPage::new_zeroed();
+                copy_nonoverlapping(pa2va!(pte.pa()) as *const u8, page as *mut u8, PAGE_SIZE);
+                dst.map(va, page, PAGE_SIZE, pte.flags() - PTEFlags::V);
+            }
+        }
+    }
+
+    /// Unmaps the user memory `[0, size)` and frees its pages.
+    pub fn user_vm_free(&mut self, size: usize) {
+        for va in (0..size).step_by(PAGE_SIZE) {
+            if let Some(pte) = self.unmap(va) {
+                unsafe { RawPage::free_user(pa2va!(pte.pa())) };
+            }
+        }
+    }
+
+    /// Frees the page-table pages below this one.
+    ///
+    /// The pages still mapped are not freed, they belong to someone else,
+    /// e.g. the trampoline.
+    pub fn free_walk(&mut self) {
+        self.free_walk_level(2);
+    }
+
+    fn free_walk_level(&mut self, level: usize) {
+        if level == 0 {
+            return;
+        }
+        for pte in self.iter_mut().filter(|pte| pte.is_valid()) {
+            let next: &mut PageTable = unsafe { as_mut(pa2va!(pte.pa())) };
+            next.free_walk_level(level - 1);
+            unsafe { PageTable::free(pa2va!(pte.pa())) };
+            *pte = PTE::empty();
+        }
+    }
+
+    /// Looks up the mapping of `va` without allocating, returns the
+    /// physical address `va` maps to and the flags of its page.
+    pub fn translate(&self, va: VirtualAddress) -> Option<(PhysicalAddress, PTEFlags)> {
+        if va >= MAX_VA {
+            return None;
+        }
+
+        let mut page_table = self;
+        for level in (1..3usize).rev() {
+            let pte = page_table[px(level, va)];
+            if !pte.is_valid() {
+                return None;
+            }
+            page_table = unsafe { as_mut(pa2va!(pte.pa())) };
+        }
+
+        let pte = page_table[px(0, va)];
+        if !pte.is_valid() {
+            return None;
+        }
+        Some((pte.pa() + va % PAGE_SIZE, pte.flags()))
+    }
+
+    /// Checks that the user range `[va, va + len)` is mapped with at
+    /// least the permissions `perm`, fails with the first address which
+    /// is not.
+    pub fn check_user_range(
+        &self,
+        va: VirtualAddress,
+        len: usize,
+        perm: PTEFlags,
+    ) -> Result<(), AddressNotMappedError> {
+        let end = va.checked_add(len).ok_or(AddressNotMappedError(va))?;
+        let mut page = pg_round_down!(va, PAGE_SIZE);
+        while page < end {
+            let addr = page.max(va);
+            match self.translate(addr) {
+                Some((_, flags)) if flags.contains(perm | PTEFlags::U) => {}
+                _ => return Err(AddressNotMappedError(addr)),
+            }
+            page += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Copies `src` to the user virtual address `dst_va`.
+    ///
+    /// Nothing is copied if a part of the destination is not writable.
+    pub fn copy_out(
+        &mut self,
+        mut dst_va: VirtualAddress,
+        src: &[u8],
+    ) -> Result<(), AddressNotMappedError> {
+        self.check_user_range(dst_va, src.len(), PTEFlags::W)?;
+
+        let mut copied = 0;
+        while copied < src.len() {
+            let (pa, _) = self.translate(dst_va).expect("checked above");
+            let n = (PAGE_SIZE - dst_va % PAGE_SIZE).min(src.len() - copied);
+            unsafe { copy_nonoverlapping(src[copied..].as_ptr(), pa2va!(pa) as *mut u8, n) };
+
+            copied += n;
+            dst_va += n;
+        }
+        Ok(())
+    }
+
+    /// Copies from the user virtual address `src_va` to `dst`.
+    pub fn copy_in(
+        &mut self,
+        dst: &mut [u8],
+        mut src_va: VirtualAddress,
+    ) -> Result<(), AddressNotMappedError> {
+        self.check_user_range(src_va, dst.len(), PTEFlags::R)?;
+
+        let mut copied = 0;
+        while copied < dst.len() {
+            let (pa, _) = self.translate(src_va).expect("checked above");
+            let n = (PAGE_SIZE - src_va % PAGE_SIZE).min(dst.len() - copied);
+            unsafe { copy_nonoverlapping(pa2va!(pa) as *const u8, dst[copied..].as_mut_ptr(), n) };
+
+            copied += n;
+            src_va += n;
+        }
+        Ok(())
+    }
+
+    /// Returns the pages accessed since the last scan, and clears their
+    /// accessed bits.
+    pub fn scan_and_clear_accessed(&mut self) -> Vec<VirtualAddress> {
+        let mut accessed = Vec::new();
+        self.for_each_leaf(2, 0, &mut |va, pte| {
+            if pte.is_accessed() {
+                pte.clear_flags(PTEFlags::A);
+                accessed.push(va);
+            }
+        });
+
+        if !accessed.is_empty() {
+            // The TLB may cache the accessed bits.
+            unsafe { asm!("sfence.vma") };
+        }
+        accessed
+    }
+
+    /// Summarizes the mappings, coalescing the contiguous pages with the
+    /// same permissions into one region.
+    ///
+    /// The accessed and dirty bits are ignored.
+    pub fn dump_mappings(&self) -> Vec<MappedRegion> {
+        let mut regions: Vec<MappedRegion> = Vec::new();
+        self.visit_leaves(2, 0, &mut |va, pte| {
+            let flags = pte.flags() - PTEFlags::V - PTEFlags::A - PTEFlags::D;
+            match regions.last_mut() {
+                Some(last)
+                    if last.va + last.size == va
+                        && last.pa + last.size == pte.pa()
+                        && last.flags == flags =>
+                {
+                    last.size += PAGE_SIZE;
+                }
+                _ => regions.push(MappedRegion {
+                    va,
+                    pa: pte.pa(),
+                    size: PAGE_SIZE,
+                    flags,
+                }),
+            }
+        });
+        regions
+    }
+
+    /// Like `for_each_leaf`, but the entries can't be modified.
+    fn visit_leaves(
+        &self,
+        level: usize,
+        base: VirtualAddress,
+        f: &mut impl FnMut(VirtualAddress, &PTE),
+    ) {
+        for (idx, pte) in self.iter().enumerate() {
+            if !pte.is_valid() {
+                continue;
+            }
+
+            let va = base | idx << (PG_SHIFT + 9 * level);
+            if level == 0 {
+                f(va, pte);
+            } else {
+                let next: &PageTable = unsafe { as_mut(pa2va!(pte.pa())) };
+                next.visit_leaves(level - 1, va, f);
+            }
+        }
+    }
+
+    /// Calls `f` with each valid level-0 entry and its virtual address.
+    fn for_each_leaf(
+        &mut self,
+        level: usize,
+        base: VirtualAddress,
+        f: &mut impl FnMut(VirtualAddress, &mut PTE),
+    ) {
+        for (idx, pte) in self.iter_mut().enumerate() {
+            if !pte.is_valid() {
+                continue;
+            }
+
+            let va = base | idx << (PG_SHIFT + 9 * level);
+            if level == 0 {
+                f(va, pte);
+            } else {
+                let next: &mut PageTable = unsafe { as_mut(pa2va!(pte.pa())) };
+                next.for_each_leaf(level - 1, va, f);
+            }
+        }
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &PTE> {
         self.0.iter()
     }
@@ -189,6 +466,20 @@ impl PageTable {
         }
     }
 
+    /// Removes the mapping of the page at `va`, returns the entry removed.
+    ///
+    /// The page itself is not freed.
+    pub fn unmap(&mut self, va: VirtualAddress) -> Option<PTE> {
+        let pte = self.walk(va, false)?;
+        if !pte.is_valid() {
+            return None;
+        }
+
+        let old = *pte;
+        *pte = PTE::empty();
+        Some(old)
+    }
+
     pub fn walk(&mut self, va: VirtualAddress, alloc: bool) -> Option<&mut PTE> {
         assert!(va < MAX_VA, "virtual address out of range: 0x{:x}", va);
 
@@ -279,7 +570,10 @@ impl IndexMut<usize> for PageTable {
 
 pub unsafe fn enable_paging(pagetable: &PageTable) {
     let token = pagetable.make_satp();
-    info!("page_table: enable paging with satp: 0x{:x}, {}", token, pagetable);
+    info!("page_table: enable paging with satp: 0x{:x}", token);
+    for region in pagetable.dump_mappings() {
+        info!("page_table: {}", region);
+    }
     satp::write(token);
     asm!("sfence.vma"); // clear tlb
 }
@@ -295,8 +589,17 @@ impl FromRawPage for RawPage {}
 
 #[cfg(test)]
 mod tests {
+    use alloc::{boxed::Box, format};
+
     use super::*;
 
+    #[test_case]
+    fn test_page_table_layout() {
+        // The sizes are asserted at compile time next to `PageTable`,
+        // only the number of entries walked is left to check.
+        assert_eq!(PageTable::empty().iter().count(), 512);
+    }
+
     #[test_case]
     fn test_walk() {
         let mut pt = PageTable::empty();
@@ -348,6 +651,170 @@ mod tests {
         assert_eq!(pte.pa(), pg_round_down!(pa, PAGE_SIZE));
     }
 
+    #[test_case]
+    fn test_scan_and_clear_accessed() {
+        let mut pt = PageTable::empty();
+        let va = 0x8000_0000;
+        let pa = 0x1000_0000;
+
+        unsafe {
+            pt.map(va, pa, 3 * PAGE_SIZE, PTEFlags::R | PTEFlags::W | PTEFlags::U);
+        }
+        assert!(pt.scan_and_clear_accessed().is_empty());
+
+        // Set the bits as the hardware does on access.
+        pt.walk(va, false).unwrap().set_flags(PTEFlags::A);
+        let pte = pt.walk(va + 2 * PAGE_SIZE, false).unwrap();
+        pte.set_flags(PTEFlags::A | PTEFlags::D);
+        assert!(pte.is_accessed());
+        assert!(pte.is_dirty());
+
+        assert_eq!(pt.scan_and_clear_accessed(), [va, va + 2 * PAGE_SIZE]);
+        let pte = pt.walk(va + 2 * PAGE_SIZE, false).unwrap();
+        assert!(!pte.is_accessed());
+        assert!(pte.is_dirty(), "the dirty bit should be kept");
+        assert!(pt.scan_and_clear_accessed().is_empty());
+    }
+
+    #[test_case]
+    fn test_dump_mappings() {
+        let mut pt = PageTable::empty();
+        let rw = PTEFlags::R | PTEFlags::W;
+        let rx = PTEFlags::R | PTEFlags::X;
+
+        unsafe {
+            // Mapped in two steps, but one region.
+            pt.map(0x8000_0000, 0x1000_0000, 2 * PAGE_SIZE, rw);
+            pt.map(0x8000_2000, 0x1000_2000, PAGE_SIZE, rw);
+            // Contiguous, but not with the same permissions.
+            pt.map(0x8000_3000, 0x1000_3000, PAGE_SIZE, rx);
+            // Contiguous virtual pages of discontiguous physical pages.
+            pt.map(0x9000_0000, 0x2000_0000, PAGE_SIZE, rw);
+            pt.map(0x9000_1000, 0x3000_0000, PAGE_SIZE, rw);
+            // Crosses a level-1 page table.
+            pt.map(0x801f_f000, 0x4000_0000, 2 * PAGE_SIZE, rw | PTEFlags::U);
+        }
+        pt.walk(0x8000_1000, false)
+            .unwrap()
+            .set_flags(PTEFlags::A | PTEFlags::D);
+
+        let region = |va, pa, pages, flags| MappedRegion {
+            va,
+            pa,
+            size: pages * PAGE_SIZE,
+            flags,
+        };
+        assert_eq!(
+            pt.dump_mappings(),
+            [
+                region(0x8000_0000, 0x1000_0000, 3, rw),
+                region(0x8000_3000, 0x1000_3000, 1, rx),
+                region(0x801f_f000, 0x4000_0000, 2, rw | PTEFlags::U),
+                region(0x9000_0000, 0x2000_0000, 1, rw),
+                region(0x9000_1000, 0x3000_0000, 1, rw),
+            ]
+        );
+        assert_eq!(
+            format!("{}", pt.dump_mappings()[1]),
+            "[0x80003000..0x80004000] -> [0x10003000..0x10004000] R-X-"
+        );
+    }
+
+    #[test_case]
+    fn test_check_user_range() {
+        let mut pt = PageTable::empty();
+        let va = 0x8000_0000;
+        let pa = 0x1000_0000;
+        let ro = PTEFlags::R | PTEFlags::U;
+
+        unsafe {
+            pt.map(va, pa, 2 * PAGE_SIZE, ro);
+            // A kernel page next to them.
+            pt.map(va + 2 * PAGE_SIZE, pa, PAGE_SIZE, PTEFlags::R | PTEFlags::W);
+        }
+        assert_eq!(pt.translate(va + 0x123), Some((pa + 0x123, ro | PTEFlags::V)));
+        assert_eq!(pt.translate(va + 3 * PAGE_SIZE), None);
+        assert_eq!(pt.translate(MAX_VA), None);
+
+        assert_eq!(pt.check_user_range(va + 100, 2 * PAGE_SIZE - 100, PTEFlags::R), Ok(()));
+        assert_eq!(
+            pt.check_user_range(va + 100, 8, PTEFlags::W),
+            Err(AddressNotMappedError(va + 100))
+        );
+        assert_eq!(
+            pt.check_user_range(va, 2 * PAGE_SIZE + 1, PTEFlags::R),
+            Err(AddressNotMappedError(va + 2 * PAGE_SIZE))
+        );
+        assert_eq!(
+            pt.check_user_range(usize::MAX, 2, PTEFlags::R),
+            Err(AddressNotMappedError(usize::MAX))
+        );
+    }
+
+    #[test_case]
+    fn test_copy_out_pages() {
+        let mut pt = PageTable::empty();
+        let va = 0x8000_0000;
+        let rw = PTEFlags::R | PTEFlags::W | PTEFlags::U;
+        // Two frames not next to each other, mapped in the reverse order.
+        let frames = unsafe { [RawPage::new_zeroed(), RawPage::new_zeroed()] };
+        unsafe {
+            pt.map(va, frames[1], PAGE_SIZE, rw);
+            pt.map(va + PAGE_SIZE, frames[0], PAGE_SIZE, rw);
+        }
+
+        let data: Vec<u8> = (0..2 * PAGE_SIZE)
+            .map(|i| (i / PAGE_SIZE + 1) as u8)
+            .collect();
+        pt.copy_out(va, &data).unwrap();
+        let page = |pa| unsafe { core::slice::from_raw_parts(pa2va!(pa) as *const u8, PAGE_SIZE) };
+        assert!(page(frames[1]).iter().all(|&b| b == 1));
+        assert!(
+            page(frames[0]).iter().all(|&b| b == 2),
+            "the second page is not a copy of the first"
+        );
+
+        let mut back = alloc::vec![0u8; 2 * PAGE_SIZE];
+        pt.copy_in(&mut back, va).unwrap();
+        assert_eq!(back, data);
+
+        pt.unmap(va);
+        pt.unmap(va + PAGE_SIZE);
+        for frame in frames {
+            unsafe { RawPage::free(frame) };
+        }
+        pt.free_walk();
+    }
+
+    #[test_case]
+    fn test_user_vm_free_scrubs() {
+        let mut pt = PageTable::empty();
+        let rw = PTEFlags::R | PTEFlags::W | PTEFlags::U;
+        let frame = unsafe { RawPage::new_zeroed() };
+        unsafe { pt.map(0, frame, PAGE_SIZE, rw) };
+        pt.copy_out(0, &[0xa5; PAGE_SIZE]).unwrap();
+
+        pt.user_vm_free(PAGE_SIZE);
+        pt.free_walk();
+
+        // Take the frame back without zeroing it.
+        let mut pages = Vec::new();
+        loop {
+            let page = Box::into_raw(Box::<RawPage>::new_uninit()) as usize;
+            pages.push(page);
+            if page == frame {
+                break;
+            }
+            assert!(pages.len() < 64, "the freed frame is not reused");
+        }
+        let data = unsafe { core::slice::from_raw_parts(frame as *const u8, PAGE_SIZE) };
+        assert!(!data.contains(&0xa5), "the freed frame leaks user data");
+
+        for page in pages {
+            unsafe { RawPage::free(page) };
+        }
+    }
+
     // #[test_case]
     // fn test_map_capacity() {
     //     let mut pt = PageTable::empty();
@@ -0,0 +1,159 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::Task;
+use crate::pg_round_down;
+
+/// The maximum number of arguments passed to a program.
+pub const MAXARG: usize = 32;
+
+/// The stack pointer alignment of the RISC-V calling convention.
+const STACK_ALIGN: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ArgsError {
+    /// More than `MAXARG` arguments.
+    TooMany(usize),
+    /// The arguments don't fit in the user stack.
+    StackOverflow,
+}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgsError::TooMany(n) => write!(f, "{} arguments, at most {}", n, MAXARG),
+            ArgsError::StackOverflow => write!(f, "arguments overflow the stack"),
+        }
+    }
+}
+
+impl Task {
+    /// Puts `argv` on the user stack below `trap_frame.sp`, so the
+    /// program starts as `main(argc, argv)`.
+    ///
+    /// The strings are copied first, NUL-terminated, then the array of
+    /// pointers to them ending with a null pointer. `sp` and `a1` point
+    /// to the array, aligned to 16 bytes, and `a0` is the count.
+    pub fn push_args(&mut self, argv: &[&str]) -> Result<(), ArgsError> {
+        if argv.len() > MAXARG {
+            return Err(ArgsError::TooMany(argv.len()));
+        }
+        let page_table = self.page_table.as_mut().expect("no user page table");
+
+        let mut sp = self.trap_frame.sp;
+        let mut ptrs = Vec::with_capacity(argv.len() + 1);
+        for arg in argv {
+            let mut bytes = Vec::from(arg.as_bytes());
+            bytes.push(0);
+            sp = sp
+                .checked_sub(bytes.len())
+                .ok_or(ArgsError::StackOverflow)?;
+            page_table
+                .copy_out(sp, &bytes)
+                .map_err(|_| ArgsError::StackOverflow)?;
+            ptrs.push(sp);
+        }
+        ptrs.push(0);
+
+        let bytes: Vec<u8> = ptrs.iter().flat_map(|ptr| ptr.to_ne_bytes()).collect();
+        sp = sp
+            .checked_sub(bytes.len())
+            .ok_or(ArgsError::StackOverflow)?;
+        sp = pg_round_down!(sp, STACK_ALIGN);
+        page_table
+            .copy_out(sp, &bytes)
+            .map_err(|_| ArgsError::StackOverflow)?;
+
+        self.trap_frame.sp = sp;
+        self.trap_frame.a0 = argv.len();
+        self.trap_frame.a1 = sp;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec};
+
+    use super::*;
+    use crate::{
+        mem::{page::PageTable, PAGE_SIZE},
+        proc::TaskList,
+    };
+
+    /// Reads back `argv` from the user memory of `task`, like the
+    /// program would.
+    fn read_args(task: &mut Task) -> Vec<String> {
+        let page_table = task.page_table.as_mut().unwrap();
+        let word = |page_table: &mut PageTable, va| {
+            let mut buf = [0u8; size_of::<usize>()];
+            page_table.copy_in(&mut buf, va).unwrap();
+            usize::from_ne_bytes(buf)
+        };
+
+        (0..task.trap_frame.a0)
+            .map(|i| {
+                let mut ptr = word(page_table, task.trap_frame.a1 + i * size_of::<usize>());
+                let mut arg = Vec::new();
+                let mut byte = [0u8];
+                loop {
+                    page_table.copy_in(&mut byte, ptr).unwrap();
+                    if byte[0] == 0 {
+                        break;
+                    }
+                    arg.push(byte[0]);
+                    ptr += 1;
+                }
+                String::from_utf8(arg).unwrap()
+            })
+            .collect()
+    }
+
+    #[test_case]
+    fn test_push_args() {
+        let mut tasks = TaskList::new();
+        let task_lock = tasks.new_task().unwrap().clone();
+        let mut task = task_lock.write();
+        task.init_user_page_table();
+        task.page_table
+            .as_mut()
+            .unwrap()
+            .user_vm_init(&vec![0u8; PAGE_SIZE]);
+        task.trap_frame.sp = PAGE_SIZE;
+
+        let argv = ["echo", "", "hello, world"];
+        task.push_args(&argv).unwrap();
+        assert_eq!(task.trap_frame.a0, 3);
+        assert_eq!(task.trap_frame.a1, task.trap_frame.sp);
+        assert_eq!(task.trap_frame.sp % STACK_ALIGN, 0);
+        assert_eq!(read_args(&mut task), argv);
+
+        // The array ends with a null pointer.
+        let mut null = [0xffu8; size_of::<usize>()];
+        let end = task.trap_frame.a1 + 3 * size_of::<usize>();
+        task.page_table
+            .as_mut()
+            .unwrap()
+            .copy_in(&mut null, end)
+            .unwrap();
+        assert_eq!(usize::from_ne_bytes(null), 0);
+    }
+
+    #[test_case]
+    fn test_push_args_overflow() {
+        let mut tasks = TaskList::new();
+        let task_lock = tasks.new_task().unwrap().clone();
+        let mut task = task_lock.write();
+        task.init_user_page_table();
+        task.page_table
+            .as_mut()
+            .unwrap()
+            .user_vm_init(&vec![0u8; PAGE_SIZE]);
+        task.trap_frame.sp = PAGE_SIZE;
+
+        let long = String::from_utf8(vec![b'x'; PAGE_SIZE]).unwrap();
+        assert_eq!(task.push_args(&[long.as_str()]), Err(ArgsError::StackOverflow));
+        assert_eq!(task.push_args(&["x"; MAXARG + 1]), Err(ArgsError::TooMany(MAXARG + 1)));
+        assert_eq!(task.trap_frame.sp, PAGE_SIZE, "left as it was");
+    }
+}
@@ -30,3 +30,28 @@ pub struct Context {
     pub s10: usize,
     pub s11: usize,
 }
+
+impl Context {
+    /// The context a new task is first switched to, `switch_to` returns
+    /// to `entry` on the kernel stack whose top is `kernel_sp`.
+    pub fn new_task(entry: usize, kernel_sp: usize) -> Self {
+        Self {
+            ra: entry,
+            sp: kernel_sp,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_new_task_context() {
+        let context = Context::new_task(0x8020_0000, 0x8040_0000);
+        assert_eq!((context.ra, context.sp), (0x8020_0000, 0x8040_0000));
+        assert_eq!(context.s0, 0);
+        assert_eq!(context.s11, 0);
+    }
+}
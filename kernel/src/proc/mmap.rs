@@ -0,0 +1,129 @@
+use alloc::sync::Arc;
+use core::arch::asm;
+
+use fs::inode::Inode;
+use log::warn;
+use spin::Mutex;
+
+use crate::{
+    mem::{
+        address::{as_u8_slice, VirtualAddress},
+        allocator::FromRawPage,
+        page::{PTEFlags, PageTable, RawPage},
+        PAGE_SIZE,
+    },
+    pa2va, pg_round_down,
+};
+
+/// Where the mappings without a requested address start.
+pub const MMAP_BASE: VirtualAddress = 0x10_0000_0000;
+
+/// A file mapped into user memory.
+///
+/// The pages are loaded from the file on the first page fault. Each page
+/// is a copy of the file data, so writes to a private mapping never
+/// reach the file, and writes to a shared one are written back when
+/// it's unmapped.
+pub struct Mmap {
+    pub start: VirtualAddress,
+    pub len:   usize,
+    inode:     Arc<Mutex<Inode>>,
+    /// The file offset mapped at `start`.
+    offset:    usize,
+    perm:      PTEFlags,
+    shared:    bool,
+    /// Number of pages loaded from the file.
+    loaded:    usize,
+}
+
+impl Mmap {
+    pub fn new(
+        start: VirtualAddress,
+        len: usize,
+        inode: Arc<Mutex<Inode>>,
+        offset: usize,
+        perm: PTEFlags,
+        shared: bool,
+    ) -> Self {
+        Self {
+            start,
+            len,
+            inode,
+            offset,
+            perm,
+            shared,
+            loaded: 0,
+        }
+    }
+
+    pub fn end(&self) -> VirtualAddress {
+        self.start + self.len
+    }
+
+    pub fn contains(&self, va: VirtualAddress) -> bool {
+        self.start <= va && va < self.end()
+    }
+
+    pub fn loaded(&self) -> usize {
+        self.loaded
+    }
+
+    /// Loads the page containing `va` from the file and maps it.
+    pub fn load_page(&mut self, page_table: &mut PageTable, va: VirtualAddress) {
+        let va = pg_round_down!(va, PAGE_SIZE);
+        let offset = self.offset + (va - self.start);
+        let len = PAGE_SIZE.min(self.end() - va);
+
+        let page = unsafe { RawPage::new_zeroed() };
+        let inode = self.inode.lock();
+        if offset < inode.size() {
+            let fs = inode.get_fs().expect("file system has been dropped");
+            let buf = unsafe { as_u8_slice(pa2va!(page), len) };
+            if let Err(err) = fs.read_inode(&inode, offset, buf) {
+                warn!("mmap: failed to load the page at offset {}: {}", offset, err);
+            }
+        }
+
+        // Set the accessed bit, otherwise the hardware may fault again
+        // to set it.
+        unsafe { page_table.map(va, page, PAGE_SIZE, self.perm | PTEFlags::U | PTEFlags::A) };
+        self.loaded += 1;
+    }
+
+    /// Unmaps the loaded pages and frees them, the written pages of a
+    /// shared mapping are written back to the file.
+    pub fn unmap(&self, page_table: &mut PageTable) {
+        for va in (self.start..self.end()).step_by(PAGE_SIZE) {
+            let Some(pte) = page_table.unmap(va) else {
+                continue;
+            };
+
+            if self.shared && pte.is_dirty() {
+                self.write_back(va, pte.pa());
+            }
+            unsafe { RawPage::free_user(pa2va!(pte.pa())) };
+        }
+        unsafe { asm!("sfence.vma") };
+    }
+
+    fn write_back(&self, va: VirtualAddress, page: usize) {
+        let offset = self.offset + (va - self.start);
+        let mut inode = self.inode.lock();
+        // Don't grow the file by the part of the page past its end.
+        let len = PAGE_SIZE
+            .min(self.end() - va)
+            .min(inode.size().saturating_sub(offset));
+        if len == 0 {
+            return;
+        }
+
+        let fs = inode.get_fs().expect("file system has been dropped");
+        match fs.write_inode(&mut inode, offset, unsafe { as_u8_slice(pa2va!(page), len) }) {
+            Ok(written) if written != len => {
+                warn!("mmap: short write back at offset {}: {}/{}", offset, written, len);
+            }
+            Ok(_) => {}
+            Err(err) => warn!("mmap: failed to write back at offset {}: {}", offset, err),
+        }
+    }
+}
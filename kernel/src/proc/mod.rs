@@ -1,13 +1,24 @@
-use core::arch::global_asm;
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use log::{debug, info};
-use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
-
-pub use self::{backtrace::*, context::Context, task::*, task_list::*};
-use crate::{mem::PAGE_SIZE, println};
-
+use spin::{RwLockReadGuard, RwLockWriteGuard};
+
+pub use self::{args::*, backtrace::*, context::Context, mmap::*, task::*, task_list::*};
+use crate::{
+    intr::wait_for_interrupt,
+    mem::PAGE_SIZE,
+    println,
+    sync::intr_lock::{IntrGuard, IntrRwLock},
+    syscall::shutdown,
+};
+
+mod args;
 mod backtrace;
 mod context;
+mod mmap;
 mod task;
 mod task_list;
 
@@ -22,13 +33,14 @@ pub const KERNEL_STACK_SIZE: usize = PAGE_SIZE * 2;
 /// The default user stack size.
 pub const USER_STACK_SIZE: usize = PAGE_SIZE * 2;
 
-pub static TASKS: RwLock<TaskList> = RwLock::new(TaskList::new());
+/// Taken by the timer interrupt to wake up the sleepers.
+pub static TASKS: IntrRwLock<TaskList> = IntrRwLock::new(TaskList::new());
 
-pub fn tasks() -> RwLockReadGuard<'static, TaskList> {
+pub fn tasks() -> IntrGuard<RwLockReadGuard<'static, TaskList>> {
     TASKS.read()
 }
 
-pub fn tasks_mut() -> RwLockWriteGuard<'static, TaskList> {
+pub fn tasks_mut() -> IntrGuard<RwLockWriteGuard<'static, TaskList>> {
     TASKS.write()
 }
 
@@ -38,23 +50,48 @@ extern "C" {
     fn switch_to(old: *mut Context, new: *const Context);
 }
 
+/// Counts the times the scheduler waited for an interrupt.
+pub static IDLE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 pub fn schedule() -> ! {
-    let init_proc_context: *const Context;
-    {
-        let tasks = tasks();
-        let init_proc = tasks.get(&0).unwrap();
-        {
-            let init_proc_lock = init_proc.read();
-            init_proc_context = &init_proc_lock.context;
+    let next_proc_context = loop {
+        if let Some(context) = pick_next(&TASKS) {
+            break context;
         }
-    }
+    };
 
     info!("switching to next process...");
-    unsafe { switch_to(&mut Context::default(), init_proc_context) }
+    unsafe { switch_to(&mut Context::default(), next_proc_context) }
 
     panic!("unreachable.")
 }
 
+/// Returns the context of the next runnable task.
+///
+/// If none is runnable but some are waiting, waits for an interrupt
+/// that may wake them up and returns `None`. Shuts down if there is
+/// nothing to wait for.
+fn pick_next(tasks: &IntrRwLock<TaskList>) -> Option<*const Context> {
+    {
+        let mut tasks = tasks.write();
+        if let Some(next_proc) = tasks.next_runnable() {
+            let mut next_proc = next_proc.write();
+            next_proc.switches += 1;
+            return Some(&next_proc.context as *const _);
+        }
+        if !tasks.has_waiting() {
+            info!("no runnable process, shutting down...");
+            shutdown()
+        }
+    }
+
+    // The timer needs the task list to wake up the sleepers, so it
+    // must not be held here.
+    IDLE_COUNT.fetch_add(1, Ordering::Relaxed);
+    wait_for_interrupt();
+    None
+}
+
 pub fn init() {
     info!("Initializing processes...");
     {
@@ -66,6 +103,57 @@ pub fn init() {
 
 #[cfg(test)]
 mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test_case]
+    fn test_idle_when_all_sleeping() {
+        let tasks = IntrRwLock::new(TaskList::new());
+        let sleeper = {
+            let mut tasks = tasks.write();
+            for _ in 0..2 {
+                let task_lock = tasks.new_task().unwrap().clone();
+                tasks.sleep(&mut task_lock.write(), 1);
+            }
+            tasks.get(&0).unwrap().clone()
+        };
+
+        let idle = IDLE_COUNT.load(Ordering::Relaxed);
+        assert!(pick_next(&tasks).is_none());
+        assert!(pick_next(&tasks).is_none());
+        assert_eq!(IDLE_COUNT.load(Ordering::Relaxed), idle + 2);
+
+        tasks.write().wake_sleepers(1);
+        let context = pick_next(&tasks).expect("no task woken up");
+        assert_eq!(IDLE_COUNT.load(Ordering::Relaxed), idle + 2);
+        assert!(core::ptr::eq(context, &sleeper.read().context));
+    }
+
+    #[test_case]
+    fn test_round_robin_fair() {
+        const TASKS_NUM: usize = 3;
+        const SWITCHES: usize = 100;
+        let tasks = IntrRwLock::new(TaskList::new());
+        let mut list = tasks.write();
+        // Tasks that never block, as if the timer preempted them.
+        let runnable: Vec<_> = (0..TASKS_NUM)
+            .map(|_| {
+                let task_lock = list.new_task().unwrap().clone();
+                task_lock.write().state = State::Runnable;
+                task_lock
+            })
+            .collect();
+        drop(list);
+
+        for _ in 0..TASKS_NUM * SWITCHES {
+            assert!(pick_next(&tasks).is_some());
+        }
+        let switches: Vec<usize> = runnable.iter().map(|task| task.read().switches).collect();
+        let (min, max) = (switches.iter().min().unwrap(), switches.iter().max().unwrap());
+        assert!(max - min <= 1, "unfair switches: {:?}", switches);
+        assert_eq!(switches.iter().sum::<usize>(), TASKS_NUM * SWITCHES);
+    }
 
     // extern fn spawned_task() {
     //     println!("Spawn new task finished");
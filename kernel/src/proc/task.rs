@@ -1,8 +1,11 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use core::pin::Pin;
 
-use super::Context;
+use log::debug;
+
+use super::{Context, Mmap};
 use crate::{
+    file::{OpenFile, NOFILE},
     intr::{trampoline, TrapFrame},
     mem::{
         page::{PTEFlags, PageTable},
@@ -15,13 +18,25 @@ pub type TaskId = u64;
 
 pub struct Task {
     pub pid:          TaskId,
+    /// The parent task id, the first task is the parent of itself.
+    pub parent:       TaskId,
     pub state:        State,
+    /// Set by `kill`, the task exits on its next trap.
+    pub killed:       bool,
     /// The kernel stack is part of the kernel space. Hence,
     /// it is not directly accessible from a user process.
     pub kernel_stack: Pin<Box<[u8]>>,
     pub context:      Context,
     pub trap_frame:   TrapFrame,
     pub page_table:   Option<Pin<Box<PageTable>>>,
+    /// Size of user memory (bytes).
+    pub mem_size:     usize,
+    /// Open files, indexed by fd.
+    pub files:        [Option<Arc<OpenFile>>; NOFILE],
+    /// Memory-mapped files.
+    pub mmaps:        Vec<Mmap>,
+    /// Times the scheduler switched to this task.
+    pub switches:     usize,
 }
 
 impl Task {
@@ -49,6 +64,58 @@ impl Task {
         }
         self.page_table = Some(page_table);
     }
+
+    /// Installs `file` at the lowest free fd, returns the fd.
+    pub fn alloc_fd(&mut self, file: Arc<OpenFile>) -> Option<usize> {
+        let fd = self.files.iter().position(Option::is_none)?;
+        self.files[fd] = Some(file);
+        Some(fd)
+    }
+
+    pub fn file(&self, fd: usize) -> Option<&Arc<OpenFile>> {
+        self.files.get(fd)?.as_ref()
+    }
+
+    /// Removes `fd` from the open files, returns the file it referred to.
+    pub fn close_fd(&mut self, fd: usize) -> Option<Arc<OpenFile>> {
+        self.files.get_mut(fd)?.take()
+    }
+
+    /// Loads the page containing `va` if it's in a mapped file, returns
+    /// false if `va` is not mapped by this task or the page is already
+    /// loaded, i.e. the access is not permitted.
+    pub fn handle_page_fault(&mut self, va: usize) -> bool {
+        let Some(mmap) = self.mmaps.iter_mut().find(|m| m.contains(va)) else {
+            return false;
+        };
+        let Some(page_table) = self.page_table.as_mut() else {
+            return false;
+        };
+        if page_table.walk(va, false).is_some_and(|pte| pte.is_valid()) {
+            return false;
+        }
+        mmap.load_page(page_table, va);
+        true
+    }
+
+    /// Terminates this task and releases its user memory.
+    ///
+    /// The task stays in the task list with its exit code until reaped,
+    /// which frees its kernel stack.
+    pub fn exit(&mut self, code: i32) {
+        debug!("proc: task {} exited with code {}", self.pid, code);
+        self.state = State::Exited(code);
+        if let Some(page_table) = self.page_table.as_mut() {
+            for mmap in self.mmaps.drain(..) {
+                mmap.unmap(page_table);
+            }
+            page_table.user_vm_free(self.mem_size);
+            page_table.free_walk();
+        }
+        self.page_table = None;
+        self.mem_size = 0;
+        self.files = Default::default();
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -1,12 +1,20 @@
-use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    vec::Vec,
+};
 
-use log::{debug, info};
+use log::{debug, info, warn};
 use spin::RwLock;
 
 use super::{State, Task, TaskId, MAX_PROC};
 use crate::{
+    file::{OpenFile, NOFILE},
     intr::{usertrapret, TrapFrame},
+    mem::PAGE_SIZE,
     proc::{Context, KERNEL_STACK_SIZE},
+    sync::wait_channel::Wait,
 };
 
 // a user program that calls exec("/init")
@@ -23,16 +31,36 @@ static INITCODE: [u8; 52] = [
     0x00, 0x00, 0x00, 0x00
 ];
 
+/// The id of the first task, the parent of the orphans.
+const FIRST_TASK: TaskId = 0;
+
+// One bit of `TaskList::used_ids` for each task id.
+const _: () = assert!(MAX_PROC <= u64::BITS as u64);
+
 pub struct TaskList {
-    tasks:   BTreeMap<TaskId, Arc<RwLock<Task>>>,
-    next_id: u64,
+    tasks:    BTreeMap<TaskId, Arc<RwLock<Task>>>,
+    /// The ids taken by the tasks not reaped yet, bit `i` for id `i`.
+    used_ids: u64,
+    /// Sleeping tasks ordered by the tick they wake up at.
+    sleepers: BTreeSet<(usize, TaskId)>,
+    /// Tasks sleeping until a child exits, with the child they wait
+    /// for, `None` for any.
+    waiters:  BTreeMap<TaskId, Option<TaskId>>,
+    /// Tasks sleeping on a wait channel until it's woken up.
+    blocked:  BTreeMap<TaskId, Wait>,
+    /// The task `next_runnable` returned last time.
+    last_run: Option<TaskId>,
 }
 
 impl TaskList {
     pub const fn new() -> Self {
         TaskList {
-            tasks:   BTreeMap::new(),
-            next_id: 0,
+            tasks:    BTreeMap::new(),
+            used_ids: 0,
+            sleepers: BTreeSet::new(),
+            waiters:  BTreeMap::new(),
+            blocked:  BTreeMap::new(),
+            last_run: None,
         }
     }
 
@@ -40,16 +68,30 @@ impl TaskList {
         self.tasks.get(id)
     }
 
-    pub fn alloc_pid(&mut self) -> TaskId {
-        self.next_id += 1;
-        self.next_id - 1
+    /// Returns the number of tasks not reaped yet.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
     }
 
-    pub fn new_task(&mut self) -> Result<&Arc<RwLock<Task>>, ()> {
-        let pid = self.alloc_pid();
-        if pid > MAX_PROC {
-            panic!("too many processes.")
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Takes the lowest free task id, `None` if `MAX_PROC` ids are taken.
+    pub fn alloc_pid(&mut self) -> Option<TaskId> {
+        let pid = (!self.used_ids).trailing_zeros() as TaskId;
+        if pid >= MAX_PROC {
+            return None;
         }
+        self.used_ids |= 1 << pid;
+        Some(pid)
+    }
+
+    pub fn new_task(&mut self) -> Result<&Arc<RwLock<Task>>, ()> {
+        let Some(pid) = self.alloc_pid() else {
+            warn!("proc: no free task id, {} tasks alive", MAX_PROC);
+            return Err(());
+        };
 
         let kernel_stack = Box::pin([0u8; KERNEL_STACK_SIZE]);
         let mut trap_frame = TrapFrame::default();
@@ -57,20 +99,32 @@ impl TaskList {
         trap_frame.epc = 0; // user program counter
         trap_frame.sp = kernel_stack.len(); // user stack pointer
 
-        let mut context = Context::default();
         // Set up new context to start executing at `usertrapret`,
         // which returns to user space. Since, we set `sp` to kernel
         // stack temporarily.
-        context.ra = usertrapret as usize;
-        context.sp = kernel_stack.as_ptr() as usize + kernel_stack.len();
+        let context = Context::new_task(
+            usertrapret as usize,
+            kernel_stack.as_ptr() as usize + kernel_stack.len(),
+        );
+
+        // The console is the standard input, output and error.
+        let console = Arc::new(OpenFile::Console);
+        let mut files: [Option<Arc<OpenFile>>; NOFILE] = Default::default();
+        files[..3].fill(Some(console));
 
         let task = Task {
             pid,
+            parent: FIRST_TASK,
             state: State::Init,
+            killed: false,
             kernel_stack,
             context,
             trap_frame,
             page_table: None,
+            mem_size: 0,
+            files,
+            mmaps: Vec::new(),
+            switches: 0,
         };
 
         assert!(self
@@ -82,6 +136,247 @@ impl TaskList {
         Ok(self.tasks.get(&pid).unwrap())
     }
 
+    /// Creates a new task as a copy of `parent`.
+    pub fn fork(&mut self, parent: &mut Task) -> Result<&Arc<RwLock<Task>>, ()> {
+        let child_lock = self.new_task()?;
+        {
+            let mut child = child_lock.write();
+            child.parent = parent.pid;
+
+            // Copy user memory from parent to child.
+            child.init_user_page_table();
+            if let Some(page_table) = parent.page_table.as_mut() {
+                page_table.user_vm_copy(child.page_table.as_mut().unwrap(), parent.mem_size);
+            }
+            child.mem_size = parent.mem_size;
+            child.files = parent.files.clone();
+
+            // Cause fork to return 0 in the child.
+            child.trap_frame = parent.trap_frame.clone();
+            child.trap_frame.a0 = 0;
+
+            child.state = State::Runnable;
+        }
+        debug!("proc: task {} forked from {}", child_lock.read().pid, parent.pid);
+
+        Ok(child_lock)
+    }
+
+    /// Finds the next runnable task in round-robin order, starting after
+    /// the one returned last time.
+    ///
+    /// The tasks whose wait channels were woken up are runnable again.
+    pub fn next_runnable(&mut self) -> Option<&Arc<RwLock<Task>>> {
+        self.wake_blocked();
+        let start = self.last_run.map_or(0, |pid| pid + 1);
+        let (&pid, _) = self
+            .tasks
+            .range(start..)
+            .chain(self.tasks.range(..start))
+            .find(|(_, task)| task.read().state == State::Runnable)?;
+        self.last_run = Some(pid);
+        self.tasks.get(&pid)
+    }
+
+    /// Puts `task` to sleep until the tick `wake_tick`.
+    pub fn sleep(&mut self, task: &mut Task, wake_tick: usize) {
+        debug!("proc: task {} sleeps until tick {}", task.pid, wake_tick);
+        task.state = State::Sleeping;
+        self.sleepers.insert((wake_tick, task.pid));
+    }
+
+    /// Wakes up the sleeping tasks whose wake tick is not after `now`.
+    pub fn wake_sleepers(&mut self, now: usize) {
+        while let Some(&(wake_tick, pid)) = self.sleepers.first() {
+            if wake_tick > now {
+                break;
+            }
+            self.sleepers.pop_first();
+            self.wake(pid);
+        }
+    }
+
+    /// Puts `task` to sleep until the channel of `wait` is woken up.
+    pub fn sleep_on(&mut self, task: &mut Task, wait: Wait) {
+        debug!("proc: task {} sleeps on a wait channel", task.pid);
+        task.state = State::Sleeping;
+        self.blocked.insert(task.pid, wait);
+    }
+
+    /// Wakes up the tasks whose wait channels were woken up.
+    ///
+    /// Checked by the scheduler rather than by the channels, which are
+    /// woken up with the task list held, e.g. by closing the files of an
+    /// exiting task.
+    fn wake_blocked(&mut self) {
+        let woken: Vec<TaskId> = self
+            .blocked
+            .iter()
+            .filter(|(_, wait)| wait.woken())
+            .map(|(&pid, _)| pid)
+            .collect();
+        for pid in woken {
+            self.blocked.remove(&pid);
+            self.wake(pid);
+        }
+    }
+
+    fn wake(&self, pid: TaskId) {
+        if let Some(task) = self.tasks.get(&pid) {
+            let mut task = task.write();
+            if task.state == State::Sleeping {
+                task.state = State::Runnable;
+            }
+        }
+    }
+
+    /// Marks the task `pid` as killed, wakes it up if it's sleeping.
+    pub fn kill(&mut self, pid: TaskId) -> Result<(), ()> {
+        let task_lock = self.tasks.get(&pid).ok_or(())?;
+        task_lock.write().killed = true;
+        self.sleepers.retain(|&(_, sleeper)| sleeper != pid);
+        self.waiters.remove(&pid);
+        self.blocked.remove(&pid);
+        self.wake(pid);
+        Ok(())
+    }
+
+    /// Terminates `task` with the exit `code`, wakes up its parent if
+    /// it's waiting for this child.
+    ///
+    /// The children of `task` are given to the first task, which reaps
+    /// them, like `reparent` in xv6.
+    pub fn exit(&mut self, task: &mut Task, code: i32) {
+        task.exit(code);
+        // The first task is the parent of itself.
+        if task.parent != task.pid {
+            self.wake_parent(task.parent, task.pid);
+        }
+        self.reparent(task.pid);
+    }
+
+    /// Wakes up `parent` if it's waiting for its exited `child`.
+    fn wake_parent(&mut self, parent: TaskId, child: TaskId) {
+        let waited = match self.waiters.get(&parent) {
+            Some(None) => true,
+            Some(&Some(pid)) => pid == child,
+            None => false,
+        };
+        if waited {
+            self.waiters.remove(&parent);
+            self.wake(parent);
+        }
+    }
+
+    fn reparent(&mut self, parent: TaskId) {
+        let mut exited = Vec::new();
+        for (&pid, task) in self.tasks.iter() {
+            // The parent itself is locked by the caller, and the first
+            // task is the parent of itself.
+            if pid == parent || pid == FIRST_TASK {
+                continue;
+            }
+            let mut task = task.write();
+            if task.parent == parent {
+                task.parent = FIRST_TASK;
+                if let State::Exited(_) = task.state {
+                    exited.push(pid);
+                }
+            }
+        }
+        for pid in exited {
+            self.wake_parent(FIRST_TASK, pid);
+        }
+    }
+
+    /// Returns the children of `parent` with their exit codes, `None`
+    /// for the ones not exited yet.
+    fn children(&self, parent: TaskId) -> impl Iterator<Item = (TaskId, Option<i32>)> + '_ {
+        self.tasks
+            .iter()
+            // The parent itself is locked by the caller.
+            .filter(move |&(&pid, _)| pid != parent)
+            .filter_map(move |(&pid, task)| {
+                let task = task.read();
+                let code = match task.state {
+                    State::Exited(code) => Some(code),
+                    _ => None,
+                };
+                (task.parent == parent).then_some((pid, code))
+            })
+    }
+
+    /// Reaps an exited child of `task`, returns its pid and exit code.
+    ///
+    /// If none has exited, puts `task` to sleep until one does and
+    /// returns `None`. Fails if `task` has no children.
+    pub fn wait(&mut self, task: &mut Task) -> Result<Option<(TaskId, i32)>, ()> {
+        let mut children = self.children(task.pid).peekable();
+        children.peek().ok_or(())?;
+        let exited = children.find_map(|(pid, code)| Some((pid, code?)));
+        match exited {
+            Some((pid, code)) => {
+                self.reap(pid);
+                Ok(Some((pid, code)))
+            }
+            None => {
+                self.sleep_on_child(task, None);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reaps the child `pid` of `task` if it has exited, returns its
+    /// exit code.
+    ///
+    /// Otherwise puts `task` to sleep until that child exits, the other
+    /// children don't wake it up. Fails if `pid` is not a child of `task`.
+    pub fn waitpid(&mut self, task: &mut Task, pid: TaskId) -> Result<Option<i32>, ()> {
+        let (_, code) = self
+            .children(task.pid)
+            .find(|&(child, _)| child == pid)
+            .ok_or(())?;
+        match code {
+            Some(code) => {
+                self.reap(pid);
+                Ok(Some(code))
+            }
+            None => {
+                self.sleep_on_child(task, Some(pid));
+                Ok(None)
+            }
+        }
+    }
+
+    fn sleep_on_child(&mut self, task: &mut Task, child: Option<TaskId>) {
+        debug!("proc: task {} waits for child {:?}", task.pid, child);
+        task.state = State::Sleeping;
+        self.waiters.insert(task.pid, child);
+    }
+
+    /// Removes the exited task `pid`, returns its exit code.
+    ///
+    /// The task is freed with its kernel stack once the last reference
+    /// is dropped.
+    pub fn reap(&mut self, pid: TaskId) -> Option<i32> {
+        let State::Exited(code) = self.tasks.get(&pid)?.read().state else {
+            return None;
+        };
+        self.tasks.remove(&pid);
+        self.waiters.remove(&pid);
+        self.blocked.remove(&pid);
+        self.used_ids &= !(1 << pid);
+        debug!("proc: reaped task {}", pid);
+        Some(code)
+    }
+
+    /// Returns whether any task is waiting to become runnable again.
+    pub fn has_waiting(&self) -> bool {
+        self.tasks
+            .values()
+            .any(|task| matches!(task.read().state, State::Sleeping | State::Blocked))
+    }
+
     pub fn current(&self) -> Result<&Arc<RwLock<Task>>, ()> {
         // TODO:
         self.tasks.get(&0).ok_or(())
@@ -101,8 +396,183 @@ impl TaskList {
                 .unwrap()
                 .as_mut()
                 .user_vm_init(&INITCODE);
+            task.mem_size = PAGE_SIZE;
 
             task.state = State::Runnable;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::slice::from_raw_parts;
+
+    use super::*;
+    use crate::{
+        mem::{allocator::free_pages_num, page::PTEFlags},
+        pa2va,
+    };
+
+    #[test_case]
+    fn test_user_init() {
+        let mut tasks = TaskList::new();
+        tasks.user_init();
+
+        let mut task = tasks.get(&0).expect("init task not created").write();
+        assert!(task.state == State::Runnable);
+        assert_eq!(task.context.ra, usertrapret as usize);
+        assert_eq!(task.context.sp, task.kernel_stack.as_ptr() as usize + KERNEL_STACK_SIZE);
+
+        // The init program is loaded at 0 and starts from its first byte.
+        let entry = task.trap_frame.epc;
+        assert_eq!(entry, 0);
+        let pte = *task
+            .page_table
+            .as_mut()
+            .expect("init task has no page table")
+            .walk(entry, false)
+            .expect("entry is not mapped");
+        assert!(pte.is_valid() && pte.is_executable());
+        assert!(pte.flags().contains(PTEFlags::U));
+
+        let code = unsafe { from_raw_parts(pa2va!(pte.pa()) as *const u8, INITCODE.len()) };
+        assert_eq!(code, &INITCODE);
+    }
+
+    #[test_case]
+    fn test_sleep_wakes_after_ticks() {
+        const N: usize = 5;
+        let mut tasks = TaskList::new();
+        let task_lock = tasks.new_task().unwrap().clone();
+        let start = 100;
+        tasks.sleep(&mut task_lock.write(), start + N);
+
+        for now in start..start + N {
+            tasks.wake_sleepers(now);
+            assert!(task_lock.read().state == State::Sleeping, "woken at tick {}", now);
+        }
+        tasks.wake_sleepers(start + N);
+        assert!(task_lock.read().state == State::Runnable);
+        assert!(tasks.sleepers.is_empty());
+    }
+
+    #[test_case]
+    fn test_kill_wakes_sleeper() {
+        let mut tasks = TaskList::new();
+        let task_lock = tasks.new_task().unwrap().clone();
+        tasks.sleep(&mut task_lock.write(), 1000);
+
+        let pid = task_lock.read().pid;
+        tasks.kill(pid).unwrap();
+        let task = task_lock.read();
+        assert!(task.killed);
+        assert!(task.state == State::Runnable);
+        assert!(tasks.sleepers.is_empty());
+    }
+
+    /// Spawns a task with user memory, forks it, then exits and reaps
+    /// both.
+    fn spawn_and_exit(tasks: &mut TaskList) {
+        let parent_lock = tasks.new_task().unwrap().clone();
+        let mut parent = parent_lock.write();
+        parent.init_user_page_table();
+        parent.page_table.as_mut().unwrap().user_vm_init(&INITCODE);
+        parent.mem_size = PAGE_SIZE;
+
+        let child_lock = tasks.fork(&mut parent).unwrap().clone();
+        let mut child = child_lock.write();
+        child.exit(0);
+        parent.exit(1);
+
+        let (parent_pid, child_pid) = (parent.pid, child.pid);
+        drop((parent, child));
+        assert_eq!(tasks.reap(child_pid), Some(0));
+        assert_eq!(tasks.reap(parent_pid), Some(1));
+    }
+
+    #[test_case]
+    fn test_waitpid_woken_by_target() {
+        let mut tasks = TaskList::new();
+        let parent_lock = tasks.new_task().unwrap().clone();
+        let mut parent = parent_lock.write();
+        let first_lock = tasks.fork(&mut parent).unwrap().clone();
+        let second_lock = tasks.fork(&mut parent).unwrap().clone();
+        let first = first_lock.read().pid;
+        let second = second_lock.read().pid;
+
+        assert_eq!(tasks.waitpid(&mut parent, second), Ok(None));
+        assert!(parent.state == State::Sleeping);
+        drop(parent);
+
+        // The other child exiting doesn't wake the parent up.
+        tasks.exit(&mut first_lock.write(), 1);
+        assert!(parent_lock.read().state == State::Sleeping);
+        tasks.exit(&mut second_lock.write(), 2);
+        assert!(parent_lock.read().state == State::Runnable);
+
+        let mut parent = parent_lock.write();
+        assert_eq!(tasks.waitpid(&mut parent, second), Ok(Some(2)));
+        assert_eq!(tasks.waitpid(&mut parent, second), Err(()));
+        assert_eq!(tasks.wait(&mut parent), Ok(Some((first, 1))));
+        assert_eq!(tasks.wait(&mut parent), Err(()));
+        assert!(tasks.waiters.is_empty());
+    }
+
+    #[test_case]
+    fn test_exit_reparents_children() {
+        let mut tasks = TaskList::new();
+        let first_lock = tasks.new_task().unwrap().clone();
+        let parent_lock = tasks.fork(&mut first_lock.write()).unwrap().clone();
+        let mut parent = parent_lock.write();
+        let live_lock = tasks.fork(&mut parent).unwrap().clone();
+        let exited_lock = tasks.fork(&mut parent).unwrap().clone();
+        tasks.exit(&mut exited_lock.write(), 3);
+        let (live, exited) = (live_lock.read().pid, exited_lock.read().pid);
+        let parent_pid = parent.pid;
+
+        assert_eq!(tasks.waitpid(&mut first_lock.write(), parent_pid), Ok(None));
+        tasks.exit(&mut parent, 1);
+        drop(parent);
+        assert_eq!(live_lock.read().parent, FIRST_TASK);
+        assert_eq!(exited_lock.read().parent, FIRST_TASK);
+        assert!(first_lock.read().state == State::Runnable);
+
+        let mut first = first_lock.write();
+        assert_eq!(tasks.wait(&mut first), Ok(Some((parent_pid, 1))));
+        assert_eq!(tasks.waitpid(&mut first, exited), Ok(Some(3)));
+        // The orphan still running wakes up the first task on exit.
+        assert_eq!(tasks.wait(&mut first), Ok(None));
+        drop(first);
+        tasks.exit(&mut live_lock.write(), 0);
+        assert!(first_lock.read().state == State::Runnable);
+        assert_eq!(tasks.wait(&mut first_lock.write()), Ok(Some((live, 0))));
+    }
+
+    #[test_case]
+    fn test_task_ids_recycled() {
+        let mut tasks = TaskList::new();
+        for pid in 0..MAX_PROC {
+            assert_eq!(tasks.new_task().unwrap().read().pid, pid);
+        }
+        assert!(tasks.new_task().is_err());
+
+        let pid = 5;
+        tasks.get(&pid).unwrap().write().exit(0);
+        assert_eq!(tasks.reap(pid), Some(0));
+        assert_eq!(tasks.new_task().unwrap().read().pid, pid);
+        assert!(tasks.new_task().is_err());
+    }
+
+    #[test_case]
+    fn test_exit_reclaims_memory() {
+        let mut tasks = TaskList::new();
+        // The first round may leave pages in the slab caches.
+        spawn_and_exit(&mut tasks);
+        let baseline = free_pages_num();
+
+        for round in 0..16 {
+            spawn_and_exit(&mut tasks);
+            assert_eq!(free_pages_num(), baseline, "pages leaked in round {}", round);
+        }
+    }
+}
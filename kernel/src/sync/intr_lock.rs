@@ -0,0 +1,152 @@
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+use spin::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::intr::{pop_off, push_off};
+
+/// A `Mutex` keeping the interrupts of the hart disabled while held, so
+/// an interrupt handler taking it can't spin on the hart holding it.
+pub struct IntrMutex<T> {
+    inner: Mutex<T>,
+}
+
+/// A `RwLock` keeping the interrupts of the hart disabled while held,
+/// see `IntrMutex`.
+pub struct IntrRwLock<T> {
+    inner: RwLock<T>,
+}
+
+/// The guard of an interrupt-disabling lock, enables the interrupts
+/// again after releasing the lock.
+pub struct IntrGuard<G> {
+    guard: ManuallyDrop<G>,
+}
+
+impl<G> IntrGuard<G> {
+    fn lock(lock: impl FnOnce() -> G) -> Self {
+        push_off();
+        Self {
+            guard: ManuallyDrop::new(lock()),
+        }
+    }
+
+    fn try_lock(lock: impl FnOnce() -> Option<G>) -> Option<Self> {
+        push_off();
+        match lock() {
+            Some(guard) => Some(Self {
+                guard: ManuallyDrop::new(guard),
+            }),
+            None => {
+                pop_off();
+                None
+            }
+        }
+    }
+}
+
+impl<G: Deref> Deref for IntrGuard<G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut> DerefMut for IntrGuard<G> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<G> Drop for IntrGuard<G> {
+    fn drop(&mut self) {
+        // Released before enabling the interrupts, not to be taken by
+        // a handler in between.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        pop_off();
+    }
+}
+
+impl<T> IntrMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> IntrGuard<MutexGuard<'_, T>> {
+        IntrGuard::lock(|| self.inner.lock())
+    }
+
+    pub fn try_lock(&self) -> Option<IntrGuard<MutexGuard<'_, T>>> {
+        IntrGuard::try_lock(|| self.inner.try_lock())
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+impl<T> IntrRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: RwLock::new(value),
+        }
+    }
+
+    pub fn read(&self) -> IntrGuard<RwLockReadGuard<'_, T>> {
+        IntrGuard::lock(|| self.inner.read())
+    }
+
+    pub fn write(&self) -> IntrGuard<RwLockWriteGuard<'_, T>> {
+        IntrGuard::lock(|| self.inner.write())
+    }
+
+    pub fn try_read(&self) -> Option<IntrGuard<RwLockReadGuard<'_, T>>> {
+        IntrGuard::try_lock(|| self.inner.try_read())
+    }
+
+    pub fn try_write(&self) -> Option<IntrGuard<RwLockWriteGuard<'_, T>>> {
+        IntrGuard::try_lock(|| self.inner.try_write())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use riscv::register::sstatus;
+
+    use super::*;
+
+    #[test_case]
+    fn test_intr_lock_disables_interrupts() {
+        let lock = IntrMutex::new(0);
+        let was_enabled = sstatus::read().sie();
+        unsafe { sstatus::set_sie() };
+
+        let mut guard = lock.lock();
+        assert!(!sstatus::read().sie());
+        *guard += 1;
+        // Taken by a handler while held, it fails rather than spinning.
+        assert!(lock.try_lock().is_none());
+        assert!(!sstatus::read().sie());
+        drop(guard);
+        assert!(sstatus::read().sie());
+        assert!(!lock.is_locked());
+
+        let rwlock = IntrRwLock::new(0);
+        {
+            let _read = rwlock.read();
+            let _nested = rwlock.try_read().unwrap();
+            assert!(rwlock.try_write().is_none());
+        }
+        assert!(sstatus::read().sie());
+        *rwlock.write() += 1;
+
+        if !was_enabled {
+            unsafe { sstatus::clear_sie() };
+        }
+    }
+}
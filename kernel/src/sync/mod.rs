@@ -1 +1,3 @@
+pub mod intr_lock;
 pub mod once_cell;
+pub mod wait_channel;
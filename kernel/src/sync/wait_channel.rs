@@ -0,0 +1,46 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A channel for tasks to sleep on until the condition they wait for
+/// changes, like `sleep` and `wakeup` in xv6.
+///
+/// The caller takes a `Wait` before checking the condition, so a wakeup
+/// in between can't be lost.
+pub struct WaitChannel {
+    seq: Arc<AtomicUsize>,
+}
+
+/// A wait on a `WaitChannel`, woken up by the next wakeup of it.
+pub struct Wait {
+    seq:   Arc<AtomicUsize>,
+    start: usize,
+}
+
+impl Wait {
+    /// Returns whether the channel was woken up since this wait started.
+    pub fn woken(&self) -> bool {
+        self.seq.load(Ordering::Acquire) != self.start
+    }
+}
+
+impl WaitChannel {
+    pub fn new() -> Self {
+        Self {
+            seq: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Starts waiting for the next wakeup, the task sleeps on the
+    /// returned `Wait` if the condition doesn't hold.
+    pub fn wait(&self) -> Wait {
+        Wait {
+            seq:   self.seq.clone(),
+            start: self.seq.load(Ordering::Acquire),
+        }
+    }
+
+    /// Wakes up all tasks sleeping on this channel.
+    pub fn wakeup(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+    }
+}
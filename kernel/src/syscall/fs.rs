@@ -0,0 +1,215 @@
+use alloc::{sync::Arc, vec};
+use core::{mem::size_of, slice::from_raw_parts};
+
+use ::syscall::{Dirent, Errno, DT_DIR, DT_FIFO, DT_REG, DT_UNKNOWN};
+use fs::block_dev::InodeType;
+use log::warn;
+
+use crate::{
+    file::{self, FileError, Pipe, NOFILE},
+    proc::{tasks_mut, Task},
+    sync::wait_channel::{Wait, WaitChannel},
+};
+
+/// Opens the file at the user string `[path, path + len)`, returns the fd.
+pub fn sys_open(task: &mut Task, path: usize, len: usize, flags: u32) -> isize {
+    let mut buf = vec![0u8; len];
+    if let Err(err) = task.page_table.as_mut().unwrap().copy_in(&mut buf, path) {
+        warn!("sys_open: {:?}", err);
+        return Errno::EFAULT.as_ret();
+    }
+    let Ok(path) = core::str::from_utf8(&buf) else {
+        return Errno::EINVAL.as_ret();
+    };
+
+    match file::open(path, flags) {
+        Ok((file, wait)) => match task.alloc_fd(Arc::new(file)) {
+            Some(fd) => {
+                // Returns the fd once the other end of the FIFO is opened.
+                if let Some(wait) = wait {
+                    tasks_mut().sleep_on(task, wait);
+                }
+                fd as isize
+            }
+            None => Errno::EMFILE.as_ret(),
+        },
+        Err(err) => {
+            warn!("sys_open: {}: {}", path, err);
+            Errno::from(err).as_ret()
+        }
+    }
+}
+
+/// Creates a pipe and puts its read fd and write fd in the user
+/// array `fds`.
+pub fn sys_pipe(task: &mut Task, fds: usize) -> isize {
+    let (reader, writer) = Pipe::new();
+    let Some(rfd) = task.alloc_fd(Arc::new(reader)) else {
+        return Errno::EMFILE.as_ret();
+    };
+    let Some(wfd) = task.alloc_fd(Arc::new(writer)) else {
+        task.close_fd(rfd);
+        return Errno::EMFILE.as_ret();
+    };
+
+    let mut buf = [0u8; 2 * size_of::<i32>()];
+    buf[..4].copy_from_slice(&(rfd as i32).to_ne_bytes());
+    buf[4..].copy_from_slice(&(wfd as i32).to_ne_bytes());
+    match task.page_table.as_mut().unwrap().copy_out(fds, &buf) {
+        Ok(_) => 0,
+        Err(err) => {
+            warn!("sys_pipe: {:?}", err);
+            task.close_fd(rfd);
+            task.close_fd(wfd);
+            Errno::EFAULT.as_ret()
+        }
+    }
+}
+
+/// Duplicates `fd` to the lowest free fd, both refer to the same
+/// open file.
+pub fn sys_dup(task: &mut Task, fd: usize) -> isize {
+    let Some(file) = task.file(fd).cloned() else {
+        return Errno::EBADF.as_ret();
+    };
+    match task.alloc_fd(file) {
+        Some(new) => new as isize,
+        None => Errno::EMFILE.as_ret(),
+    }
+}
+
+/// Makes `new` refer to the open file of `old`, closes `new` first
+/// if it's open.
+pub fn sys_dup2(task: &mut Task, old: usize, new: usize) -> isize {
+    let Some(file) = task.file(old).cloned() else {
+        return Errno::EBADF.as_ret();
+    };
+    if new >= NOFILE {
+        return Errno::EBADF.as_ret();
+    }
+
+    task.files[new] = Some(file);
+    new as isize
+}
+
+pub fn sys_read(task: &mut Task, fd: usize, buf_va: usize, len: usize) -> isize {
+    let Some(file) = task.file(fd).cloned() else {
+        return Errno::EBADF.as_ret();
+    };
+
+    let mut buf = vec![0u8; len];
+    // Taken before reading, not to miss the wakeup of a write in between.
+    let wait = file.wait_channel(false).map(WaitChannel::wait);
+    let n = match (file.read(&mut buf), wait) {
+        (Ok(n), _) => n,
+        (Err(FileError::WouldBlock), Some(wait)) => return block(task, wait),
+        (Err(err), _) => {
+            warn!("sys_read: fd {}: {}", fd, err);
+            return Errno::from(err).as_ret();
+        }
+    };
+    match task
+        .page_table
+        .as_mut()
+        .unwrap()
+        .copy_out(buf_va, &buf[..n])
+    {
+        Ok(_) => n as isize,
+        Err(err) => {
+            warn!("sys_read: {:?}", err);
+            Errno::EFAULT.as_ret()
+        }
+    }
+}
+
+pub fn sys_write(task: &mut Task, fd: usize, buf_va: usize, len: usize) -> isize {
+    let Some(file) = task.file(fd).cloned() else {
+        return Errno::EBADF.as_ret();
+    };
+
+    let mut buf = vec![0u8; len];
+    if let Err(err) = task.page_table.as_mut().unwrap().copy_in(&mut buf, buf_va) {
+        warn!("sys_write: {:?}", err);
+        return Errno::EFAULT.as_ret();
+    }
+    let wait = file.wait_channel(true).map(WaitChannel::wait);
+    match (file.write(&buf), wait) {
+        (Ok(n), _) => n as isize,
+        (Err(FileError::WouldBlock), Some(wait)) => block(task, wait),
+        (Err(err), _) => {
+            warn!("sys_write: fd {}: {}", fd, err);
+            Errno::from(err).as_ret()
+        }
+    }
+}
+
+/// Puts `task` to sleep until `wait` is woken up, the syscall is made
+/// again then.
+///
+/// Returns the first argument, which is kept in `a0` for the syscall
+/// made again.
+fn block(task: &mut Task, wait: Wait) -> isize {
+    tasks_mut().sleep_on(task, wait);
+    // Back to the `ecall`, which `usertrap` moved past.
+    task.trap_frame.epc -= 4;
+    task.trap_frame.a0 as isize
+}
+
+/// Fills the user buffer `[buf_va, buf_va + len)` with the next entries
+/// of the directory `fd`, returns the bytes filled, 0 at the end.
+///
+/// Only whole `Dirent`s are filled, the entries not fitting are left to
+/// the next call.
+pub fn sys_getdents(task: &mut Task, fd: usize, buf_va: usize, len: usize) -> isize {
+    let Some(file) = task.file(fd).cloned() else {
+        return Errno::EBADF.as_ret();
+    };
+    let max = len / size_of::<Dirent>();
+    if max == 0 {
+        return Errno::EINVAL.as_ret();
+    }
+
+    let items = match file.read_dir(max) {
+        Ok(items) => items,
+        Err(err) => {
+            warn!("sys_getdents: fd {}: {}", fd, err);
+            return Errno::from(err).as_ret();
+        }
+    };
+    let mut buf = vec![0u8; items.len() * size_of::<Dirent>()];
+    for (item, chunk) in items.iter().zip(buf.chunks_exact_mut(size_of::<Dirent>())) {
+        let mut dirent = Dirent {
+            ino: item.inode_num,
+            type_: dirent_type(item.type_),
+            name_len: item.name.len() as u8,
+            ..Default::default()
+        };
+        dirent.name[..item.name.len()].copy_from_slice(item.name.as_bytes());
+        chunk.copy_from_slice(unsafe {
+            from_raw_parts(&dirent as *const Dirent as *const u8, size_of::<Dirent>())
+        });
+    }
+    match task.page_table.as_mut().unwrap().copy_out(buf_va, &buf) {
+        Ok(_) => buf.len() as isize,
+        Err(err) => {
+            warn!("sys_getdents: {:?}", err);
+            Errno::EFAULT.as_ret()
+        }
+    }
+}
+
+fn dirent_type(type_: InodeType) -> u8 {
+    match type_ {
+        InodeType::File => DT_REG,
+        InodeType::Directory => DT_DIR,
+        InodeType::Fifo => DT_FIFO,
+        InodeType::Invalid => DT_UNKNOWN,
+    }
+}
+
+pub fn sys_close(task: &mut Task, fd: usize) -> isize {
+    match task.close_fd(fd) {
+        Some(_) => 0,
+        None => Errno::EBADF.as_ret(),
+    }
+}
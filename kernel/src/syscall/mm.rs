@@ -0,0 +1,97 @@
+use ::syscall::{Errno, MAP_PRIVATE, MAP_SHARED, PROT_EXEC, PROT_READ, PROT_WRITE};
+use log::warn;
+
+use crate::{
+    file::OpenFile,
+    is_aligned,
+    mem::{page::PTEFlags, PAGE_SIZE, TRAPFRAME},
+    pg_round_up,
+    proc::{Mmap, Task, MMAP_BASE},
+};
+
+/// Maps `len` bytes of the file `fd` from `offset` into the user memory
+/// of `task`, returns the start address of the mapping.
+///
+/// The pages are loaded lazily, see `Task::handle_page_fault`. If `addr`
+/// is 0 the kernel picks the address.
+pub fn sys_mmap(
+    task: &mut Task,
+    addr: usize,
+    len: usize,
+    prot: u32,
+    flags: u32,
+    fd: usize,
+    offset: usize,
+) -> isize {
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Errno::EINVAL.as_ret(),
+    };
+    if len == 0 || !is_aligned!(addr, PAGE_SIZE) || !is_aligned!(offset, PAGE_SIZE) {
+        return Errno::EINVAL.as_ret();
+    }
+
+    let Some(OpenFile::Inode(file)) = task.file(fd).map(|f| f.as_ref()) else {
+        warn!("sys_mmap: fd {} is not a file", fd);
+        return Errno::EBADF.as_ret();
+    };
+    // Writes to a private mapping never reach the file, so it only
+    // needs to be readable.
+    if !file.readable() || (shared && prot & PROT_WRITE != 0 && !file.writable()) {
+        return Errno::EACCES.as_ret();
+    }
+    let inode = file.inode().clone();
+
+    let mut perm = PTEFlags::empty();
+    if prot & PROT_READ != 0 {
+        perm |= PTEFlags::R;
+    }
+    if prot & PROT_WRITE != 0 {
+        perm |= PTEFlags::R | PTEFlags::W;
+    }
+    if prot & PROT_EXEC != 0 {
+        perm |= PTEFlags::X;
+    }
+    if perm.is_empty() {
+        return Errno::EINVAL.as_ret();
+    }
+
+    let len = pg_round_up!(len, PAGE_SIZE);
+    let start = match addr {
+        0 => task.mmaps.iter().map(Mmap::end).max().unwrap_or(MMAP_BASE),
+        _ => addr,
+    };
+    let Some(end) = start.checked_add(len) else {
+        return Errno::ENOMEM.as_ret();
+    };
+    if start < task.mem_size
+        || end > TRAPFRAME
+        || task.mmaps.iter().any(|m| start < m.end() && m.start < end)
+    {
+        warn!("sys_mmap: bad range 0x{:x}-0x{:x}", start, end);
+        return Errno::EINVAL.as_ret();
+    }
+
+    task.mmaps
+        .push(Mmap::new(start, len, inode, offset, perm, shared));
+    start as isize
+}
+
+/// Removes the mapping created by `sys_mmap` at `addr`.
+pub fn sys_munmap(task: &mut Task, addr: usize, len: usize) -> isize {
+    let len = pg_round_up!(len, PAGE_SIZE);
+    let Some(idx) = task
+        .mmaps
+        .iter()
+        .position(|m| m.start == addr && m.len == len)
+    else {
+        return Errno::EINVAL.as_ret();
+    };
+
+    let mmap = task.mmaps.remove(idx);
+    if let Some(page_table) = task.page_table.as_mut() {
+        mmap.unmap(page_table);
+    }
+    0
+}
@@ -0,0 +1,345 @@
+//! System calls from user space.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub use ::syscall::{console_getchar, console_putchar, set_timer, shutdown};
+use ::syscall::{
+    Errno, SYSCALL_CLOSE, SYSCALL_DUP, SYSCALL_DUP2, SYSCALL_EXIT, SYSCALL_FORK, SYSCALL_GETDENTS,
+    SYSCALL_GETPID, SYSCALL_GETPPID, SYSCALL_MMAP, SYSCALL_MUNMAP, SYSCALL_OPEN, SYSCALL_PIPE,
+    SYSCALL_READ, SYSCALL_SLEEP, SYSCALL_SYSINFO, SYSCALL_WRITE,
+};
+use log::{trace, warn};
+
+use self::{
+    fs::{sys_close, sys_dup, sys_dup2, sys_getdents, sys_open, sys_pipe, sys_read, sys_write},
+    mm::{sys_mmap, sys_munmap},
+    process::{sys_exit, sys_fork, sys_getpid, sys_getppid, sys_sleep_ms, sys_sysinfo},
+};
+use crate::proc::Task;
+
+mod fs;
+mod mm;
+mod process;
+
+/// The number of syscall ids counted.
+pub const NR_SYSCALLS: usize = 256;
+
+/// The system calls made, per id.
+static SYSCALL_COUNTS: [AtomicUsize; NR_SYSCALLS] = [const { AtomicUsize::new(0) }; NR_SYSCALLS];
+
+/// Returns the number of calls to the syscall `id`.
+pub fn syscall_count(id: usize) -> usize {
+    SYSCALL_COUNTS
+        .get(id)
+        .map_or(0, |n| n.load(Ordering::Relaxed))
+}
+
+/// Dispatches the system call `id` made by `task`.
+///
+/// Returns the value to be put in the user `a0`.
+pub fn dispatch(task: &mut Task, id: usize, args: [usize; 6]) -> isize {
+    trace!("syscall: task {} calls {} with {:?}", task.pid, id, args);
+    if let Some(n) = SYSCALL_COUNTS.get(id) {
+        n.fetch_add(1, Ordering::Relaxed);
+    }
+    match id {
+        SYSCALL_OPEN => sys_open(task, args[0], args[1], args[2] as u32),
+        SYSCALL_DUP => sys_dup(task, args[0]),
+        SYSCALL_DUP2 => sys_dup2(task, args[0], args[1]),
+        SYSCALL_PIPE => sys_pipe(task, args[0]),
+        SYSCALL_GETDENTS => sys_getdents(task, args[0], args[1], args[2]),
+        SYSCALL_CLOSE => sys_close(task, args[0]),
+        SYSCALL_READ => sys_read(task, args[0], args[1], args[2]),
+        SYSCALL_WRITE => sys_write(task, args[0], args[1], args[2]),
+        SYSCALL_MMAP => {
+            sys_mmap(task, args[0], args[1], args[2] as u32, args[3] as u32, args[4], args[5])
+        }
+        SYSCALL_MUNMAP => sys_munmap(task, args[0], args[1]),
+        SYSCALL_EXIT => sys_exit(task, args[0] as i32),
+        SYSCALL_FORK => sys_fork(task),
+        SYSCALL_GETPID => sys_getpid(task),
+        SYSCALL_GETPPID => sys_getppid(task),
+        SYSCALL_SLEEP => sys_sleep_ms(task, args[0]),
+        SYSCALL_SYSINFO => sys_sysinfo(task, args[0]),
+        _ => {
+            warn!("syscall: unsupported syscall: {}", id);
+            Errno::ENOSYS.as_ret()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{
+        string::{String, ToString},
+        sync::Arc,
+        vec::Vec,
+    };
+
+    use ::fs::{block_dev::InodeType, ram_disk::RamDisk, FileSystem};
+    use ::syscall::{Dirent, SysInfo, DT_REG, MAP_PRIVATE, PROT_READ};
+
+    use super::*;
+    use crate::{
+        file::{InodeFile, OpenFile},
+        intr::{interrupts, wait_for_interrupt},
+        mem::PAGE_SIZE,
+        proc::{State, TaskList, MMAP_BASE},
+    };
+
+    fn user_main() -> i32 {
+        42
+    }
+
+    /// Creates a file system in memory, not to leave the files of the
+    /// tests on the root file system.
+    fn ram_fs() -> Arc<FileSystem> {
+        FileSystem::create(Arc::new(RamDisk::new(256)), 256, 16).unwrap()
+    }
+
+    #[test_case]
+    fn test_exit() {
+        let mut tasks = TaskList::new();
+        let task_lock = tasks.new_task().unwrap().clone();
+        let mut task = task_lock.write();
+
+        // Same as what `_start` passes to `sys_exit` in user space.
+        dispatch(&mut task, SYSCALL_EXIT, [user_main() as usize, 0, 0, 0, 0, 0]);
+        assert!(task.state == State::Exited(42));
+        assert!(task.page_table.is_none());
+    }
+
+    #[test_case]
+    fn test_exit_negative_code() {
+        let mut tasks = TaskList::new();
+        let task_lock = tasks.new_task().unwrap().clone();
+        let mut task = task_lock.write();
+
+        dispatch(&mut task, SYSCALL_EXIT, [-1i32 as usize, 0, 0, 0, 0, 0]);
+        assert!(task.state == State::Exited(-1));
+    }
+
+    #[test_case]
+    fn test_unsupported_syscall() {
+        let mut tasks = TaskList::new();
+        let task_lock = tasks.new_task().unwrap().clone();
+        let mut task = task_lock.write();
+
+        assert_eq!(dispatch(&mut task, usize::MAX, [0; 6]), Errno::ENOSYS.as_ret());
+    }
+
+    #[test_case]
+    fn test_first_task_parent() {
+        let mut tasks = TaskList::new();
+        let task_lock = tasks.new_task().unwrap().clone();
+        let mut task = task_lock.write();
+
+        assert_eq!(dispatch(&mut task, SYSCALL_GETPID, [0; 6]), 0);
+        assert_eq!(dispatch(&mut task, SYSCALL_GETPPID, [0; 6]), 0);
+    }
+
+    #[test_case]
+    fn test_console_fds() {
+        let mut tasks = TaskList::new();
+        let task_lock = tasks.new_task().unwrap().clone();
+        let task = task_lock.read();
+
+        let console = task.file(0).expect("no standard input");
+        assert!(matches!(console.as_ref(), OpenFile::Console));
+        for fd in 1..3 {
+            assert!(Arc::ptr_eq(task.file(fd).unwrap(), console));
+        }
+        assert_eq!(task.file(1).unwrap().write(b"console fd\n"), Ok(11));
+        assert!(task.file(3).is_none());
+    }
+
+    #[test_case]
+    fn test_getppid_after_fork() {
+        let mut tasks = TaskList::new();
+        let parent_lock = tasks.new_task().unwrap().clone();
+        let mut parent = parent_lock.write();
+        let child_lock = tasks.fork(&mut parent).unwrap().clone();
+        let mut child = child_lock.write();
+
+        let parent_pid = dispatch(&mut parent, SYSCALL_GETPID, [0; 6]);
+        assert_ne!(dispatch(&mut child, SYSCALL_GETPID, [0; 6]), parent_pid);
+        assert_eq!(dispatch(&mut child, SYSCALL_GETPPID, [0; 6]), parent_pid);
+        // Fork returns 0 in the child.
+        assert_eq!(child.trap_frame.a0, 0);
+    }
+
+    #[test_case]
+    fn test_dup_shares_offset() {
+        let mut tasks = TaskList::new();
+        let task_lock = tasks.new_task().unwrap().clone();
+        let mut task = task_lock.write();
+
+        let fs = ram_fs();
+        let inode = fs
+            .create_inode(&mut fs.root().lock(), "dup_test", InodeType::File)
+            .unwrap();
+        let file = OpenFile::Inode(InodeFile::new(inode, true, true));
+        let fd = task.alloc_fd(Arc::new(file)).unwrap();
+
+        let copy = dispatch(&mut task, SYSCALL_DUP, [fd, 0, 0, 0, 0, 0]);
+        assert_eq!(copy, fd as isize + 1);
+        let copy = copy as usize;
+        assert_eq!(task.file(copy).unwrap().write(b"dup"), Ok(3));
+
+        let offset = |task: &Task, fd| match task.file(fd).unwrap().as_ref() {
+            OpenFile::Inode(file) => file.offset(),
+            _ => unreachable!(),
+        };
+        assert_eq!(offset(&task, fd), 3);
+
+        // The original is still valid after the copy is closed.
+        assert_eq!(dispatch(&mut task, SYSCALL_CLOSE, [copy, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(task.file(fd).unwrap().write(b"!"), Ok(1));
+        assert_eq!(offset(&task, fd), 4);
+
+        assert_eq!(dispatch(&mut task, SYSCALL_DUP2, [fd, 5, 0, 0, 0, 0]), 5);
+        assert!(Arc::ptr_eq(task.file(5).unwrap(), task.file(fd).unwrap()));
+        assert_eq!(dispatch(&mut task, SYSCALL_DUP2, [copy, 6, 0, 0, 0, 0]), Errno::EBADF.as_ret());
+    }
+
+    #[test_case]
+    fn test_mmap_demand_paging() {
+        let mut tasks = TaskList::new();
+        let task_lock = tasks.new_task().unwrap().clone();
+        let mut task = task_lock.write();
+        task.init_user_page_table();
+
+        let pattern = |i: usize| (i % 251) as u8;
+        let fs = ram_fs();
+        let inode = fs
+            .create_inode(&mut fs.root().lock(), "mmap_test", InodeType::File)
+            .unwrap();
+        let data: Vec<u8> = (0..3 * PAGE_SIZE).map(pattern).collect();
+        assert_eq!(fs.write_inode(&mut inode.lock(), 0, &data).unwrap(), data.len());
+
+        let file = OpenFile::Inode(InodeFile::new(inode, true, false));
+        let fd = task.alloc_fd(Arc::new(file)).unwrap();
+        let args = [
+            0,
+            3 * PAGE_SIZE,
+            PROT_READ as usize,
+            MAP_PRIVATE as usize,
+            fd,
+            0,
+        ];
+        let addr = dispatch(&mut task, SYSCALL_MMAP, args);
+        assert_eq!(addr as usize, MMAP_BASE);
+        let addr = addr as usize;
+
+        // Nothing is loaded until the first access.
+        let mut byte = [0u8; 1];
+        let page_table = task.page_table.as_mut().unwrap();
+        assert!(page_table.copy_in(&mut byte, addr).is_err());
+
+        let va = addr + PAGE_SIZE + 100;
+        assert!(task.handle_page_fault(va));
+        assert!(!task.handle_page_fault(va), "the page is loaded already");
+        assert!(!task.handle_page_fault(addr + 3 * PAGE_SIZE));
+        assert_eq!(task.mmaps[0].loaded(), 1);
+
+        let page_table = task.page_table.as_mut().unwrap();
+        page_table.copy_in(&mut byte, va).unwrap();
+        assert_eq!(byte[0], pattern(PAGE_SIZE + 100));
+        assert!(page_table.copy_in(&mut byte, addr).is_err());
+        assert!(page_table.copy_in(&mut byte, addr + 2 * PAGE_SIZE).is_err());
+
+        assert_eq!(dispatch(&mut task, SYSCALL_MUNMAP, [addr, 3 * PAGE_SIZE, 0, 0, 0, 0]), 0);
+        assert!(task.mmaps.is_empty());
+        let page_table = task.page_table.as_mut().unwrap();
+        assert!(page_table.copy_in(&mut byte, va).is_err());
+    }
+
+    #[test_case]
+    fn test_sysinfo() {
+        let mut tasks = TaskList::new();
+        let task_lock = tasks.new_task().unwrap().clone();
+        let mut task = task_lock.write();
+        task.init_user_page_table();
+        let page_table = task.page_table.as_mut().unwrap();
+        page_table.user_vm_init(&alloc::vec![0u8; PAGE_SIZE]);
+
+        let calls = syscall_count(SYSCALL_GETPID);
+        for _ in 0..3 {
+            dispatch(&mut task, SYSCALL_GETPID, [0; 6]);
+        }
+        assert_eq!(syscall_count(SYSCALL_GETPID), calls + 3);
+
+        // The next timer tick at the latest.
+        let before = interrupts();
+        wait_for_interrupt();
+        assert!(interrupts() > before);
+
+        let calls = syscall_count(SYSCALL_SYSINFO);
+        assert_eq!(dispatch(&mut task, SYSCALL_SYSINFO, [8, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(syscall_count(SYSCALL_SYSINFO), calls + 1);
+
+        let mut info = SysInfo::default();
+        let buf = unsafe {
+            core::slice::from_raw_parts_mut(
+                &mut info as *mut SysInfo as *mut u8,
+                core::mem::size_of::<SysInfo>(),
+            )
+        };
+        task.page_table.as_mut().unwrap().copy_in(buf, 8).unwrap();
+        assert!(info.uptime_ms > 0);
+        assert!(info.interrupts > before);
+        assert!(info.free_mem > 0);
+
+        let bad = dispatch(&mut task, SYSCALL_SYSINFO, [PAGE_SIZE, 0, 0, 0, 0, 0]);
+        assert_eq!(bad, Errno::EFAULT.as_ret());
+    }
+
+    #[test_case]
+    fn test_getdents_chunks() {
+        let mut tasks = TaskList::new();
+        let task_lock = tasks.new_task().unwrap().clone();
+        let mut task = task_lock.write();
+        task.init_user_page_table();
+        let page_table = task.page_table.as_mut().unwrap();
+        page_table.user_vm_init(&alloc::vec![0u8; PAGE_SIZE]);
+
+        let fs = ram_fs();
+        let root = fs.root();
+        let expected: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+        for name in &expected {
+            fs.create_inode(&mut root.lock(), name, InodeType::File)
+                .unwrap();
+        }
+        let file = OpenFile::Inode(InodeFile::new(root.clone(), true, false));
+        let fd = task.alloc_fd(Arc::new(file)).unwrap();
+
+        let size = core::mem::size_of::<Dirent>();
+        let short = dispatch(&mut task, SYSCALL_GETDENTS, [fd, 0, size - 1, 0, 0, 0]);
+        assert_eq!(short, Errno::EINVAL.as_ret());
+
+        // Room for three entries and a part of the fourth.
+        let mut seen = Vec::new();
+        loop {
+            let n = dispatch(&mut task, SYSCALL_GETDENTS, [fd, 0, 3 * size + 5, 0, 0, 0]);
+            assert!(n >= 0 && n as usize % size == 0 && n as usize <= 3 * size);
+            if n == 0 {
+                break;
+            }
+            for i in 0..n as usize / size {
+                let mut dirent = Dirent::default();
+                let buf = unsafe {
+                    core::slice::from_raw_parts_mut(&mut dirent as *mut Dirent as *mut u8, size)
+                };
+                let page_table = task.page_table.as_mut().unwrap();
+                page_table.copy_in(buf, i * size).unwrap();
+                assert_eq!(dirent.type_, DT_REG);
+                seen.push(dirent.name().to_string());
+            }
+            // The last entry moves into the slot of one already listed.
+            if seen.len() == 3 {
+                fs.unlink(&mut root.lock(), &seen[0]).unwrap();
+            }
+        }
+        assert_eq!(seen, expected);
+    }
+}
@@ -0,0 +1,59 @@
+use core::{mem::size_of, slice::from_raw_parts};
+
+use ::syscall::{Errno, SysInfo};
+use log::warn;
+
+use crate::{
+    intr::{interrupts, ms_to_ticks, ticks, uptime_ms},
+    mem::{allocator::free_pages_num, PAGE_SIZE},
+    proc::{tasks, tasks_mut, Task},
+};
+
+/// Terminates `task` with the exit `code`.
+pub fn sys_exit(task: &mut Task, code: i32) -> isize {
+    tasks_mut().exit(task, code);
+    0
+}
+
+/// Creates a child of `task`, returns its pid.
+pub fn sys_fork(task: &mut Task) -> isize {
+    match tasks_mut().fork(task) {
+        Ok(child) => child.read().pid as isize,
+        Err(_) => Errno::EAGAIN.as_ret(),
+    }
+}
+
+pub fn sys_getpid(task: &Task) -> isize {
+    task.pid as isize
+}
+
+pub fn sys_getppid(task: &Task) -> isize {
+    task.parent as isize
+}
+
+/// Puts `task` to sleep for at least `ms` milliseconds.
+pub fn sys_sleep_ms(task: &mut Task, ms: usize) -> isize {
+    if ms > 0 {
+        tasks_mut().sleep(task, ticks() + ms_to_ticks(ms));
+    }
+    0
+}
+
+/// Puts the statistics of the system in the user `SysInfo` at `info`.
+pub fn sys_sysinfo(task: &mut Task, info: usize) -> isize {
+    let sysinfo = SysInfo {
+        uptime_ms:  uptime_ms(),
+        interrupts: interrupts(),
+        free_mem:   free_pages_num() * PAGE_SIZE,
+        procs:      tasks().len(),
+    };
+    let buf =
+        unsafe { from_raw_parts(&sysinfo as *const SysInfo as *const u8, size_of::<SysInfo>()) };
+    match task.page_table.as_mut().unwrap().copy_out(info, buf) {
+        Ok(_) => 0,
+        Err(err) => {
+            warn!("sys_sysinfo: {:?}", err);
+            Errno::EFAULT.as_ret()
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Error codes shared by the kernel and user space.
+//!
+//! A failed syscall returns the negated error number, like Linux.
+
+/// The reason a syscall failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(isize)]
+pub enum Errno {
+    /// Operation not permitted.
+    EPERM = 1,
+    /// No such file or directory.
+    ENOENT = 2,
+    /// No such process.
+    ESRCH = 3,
+    /// Bad file descriptor.
+    EBADF = 9,
+    /// Resource temporarily unavailable, the operation would block.
+    EAGAIN = 11,
+    /// Out of memory.
+    ENOMEM = 12,
+    /// Permission denied.
+    EACCES = 13,
+    /// Bad address.
+    EFAULT = 14,
+    /// File exists.
+    EEXIST = 17,
+    /// Not a directory.
+    ENOTDIR = 20,
+    /// Is a directory.
+    EISDIR = 21,
+    /// Invalid argument.
+    EINVAL = 22,
+    /// Too many open files.
+    EMFILE = 24,
+    /// File too large.
+    EFBIG = 27,
+    /// No space left on device.
+    ENOSPC = 28,
+    /// Broken pipe.
+    EPIPE = 32,
+    /// Function not implemented.
+    ENOSYS = 38,
+}
+
+impl Errno {
+    pub const ALL: [Errno; 17] = [
+        Errno::EPERM,
+        Errno::ENOENT,
+        Errno::ESRCH,
+        Errno::EBADF,
+        Errno::EAGAIN,
+        Errno::ENOMEM,
+        Errno::EACCES,
+        Errno::EFAULT,
+        Errno::EEXIST,
+        Errno::ENOTDIR,
+        Errno::EISDIR,
+        Errno::EINVAL,
+        Errno::EMFILE,
+        Errno::EFBIG,
+        Errno::ENOSPC,
+        Errno::EPIPE,
+        Errno::ENOSYS,
+    ];
+
+    /// Returns the value a failed syscall puts in `a0`.
+    pub const fn as_ret(self) -> isize {
+        -(self as isize)
+    }
+
+    /// Gets the error from the return value of a syscall, `None` if it
+    /// succeeded or the error number is unknown.
+    pub fn from_ret(ret: isize) -> Option<Self> {
+        Self::ALL.into_iter().find(|errno| errno.as_ret() == ret)
+    }
+}
+
+impl From<Errno> for isize {
+    fn from(errno: Errno) -> Self {
+        errno.as_ret()
+    }
+}
+
+#[cfg(feature = "fs")]
+impl From<fs::FileSystemAllocationError> for Errno {
+    fn from(err: fs::FileSystemAllocationError) -> Self {
+        use fs::FileSystemAllocationError::*;
+
+        match err {
+            Exhausted(_) | InodeExhausted | XattrFull(_) => Errno::ENOSPC,
+            AlreadyExist(..) => Errno::EEXIST,
+            TooLarge(_) => Errno::EFBIG,
+            InvalidName(_) => Errno::EINVAL,
+            NotFound(_) => Errno::ENOENT,
+            IsADirectory => Errno::EISDIR,
+        }
+    }
+}
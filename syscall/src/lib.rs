@@ -1,18 +1,29 @@
 #![no_std]
 
+mod errno;
 mod sbi;
 
+#[cfg(not(test))]
 use core::arch::asm;
 
+pub use errno::Errno;
 pub use sbi::{console_getchar, console_putchar, set_timer, shutdown};
 
-fn syscall(id: usize, args: [usize; 3]) -> isize {
+/// Traps into the kernel with `ecall`.
+///
+/// The kernel expects the syscall id in `a7`, the arguments in `a0`-`a5`,
+/// and puts the return value in `a0`.
+#[cfg(not(test))]
+fn syscall6(id: usize, args: [usize; 6]) -> isize {
     let mut ret: isize;
     unsafe {
         asm!("ecall",
             inlateout("x10") args[0] => ret,
             in("x11") args[1],
             in("x12") args[2],
+            in("x13") args[3],
+            in("x14") args[4],
+            in("x15") args[5],
             in("x17") id,
             options(nostack)
         )
@@ -20,13 +31,378 @@ fn syscall(id: usize, args: [usize; 3]) -> isize {
     ret
 }
 
+fn syscall(id: usize, args: [usize; 3]) -> isize {
+    syscall6(id, [args[0], args[1], args[2], 0, 0, 0])
+}
+
+// The syscall numbers follow Linux on RISC-V, shared by the kernel and
+// the user library.
+pub const SYSCALL_DUP: usize = 23;
+pub const SYSCALL_DUP2: usize = 24;
+pub const SYSCALL_OPEN: usize = 56;
+pub const SYSCALL_CLOSE: usize = 57;
+pub const SYSCALL_PIPE: usize = 59;
+pub const SYSCALL_GETDENTS: usize = 61;
+pub const SYSCALL_READ: usize = 63;
 pub const SYSCALL_WRITE: usize = 64;
+pub const SYSCALL_EXIT: usize = 93;
+pub const SYSCALL_SLEEP: usize = 101;
+pub const SYSCALL_YIELD: usize = 124;
 pub const SYSCALL_TIME: usize = 169;
+pub const SYSCALL_GETPID: usize = 172;
+pub const SYSCALL_GETPPID: usize = 173;
+pub const SYSCALL_SYSINFO: usize = 179;
+pub const SYSCALL_MUNMAP: usize = 215;
+pub const SYSCALL_FORK: usize = 220;
+pub const SYSCALL_EXEC: usize = 221;
+pub const SYSCALL_MMAP: usize = 222;
+
+/// Open for reading only.
+pub const O_RDONLY: u32 = 0;
+/// Open for writing only.
+pub const O_WRONLY: u32 = 1 << 0;
+/// Open for reading and writing.
+pub const O_RDWR: u32 = 1 << 1;
+/// Create the file if it doesn't exist.
+pub const O_CREATE: u32 = 1 << 9;
+/// Truncate the file to zero length.
+pub const O_TRUNC: u32 = 1 << 10;
+/// Don't block on opening or accessing the file.
+pub const O_NONBLOCK: u32 = 1 << 11;
+
+/// Pages may be read.
+pub const PROT_READ: u32 = 1 << 0;
+/// Pages may be written.
+pub const PROT_WRITE: u32 = 1 << 1;
+/// Pages may be executed.
+pub const PROT_EXEC: u32 = 1 << 2;
+
+/// Writes to the mapping are carried to the file.
+pub const MAP_SHARED: u32 = 1 << 0;
+/// Writes to the mapping are private to the process.
+pub const MAP_PRIVATE: u32 = 1 << 1;
+
+/// The statistics of the system, filled by `sys_sysinfo`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SysInfo {
+    /// Milliseconds since boot.
+    pub uptime_ms: usize,
+    /// Timer and external interrupts handled since boot.
+    pub interrupts: usize,
+    /// Free memory in bytes.
+    pub free_mem: usize,
+    /// Number of processes.
+    pub procs: usize,
+}
+
+/// The longest name in a `Dirent`, which makes it 40 bytes without
+/// padding.
+pub const DIRENT_NAME_MAX: usize = 30;
+
+/// Unknown file type.
+pub const DT_UNKNOWN: u8 = 0;
+/// Named pipe.
+pub const DT_FIFO: u8 = 1;
+/// Directory.
+pub const DT_DIR: u8 = 4;
+/// Regular file.
+pub const DT_REG: u8 = 8;
+
+/// A directory entry filled by `sys_getdents`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Dirent {
+    pub ino: u64,
+    /// One of the `DT_*` types.
+    pub type_: u8,
+    pub name_len: u8,
+    pub name: [u8; DIRENT_NAME_MAX],
+}
+
+impl Dirent {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("")
+    }
+}
+
+pub fn sys_open(path: &str, flags: u32) -> isize {
+    syscall(
+        SYSCALL_OPEN,
+        [path.as_ptr() as usize, path.len(), flags as usize],
+    )
+}
+
+pub fn sys_close(fd: usize) -> isize {
+    syscall(SYSCALL_CLOSE, [fd, 0, 0])
+}
+
+pub fn sys_dup(fd: usize) -> isize {
+    syscall(SYSCALL_DUP, [fd, 0, 0])
+}
+
+pub fn sys_dup2(old: usize, new: usize) -> isize {
+    syscall(SYSCALL_DUP2, [old, new, 0])
+}
+
+pub fn sys_pipe(fds: &mut [i32; 2]) -> isize {
+    syscall(SYSCALL_PIPE, [fds.as_mut_ptr() as usize, 0, 0])
+}
+
+pub fn sys_getdents(fd: usize, dirents: &mut [Dirent]) -> isize {
+    let len = core::mem::size_of_val(dirents);
+    syscall(SYSCALL_GETDENTS, [fd, dirents.as_mut_ptr() as usize, len])
+}
+
+pub fn sys_read(fd: usize, buffer: &mut [u8]) -> isize {
+    syscall(
+        SYSCALL_READ,
+        [fd, buffer.as_mut_ptr() as usize, buffer.len()],
+    )
+}
 
 pub fn sys_write(fd: usize, buffer: &[u8]) -> isize {
     syscall(SYSCALL_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()])
 }
 
+pub fn sys_exit(code: i32) -> ! {
+    syscall(SYSCALL_EXIT, [code as usize, 0, 0]);
+    unreachable!("sys_exit never returns")
+}
+
+pub fn sys_yield() -> isize {
+    syscall(SYSCALL_YIELD, [0; 3])
+}
+
+pub fn sys_sleep_ms(ms: usize) -> isize {
+    syscall(SYSCALL_SLEEP, [ms, 0, 0])
+}
+
 pub fn sys_time() -> isize {
     syscall(SYSCALL_TIME, [0; 3])
 }
+
+pub fn sys_getpid() -> isize {
+    syscall(SYSCALL_GETPID, [0; 3])
+}
+
+pub fn sys_getppid() -> isize {
+    syscall(SYSCALL_GETPPID, [0; 3])
+}
+
+pub fn sys_sysinfo(info: &mut SysInfo) -> isize {
+    syscall(SYSCALL_SYSINFO, [info as *mut SysInfo as usize, 0, 0])
+}
+
+pub fn sys_fork() -> isize {
+    syscall(SYSCALL_FORK, [0; 3])
+}
+
+pub fn sys_exec(path: &str) -> isize {
+    syscall(SYSCALL_EXEC, [path.as_ptr() as usize, path.len(), 0])
+}
+
+pub fn sys_mmap(addr: usize, len: usize, prot: u32, flags: u32, fd: usize, offset: usize) -> isize {
+    syscall6(
+        SYSCALL_MMAP,
+        [addr, len, prot as usize, flags as usize, fd, offset],
+    )
+}
+
+pub fn sys_munmap(addr: usize, len: usize) -> isize {
+    syscall(SYSCALL_MUNMAP, [addr, len, 0])
+}
+
+#[cfg(test)]
+extern crate std;
+
+/// Records the registers instead of trapping, so that the marshalling
+/// can be checked on the host.
+#[cfg(test)]
+fn syscall6(id: usize, args: [usize; 6]) -> isize {
+    tests::LAST_CALL.with(|call| call.set(Some((id, args))));
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    std::thread_local! {
+        pub static LAST_CALL: Cell<Option<(usize, [usize; 6])>> = const { Cell::new(None) };
+    }
+
+    fn last_call6() -> (usize, [usize; 6]) {
+        LAST_CALL
+            .with(|call| call.take())
+            .expect("no syscall issued")
+    }
+
+    fn last_call() -> (usize, [usize; 3]) {
+        let (id, args) = last_call6();
+        assert_eq!(args[3..], [0; 3]);
+        (id, [args[0], args[1], args[2]])
+    }
+
+    #[test]
+    fn test_marshalling() {
+        let path = "/bin/hello";
+        sys_open(path, O_RDWR | O_CREATE);
+        assert_eq!(
+            last_call(),
+            (
+                SYSCALL_OPEN,
+                [
+                    path.as_ptr() as usize,
+                    path.len(),
+                    (O_RDWR | O_CREATE) as usize
+                ]
+            )
+        );
+
+        let mut buf = [0u8; 16];
+        sys_read(3, &mut buf);
+        assert_eq!(
+            last_call(),
+            (SYSCALL_READ, [3, buf.as_ptr() as usize, buf.len()])
+        );
+
+        let buf = [0u8; 8];
+        sys_write(1, &buf);
+        assert_eq!(
+            last_call(),
+            (SYSCALL_WRITE, [1, buf.as_ptr() as usize, buf.len()])
+        );
+
+        sys_close(3);
+        assert_eq!(last_call(), (SYSCALL_CLOSE, [3, 0, 0]));
+
+        sys_dup(3);
+        assert_eq!(last_call(), (SYSCALL_DUP, [3, 0, 0]));
+
+        sys_dup2(3, 1);
+        assert_eq!(last_call(), (SYSCALL_DUP2, [3, 1, 0]));
+
+        let mut fds = [0i32; 2];
+        sys_pipe(&mut fds);
+        assert_eq!(last_call(), (SYSCALL_PIPE, [fds.as_ptr() as usize, 0, 0]));
+
+        let mut dirents = [Dirent::default(); 4];
+        sys_getdents(3, &mut dirents);
+        let len = 4 * core::mem::size_of::<Dirent>();
+        assert_eq!(
+            last_call(),
+            (SYSCALL_GETDENTS, [3, dirents.as_ptr() as usize, len])
+        );
+
+        sys_fork();
+        assert_eq!(last_call(), (SYSCALL_FORK, [0; 3]));
+
+        sys_exec(path);
+        assert_eq!(
+            last_call(),
+            (SYSCALL_EXEC, [path.as_ptr() as usize, path.len(), 0])
+        );
+
+        sys_yield();
+        assert_eq!(last_call(), (SYSCALL_YIELD, [0; 3]));
+
+        sys_sleep_ms(250);
+        assert_eq!(last_call(), (SYSCALL_SLEEP, [250, 0, 0]));
+
+        sys_getpid();
+        assert_eq!(last_call(), (SYSCALL_GETPID, [0; 3]));
+
+        sys_getppid();
+        assert_eq!(last_call(), (SYSCALL_GETPPID, [0; 3]));
+
+        let mut info = SysInfo::default();
+        sys_sysinfo(&mut info);
+        assert_eq!(
+            last_call(),
+            (SYSCALL_SYSINFO, [&info as *const SysInfo as usize, 0, 0])
+        );
+
+        sys_mmap(0, 8192, PROT_READ, MAP_PRIVATE, 3, 4096);
+        assert_eq!(
+            last_call6(),
+            (
+                SYSCALL_MMAP,
+                [0, 8192, PROT_READ as usize, MAP_PRIVATE as usize, 3, 4096]
+            )
+        );
+
+        sys_munmap(0x1000, 8192);
+        assert_eq!(last_call(), (SYSCALL_MUNMAP, [0x1000, 8192, 0]));
+    }
+
+    #[test]
+    fn test_syscall_numbers() {
+        let numbers = [
+            (SYSCALL_DUP, 23),
+            (SYSCALL_DUP2, 24),
+            (SYSCALL_OPEN, 56),
+            (SYSCALL_CLOSE, 57),
+            (SYSCALL_PIPE, 59),
+            (SYSCALL_GETDENTS, 61),
+            (SYSCALL_READ, 63),
+            (SYSCALL_WRITE, 64),
+            (SYSCALL_EXIT, 93),
+            (SYSCALL_SLEEP, 101),
+            (SYSCALL_YIELD, 124),
+            (SYSCALL_TIME, 169),
+            (SYSCALL_GETPID, 172),
+            (SYSCALL_GETPPID, 173),
+            (SYSCALL_SYSINFO, 179),
+            (SYSCALL_MUNMAP, 215),
+            (SYSCALL_FORK, 220),
+            (SYSCALL_EXEC, 221),
+            (SYSCALL_MMAP, 222),
+        ];
+        for (id, expected) in numbers {
+            assert_eq!(id, expected);
+        }
+
+        sys_time();
+        assert_eq!(last_call(), (SYSCALL_TIME, [0; 3]));
+    }
+
+    #[test]
+    fn test_errno_round_trip() {
+        for errno in Errno::ALL {
+            assert!(errno.as_ret() < 0);
+            assert_eq!(isize::from(errno), errno.as_ret());
+            assert_eq!(Errno::from_ret(errno.as_ret()), Some(errno));
+        }
+        assert_eq!(Errno::from_ret(0), None);
+        assert_eq!(Errno::from_ret(3), None);
+        assert_eq!(Errno::from_ret(-1000), None);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_errno_from_fs_error() {
+        use std::string::ToString;
+
+        use fs::{block_dev::InodeType, FileSystemAllocationError::*};
+
+        let cases = [
+            (Exhausted(4096), Errno::ENOSPC),
+            (InodeExhausted, Errno::ENOSPC),
+            (
+                AlreadyExist("a".to_string(), InodeType::File),
+                Errno::EEXIST,
+            ),
+            (TooLarge(usize::MAX), Errno::EFBIG),
+            (InvalidName("/a".to_string()), Errno::EINVAL),
+            (XattrFull(4099), Errno::ENOSPC),
+            (IsADirectory, Errno::EISDIR),
+        ];
+        for (err, errno) in cases {
+            let ret = Errno::from(err).as_ret();
+            assert_eq!(Errno::from_ret(ret), Some(errno));
+        }
+    }
+}
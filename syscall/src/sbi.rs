@@ -4,6 +4,7 @@
 
 #![allow(unused)]
 
+#[cfg(not(test))]
 use core::arch::asm;
 
 pub const SBI_SET_TIMER: usize = 0;
@@ -16,6 +17,7 @@ pub const SBI_REMOTE_SFENCE_VMA: usize = 6;
 pub const SBI_REMOTE_SFENCE_VMA_ASID: usize = 7;
 pub const SBI_SHUTDOWN: usize = 8;
 
+#[cfg(not(test))]
 #[inline(always)]
 fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     let ret;
@@ -31,8 +33,17 @@ fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     ret
 }
 
-pub fn console_putchar(c: u8) {
-    sbi_call(SBI_CONSOLE_PUTCHAR, c as usize, 0, 0);
+#[cfg(test)]
+fn sbi_call(_which: usize, _arg0: usize, _arg1: usize, _arg2: usize) -> usize {
+    0
+}
+
+/// Prints `c` on the console, fails with the error code of the SBI.
+pub fn console_putchar(c: u8) -> Result<(), isize> {
+    match sbi_call(SBI_CONSOLE_PUTCHAR, c as usize, 0, 0) as isize {
+        0 => Ok(()),
+        err => Err(err),
+    }
 }
 
 pub fn console_getchar() -> usize {
@@ -1,6 +1,6 @@
 use core::fmt::{self, Write};
 
-use syscall::sys_write;
+use crate::sys::write;
 
 struct Stdout;
 
@@ -8,13 +8,16 @@ const STDOUT: usize = 1;
 
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        sys_write(STDOUT, s.as_bytes());
-        Ok(())
+        write(STDOUT, s.as_bytes())
+            .map(|_| ())
+            .map_err(|_| fmt::Error)
     }
 }
 
+/// Prints formatted string by [`core::format_args!`], the output failed
+/// to write is dropped.
 pub fn _print(args: fmt::Arguments) {
-    Stdout.write_fmt(args).unwrap();
+    let _ = Stdout.write_fmt(args);
 }
 
 #[macro_export]
@@ -0,0 +1,24 @@
+//! The arguments the program is started with.
+
+use core::{
+    ffi::{c_char, CStr},
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const c_char> = AtomicPtr::new(null_mut());
+
+/// Keeps `argc` and `argv` put on the stack by the kernel.
+pub(crate) unsafe fn init(argc: usize, argv: *const *const c_char) {
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv as *mut _, Ordering::Relaxed);
+}
+
+/// Returns the arguments of the program, the first is its name by
+/// convention.
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let argv = ARGV.load(Ordering::Relaxed);
+    (0..ARGC.load(Ordering::Relaxed))
+        .map(move |i| unsafe { CStr::from_ptr(*argv.add(i)).to_str().unwrap_or("") })
+}
@@ -1,17 +1,19 @@
 #![no_std]
 #![feature(linkage)]
 
-use core::panic::PanicInfo;
+use core::{ffi::c_char, panic::PanicInfo};
 
 extern crate syscall;
 
 pub mod console;
+pub mod env;
+pub mod sys;
 
 #[no_mangle]
 #[link_section = ".text.entry"]
-pub extern "C" fn _start() -> ! {
-    main();
-    panic!()
+pub extern "C" fn _start(argc: usize, argv: *const *const c_char) -> ! {
+    unsafe { env::init(argc, argv) };
+    sys::exit(main())
 }
 
 #[no_mangle]
@@ -0,0 +1,152 @@
+//! Thin wrappers over the raw system calls.
+
+use syscall::{
+    sys_close, sys_dup, sys_dup2, sys_exec, sys_exit, sys_fork, sys_getdents, sys_getpid,
+    sys_getppid, sys_mmap, sys_munmap, sys_open, sys_pipe, sys_read, sys_sleep_ms, sys_sysinfo,
+    sys_write, sys_yield,
+};
+pub use syscall::{
+    Dirent, Errno, SysInfo, DT_DIR, DT_FIFO, DT_REG, DT_UNKNOWN, MAP_PRIVATE, MAP_SHARED, O_CREATE,
+    O_NONBLOCK, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, PROT_EXEC, PROT_READ, PROT_WRITE,
+};
+
+/// The error of a failed system call, holding the negative return value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error(isize);
+
+impl Error {
+    pub fn code(&self) -> isize {
+        self.0
+    }
+
+    /// Returns the error number, `None` if the kernel returned an
+    /// unknown one.
+    pub fn errno(&self) -> Option<Errno> {
+        Errno::from_ret(self.0)
+    }
+}
+
+impl From<Errno> for Error {
+    fn from(errno: Errno) -> Self {
+        Error(errno.as_ret())
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+fn cvt(ret: isize) -> Result<usize> {
+    if ret < 0 {
+        Err(Error(ret))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Opens the file at `path`, returns the file descriptor.
+pub fn open(path: &str, flags: u32) -> Result<usize> {
+    cvt(sys_open(path, flags))
+}
+
+/// Reads from `fd` into `buf`, returns the number of bytes read.
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize> {
+    cvt(sys_read(fd, buf))
+}
+
+/// Writes `buf` to `fd`, returns the number of bytes written.
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize> {
+    cvt(sys_write(fd, buf))
+}
+
+/// Reads the next entries of the directory `fd` into `dirents`, returns
+/// the number of entries read, 0 at the end.
+pub fn getdents(fd: usize, dirents: &mut [Dirent]) -> Result<usize> {
+    let n = cvt(sys_getdents(fd, dirents))?;
+    Ok(n / core::mem::size_of::<Dirent>())
+}
+
+pub fn close(fd: usize) -> Result<()> {
+    cvt(sys_close(fd)).map(|_| ())
+}
+
+/// Duplicates `fd` to the lowest free fd.
+pub fn dup(fd: usize) -> Result<usize> {
+    cvt(sys_dup(fd))
+}
+
+/// Makes `new` refer to the same file as `old`, closing `new` first.
+pub fn dup2(old: usize, new: usize) -> Result<usize> {
+    cvt(sys_dup2(old, new))
+}
+
+/// Creates a pipe, returns its read fd and write fd.
+pub fn pipe() -> Result<(usize, usize)> {
+    let mut fds = [0i32; 2];
+    cvt(sys_pipe(&mut fds))?;
+    Ok((fds[0] as usize, fds[1] as usize))
+}
+
+/// Maps `len` bytes of `fd` from `offset` at `addr`, or where the
+/// kernel picks if `addr` is 0. Returns the start of the mapping.
+pub fn mmap(
+    addr: usize,
+    len: usize,
+    prot: u32,
+    flags: u32,
+    fd: usize,
+    offset: usize,
+) -> Result<usize> {
+    cvt(sys_mmap(addr, len, prot, flags, fd, offset))
+}
+
+/// Removes the mapping created by `mmap`.
+pub fn munmap(addr: usize, len: usize) -> Result<()> {
+    cvt(sys_munmap(addr, len)).map(|_| ())
+}
+
+/// Terminates the current process with `code`.
+pub fn exit(code: i32) -> ! {
+    sys_exit(code)
+}
+
+/// Creates a child process, returns 0 in the child and
+/// the child's pid in the parent.
+pub fn fork() -> Result<usize> {
+    cvt(sys_fork())
+}
+
+/// Returns the pid of the current process.
+pub fn getpid() -> usize {
+    sys_getpid() as usize
+}
+
+/// Returns the pid of the parent process.
+pub fn getppid() -> usize {
+    sys_getppid() as usize
+}
+
+/// Replaces the current process with the program at `path`.
+///
+/// Only returns on failure.
+pub fn exec(path: &str) -> Error {
+    match cvt(sys_exec(path)) {
+        Ok(_) => unreachable!("exec returned without error"),
+        Err(err) => err,
+    }
+}
+
+/// Gives up the CPU to other processes.
+pub fn yield_now() {
+    sys_yield();
+}
+
+/// Sleeps for at least `ms` milliseconds.
+pub fn sleep_ms(ms: usize) {
+    sys_sleep_ms(ms);
+}
+
+/// Returns the statistics of the system.
+pub fn sysinfo() -> Result<SysInfo> {
+    let mut info = SysInfo::default();
+    cvt(sys_sysinfo(&mut info))?;
+    Ok(info)
+}